@@ -0,0 +1,690 @@
+use std::{
+    mem,
+    hash::{
+        Hash,
+    },
+    collections::{
+        HashMap,
+        BTreeMap,
+    },
+};
+
+use crate::{
+    set::{
+        Ref,
+        Set,
+    },
+    dll::{
+        List,
+    },
+};
+
+pub type LruCache<K, V> = Cache<K, V, Lru>;
+pub type LfuCache<K, V> = Cache<K, V, Lfu>;
+pub type ArcCache<K, V> = Cache<K, V, Adaptive<K>>;
+
+// Replacement order of cache entries. Entries are identified by refs into the cache storage, `Meta`
+// is kept along with each entry for the policy to find it in its lists.
+pub trait Policy<K> {
+    type Meta;
+
+    fn new(capacity: usize) -> Self;
+
+    // Called for a key missing from the cache before room is made for it.
+    fn admit(&mut self, _key: &K) {
+    }
+
+    // Links a new entry.
+    fn insert(&mut self, entry_ref: Ref) -> Self::Meta;
+
+    // Entry is accessed.
+    fn touch(&mut self, meta: &mut Self::Meta);
+
+    // Entry leaves the replacement order: it is removed or pinned.
+    fn detach(&mut self, meta: &mut Self::Meta);
+
+    // Pinned entry is back in the replacement order.
+    fn attach(&mut self, entry_ref: Ref, meta: &mut Self::Meta);
+
+    // Unlinks the entry to evict next, none if all entries are detached.
+    fn victim(&mut self) -> Option<Ref>;
+
+    // Victim entry of `key` has left the cache.
+    fn evicted(&mut self, _key: &K) {
+    }
+}
+
+// Key-value cache with a size and an optional weight limit. Lookups go through a hash index of refs
+// into a `Set` of entries, so every operation but eviction is O(1) for LRU and ARC.
+//
+// Pinned entries are never evicted. When all entries are pinned the cache grows over its limits
+// until some are unpinned or removed.
+pub struct Cache<K, V, P> where P: Policy<K> {
+    entries: Set<CacheEntry<K, V, P::Meta>>,
+    index: HashMap<K, Ref>,
+    policy: P,
+    capacity: usize,
+    weight_limit: usize,
+    weight: usize,
+    hits: u64,
+    misses: u64,
+    on_evict: Option<Box<dyn FnMut(K, V)>>,
+}
+
+struct CacheEntry<K, V, M> {
+    key: K,
+    value: V,
+    weight: usize,
+    pinned: bool,
+    meta: M,
+}
+
+impl<K, V, P> Cache<K, V, P> where K: Clone + Eq + Hash, P: Policy<K> {
+    // A cache holding up to `capacity` entries.
+    pub fn new(capacity: usize) -> Cache<K, V, P> {
+        Cache {
+            entries: Set::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            policy: P::new(capacity),
+            capacity,
+            weight_limit: usize::MAX,
+            weight: 0,
+            hits: 0,
+            misses: 0,
+            on_evict: None,
+        }
+    }
+
+    // Limits the total weight of entries, see `put_weighted`.
+    pub fn set_weight_limit(&mut self, weight_limit: usize) {
+        self.weight_limit = weight_limit;
+        self.evict_over_limits();
+    }
+
+    // Receives evicted entries (not the removed ones).
+    pub fn set_on_evict<F>(&mut self, on_evict: F) where F: FnMut(K, V) + 'static {
+        self.on_evict = Some(Box::new(on_evict));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Total weight of entries.
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    // Lookups by `get` and `get_mut` which found the key.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    // Value of `key` without updating the replacement order.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let entry_ref = *self.index.get(key)?;
+        self.entries.get(entry_ref).map(|entry| &entry.value)
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let entry = match self.index.get(key).and_then(|&entry_ref| self.entries.get_mut(entry_ref)) {
+            Some(entry) =>
+                entry,
+            None => {
+                self.misses += 1;
+                return None;
+            },
+        };
+        self.hits += 1;
+        self.policy.touch(&mut entry.meta);
+        Some(&mut entry.value)
+    }
+
+    // Stores an entry of weight one returning the value it replaced.
+    pub fn put(&mut self, key: K, value: V) -> Option<V> {
+        self.put_weighted(key, value, 1)
+    }
+
+    // Stores an entry evicting others to fit the limits. An entry heavier than the weight limit is
+    // evicted right away.
+    pub fn put_weighted(&mut self, key: K, value: V, weight: usize) -> Option<V> {
+        if let Some(&entry_ref) = self.index.get(&key) {
+            let entry = self.entries.get_mut(entry_ref)?;
+            let prev_value = mem::replace(&mut entry.value, value);
+            self.weight = self.weight - entry.weight + weight;
+            entry.weight = weight;
+            self.policy.touch(&mut entry.meta);
+            self.evict_over_limits();
+            return Some(prev_value);
+        }
+        if self.capacity == 0 || weight > self.weight_limit {
+            if let Some(on_evict) = self.on_evict.as_mut() {
+                on_evict(key, value);
+            }
+            return None;
+        }
+        self.policy.admit(&key);
+        while self.entries.len() >= self.capacity || self.weight.saturating_add(weight) > self.weight_limit {
+            if !self.evict_next() {
+                break;
+            }
+        }
+        let policy = &mut self.policy;
+        let entry_ref = self.entries.insert_with(|entry_ref| {
+            let meta = policy.insert(entry_ref);
+            CacheEntry { key: key.clone(), value, weight, pinned: false, meta, }
+        });
+        self.index.insert(key, entry_ref);
+        self.weight += weight;
+        None
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry_ref = self.index.remove(key)?;
+        let mut entry = self.entries.remove(entry_ref)?;
+        if !entry.pinned {
+            self.policy.detach(&mut entry.meta);
+        }
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    // Protects an entry from eviction, false if the key is missing.
+    pub fn pin(&mut self, key: &K) -> bool {
+        match self.index.get(key).and_then(|&entry_ref| self.entries.get_mut(entry_ref)) {
+            Some(entry) => {
+                if !entry.pinned {
+                    entry.pinned = true;
+                    self.policy.detach(&mut entry.meta);
+                }
+                true
+            },
+            None =>
+                false,
+        }
+    }
+
+    // Makes a pinned entry evictable again, evicting entries if the cache is over its limits.
+    pub fn unpin(&mut self, key: &K) -> bool {
+        let entry_ref = match self.index.get(key) {
+            Some(&entry_ref) =>
+                entry_ref,
+            None =>
+                return false,
+        };
+        if let Some(entry) = self.entries.get_mut(entry_ref) {
+            if entry.pinned {
+                entry.pinned = false;
+                self.policy.attach(entry_ref, &mut entry.meta);
+            }
+        }
+        self.evict_over_limits();
+        true
+    }
+
+    pub fn is_pinned(&self, key: &K) -> bool {
+        self.index.get(key)
+            .and_then(|&entry_ref| self.entries.get(entry_ref))
+            .is_some_and(|entry| entry.pinned)
+    }
+
+    fn evict_over_limits(&mut self) {
+        while self.entries.len() > self.capacity || self.weight > self.weight_limit {
+            if !self.evict_next() {
+                break;
+            }
+        }
+    }
+
+    fn evict_next(&mut self) -> bool {
+        let entry = match self.policy.victim().and_then(|entry_ref| self.entries.remove(entry_ref)) {
+            Some(entry) =>
+                entry,
+            None =>
+                return false,
+        };
+        self.index.remove(&entry.key);
+        self.weight -= entry.weight;
+        self.policy.evicted(&entry.key);
+        if let Some(on_evict) = self.on_evict.as_mut() {
+            on_evict(entry.key, entry.value);
+        }
+        true
+    }
+}
+
+// Least recently used entries are evicted first.
+pub struct Lru {
+    // most recently used first
+    order: List<Ref>,
+}
+
+impl<K> Policy<K> for Lru {
+    // link in `order`, none for detached entries
+    type Meta = Option<Ref>;
+
+    fn new(capacity: usize) -> Lru {
+        Lru { order: List::with_capacity(capacity), }
+    }
+
+    fn insert(&mut self, entry_ref: Ref) -> Option<Ref> {
+        Some(self.order.prepend(entry_ref))
+    }
+
+    fn touch(&mut self, link: &mut Option<Ref>) {
+        if let Some(entry_ref) = link.and_then(|link_ref| self.order.remove(link_ref)) {
+            *link = Some(self.order.prepend(entry_ref));
+        }
+    }
+
+    fn detach(&mut self, link: &mut Option<Ref>) {
+        if let Some(link_ref) = link.take() {
+            self.order.remove(link_ref);
+        }
+    }
+
+    fn attach(&mut self, entry_ref: Ref, link: &mut Option<Ref>) {
+        *link = Some(self.order.prepend(entry_ref));
+    }
+
+    fn victim(&mut self) -> Option<Ref> {
+        self.order.pop_back()
+    }
+}
+
+// Least frequently used entries are evicted first, the least recently used one among equally
+// frequent entries. Entries are kept in lists per access count, eviction is O(log n) in the number
+// of distinct counts.
+pub struct Lfu {
+    buckets: BTreeMap<u64, List<Ref>>,
+}
+
+pub struct LfuMeta {
+    hits: u64,
+    link: Option<Ref>,
+}
+
+impl Lfu {
+    fn link(&mut self, entry_ref: Ref, meta: &mut LfuMeta) {
+        meta.link = Some(self.buckets.entry(meta.hits).or_default().prepend(entry_ref));
+    }
+
+    fn unlink(&mut self, meta: &mut LfuMeta) -> Option<Ref> {
+        let link_ref = meta.link.take()?;
+        let bucket = self.buckets.get_mut(&meta.hits)?;
+        let entry_ref = bucket.remove(link_ref);
+        if bucket.is_empty() {
+            self.buckets.remove(&meta.hits);
+        }
+        entry_ref
+    }
+}
+
+impl<K> Policy<K> for Lfu {
+    type Meta = LfuMeta;
+
+    fn new(_capacity: usize) -> Lfu {
+        Lfu { buckets: BTreeMap::new(), }
+    }
+
+    fn insert(&mut self, entry_ref: Ref) -> LfuMeta {
+        let mut meta = LfuMeta { hits: 1, link: None, };
+        self.link(entry_ref, &mut meta);
+        meta
+    }
+
+    fn touch(&mut self, meta: &mut LfuMeta) {
+        match self.unlink(meta) {
+            Some(entry_ref) => {
+                meta.hits += 1;
+                self.link(entry_ref, meta);
+            },
+            None =>
+                meta.hits += 1,
+        }
+    }
+
+    fn detach(&mut self, meta: &mut LfuMeta) {
+        self.unlink(meta);
+    }
+
+    fn attach(&mut self, entry_ref: Ref, meta: &mut LfuMeta) {
+        self.link(entry_ref, meta);
+    }
+
+    fn victim(&mut self) -> Option<Ref> {
+        let mut bucket = self.buckets.first_entry()?;
+        let entry_ref = bucket.get_mut().pop_back();
+        if bucket.get().is_empty() {
+            bucket.remove();
+        }
+        entry_ref
+    }
+}
+
+// Adaptive replacement cache (Megiddo and Modha, 2003): entries seen once and entries seen again
+// are kept in separate lists, the split between them adapts using ghost lists of recently evicted
+// keys.
+pub struct Adaptive<K> {
+    capacity: usize,
+    // target size of `recent`
+    target: usize,
+    recent: List<Ref>,
+    frequent: List<Ref>,
+    recent_ghosts: List<K>,
+    frequent_ghosts: List<K>,
+    // ghost links by key, true for `frequent_ghosts`
+    ghosts: HashMap<K, (bool, Ref)>,
+    // the key being admitted was found in `frequent_ghosts`
+    admitted_frequent_ghost: bool,
+    // the key being admitted was found in either ghost list
+    admitted_ghost: bool,
+    // the last victim was taken from `frequent`
+    victim_frequent: bool,
+}
+
+pub struct AdaptiveMeta {
+    frequent: bool,
+    link: Option<Ref>,
+}
+
+impl<K> Adaptive<K> where K: Clone + Eq + Hash {
+    fn list(&mut self, frequent: bool) -> &mut List<Ref> {
+        if frequent {
+            &mut self.frequent
+        } else {
+            &mut self.recent
+        }
+    }
+
+    fn drop_ghost(&mut self, frequent: bool) {
+        let ghosts = if frequent {
+            &mut self.frequent_ghosts
+        } else {
+            &mut self.recent_ghosts
+        };
+        if let Some(key) = ghosts.pop_back() {
+            self.ghosts.remove(&key);
+        }
+    }
+
+    // Keeps the directory of entries and ghosts within twice the capacity.
+    fn trim_ghosts(&mut self) {
+        while self.recent.len() + self.recent_ghosts.len() > self.capacity && !self.recent_ghosts.is_empty() {
+            self.drop_ghost(false);
+        }
+        let total = self.recent.len() + self.frequent.len() + self.recent_ghosts.len() + self.frequent_ghosts.len();
+        if total > 2 * self.capacity {
+            let frequent = !self.frequent_ghosts.is_empty();
+            self.drop_ghost(frequent);
+        }
+    }
+}
+
+impl<K> Policy<K> for Adaptive<K> where K: Clone + Eq + Hash {
+    type Meta = AdaptiveMeta;
+
+    fn new(capacity: usize) -> Adaptive<K> {
+        Adaptive {
+            capacity,
+            target: 0,
+            recent: List::new(),
+            frequent: List::new(),
+            recent_ghosts: List::new(),
+            frequent_ghosts: List::new(),
+            ghosts: HashMap::new(),
+            admitted_frequent_ghost: false,
+            admitted_ghost: false,
+            victim_frequent: false,
+        }
+    }
+
+    fn admit(&mut self, key: &K) {
+        let ghost = self.ghosts.remove(key);
+        self.admitted_ghost = ghost.is_some();
+        self.admitted_frequent_ghost = false;
+        match ghost {
+            Some((false, link_ref)) => {
+                self.recent_ghosts.remove(link_ref);
+                let delta = (self.frequent_ghosts.len() / (self.recent_ghosts.len() + 1)).max(1);
+                self.target = (self.target + delta).min(self.capacity);
+            },
+            Some((true, link_ref)) => {
+                self.frequent_ghosts.remove(link_ref);
+                let delta = (self.recent_ghosts.len() / (self.frequent_ghosts.len() + 1)).max(1);
+                self.target = self.target.saturating_sub(delta);
+                self.admitted_frequent_ghost = true;
+            },
+            None =>
+                (),
+        }
+    }
+
+    fn insert(&mut self, entry_ref: Ref) -> AdaptiveMeta {
+        // keys coming back from ghost lists have been seen before
+        let frequent = self.admitted_ghost;
+        self.admitted_ghost = false;
+        self.admitted_frequent_ghost = false;
+        let link = self.list(frequent).prepend(entry_ref);
+        self.trim_ghosts();
+        AdaptiveMeta { frequent, link: Some(link), }
+    }
+
+    fn touch(&mut self, meta: &mut AdaptiveMeta) {
+        match meta.link {
+            Some(link_ref) if meta.frequent => {
+                if let Some(entry_ref) = self.frequent.remove(link_ref) {
+                    meta.link = Some(self.frequent.prepend(entry_ref));
+                }
+            },
+            Some(link_ref) => {
+                if let Some(entry_ref) = self.recent.remove(link_ref) {
+                    meta.frequent = true;
+                    meta.link = Some(self.frequent.prepend(entry_ref));
+                }
+            },
+            None =>
+                meta.frequent = true,
+        }
+    }
+
+    fn detach(&mut self, meta: &mut AdaptiveMeta) {
+        if let Some(link_ref) = meta.link.take() {
+            self.list(meta.frequent).remove(link_ref);
+        }
+    }
+
+    fn attach(&mut self, entry_ref: Ref, meta: &mut AdaptiveMeta) {
+        meta.link = Some(self.list(meta.frequent).prepend(entry_ref));
+    }
+
+    fn victim(&mut self) -> Option<Ref> {
+        let recent_len = self.recent.len();
+        let from_recent = recent_len > 0
+            && (recent_len > self.target || (self.admitted_frequent_ghost && recent_len == self.target) || self.frequent.is_empty());
+        self.victim_frequent = !from_recent;
+        self.list(!from_recent).pop_back()
+    }
+
+    fn evicted(&mut self, key: &K) {
+        let frequent = self.victim_frequent;
+        let ghosts = if frequent {
+            &mut self.frequent_ghosts
+        } else {
+            &mut self.recent_ghosts
+        };
+        let link_ref = ghosts.prepend(key.clone());
+        self.ghosts.insert(key.clone(), (frequent, link_ref));
+        self.trim_ghosts();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        rc::{
+            Rc,
+        },
+        cell::{
+            RefCell,
+        },
+    };
+
+    use rand::{self, Rng};
+
+    use super::{
+        ArcCache,
+        LfuCache,
+        LruCache,
+    };
+
+    #[test]
+    fn lru() {
+        let evicted = Rc::new(RefCell::new(Vec::new()));
+        let mut cache = LruCache::new(3);
+        let sink = evicted.clone();
+        cache.set_on_evict(move |key, value| sink.borrow_mut().push((key, value)));
+        cache.put("a", 1);
+        cache.put("b", 2);
+        cache.put("c", 3);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.peek(&"b"), Some(&2));
+        cache.put("d", 4);
+        assert_eq!(evicted.borrow().as_slice(), &[("b", 2)]);
+        assert_eq!(cache.put("c", 30), Some(3));
+        assert!(cache.pin(&"a"));
+        cache.put("e", 5);
+        cache.put("f", 6);
+        assert_eq!(evicted.borrow().as_slice(), &[("b", 2), ("d", 4), ("c", 30)]);
+        assert!(cache.contains(&"a") && cache.is_pinned(&"a"));
+        assert_eq!(cache.remove(&"e"), Some(5));
+        assert_eq!(cache.remove(&"e"), None);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.pin(&"x"));
+    }
+
+    #[test]
+    fn weights_and_pinning() {
+        let mut cache = LruCache::new(10);
+        cache.put_weighted(1, "one", 4);
+        cache.put_weighted(2, "two", 4);
+        cache.set_weight_limit(10);
+        assert!(cache.pin(&1));
+        cache.put_weighted(3, "three", 4);
+        assert_eq!((cache.len(), cache.weight()), (2, 8));
+        assert!(!cache.contains(&2));
+        cache.put_weighted(4, "four", 20);
+        assert!(!cache.contains(&4));
+        assert_eq!(cache.put_weighted(1, "uno", 9), Some("one"));
+        assert!(!cache.contains(&3));
+        assert!(cache.pin(&1));
+        cache.set_weight_limit(5);
+        assert_eq!(cache.weight(), 9);
+        assert!(cache.unpin(&1));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn lfu() {
+        let mut cache = LfuCache::new(3);
+        cache.put("a", 1);
+        cache.put("b", 2);
+        cache.put("c", 3);
+        cache.get(&"a");
+        cache.get(&"a");
+        cache.get(&"c");
+        cache.put("d", 4);
+        assert!(!cache.contains(&"b"));
+        cache.put("e", 5);
+        assert!(!cache.contains(&"d"));
+        assert!(cache.pin(&"e"));
+        cache.put("f", 6);
+        assert!(!cache.contains(&"c"));
+        assert!(cache.contains(&"a") && cache.contains(&"e") && cache.contains(&"f"));
+    }
+
+    #[test]
+    fn arc_resists_scans() {
+        let mut cache = ArcCache::new(4);
+        for _ in 0 .. 2 {
+            for key in 0 .. 2 {
+                cache.put(key, key);
+                cache.get(&key);
+            }
+        }
+        for key in 100 .. 120 {
+            cache.put(key, key);
+        }
+        assert!(cache.contains(&0) && cache.contains(&1));
+        assert_eq!(cache.len(), 4);
+
+        // a key evicted after one use comes back without pushing the frequent ones out
+        assert!(cache.get(&117).is_none());
+        cache.put(117, 117);
+        assert!(cache.contains(&0) && cache.contains(&1) && cache.contains(&117));
+        assert_eq!(cache.get(&117), Some(&117));
+        assert_eq!((cache.hits(), cache.misses()), (5, 1));
+    }
+
+    #[test]
+    fn stress() {
+        let mut rng = rand::thread_rng();
+        let mut lru = LruCache::new(16);
+        let mut lfu = LfuCache::new(16);
+        let mut arc = ArcCache::new(16);
+        let mut lookups = 0;
+        for cache_weight_limit in [usize::MAX, 40] {
+            lru.set_weight_limit(cache_weight_limit);
+            lfu.set_weight_limit(cache_weight_limit);
+            arc.set_weight_limit(cache_weight_limit);
+            for _ in 0 .. 8192 {
+                let key = rng.gen_range(0 .. 64u32);
+                let weight = rng.gen_range(1 .. 6);
+                match rng.gen_range(0 .. 100) {
+                    0 ..= 49 => {
+                        lru.put_weighted(key, key, weight);
+                        lfu.put_weighted(key, key, weight);
+                        arc.put_weighted(key, key, weight);
+                    },
+                    50 ..= 89 => {
+                        lookups += 1;
+                        assert!(lru.get(&key).is_none_or(|&value| value == key));
+                        assert!(lfu.get(&key).is_none_or(|&value| value == key));
+                        assert!(arc.get(&key).is_none_or(|&value| value == key));
+                    },
+                    _ => {
+                        lru.remove(&key);
+                        lfu.remove(&key);
+                        arc.remove(&key);
+                    },
+                }
+                assert!(lru.len() <= 16 && lru.weight() <= cache_weight_limit);
+                assert!(lfu.len() <= 16 && lfu.weight() <= cache_weight_limit);
+                assert!(arc.len() <= 16 && arc.weight() <= cache_weight_limit);
+            }
+        }
+        assert_eq!(lru.hits() + lru.misses(), lookups);
+        assert_eq!(lfu.hits() + lfu.misses(), lookups);
+        assert_eq!(arc.hits() + arc.misses(), lookups);
+    }
+}
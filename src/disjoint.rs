@@ -0,0 +1,350 @@
+use std::{
+    iter,
+};
+
+use crate::{
+    set::{
+        Ref,
+        Set,
+    },
+};
+
+// Union-find over items stored in a `Set`: path compression and union by rank, with members of
+// every class linked in a circular list.
+//
+// A removed element keeps its place in the tree (without an item) while other elements still go
+// through it on the way to the root, so the classes are never split. Such elements are dropped as
+// soon as path compression or removals leave them without children.
+pub struct DisjointSets<T> {
+    set: Set<Element<T>>,
+    len: usize,
+    classes: usize,
+}
+
+struct Element<T> {
+    // none for removed elements kept in the tree
+    item: Option<T>,
+    // self for roots
+    parent: Ref,
+    rank: usize,
+    // number of elements having this one as parent
+    children: usize,
+    // number of members of the class, meaningful for roots only
+    members: usize,
+    // circular list of class members (not maintained for removed elements)
+    prev: Ref,
+    next: Ref,
+}
+
+impl<T> Default for DisjointSets<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> DisjointSets<T> {
+    pub fn new() -> DisjointSets<T> {
+        DisjointSets {
+            set: Set::new(),
+            len: 0,
+            classes: 0,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> DisjointSets<T> {
+        DisjointSets {
+            set: Set::with_capacity(capacity),
+            len: 0,
+            classes: 0,
+        }
+    }
+
+    // Number of elements.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Number of classes.
+    pub fn classes(&self) -> usize {
+        self.classes
+    }
+
+    // Adds an element in a class of its own.
+    pub fn make_set(&mut self, item: T) -> Ref {
+        self.len += 1;
+        self.classes += 1;
+        self.set.insert_with(|element_ref| Element {
+            item: Some(item),
+            parent: element_ref,
+            rank: 0,
+            children: 0,
+            members: 1,
+            prev: element_ref,
+            next: element_ref,
+        })
+    }
+
+    pub fn get(&self, element_ref: Ref) -> Option<&T> {
+        self.set.get(element_ref).and_then(|element| element.item.as_ref())
+    }
+
+    pub fn get_mut(&mut self, element_ref: Ref) -> Option<&mut T> {
+        self.set.get_mut(element_ref).and_then(|element| element.item.as_mut())
+    }
+
+    pub fn contains(&self, element_ref: Ref) -> bool {
+        self.get(element_ref).is_some()
+    }
+
+    // Representative of the class of an element, compressing the path to it. The representative
+    // identifies the class until the next `union` and may be a removed element.
+    pub fn find(&mut self, element_ref: Ref) -> Option<Ref> {
+        self.get(element_ref)?;
+        let mut path = Vec::new();
+        let mut cursor = element_ref;
+        loop {
+            let parent_ref = self.set.get(cursor)?.parent;
+            if parent_ref == cursor {
+                break;
+            }
+            path.push(cursor);
+            cursor = parent_ref;
+        }
+        let root_ref = cursor;
+        for &node_ref in path.iter() {
+            let parent_ref = self.set.get(node_ref)?.parent;
+            if parent_ref == root_ref {
+                continue;
+            }
+            self.set.get_mut(node_ref)?.parent = root_ref;
+            self.set.get_mut(parent_ref)?.children -= 1;
+            self.set.get_mut(root_ref)?.children += 1;
+        }
+        for node_ref in path {
+            self.drop_unused(node_ref);
+        }
+        Some(root_ref)
+    }
+
+    // Representative of the class of an element without compressing the path.
+    pub fn find_shared(&self, element_ref: Ref) -> Option<Ref> {
+        self.get(element_ref)?;
+        let mut cursor = element_ref;
+        loop {
+            let parent_ref = self.set.get(cursor)?.parent;
+            if parent_ref == cursor {
+                return Some(cursor);
+            }
+            cursor = parent_ref;
+        }
+    }
+
+    pub fn same(&mut self, a_ref: Ref, b_ref: Ref) -> bool {
+        match (self.find(a_ref), self.find(b_ref)) {
+            (Some(a_root_ref), Some(b_root_ref)) =>
+                a_root_ref == b_root_ref,
+            _ =>
+                false,
+        }
+    }
+
+    // Merges the classes of two elements returning the representative of the result.
+    pub fn union(&mut self, a_ref: Ref, b_ref: Ref) -> Option<Ref> {
+        let a_root_ref = self.find(a_ref)?;
+        let b_root_ref = self.find(b_ref)?;
+        if a_root_ref == b_root_ref {
+            return Some(a_root_ref);
+        }
+        let a_rank = self.set.get(a_root_ref)?.rank;
+        let b_rank = self.set.get(b_root_ref)?.rank;
+        let (root_ref, child_ref) = if a_rank < b_rank {
+            (b_root_ref, a_root_ref)
+        } else {
+            (a_root_ref, b_root_ref)
+        };
+        let child_members = self.set.get(child_ref)?.members;
+        self.set.get_mut(child_ref)?.parent = root_ref;
+        let root = self.set.get_mut(root_ref)?;
+        root.children += 1;
+        root.members += child_members;
+        if a_rank == b_rank {
+            root.rank += 1;
+        }
+        self.splice(a_ref, b_ref)?;
+        self.classes -= 1;
+        Some(root_ref)
+    }
+
+    // Number of members in the class of an element.
+    pub fn class_len(&self, element_ref: Ref) -> usize {
+        self.find_shared(element_ref)
+            .and_then(|root_ref| self.set.get(root_ref))
+            .map_or(0, |root| root.members)
+    }
+
+    // Members of the class of an element starting with the element itself.
+    pub fn members(&self, element_ref: Ref) -> impl Iterator<Item = (Ref, &T)> + '_ {
+        let start = self.get(element_ref).map(|_| element_ref);
+        let mut cursor = start;
+        iter::from_fn(move || {
+            let current_ref = cursor?;
+            let element = self.set.get(current_ref)?;
+            cursor = Some(element.next).filter(|&next_ref| Some(next_ref) != start);
+            element.item.as_ref().map(|item| (current_ref, item))
+        })
+    }
+
+    // Removes an element, the rest of its class stays together.
+    pub fn remove(&mut self, element_ref: Ref) -> Option<T> {
+        let root_ref = self.find(element_ref)?;
+        let element = self.set.get_mut(element_ref)?;
+        let item = element.item.take()?;
+        let (prev_ref, next_ref) = (element.prev, element.next);
+        if let Some(prev) = self.set.get_mut(prev_ref) {
+            prev.next = next_ref;
+        }
+        if let Some(next) = self.set.get_mut(next_ref) {
+            next.prev = prev_ref;
+        }
+        if let Some(root) = self.set.get_mut(root_ref) {
+            root.members -= 1;
+            if root.members == 0 {
+                self.classes -= 1;
+            }
+        }
+        self.len -= 1;
+        self.drop_unused(element_ref);
+        Some(item)
+    }
+
+    // Joins circular member lists of two elements from different classes.
+    fn splice(&mut self, a_ref: Ref, b_ref: Ref) -> Option<()> {
+        let a_next_ref = self.set.get(a_ref)?.next;
+        let b_next_ref = self.set.get(b_ref)?.next;
+        self.set.get_mut(a_ref)?.next = b_next_ref;
+        self.set.get_mut(b_next_ref)?.prev = a_ref;
+        self.set.get_mut(b_ref)?.next = a_next_ref;
+        self.set.get_mut(a_next_ref)?.prev = b_ref;
+        Some(())
+    }
+
+    // Drops removed elements no longer needed in the tree, walking towards the root.
+    fn drop_unused(&mut self, mut element_ref: Ref) {
+        while let Some(element) = self.set.get(element_ref) {
+            if element.item.is_some() || element.children > 0 {
+                return;
+            }
+            let parent_ref = element.parent;
+            self.set.remove(element_ref);
+            if parent_ref == element_ref {
+                return;
+            }
+            if let Some(parent) = self.set.get_mut(parent_ref) {
+                parent.children -= 1;
+            }
+            element_ref = parent_ref;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{self, Rng};
+
+    use super::{
+        DisjointSets,
+    };
+
+    #[test]
+    fn union_find_members() {
+        let mut sets = DisjointSets::new();
+        let refs: Vec<_> = (0 .. 6).map(|value| sets.make_set(value)).collect();
+        assert_eq!(sets.classes(), 6);
+        assert!(!sets.same(refs[0], refs[1]));
+        sets.union(refs[0], refs[1]);
+        sets.union(refs[2], refs[3]);
+        sets.union(refs[1], refs[3]);
+        assert_eq!(sets.union(refs[0], refs[2]), sets.find(refs[3]));
+        assert!(sets.same(refs[0], refs[3]));
+        assert!(!sets.same(refs[0], refs[4]));
+        assert_eq!(sets.classes(), 3);
+        assert_eq!(sets.class_len(refs[2]), 4);
+        let mut members: Vec<_> = sets.members(refs[2]).map(|(_, &value)| value).collect();
+        assert_eq!(members[0], 2);
+        members.sort();
+        assert_eq!(members, vec![0, 1, 2, 3]);
+        assert_eq!(sets.members(refs[5]).count(), 1);
+
+        let root_ref = sets.find(refs[0]).unwrap();
+        let root_value = *sets.get(root_ref).unwrap();
+        assert_eq!(sets.remove(root_ref), Some(root_value));
+        assert_eq!(sets.remove(root_ref), None);
+        assert_eq!(sets.find(root_ref), None);
+        let rest: Vec<_> = refs[0 .. 4].iter().cloned().filter(|&r| r != root_ref).collect();
+        assert!(sets.same(rest[0], rest[1]) && sets.same(rest[1], rest[2]));
+        assert_eq!(sets.class_len(rest[0]), 3);
+        assert_eq!(sets.members(rest[1]).count(), 3);
+        assert_eq!(sets.len(), 5);
+
+        assert_eq!(sets.remove(refs[5]), Some(5));
+        assert_eq!(sets.classes(), 2);
+        for &r in rest.iter() {
+            sets.remove(r);
+        }
+        assert_eq!(sets.classes(), 1);
+        assert_eq!(sets.set.len(), 1);
+        assert!(sets.same(refs[4], refs[4]));
+    }
+
+    #[test]
+    fn stress() {
+        let mut sets = DisjointSets::new();
+        let mut rng = rand::thread_rng();
+        let mut live = Vec::new();
+        // naive class labels for every live element
+        let mut labels = Vec::new();
+
+        for step in 0 .. 4096 {
+            let choice = rng.gen_range(0 .. 100);
+            if choice < 40 || live.len() < 2 {
+                live.push(sets.make_set(step));
+                labels.push(step);
+            } else if choice < 75 {
+                let a = rng.gen_range(0 .. live.len());
+                let b = rng.gen_range(0 .. live.len());
+                sets.union(live[a], live[b]);
+                let (from, to) = (labels[b], labels[a]);
+                for label in labels.iter_mut().filter(|label| **label == from) {
+                    *label = to;
+                }
+            } else if choice < 90 {
+                let a = rng.gen_range(0 .. live.len());
+                let b = rng.gen_range(0 .. live.len());
+                assert_eq!(sets.same(live[a], live[b]), labels[a] == labels[b]);
+            } else {
+                let a = rng.gen_range(0 .. live.len());
+                let element_ref = live.swap_remove(a);
+                labels.swap_remove(a);
+                assert!(sets.remove(element_ref).is_some());
+            }
+        }
+        assert_eq!(sets.len(), live.len());
+        let mut distinct = labels.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(sets.classes(), distinct.len());
+        for (index, &element_ref) in live.iter().enumerate() {
+            let class_size = labels.iter().filter(|&&label| label == labels[index]).count();
+            assert_eq!(sets.class_len(element_ref), class_size);
+            assert_eq!(sets.members(element_ref).count(), class_size);
+        }
+        for element_ref in live {
+            sets.remove(element_ref);
+        }
+        assert!(sets.set.is_empty());
+    }
+}
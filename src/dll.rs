@@ -9,6 +9,7 @@ use crate::{
 pub struct List<T> {
     set: Set<Link<T>>,
     head: Option<Ref>,
+    tail: Option<Ref>,
 }
 
 pub struct Link<T> {
@@ -28,6 +29,7 @@ impl<T> List<T> {
         List {
             set: Set::new(),
             head: None,
+            tail: None,
         }
     }
 
@@ -35,6 +37,7 @@ impl<T> List<T> {
         List {
             set: Set::with_capacity(capacity),
             head: None,
+            tail: None,
         }
     }
 
@@ -59,16 +62,32 @@ impl<T> List<T> {
             let item_ref =
                 self.set.insert(Link { item, prev: None, next: None, });
             self.head = Some(item_ref);
+            self.tail = Some(item_ref);
             item_ref
         }
     }
 
-    #[allow(clippy::option_map_unit_fn)]
+    pub fn push_back(&mut self, item: T) -> Ref {
+        let item_ref = self.set.insert(Link { item, prev: self.tail, next: None, });
+        match self.tail.and_then(|tail_ref| self.set.get_mut(tail_ref)) {
+            Some(prev_tail) =>
+                prev_tail.next = Some(item_ref),
+            None =>
+                self.head = Some(item_ref),
+        }
+        self.tail = Some(item_ref);
+        item_ref
+    }
+
+    #[allow(clippy::option_map_unit_fn, clippy::unnecessary_map_or)]
     pub fn remove(&mut self, link_ref: Ref) -> Option<T> {
         if let Some(Link { item, prev, next, }) = self.set.remove(link_ref) {
             if self.head.map_or(false, |head_ref| head_ref == link_ref) {
                 self.head = next;
             }
+            if self.tail == Some(link_ref) {
+                self.tail = prev;
+            }
             prev.and_then(|prev_ref| self.set.get_mut(prev_ref))
                 .map(|Link { next: prev_next, .. }| *prev_next = next);
             next.and_then(|next_ref| self.set.get_mut(next_ref))
@@ -83,10 +102,32 @@ impl<T> List<T> {
         self.head.and_then(|head_ref| self.remove(head_ref))
     }
 
+    pub fn pop_back(&mut self) -> Option<T> {
+        self.tail.and_then(|tail_ref| self.remove(tail_ref))
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.head.and_then(|head_ref| self.set.get(head_ref)).map(|link| &link.item)
+    }
+
+    pub fn front_mut(&mut self) -> Option<&mut T> {
+        self.head.and_then(|head_ref| self.set.get_mut(head_ref)).map(|link| &mut link.item)
+    }
+
+    pub fn back(&self) -> Option<&T> {
+        self.tail.and_then(|tail_ref| self.set.get(tail_ref)).map(|link| &link.item)
+    }
+
+    pub fn back_mut(&mut self) -> Option<&mut T> {
+        self.tail.and_then(|tail_ref| self.set.get_mut(tail_ref)).map(|link| &mut link.item)
+    }
+
     pub fn iter(&self) -> ListIter<'_, T> {
         ListIter {
             set: &self.set,
             cur: self.head,
+            cur_back: self.tail,
+            left: self.len(),
         }
     }
 }
@@ -94,23 +135,49 @@ impl<T> List<T> {
 pub struct ListIter<'a, T: 'a> {
     set: &'a Set<Link<T>>,
     cur: Option<Ref>,
+    cur_back: Option<Ref>,
+    // items not yet yielded from either end
+    left: usize,
 }
 
 impl<'a, T> Iterator for ListIter<'a, T> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
         let &Link { ref item, next, .. } = self.set.get(self.cur?)?;
         self.cur = next;
+        self.left -= 1;
         Some(item)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
 }
 
+impl<'a, T> DoubleEndedIterator for ListIter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        let &Link { ref item, prev, .. } = self.set.get(self.cur_back?)?;
+        self.cur_back = prev;
+        self.left -= 1;
+        Some(item)
+    }
+}
+
+impl<'a, T> ExactSizeIterator for ListIter<'a, T> {}
+
 #[cfg(test)]
 mod test {
     use std::{
         collections::{
             HashMap,
+            VecDeque,
         },
     };
 
@@ -156,6 +223,9 @@ mod test {
         assert_eq!(list.remove(ref_a), Some("a"));
         assert_eq!(list.iter().collect::<Vec<_>>(), vec![&"c", &"b"]);
         assert_eq!(list.len(), list.iter().count());
+        assert_eq!(list.back(), Some(&"b"));
+        list.push_back("d");
+        assert_eq!(list.iter().rev().collect::<Vec<_>>(), vec![&"d", &"b", &"c"]);
     }
 
     #[test]
@@ -176,6 +246,56 @@ mod test {
         assert_eq!(list.iter().collect::<Vec<_>>(), vec![&"c", &"a"]);
     }
 
+    #[test]
+    fn deque() {
+        let mut list = List::new();
+        assert_eq!((list.front(), list.back()), (None, None));
+        let ref_b = list.push_back("b");
+        list.prepend("a");
+        list.push_back("c");
+        assert_eq!((list.front(), list.back()), (Some(&"a"), Some(&"c")));
+        *list.back_mut().unwrap() = "d";
+        *list.front_mut().unwrap() = "z";
+        let mut iter = list.iter();
+        assert_eq!(iter.len(), 3);
+        assert_eq!((iter.next(), iter.next_back(), iter.next()), (Some(&"z"), Some(&"d"), Some(&"b")));
+        assert_eq!((iter.next(), iter.next_back()), (None, None));
+        assert_eq!(list.remove(ref_b), Some("b"));
+        assert_eq!(list.pop_back(), Some("d"));
+        assert_eq!(list.pop_back(), Some("z"));
+        assert_eq!(list.pop_back(), None);
+        list.push_back("e");
+        assert_eq!((list.front(), list.back()), (Some(&"e"), Some(&"e")));
+    }
+
+    #[test]
+    fn stress_deque() {
+        let mut list = List::new();
+        let mut model = VecDeque::new();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0 .. 16384 {
+            let item: u64 = rng.gen();
+            match rng.gen_range(0 .. 100) {
+                0 ..= 29 => {
+                    list.push_back(item);
+                    model.push_back(item);
+                },
+                30 ..= 59 => {
+                    list.prepend(item);
+                    model.push_front(item);
+                },
+                60 ..= 79 =>
+                    assert_eq!(list.pop_back(), model.pop_back()),
+                _ =>
+                    assert_eq!(list.pop_front(), model.pop_front()),
+            }
+            assert_eq!((list.front(), list.back()), (model.front(), model.back()));
+        }
+        assert!(list.iter().eq(model.iter()));
+        assert!(list.iter().rev().eq(model.iter().rev()));
+    }
+
     #[test]
     fn stress() {
         let mut list = List::new();
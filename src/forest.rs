@@ -1,3 +1,24 @@
+use std::{
+    mem,
+    ops::{
+        Deref,
+    },
+    sync::{
+        Arc,
+    },
+    cmp::{
+        Reverse,
+    },
+    hash::{
+        Hash,
+    },
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+};
+
 use crate::{
     set::{
         Set,
@@ -14,7 +35,10 @@ use crate::{
 
 use rayon::{
     iter::{
+        Either,
         ParallelIterator,
+        IntoParallelIterator,
+        IntoParallelRefIterator,
     },
 };
 
@@ -24,912 +48,5606 @@ pub struct Node<T, R> {
     pub depth: usize,
 }
 
-pub struct Forest1<T> {
-    nodes: Set<Node<T, Ref>>,
+// Nodes removed at once along with their refs.
+pub type RemovedNodes<T, K, R> = Vec<(K, Node<T, R>)>;
+
+// Nodes with their parents and items.
+type LayerNodes<'a, T, R> = Vec<(R, Option<R>, &'a T)>;
+
+// Storage cell of a forest: the node itself plus intrusive child index links and an ancestor jump pointer
+struct Entry<T, R> {
+    node: Node<T, R>,
+    children: Chain,
+    siblings: Siblings,
+    jump: Option<(R, usize)>,
 }
 
-impl<T> Forest1<T> {
-    pub fn new() -> Forest1<T> {
-        Forest1 {
-            nodes: Set::new(),
+impl<T, R> Entry<T, R> {
+    fn new(node: Node<T, R>) -> Entry<T, R> {
+        Entry {
+            node,
+            children: Chain::default(),
+            siblings: Siblings::default(),
+            jump: None,
         }
     }
+}
 
-    pub fn with_capacity(capacity: usize) -> Forest1<T> {
-        Forest1 {
-            nodes: Set::with_capacity(capacity),
+#[derive(Clone, Copy, Default)]
+struct Chain {
+    first: Option<Ref>,
+    last: Option<Ref>,
+}
+
+impl Chain {
+    fn transform<F>(self, ref_transform: F) -> Chain where F: Fn(Ref) -> Option<Ref> {
+        Chain {
+            first: self.first.and_then(&ref_transform),
+            last: self.last.and_then(&ref_transform),
         }
     }
+}
 
-    pub fn clear(&mut self) {
-        self.nodes.clear();
+#[derive(Clone, Copy, Default)]
+struct Siblings {
+    prev: Option<Ref>,
+    next: Option<Ref>,
+}
+
+impl Siblings {
+    fn transform<F>(self, ref_transform: F) -> Siblings where F: Fn(Ref) -> Option<Ref> {
+        Siblings {
+            prev: self.prev.and_then(&ref_transform),
+            next: self.next.and_then(&ref_transform),
+        }
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.nodes.len()
+// Common child index maintenance for forest layers: children chains are owned either
+// by a parent entry, by the layer itself (roots) or by an external node (`Forest2` only).
+trait Layer {
+    type Item;
+    type Parent;
+
+    fn entries(&self) -> &Set<Entry<Self::Item, Self::Parent>>;
+    fn entries_mut(&mut self) -> &mut Set<Entry<Self::Item, Self::Parent>>;
+    fn child_index(&self) -> bool;
+    fn jump_index(&self) -> bool;
+    fn depth_index(&self) -> Option<&DepthIndex>;
+    fn depth_index_mut(&mut self) -> Option<&mut DepthIndex>;
+    fn local_parent(node_ref: Ref) -> Self::Parent;
+    fn as_local(parent: &Self::Parent) -> Option<Ref>;
+    fn chain_of(&self, parent: Option<&Self::Parent>) -> Chain;
+    fn set_chain_of(&mut self, parent: Option<&Self::Parent>, chain: Chain);
+
+    fn local_children_refs(&self, parent: &Self::Parent) -> Vec<Ref> where Self::Parent: PartialEq {
+        let maybe_chain = if self.child_index() {
+            Some(self.chain_of(Some(parent)))
+        } else {
+            None
+        };
+        chain_or_scan(self.entries(), maybe_chain, |entry| entry.node.parent.as_ref() == Some(parent))
+            .collect()
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    // All local nodes of the subtree rooted at `node_ref` in level order, the root included.
+    fn local_subtree(&self, node_ref: Ref) -> Vec<Ref> where Self::Parent: PartialEq {
+        self.local_subtrees(&[node_ref])
     }
 
-    pub fn make_root(&mut self, item: T) -> Ref {
-        self.insert(Node { item, parent: None, depth: 0, })
+    // Local nodes of several disjoint subtrees in level order starting with their roots.
+    fn local_subtrees(&self, root_refs: &[Ref]) -> Vec<Ref> where Self::Parent: PartialEq {
+        // without the child index children of all the nodes are collected in a single scan
+        let maybe_children = if self.child_index() {
+            None
+        } else {
+            let mut children: HashMap<Ref, Vec<Ref>> = HashMap::new();
+            for (child_ref, entry) in self.entries().iter() {
+                if let Some(parent_ref) = entry.node.parent.as_ref().and_then(Self::as_local) {
+                    children.entry(parent_ref).or_default().push(child_ref);
+                }
+            }
+            Some(children)
+        };
+        let mut subtree = root_refs.to_vec();
+        let mut index = 0;
+        while index < subtree.len() {
+            let parent_ref = subtree[index];
+            match maybe_children {
+                Some(ref children) =>
+                    subtree.extend(children.get(&parent_ref).into_iter().flatten()),
+                None =>
+                    subtree.extend(self.local_children_refs(&Self::local_parent(parent_ref))),
+            }
+            index += 1;
+        }
+        subtree
     }
 
-    pub fn insert(&mut self, node: Node<T, Ref>) -> Ref {
-        self.nodes.insert(node)
+    // Skew-binary jump pointer (Myers, 1983) for a node at `depth` attached to `parent`: the node jumps
+    // either to its parent or twice as far as the parent does. Jumps are restarted from external nodes.
+    fn make_jump(&self, parent: Option<&Self::Parent>, depth: usize) -> Option<(Self::Parent, usize)> where Self::Parent: Clone {
+        let parent = parent?;
+        let parent_depth = depth.saturating_sub(1);
+        let fallback = Some((parent.clone(), parent_depth));
+        let parent_jump = match Self::as_local(parent).and_then(|parent_ref| self.entries().get(parent_ref)) {
+            Some(entry) =>
+                entry.jump.clone().unwrap_or_else(|| (parent.clone(), parent_depth)),
+            None =>
+                return fallback,
+        };
+        let parent_jump_jump = match Self::as_local(&parent_jump.0).and_then(|jump_ref| self.entries().get(jump_ref)) {
+            Some(entry) =>
+                entry.jump.clone().unwrap_or_else(|| parent_jump.clone()),
+            None =>
+                return fallback,
+        };
+        if parent_depth - parent_jump.1 == parent_jump.1 - parent_jump_jump.1 {
+            Some(parent_jump_jump)
+        } else {
+            fallback
+        }
     }
 
-    pub fn get(&self, node_ref: Ref) -> Option<Node<&T, Ref>> {
-        self.nodes.get(node_ref)
-            .map(|node| Node { item: &node.item, parent: node.parent, depth: node.depth, })
+    // Recomputes jump pointers of the given nodes, parents should come before their children.
+    fn refresh_jumps(&mut self, node_refs: &[Ref]) where Self::Parent: Clone {
+        if !self.jump_index() {
+            return;
+        }
+        for &node_ref in node_refs {
+            let jump = match self.entries().get(node_ref) {
+                Some(entry) =>
+                    self.make_jump(entry.node.parent.as_ref(), entry.node.depth),
+                None =>
+                    continue,
+            };
+            if let Some(entry) = self.entries_mut().get_mut(node_ref) {
+                entry.jump = jump;
+            }
+        }
     }
 
-    pub fn get_mut(&mut self, node_ref: Ref) -> Option<Node<&mut T, Ref>> {
-        self.nodes.get_mut(node_ref)
-            .map(|node| Node { item: &mut node.item, parent: node.parent, depth: node.depth, })
+    fn rebuild_jump_index(&mut self) where Self::Parent: Clone {
+        let mut node_refs: Vec<_> = self.entries().iter()
+            .map(|(node_ref, entry)| (entry.node.depth, node_ref))
+            .collect();
+        node_refs.sort_by_key(|pair| pair.0);
+        let node_refs: Vec<_> = node_refs.into_iter().map(|pair| pair.1).collect();
+        self.refresh_jumps(&node_refs);
     }
 
-    pub fn remove(&mut self, node_ref: Ref) -> Option<Node<T, Ref>> {
-        self.nodes.remove(node_ref)
+    // Sets the depth of the subtree root and recomputes depths of all its descendants from their parents
+    // going down level by level.
+    fn set_subtree_depth(&mut self, node_ref: Ref, depth: usize) where Self::Parent: Clone + PartialEq {
+        self.set_subtrees_depth(&[node_ref], depth);
     }
 
-    pub fn make_node(&mut self, parent_ref: Ref, item: T) -> Ref {
-        if let Some(parent_depth) = self.get(parent_ref).map(|node| node.depth) {
-            self.insert(Node { item, parent: Some(parent_ref), depth: parent_depth + 1, })
-        } else {
-            self.insert(Node { item, parent: None, depth: 0, })
+    // `set_subtree_depth` for several disjoint subtrees at once, so their nodes are collected in one pass.
+    fn set_subtrees_depth(&mut self, root_refs: &[Ref], depth: usize) where Self::Parent: Clone + PartialEq {
+        let unchanged = root_refs.iter()
+            .all(|&root_ref| self.entries().get(root_ref).is_none_or(|entry| entry.node.depth == depth));
+        if unchanged && !self.jump_index() {
+            return;
+        }
+        let subtree = self.local_subtrees(root_refs);
+        for (index, &subtree_ref) in subtree.iter().enumerate() {
+            let entry_depth = if index < root_refs.len() {
+                depth
+            } else {
+                let parent_ref = self.entries().get(subtree_ref).and_then(|entry| entry.node.parent.as_ref().and_then(Self::as_local));
+                match parent_ref.and_then(|parent_ref| self.entries().get(parent_ref)) {
+                    Some(parent) =>
+                        parent.node.depth + 1,
+                    None =>
+                        continue,
+                }
+            };
+            let entry_prev_depth = match self.entries_mut().get_mut(subtree_ref) {
+                Some(entry) =>
+                    mem::replace(&mut entry.node.depth, entry_depth),
+                None =>
+                    continue,
+            };
+            if let (true, Some(depth_index)) = (entry_prev_depth != entry_depth, self.depth_index_mut()) {
+                depth_index.remove(subtree_ref, entry_prev_depth);
+                depth_index.add(subtree_ref, entry_depth);
+            }
         }
+        self.refresh_jumps(&subtree);
     }
 
-    pub fn merge_aflat(self, target: Forest1<T>) -> Forest1InitMerger<T> {
-        Forest1InitMerger(target.nodes.merge(self.nodes))
+    fn adopt_orphans(
+        &mut self,
+        orphans: Vec<Ref>,
+        policy: OrphanPolicy,
+        removed_ref: &Self::Parent,
+        removed_node: Node<(), Self::Parent>,
+    )
+        where Self::Parent: Clone + PartialEq
+    {
+        let (new_parent, new_depth) = match policy {
+            OrphanPolicy::Reparent =>
+                (removed_node.parent, removed_node.depth),
+            OrphanPolicy::Reject | OrphanPolicy::Promote =>
+                (None, 0),
+        };
+        for &orphan_ref in &orphans {
+            let siblings = match self.entries_mut().get_mut(orphan_ref) {
+                Some(entry) => {
+                    entry.node.parent = new_parent.clone();
+                    entry.siblings
+                },
+                None =>
+                    continue,
+            };
+            if self.child_index() {
+                self.unlink(Some(removed_ref), orphan_ref, siblings);
+                self.link_last(new_parent.as_ref(), orphan_ref);
+            }
+        }
+        self.set_subtrees_depth(&orphans, new_depth);
     }
 
-    pub fn local_iter(&self) -> impl Iterator<Item = (Ref, &T)> {
-        self.nodes.iter().map(|(set_ref, node)| (set_ref, &node.item))
+    fn move_local(&mut self, node_ref: Ref, new_parent: Option<Self::Parent>, new_depth: usize) where Self::Parent: Clone + PartialEq {
+        self.move_local_before(node_ref, new_parent, new_depth, None);
     }
 
-    pub fn local_par_iter(&self) -> impl ParallelIterator<Item = (Ref, &T)> where T: Sync {
-        self.nodes.par_iter().map(|(set_ref, node)| (set_ref, &node.item))
+    // Moves a node in front of `next_ref` among the children of `new_parent` (to the end when none),
+    // depths of the subtree are only updated when the parent changes.
+    fn move_local_before(&mut self, node_ref: Ref, new_parent: Option<Self::Parent>, new_depth: usize, next_ref: Option<Ref>)
+        where Self::Parent: Clone + PartialEq
+    {
+        let (prev_parent, siblings) = match self.entries_mut().get_mut(node_ref) {
+            Some(entry) =>
+                (mem::replace(&mut entry.node.parent, new_parent.clone()), entry.siblings),
+            None =>
+                return,
+        };
+        if self.child_index() {
+            self.unlink(prev_parent.as_ref(), node_ref, siblings);
+            self.link_before(new_parent.as_ref(), node_ref, next_ref);
+        }
+        if prev_parent != new_parent || self.entries().get(node_ref).is_some_and(|entry| entry.node.depth != new_depth) {
+            self.set_subtree_depth(node_ref, new_depth);
+        }
     }
-}
 
-impl<T> Default for Forest1<T> {
-    fn default() -> Self {
-        Self::new()
+    fn remove_local(&mut self, node_ref: Ref, policy: OrphanPolicy) -> Option<Node<Self::Item, Self::Parent>>
+        where Self::Parent: Clone + PartialEq
+    {
+        let removed_ref = Self::local_parent(node_ref);
+        let orphans = self.local_children_refs(&removed_ref);
+        if policy == OrphanPolicy::Reject && !orphans.is_empty() {
+            return None;
+        }
+        let entry = self.entries_mut().remove(node_ref)?;
+        if self.child_index() {
+            self.unlink(entry.node.parent.as_ref(), node_ref, entry.siblings);
+        }
+        if let Some(depth_index) = self.depth_index_mut() {
+            depth_index.remove(node_ref, entry.node.depth);
+        }
+        let removed_node = Node { item: (), parent: entry.node.parent.clone(), depth: entry.node.depth, };
+        self.adopt_orphans(orphans, policy, &removed_ref, removed_node);
+        Some(entry.node)
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub enum Ref2<R> {
-    Local(Ref),
-    External(R),
-}
+    // Removes a node leaving its children (if any) to be removed as well by the caller.
+    fn drop_local(&mut self, node_ref: Ref) -> Option<Node<Self::Item, Self::Parent>> {
+        let entry = self.entries_mut().remove(node_ref)?;
+        if self.child_index() {
+            self.unlink(entry.node.parent.as_ref(), node_ref, entry.siblings);
+        }
+        if let Some(depth_index) = self.depth_index_mut() {
+            depth_index.remove(node_ref, entry.node.depth);
+        }
+        Some(entry.node)
+    }
 
-pub struct Forest2<T, R> {
-    local_nodes: Set<Node<T, Ref2<R>>>,
-}
+    fn remove_local_subtree(&mut self, node_ref: Ref) -> RemovedNodes<Self::Item, Ref, Self::Parent> where Self::Parent: PartialEq {
+        let subtree = self.local_subtree(node_ref);
+        let mut removed = Vec::with_capacity(subtree.len());
+        for subtree_ref in subtree {
+            if let Some(entry) = self.entries_mut().remove(subtree_ref) {
+                if subtree_ref == node_ref && self.child_index() {
+                    self.unlink(entry.node.parent.as_ref(), node_ref, entry.siblings);
+                }
+                if let Some(depth_index) = self.depth_index_mut() {
+                    depth_index.remove(subtree_ref, entry.node.depth);
+                }
+                removed.push((subtree_ref, entry.node));
+            }
+        }
+        removed
+    }
 
-impl<T, R> Forest2<T, R> {
-    pub fn new() -> Forest2<T, R> {
-        Forest2 {
-            local_nodes: Set::new(),
+    fn link_last(&mut self, parent: Option<&Self::Parent>, node_ref: Ref) {
+        let mut chain = self.chain_of(parent);
+        let entries = self.entries_mut();
+        match entries.get_mut(node_ref) {
+            Some(entry) =>
+                entry.siblings = Siblings { prev: chain.last, next: None, },
+            None =>
+                return,
+        }
+        match chain.last.and_then(|last_ref| entries.get_mut(last_ref)) {
+            Some(last) =>
+                last.siblings.next = Some(node_ref),
+            None =>
+                chain.first = Some(node_ref),
         }
+        chain.last = Some(node_ref);
+        self.set_chain_of(parent, chain);
     }
 
-    pub fn with_capacity(capacity: usize) -> Forest2<T, R> {
-        Forest2 {
-            local_nodes: Set::with_capacity(capacity),
+    fn link_before(&mut self, parent: Option<&Self::Parent>, node_ref: Ref, next_ref: Option<Ref>) {
+        let next_ref = match next_ref {
+            Some(next_ref) if self.entries().get(next_ref).is_some() && next_ref != node_ref =>
+                next_ref,
+            _ =>
+                return self.link_last(parent, node_ref),
+        };
+        let mut chain = self.chain_of(parent);
+        let entries = self.entries_mut();
+        let prev_ref = match entries.get_mut(next_ref) {
+            Some(next) =>
+                next.siblings.prev.replace(node_ref),
+            None =>
+                return,
+        };
+        if let Some(entry) = entries.get_mut(node_ref) {
+            entry.siblings = Siblings { prev: prev_ref, next: Some(next_ref), };
+        }
+        match prev_ref.and_then(|prev_ref| entries.get_mut(prev_ref)) {
+            Some(prev) =>
+                prev.siblings.next = Some(node_ref),
+            None =>
+                chain.first = Some(node_ref),
         }
+        self.set_chain_of(parent, chain);
     }
 
-    pub fn clear(&mut self) {
-        self.local_nodes.clear();
+    // Local siblings of a node: local children of its parent or local roots, in order.
+    fn local_siblings(&self, parent: Option<&Self::Parent>) -> Vec<Ref> where Self::Parent: PartialEq {
+        let maybe_chain = if self.child_index() {
+            Some(self.chain_of(parent))
+        } else {
+            None
+        };
+        chain_or_scan(self.entries(), maybe_chain, |entry| entry.node.parent.as_ref() == parent).collect()
     }
 
-    pub fn len(&self) -> usize {
-        self.local_nodes.len()
+    fn local_next_sibling(&self, node_ref: Ref) -> Option<Ref> where Self::Parent: PartialEq {
+        let entry = self.entries().get(node_ref)?;
+        if self.child_index() {
+            entry.siblings.next
+        } else {
+            self.local_siblings(entry.node.parent.as_ref()).into_iter().skip_while(|&r| r != node_ref).nth(1)
+        }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    fn local_prev_sibling(&self, node_ref: Ref) -> Option<Ref> where Self::Parent: PartialEq {
+        let entry = self.entries().get(node_ref)?;
+        if self.child_index() {
+            entry.siblings.prev
+        } else {
+            self.local_siblings(entry.node.parent.as_ref()).into_iter().take_while(|&r| r != node_ref).last()
+        }
     }
 
-    pub fn make_root(&mut self, item: T) -> Ref2<R> {
-        self.insert(Node { item, parent: None, depth: 0, })
+    fn local_last_child(&self, parent: &Self::Parent) -> Option<Ref> where Self::Parent: PartialEq {
+        if self.child_index() {
+            self.chain_of(Some(parent)).last
+        } else {
+            self.local_siblings(Some(parent)).pop()
+        }
     }
 
-    pub fn insert(&mut self, node: Node<T, Ref2<R>>) -> Ref2<R> {
-        Ref2::Local(self.local_nodes.insert(node))
+    fn unlink(&mut self, parent: Option<&Self::Parent>, node_ref: Ref, siblings: Siblings) {
+        let mut chain = self.chain_of(parent);
+        let entries = self.entries_mut();
+        match siblings.prev.and_then(|prev_ref| entries.get_mut(prev_ref)) {
+            Some(prev) =>
+                prev.siblings.next = siblings.next,
+            None if chain.first == Some(node_ref) =>
+                chain.first = siblings.next,
+            None =>
+                (),
+        }
+        match siblings.next.and_then(|next_ref| entries.get_mut(next_ref)) {
+            Some(next) =>
+                next.siblings.prev = siblings.prev,
+            None if chain.last == Some(node_ref) =>
+                chain.last = siblings.prev,
+            None =>
+                (),
+        }
+        self.set_chain_of(parent, chain);
     }
 
-    pub fn get<'s, 'a: 's, A>(&'s self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<&'s T, Ref2<R>>>
-        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a T, R>>
-    {
-        match node_ref {
-            Ref2::Local(local_node_ref) => {
-                self.local_nodes.get(local_node_ref)
-                    .map(|node| Node {
-                        item: &node.item,
-                        parent: node.parent.clone(),
-                        depth: node.depth,
-                    })
-            },
-            Ref2::External(external_node_ref) => {
-                upper_layer_access(external_node_ref)
-                    .map(|node| Node {
-                        item: node.item,
-                        parent: node.parent.map(Ref2::External),
-                        depth: node.depth,
-                    })
-            },
+    fn splice(&mut self, parent: Option<&Self::Parent>, chain: Chain) {
+        let (first_ref, last_ref) = match (chain.first, chain.last) {
+            (Some(first_ref), Some(last_ref)) =>
+                (first_ref, last_ref),
+            _ =>
+                return,
+        };
+        let mut target_chain = self.chain_of(parent);
+        let entries = self.entries_mut();
+        match target_chain.last.and_then(|target_last_ref| entries.get_mut(target_last_ref)) {
+            Some(target_last) =>
+                target_last.siblings.next = Some(first_ref),
+            None =>
+                target_chain.first = Some(first_ref),
+        }
+        if let Some(first) = entries.get_mut(first_ref) {
+            first.siblings.prev = target_chain.last;
         }
+        target_chain.last = Some(last_ref);
+        self.set_chain_of(parent, target_chain);
     }
 
-    pub fn get_mut<'s, 'a: 's, A>(&'s mut self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<&'s mut T, Ref2<R>>>
-        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a mut T, R>>
-    {
-        match node_ref {
-            Ref2::Local(local_node_ref) => {
-                self.local_nodes.get_mut(local_node_ref)
-                    .map(|node| Node {
-                        item: &mut node.item,
-                        parent: node.parent.clone(),
-                        depth: node.depth,
-                    })
-            },
-            Ref2::External(external_node_ref) => {
-                upper_layer_access(external_node_ref)
-                    .map(|node| Node {
-                        item: node.item,
-                        parent: node.parent.map(Ref2::External),
-                        depth: node.depth,
-                    })
-            },
+    fn rebuild_child_index(&mut self) where Self::Parent: Clone {
+        for (_, entry) in self.entries_mut().iter_mut() {
+            entry.children = Chain::default();
+            entry.siblings = Siblings::default();
+        }
+        self.set_chain_of(None, Chain::default());
+        let refs: Vec<_> = self.entries().refs().collect();
+        for node_ref in refs {
+            let parent = self.entries().get(node_ref).and_then(|entry| entry.node.parent.clone());
+            self.link_last(parent.as_ref(), node_ref);
         }
     }
 
-    pub fn remove<A>(&mut self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<T, Ref2<R>>>
-        where A: FnOnce(R) -> Option<Node<T, R>>
-    {
-        match node_ref {
-            Ref2::Local(local_node_ref) =>
-                self.local_nodes.remove(local_node_ref),
-            Ref2::External(external_node_ref) => {
-                upper_layer_access(external_node_ref)
-                    .map(|node| Node {
-                        item: node.item,
-                        parent: node.parent.map(Ref2::External),
-                        depth: node.depth,
-                    })
-            },
+    fn rebuild_depth_index(&mut self) {
+        let mut depth_index = DepthIndex::default();
+        for (node_ref, entry) in self.entries().iter() {
+            depth_index.add(node_ref, entry.node.depth);
+        }
+        if let Some(current) = self.depth_index_mut() {
+            *current = depth_index;
         }
     }
 
-    pub fn make_node<'s, 'a, A>(&'s mut self, upper_layer_access: A, parent_ref: Ref2<R>, item: T) -> Ref2<R>
-        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    // Checks the invariants local to the layer: depths and parents of nodes attached to local nodes,
+    // absence of cycles, children chains and jump pointers. External parents are not resolved here.
+    fn verify_entries(&self) -> VerifyReport<Self::Parent> where Self::Parent: Clone + Eq + Hash {
+        let entries = self.entries();
+        let mut report = VerifyReport::new();
+
+        // node state while walking towards roots: false while on the current path, true once checked
+        let mut visited: HashMap<Ref, bool> = HashMap::new();
+        for (node_ref, entry) in entries.iter() {
+            match entry.node.parent.as_ref() {
+                None if entry.node.depth != 0 =>
+                    report.depth_mismatches.push(Self::local_parent(node_ref)),
+                None =>
+                    (),
+                Some(parent) =>
+                    if let Some(parent_ref) = Self::as_local(parent) {
+                        match entries.get(parent_ref) {
+                            None =>
+                                report.dangling_parents.push(Self::local_parent(node_ref)),
+                            Some(parent_entry) if parent_entry.node.depth + 1 != entry.node.depth =>
+                                report.depth_mismatches.push(Self::local_parent(node_ref)),
+                            Some(..) =>
+                                (),
+                        }
+                    },
+            }
+
+            let mut path = Vec::new();
+            let mut cursor = Some(node_ref);
+            while let Some(current_ref) = cursor {
+                match (visited.get(&current_ref), entries.get(current_ref)) {
+                    (Some(true), _) | (None, None) =>
+                        break,
+                    (Some(false), _) => {
+                        report.cycles.push(Self::local_parent(current_ref));
+                        break;
+                    },
+                    (None, Some(current)) => {
+                        visited.insert(current_ref, false);
+                        path.push(current_ref);
+                        cursor = current.node.parent.as_ref().and_then(Self::as_local);
+                    },
+                }
+            }
+            for path_ref in path {
+                visited.insert(path_ref, true);
+            }
+        }
+
+        if self.child_index() {
+            let mut expected: HashMap<Option<Self::Parent>, HashSet<Ref>> = HashMap::new();
+            expected.insert(None, HashSet::new());
+            for (node_ref, entry) in entries.iter() {
+                expected.entry(entry.node.parent.clone()).or_default().insert(node_ref);
+                if entry.children.first.is_some() {
+                    expected.entry(Some(Self::local_parent(node_ref))).or_default();
+                }
+            }
+            for (parent, children_refs) in expected {
+                let chain = self.chain_of(parent.as_ref());
+                let mut linked = HashSet::new();
+                let mut prev = None;
+                let mut cursor = chain.first;
+                while let Some(current_ref) = cursor {
+                    let current = match entries.get(current_ref) {
+                        Some(current) if linked.insert(current_ref) =>
+                            current,
+                        _ =>
+                            break,
+                    };
+                    if current.node.parent != parent || current.siblings.prev != prev {
+                        report.index_errors.push(Self::local_parent(current_ref));
+                    }
+                    prev = Some(current_ref);
+                    cursor = current.siblings.next;
+                }
+                if chain.last != prev {
+                    report.index_errors.extend(chain.last.or(prev).map(Self::local_parent));
+                }
+                for &node_ref in children_refs.difference(&linked) {
+                    report.index_errors.push(Self::local_parent(node_ref));
+                }
+            }
+        }
+
+        if let Some(depth_index) = self.depth_index() {
+            for (node_ref, entry) in entries.iter() {
+                if !depth_index.levels.get(entry.node.depth).is_some_and(|level| level.contains(&node_ref)) {
+                    report.index_errors.push(Self::local_parent(node_ref));
+                }
+            }
+            for &node_ref in depth_index.levels.iter().flatten() {
+                if entries.get(node_ref).is_none() {
+                    report.index_errors.push(Self::local_parent(node_ref));
+                }
+            }
+        }
+
+        if self.jump_index() {
+            for (node_ref, entry) in entries.iter() {
+                let valid = match (&entry.node.parent, &entry.jump) {
+                    (None, None) =>
+                        true,
+                    (Some(parent), Some((jump_ref, jump_depth))) if *jump_depth < entry.node.depth =>
+                        self.reaches(parent.clone(), entry.node.depth - 1, jump_ref, *jump_depth),
+                    _ =>
+                        false,
+                };
+                if !valid {
+                    report.index_errors.push(Self::local_parent(node_ref));
+                }
+            }
+        }
+
+        report
+    }
+
+    // Whether `target` at `target_depth` is an ancestor of (or is) `node` at `depth`, following jump pointers
+    // when they do not overshoot. Ancestors in upper layers are assumed right.
+    fn reaches(&self, mut node: Self::Parent, mut depth: usize, target: &Self::Parent, target_depth: usize) -> bool
+        where Self::Parent: Clone + Eq
     {
-        if let Some(parent_depth) = self.get(upper_layer_access, parent_ref.clone()).map(|node| node.depth) {
-            self.insert(Node { item, parent: Some(parent_ref), depth: parent_depth + 1, })
-        } else {
-            self.insert(Node { item, parent: None, depth: 0, })
+        while depth > target_depth {
+            let entry = match Self::as_local(&node).and_then(|node_ref| self.entries().get(node_ref)) {
+                Some(entry) =>
+                    entry,
+                None =>
+                    return Self::as_local(target).is_none(),
+            };
+            (node, depth) = match (&entry.jump, &entry.node.parent) {
+                (Some((jump_ref, jump_depth)), _) if *jump_depth >= target_depth && *jump_depth < depth =>
+                    (jump_ref.clone(), *jump_depth),
+                (_, Some(parent)) =>
+                    (parent.clone(), depth - 1),
+                (_, None) =>
+                    return false,
+            };
         }
+        depth == target_depth && &node == target
     }
+}
 
-    pub fn external_ref(&self, node_ref: R) -> Ref2<R> {
-        Ref2::External(node_ref)
+// Local nodes grouped by depth, the deepest level is never empty.
+#[derive(Default)]
+struct DepthIndex {
+    levels: Vec<HashSet<Ref>>,
+}
+
+impl DepthIndex {
+    fn add(&mut self, node_ref: Ref, depth: usize) {
+        if self.levels.len() <= depth {
+            self.levels.resize_with(depth + 1, HashSet::new);
+        }
+        self.levels[depth].insert(node_ref);
     }
 
-    pub fn local_iter(&self) -> impl Iterator<Item = (Ref2<R>, &T)> {
-        self.local_nodes.iter()
-            .map(|(set_ref, node)| (Ref2::Local(set_ref), &node.item))
+    fn remove(&mut self, node_ref: Ref, depth: usize) {
+        if let Some(level) = self.levels.get_mut(depth) {
+            level.remove(&node_ref);
+        }
+        while self.levels.last().is_some_and(HashSet::is_empty) {
+            self.levels.pop();
+        }
     }
 
-    pub fn local_par_iter(&self) -> impl ParallelIterator<Item = (Ref2<R>, &T)> where T: Sync, R: Sync + Send {
-        self.local_nodes.par_iter()
-            .map(|(set_ref, node)| (Ref2::Local(set_ref), &node.item))
+    fn histogram(&self) -> Vec<usize> {
+        self.levels.iter().map(HashSet::len).collect()
     }
 }
 
-impl<T, R> Default for Forest2<T, R> {
-    fn default() -> Self {
-        Self::new()
-    }
+// Refs of the nodes at `depth` taken from the depth index when enabled and found with a full scan otherwise.
+fn depth_or_scan<'a, T, P>(entries: &'a Set<Entry<T, P>>, depth_index: Option<&'a DepthIndex>, depth: usize) -> impl Iterator<Item = Ref> + 'a {
+    let (indexed, scan) = match depth_index {
+        Some(depth_index) =>
+            (Some(depth_index.levels.get(depth).into_iter().flatten().cloned()), None),
+        None =>
+            (None, Some(entries.iter().filter(move |pair| pair.1.node.depth == depth).map(|pair| pair.0))),
+    };
+    indexed.into_iter().flatten().chain(scan.into_iter().flatten())
 }
 
-impl<T, R> Forest2<T, R> {
-    pub fn merge_aflat(self, target: Forest2<T, R>) -> Forest2AflatInitMerger<T, R> {
-        Forest2AflatInitMerger(target.local_nodes.merge(self.local_nodes))
+fn par_depth_or_scan<'a, T, P>(entries: &'a Set<Entry<T, P>>, depth_index: Option<&'a DepthIndex>, depth: usize)
+    -> impl ParallelIterator<Item = Ref> + 'a
+    where T: Sync, P: Sync
+{
+    match depth_index {
+        Some(depth_index) =>
+            Either::Left(depth_index.levels.get(depth).into_par_iter().flat_map(|level| level.par_iter().cloned())),
+        None =>
+            Either::Right(entries.par_iter().filter(move |pair| pair.1.node.depth == depth).map(|pair| pair.0)),
     }
 }
 
-impl<T> Forest2<T, Ref> {
-    pub fn merge_down(self, target: Forest1<T>) -> Forest2Down1InitMerger<T> {
-        Forest2Down1InitMerger(target.nodes.merge(self.local_nodes))
+fn histogram_or_scan<T, P>(entries: &Set<Entry<T, P>>, depth_index: Option<&DepthIndex>) -> Vec<usize> {
+    match depth_index {
+        Some(depth_index) =>
+            depth_index.histogram(),
+        None => {
+            let mut histogram = Vec::new();
+            for (_, entry) in entries.iter() {
+                if histogram.len() <= entry.node.depth {
+                    histogram.resize(entry.node.depth + 1, 0);
+                }
+                histogram[entry.node.depth] += 1;
+            }
+            histogram
+        },
     }
 }
 
-impl<T, R> Forest2<T, Ref2<R>> {
-    pub fn merge_down(self, target: Forest2<T, R>) -> Forest2Down2InitMerger<T, R> {
-        Forest2Down2InitMerger(target.local_nodes.merge(self.local_nodes))
+struct ChainIter<'a, T, P> {
+    entries: &'a Set<Entry<T, P>>,
+    cursor: Option<Ref>,
+}
+
+impl<'a, T, P> Iterator for ChainIter<'a, T, P> {
+    type Item = Ref;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node_ref = self.cursor.take()?;
+        self.cursor = self.entries.get(node_ref)?.siblings.next;
+        Some(node_ref)
     }
 }
 
-#[macro_export]
-macro_rules! layers {
-    // [&forest].get(ref)
-    { [$f:expr].get($ref:expr) } => {
-        $crate::forest::Forest1::get($f, $ref)
-    };
-    { [$f:expr $(, $fs:expr)+].get($ref:expr) } => {
-        $crate::forest::Forest2::get($f, |r| layers!([$($fs),*].get(r)), $ref)
+// Walks a children chain when the child index is enabled and falls back to a full scan otherwise.
+fn chain_or_scan<'a, T, P, F>(
+    entries: &'a Set<Entry<T, P>>,
+    maybe_chain: Option<Chain>,
+    scan_filter: F,
+)
+    -> impl Iterator<Item = Ref> + 'a
+    where F: Fn(&Entry<T, P>) -> bool + 'a
+{
+    let (linked, scan) = match maybe_chain {
+        Some(chain) =>
+            (Some(ChainIter { entries, cursor: chain.first, }), None),
+        None =>
+            (None, Some(entries.iter().filter(move |pair| scan_filter(pair.1)).map(|pair| pair.0))),
     };
+    linked.into_iter().flatten().chain(scan.into_iter().flatten())
+}
 
-    // [&mut forest].get_mut(ref)
-    { [$f:expr].get_mut($ref:expr) } => {
-        $crate::forest::Forest1::get_mut($f, $ref)
-    };
-    { [$f:expr $(, $fs:expr)+].get_mut($ref:expr) } => {
-        $crate::forest::Forest2::get_mut($f, |r| layers!([$($fs),*].get_mut(r)), $ref)
-    };
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReparentError {
+    NodeNotFound,
+    ParentNotFound,
+    // new parent is the node itself or one of its descendants
+    Cycle,
+    // upper layer nodes cannot be moved through a lower layer
+    UpperLayerNode,
+    // sibling to move the node next to is missing
+    SiblingNotFound,
+}
 
-    // [&mut forest].make_node(parent_ref, item)
-    { [$f:expr].make_node($ref:expr, $item:expr) } => {
-        $crate::forest::Forest1::make_node($f, $ref, $item)
-    };
-    { [$f:expr $(, $fs:expr)+].make_node($ref:expr, $item:expr) } => {
-        $crate::forest::Forest2::make_node($f, |r| layers!([$($fs),*].get(r)), $ref, $item)
-    };
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InsertError {
+    // parent is missing, removed, masked or belongs to another forest
+    ParentNotFound,
+    // depth is not one more than the depth of the parent (zero for roots)
+    DepthMismatch { expected: usize, found: usize, },
+}
 
-    // [&mut forest].remove(ref)
-    { [$f:expr].remove($ref:expr) } => {
-        $crate::forest::Forest1::remove($f, $ref)
-    };
-    { [$f:expr $(, $fs:expr)+].remove($ref:expr) } => {
-        $crate::forest::Forest2::remove($f, |r| layers!([$($fs),*].remove(r)), $ref)
-    };
+// What happens to the children of a node removed with `remove_with`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OrphanPolicy {
+    // removal of a node having children fails
+    Reject,
+    // children are attached to the parent of the removed node
+    Reparent,
+    // children become roots
+    Promote,
+}
 
-    // [&forest].towards_root_iter(ref)
-    { [$($fs:expr),+].towards_root_iter($ref:expr) } => {
-        $crate::forest::TowardsRootIter::new(|r| layers!([$($fs),*].get(r)), $ref)
-    };
+pub struct Forest1<T> {
+    nodes: Set<Entry<T, Ref>>,
+    roots: Chain,
+    child_index: bool,
+    jump_index: bool,
+    depth_index: Option<DepthIndex>,
+}
 
-    // [&forest].iter()
-    { [$f:expr].iter() } => {
-        $crate::forest::Forest1::local_iter($f)
-    };
-    { [$f:expr $(, $fs:expr)+].iter() } => {
-        $crate::forest::Forest2::local_iter($f)
-            .chain(layers!([$($fs),*].iter()).map(|(set_ref, item)| ($crate::forest::Ref2::External(set_ref), item)))
-    };
-
-    // [&forest].par_iter()
-    { [$f:expr].par_iter() } => {
-        $crate::forest::Forest1::local_par_iter($f)
-    };
-    { [$f:expr $(, $fs:expr)+].par_iter() } => {
-        $crate::forest::Forest2::local_par_iter($f)
-            .chain(layers!([$($fs),*].par_iter()).map(|(set_ref, item)| ($crate::forest::Ref2::External(set_ref), item)))
-    };
-}
+impl<T> Forest1<T> {
+    pub fn new() -> Forest1<T> {
+        Forest1 {
+            nodes: Set::new(),
+            roots: Chain::default(),
+            child_index: false,
+            jump_index: false,
+            depth_index: None,
+        }
+    }
 
-pub struct TowardsRootIter<R, A> {
-    start: R,
-    cursor: Option<R>,
-    layer_access: A,
-}
+    pub fn with_capacity(capacity: usize) -> Forest1<T> {
+        Forest1 {
+            nodes: Set::with_capacity(capacity),
+            roots: Chain::default(),
+            child_index: false,
+            jump_index: false,
+            depth_index: None,
+        }
+    }
 
-impl<R, A> TowardsRootIter<R, A> {
-    pub fn new(layer_access: A, node_ref: R) -> TowardsRootIter<R, A> where R: Clone {
-        TowardsRootIter {
-            start: node_ref.clone(),
-            cursor: Some(node_ref),
-            layer_access,
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.roots = Chain::default();
+        if let Some(depth_index) = self.depth_index.as_mut() {
+            *depth_index = DepthIndex::default();
         }
     }
-}
 
-impl<'a, T: 'a, R, A> Iterator for TowardsRootIter<R, A> where R: Clone, A: Fn(R) -> Option<Node<&'a T, R>> {
-    type Item = Node<&'a T, R>;
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let node_ref = self.cursor.take()?;
-        let node = (self.layer_access)(node_ref)?;
-        self.cursor = node.parent.clone();
-        Some(node)
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.layer_access)(self.start.clone())
-            .map_or((0, None), |node| (node.depth + 1, Some(node.depth + 1)))
+    pub fn has_child_index(&self) -> bool {
+        self.child_index
     }
 
-    fn count(self) -> usize where Self: Sized {
-        (self.layer_access)(self.start.clone())
-            .map_or(0, |node| node.depth + 1)
+    // Builds the child index over already existing nodes and maintains it from now on.
+    pub fn enable_child_index(&mut self) {
+        if !self.child_index {
+            self.child_index = true;
+            self.rebuild_child_index();
+        }
     }
-}
 
-pub struct Forest1InitMerger<T>(SetsInitMerger<Node<T, Ref>, Node<T, Ref>>);
+    pub fn has_jump_index(&self) -> bool {
+        self.jump_index
+    }
 
-pub struct Forest1InProgressMerger<T> {
-    inner_merger: SetsInProgressMerger<Node<T, Ref>, Node<T, Ref>>,
-    parent: Option<Ref>,
-    depth: usize,
-}
+    // Builds ancestor jump pointers for already existing nodes and maintains them from now on,
+    // making ancestor queries O(log depth).
+    pub fn enable_jump_index(&mut self) {
+        if !self.jump_index {
+            self.jump_index = true;
+            self.rebuild_jump_index();
+        }
+    }
 
-impl<T> InitMerger<Ref, Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> for Forest1InitMerger<T> {
-    fn ref_transform(&self, source_ref: Ref) -> Option<Ref> {
-        self.0.ref_transform(source_ref)
+    pub fn has_depth_index(&self) -> bool {
+        self.depth_index.is_some()
     }
 
-    fn merge_start(self) -> MergeState<Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> {
-        Forest1InProgressMerger::make_state(self.0.merge_start())
+    // Groups already existing nodes by depth and maintains the grouping from now on, so nodes of a level,
+    // the maximum depth and the histogram of depths are available without a full scan.
+    pub fn enable_depth_index(&mut self) {
+        if self.depth_index.is_none() {
+            self.depth_index = Some(DepthIndex::default());
+            self.rebuild_depth_index();
+        }
     }
-}
 
-type Forest1MergerInnerState<T> =
-    MergeState<Ref, Node<T, Ref>, SetsInProgressMerger<Node<T, Ref>, Node<T, Ref>>, Set<Node<T, Ref>>, Set<Node<T, Ref>>>;
+    pub fn nodes_at_depth(&self, depth: usize) -> impl Iterator<Item = Ref> + '_ {
+        depth_or_scan(&self.nodes, self.depth_index.as_ref(), depth)
+    }
 
-impl<T> Forest1InProgressMerger<T> {
-    fn make_state(inner_state: Forest1MergerInnerState<T>) -> MergeState<Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> {
-        match inner_state {
-            MergeState::Continue { item_ref, item: node, next, } =>
-                MergeState::Continue {
-                    item_ref,
-                    item: node.item,
-                    next: Forest1InProgressMerger {
-                        inner_merger: next,
-                        parent: node.parent,
-                        depth: node.depth,
-                    },
-                },
-            MergeState::Finish { merged, empty, } =>
-                MergeState::Finish {
-                    merged: Forest1 { nodes: merged, },
-                    empty: Forest1 { nodes: empty, },
-                },
-        }
+    pub fn par_nodes_at_depth(&self, depth: usize) -> impl ParallelIterator<Item = Ref> + '_ where T: Sync {
+        par_depth_or_scan(&self.nodes, self.depth_index.as_ref(), depth)
     }
-}
 
-impl<T> InProgressMerger<Ref, Ref, T, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> for Forest1InProgressMerger<T> {
-    fn ref_transform(&self, source_ref: Ref) -> Option<Ref> {
-        self.inner_merger.ref_transform(source_ref)
+    // Number of nodes at each depth, the last entry is for the maximum depth.
+    pub fn depth_histogram(&self) -> Vec<usize> {
+        histogram_or_scan(&self.nodes, self.depth_index.as_ref())
     }
 
-    fn proceed(self, transformed_item: T) -> MergeState<Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> {
-        let node = Node {
-            item: transformed_item,
-            parent: self.parent.and_then(|parent_ref| self.inner_merger.ref_transform(parent_ref)),
-            depth: self.depth,
-        };
-        Forest1InProgressMerger::make_state(self.inner_merger.proceed(node))
+    pub fn max_depth(&self) -> Option<usize> {
+        self.depth_histogram().len().checked_sub(1)
     }
-}
 
-pub struct Forest2AflatInitMerger<T, R>(SetsInitMerger<Node<T, Ref2<R>>, Node<T, Ref2<R>>>);
+    pub fn make_root(&mut self, item: T) -> Ref {
+        self.insert(Node { item, parent: None, depth: 0, })
+    }
 
-pub struct Forest2AflatInProgressMerger<T, R> {
-    inner_merger: SetsInProgressMerger<Node<T, Ref2<R>>, Node<T, Ref2<R>>>,
-    parent: Option<Ref2<R>>,
-    depth: usize,
-}
+    pub fn insert(&mut self, node: Node<T, Ref>) -> Ref {
+        let parent = node.parent;
+        let mut entry = Entry::new(node);
+        if self.jump_index {
+            entry.jump = self.make_jump(parent.as_ref(), entry.node.depth);
+        }
+        let depth = entry.node.depth;
+        let node_ref = self.nodes.insert(entry);
+        if self.child_index {
+            self.link_last(parent.as_ref(), node_ref);
+        }
+        if let Some(depth_index) = self.depth_index.as_mut() {
+            depth_index.add(node_ref, depth);
+        }
+        node_ref
+    }
 
-impl<T, R> InitMerger<Ref2<R>, Ref2<R>, T, Forest2AflatInProgressMerger<T, R>, Forest2<T, R>, Forest2<T, R>> for Forest2AflatInitMerger<T, R> {
-    fn ref_transform(&self, source_ref: Ref2<R>) -> Option<Ref2<R>> {
-        match source_ref {
-            Ref2::Local(local_ref) =>
-                self.0.ref_transform(local_ref).map(Ref2::Local),
-            Ref2::External(external_ref) =>
-                Some(Ref2::External(external_ref)),
+    // `insert` checking the parent and the depth of the node first.
+    pub fn try_insert(&mut self, node: Node<T, Ref>) -> Result<Ref, InsertError> {
+        let expected = match node.parent {
+            None =>
+                0,
+            Some(parent_ref) =>
+                self.nodes.get(parent_ref).ok_or(InsertError::ParentNotFound)?.node.depth + 1,
+        };
+        if node.depth != expected {
+            return Err(InsertError::DepthMismatch { expected, found: node.depth, });
         }
+        Ok(self.insert(node))
     }
 
-    fn merge_start(self) -> Forest2AflatMergerOuterState<T, R> {
-        Forest2AflatInProgressMerger::make_state(self.0.merge_start())
+    // Checks structural invariants of the forest, see `VerifyReport`.
+    pub fn verify(&self) -> VerifyReport<Ref> {
+        self.verify_entries()
     }
-}
 
-type Forest2AflatMergerOuterState<T, R> =
-    MergeState<Ref2<R>, T, Forest2AflatInProgressMerger<T, R>, Forest2<T, R>, Forest2<T, R>>;
+    pub fn get(&self, node_ref: Ref) -> Option<Node<&T, Ref>> {
+        self.nodes.get(node_ref)
+            .map(|entry| Node { item: &entry.node.item, parent: entry.node.parent, depth: entry.node.depth, })
+    }
 
-type Forest2AflatMergerInnerState<T, R> =
-    MergeState<Ref, Node<T, Ref2<R>>, SetsInProgressMerger<Node<T, Ref2<R>>, Node<T, Ref2<R>>>, Set<Node<T, Ref2<R>>>, Set<Node<T, Ref2<R>>>>;
+    pub fn get_mut(&mut self, node_ref: Ref) -> Option<Node<&mut T, Ref>> {
+        self.nodes.get_mut(node_ref)
+            .map(|entry| Node { item: &mut entry.node.item, parent: entry.node.parent, depth: entry.node.depth, })
+    }
 
-impl<T, R> Forest2AflatInProgressMerger<T, R> {
-    fn make_state(inner_state: Forest2AflatMergerInnerState<T, R>) -> Forest2AflatMergerOuterState<T, R> {
-        match inner_state {
-            MergeState::Continue { item_ref, item: node, next, } =>
-                MergeState::Continue {
-                    item_ref: Ref2::Local(item_ref),
-                    item: node.item,
-                    next: Forest2AflatInProgressMerger {
-                        inner_merger: next,
-                        parent: node.parent,
-                        depth: node.depth,
-                    },
-                },
-            MergeState::Finish { merged, empty, } =>
-                MergeState::Finish {
-                    merged: Forest2 { local_nodes: merged, },
-                    empty: Forest2 { local_nodes: empty, },
-                },
+    // Removes a single node in O(1): its children are left in place still pointing to it,
+    // see `remove_with` and `remove_subtree` to take care of them.
+    pub fn remove(&mut self, node_ref: Ref) -> Option<Node<T, Ref>> {
+        self.drop_local(node_ref)
+    }
+
+    // Removes a single node handling its children according to `policy`. Without the child index the
+    // children and their subtrees are found by a scan over the whole forest.
+    pub fn remove_with(&mut self, node_ref: Ref, policy: OrphanPolicy) -> Option<Node<T, Ref>> {
+        self.remove_local(node_ref, policy)
+    }
+
+    // Removes every node not descending from `roots_to_keep`, including the nodes with dangling parents.
+    // Kept roots which are not roots of the forest are detached.
+    pub fn gc(&mut self, roots_to_keep: &[Ref]) -> GcReport<Ref> {
+        let nodes = self.nodes.iter().map(|(node_ref, entry)| (node_ref, entry.node.parent));
+        let report = sweep(nodes, |node_ref| roots_to_keep.contains(node_ref), true);
+        let removed: HashSet<_> = report.removed.iter().cloned().collect();
+        for &root_ref in roots_to_keep {
+            let parent = self.nodes.get(root_ref).and_then(|entry| entry.node.parent);
+            if parent.is_some_and(|parent_ref| removed.contains(&parent_ref) || self.nodes.get(parent_ref).is_none()) {
+                self.move_local(root_ref, None, 0);
+            }
         }
+        for &node_ref in &report.removed {
+            self.drop_local(node_ref);
+        }
+        report
     }
-}
 
-impl<T, R> InProgressMerger<Ref2<R>, Ref2<R>, T, T, Forest2AflatInProgressMerger<T, R>, Forest2<T, R>, Forest2<T, R>>
-    for Forest2AflatInProgressMerger<T, R>
-{
-    fn ref_transform(&self, source_ref: Ref2<R>) -> Option<Ref2<R>> {
-        match source_ref {
-            Ref2::Local(local_ref) =>
-                self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
-            Ref2::External(external_ref) =>
-                Some(Ref2::External(external_ref)),
+    // Removes the subtrees of all the nodes matching `predicate`.
+    pub fn prune<P>(&mut self, predicate: P) -> GcReport<Ref> where P: Fn(&Ref, &T) -> bool {
+        let nodes = self.nodes.iter().map(|(node_ref, entry)| (node_ref, entry.node.parent));
+        let matched: HashSet<_> = self.nodes.iter()
+            .filter(|(node_ref, entry)| predicate(node_ref, &entry.node.item))
+            .map(|pair| pair.0)
+            .collect();
+        let report = sweep(nodes, |node_ref| matched.contains(node_ref), false);
+        for &node_ref in &report.removed {
+            self.drop_local(node_ref);
         }
+        report
     }
 
-    fn proceed(self, transformed_item: T) -> Forest2AflatMergerOuterState<T, R> {
-        let node = Node {
-            item: transformed_item,
-            parent: match self.parent {
-                Some(Ref2::Local(local_ref)) =>
-                    self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
-                Some(Ref2::External(external_ref)) =>
-                    Some(Ref2::External(external_ref)),
-                None =>
-                    None,
-            },
-            depth: self.depth,
-        };
-        Forest2AflatInProgressMerger::make_state(self.inner_merger.proceed(node))
+    // Removes a node with all of its descendants, returned in level order.
+    pub fn remove_subtree(&mut self, node_ref: Ref) -> RemovedNodes<T, Ref, Ref> {
+        self.remove_local_subtree(node_ref)
     }
-}
 
-pub struct Forest2Down1InitMerger<T>(SetsInitMerger<Node<T, Ref2<Ref>>, Node<T, Ref>>);
+    pub fn make_node(&mut self, parent_ref: Ref, item: T) -> Ref {
+        if let Some(parent_depth) = self.get(parent_ref).map(|node| node.depth) {
+            self.insert(Node { item, parent: Some(parent_ref), depth: parent_depth + 1, })
+        } else {
+            self.insert(Node { item, parent: None, depth: 0, })
+        }
+    }
 
-pub struct Forest2Down1InProgressMerger<T> {
-    inner_merger: SetsInProgressMerger<Node<T, Ref2<Ref>>, Node<T, Ref>>,
-    parent: Option<Ref2<Ref>>,
-    depth: usize,
-}
+    // Moves the subtree of `node_ref` under `parent_ref` recomputing depths of the whole subtree.
+    // Depths in lower layers stacked on this forest are updated with `Forest2::refresh_depths`.
+    pub fn reparent(&mut self, node_ref: Ref, parent_ref: Ref) -> Result<(), ReparentError> {
+        if self.nodes.get(node_ref).is_none() {
+            return Err(ReparentError::NodeNotFound);
+        }
+        let parent_depth = self.get(parent_ref).ok_or(ReparentError::ParentNotFound)?.depth;
+        let mut cursor = Some(parent_ref);
+        while let Some(cursor_ref) = cursor {
+            if cursor_ref == node_ref {
+                return Err(ReparentError::Cycle);
+            }
+            cursor = self.nodes.get(cursor_ref).and_then(|entry| entry.node.parent);
+        }
+        self.move_local(node_ref, Some(parent_ref), parent_depth + 1);
+        Ok(())
+    }
 
-impl<T> InitMerger<Ref2<Ref>, Ref, T, Forest2Down1InProgressMerger<T>, Forest1<T>, Forest2<T, Ref>> for Forest2Down1InitMerger<T> {
-    fn ref_transform(&self, source_ref: Ref2<Ref>) -> Option<Ref> {
-        match source_ref {
-            Ref2::Local(local_ref) =>
-                self.0.ref_transform(local_ref),
-            Ref2::External(external_ref) =>
-                Some(external_ref),
+    // Detaches the subtree of `node_ref` from its parent making it a separate tree, see `reparent`
+    // for lower layers.
+    pub fn detach(&mut self, node_ref: Ref) -> Result<(), ReparentError> {
+        if self.nodes.get(node_ref).is_none() {
+            return Err(ReparentError::NodeNotFound);
         }
+        self.move_local(node_ref, None, 0);
+        Ok(())
     }
 
-    fn merge_start(self) -> Forest2Down1MergerOuterState<T> {
-        Forest2Down1InProgressMerger::make_state(self.0.merge_start())
+    pub fn ancestry(&self, node_ref: Ref) -> Option<Ancestry<Ref>> {
+        self.nodes.get(node_ref)
+            .map(|entry| Ancestry::from_entry(entry, self.jump_index))
     }
-}
 
-type Forest2Down1MergerOuterState<T> =
-    MergeState<Ref2<Ref>, T, Forest2Down1InProgressMerger<T>, Forest1<T>, Forest2<T, Ref>>;
+    pub fn ancestors(&self) -> Ancestors<impl Fn(Ref) -> Option<Ancestry<Ref>> + '_> {
+        Ancestors::new(move |node_ref| self.ancestry(node_ref))
+    }
 
-type Forest2Down1MergerInnerState<T> =
-    MergeState<Ref, Node<T, Ref2<Ref>>, SetsInProgressMerger<Node<T, Ref2<Ref>>, Node<T, Ref>>, Set<Node<T, Ref>>, Set<Node<T, Ref2<Ref>>>>;
+    pub fn is_ancestor(&self, ancestor_ref: Ref, node_ref: Ref) -> bool {
+        self.ancestors().is_ancestor(ancestor_ref, node_ref)
+    }
 
-impl<T> Forest2Down1InProgressMerger<T> {
-    fn make_state(inner_state: Forest2Down1MergerInnerState<T>) -> Forest2Down1MergerOuterState<T> {
-        match inner_state {
-            MergeState::Continue { item_ref, item: node, next, } =>
-                MergeState::Continue {
-                    item_ref: Ref2::Local(item_ref),
-                    item: node.item,
-                    next: Forest2Down1InProgressMerger {
-                        inner_merger: next,
-                        parent: node.parent,
-                        depth: node.depth,
-                    },
-                },
-            MergeState::Finish { merged, empty, } =>
-                MergeState::Finish {
-                    merged: Forest1 { nodes: merged, },
-                    empty: Forest2 { local_nodes: empty, },
-                },
-        }
+    pub fn lca(&self, node_a_ref: Ref, node_b_ref: Ref) -> Option<Ref> {
+        self.ancestors().lca(node_a_ref, node_b_ref)
     }
-}
 
-impl<T> InProgressMerger<Ref2<Ref>, Ref, T, T, Forest2Down1InProgressMerger<T>, Forest1<T>, Forest2<T, Ref>>
-    for Forest2Down1InProgressMerger<T>
-{
-    fn ref_transform(&self, source_ref: Ref2<Ref>) -> Option<Ref> {
-        match source_ref {
-            Ref2::Local(local_ref) =>
-                self.inner_merger.ref_transform(local_ref),
-            Ref2::External(external_ref) =>
-                Some(external_ref),
-        }
+    pub fn distance(&self, node_a_ref: Ref, node_b_ref: Ref) -> Option<usize> {
+        self.ancestors().distance(node_a_ref, node_b_ref)
     }
 
-    fn proceed(self, transformed_item: T) -> Forest2Down1MergerOuterState<T> {
-        let node = Node {
-            item: transformed_item,
-            parent: match self.parent {
-                Some(Ref2::Local(local_ref)) =>
-                    self.inner_merger.ref_transform(local_ref),
-                Some(Ref2::External(external_ref)) =>
-                    Some(external_ref),
+    pub fn path(&self, node_a_ref: Ref, node_b_ref: Ref) -> Option<Vec<Ref>> {
+        self.ancestors().path(node_a_ref, node_b_ref)
+    }
+
+    pub fn path_to_root(&self, node_ref: Ref, order: PathOrder) -> Option<Vec<Ref>> {
+        self.ancestors().path_to_root(node_ref, order)
+    }
+
+    // Walks from `node_ref` up to its root calling `f` on every node, the node itself included.
+    pub fn towards_root_mut<F>(&mut self, node_ref: Ref, mut f: F) where F: FnMut(Ref, &mut T) {
+        let mut cursor = Some(node_ref);
+        while let Some(cursor_ref) = cursor {
+            let entry = match self.nodes.get_mut(cursor_ref) {
+                Some(entry) =>
+                    entry,
                 None =>
-                    None,
-            },
-            depth: self.depth,
+                    return,
+            };
+            f(cursor_ref, &mut entry.node.item);
+            cursor = entry.node.parent;
+        }
+    }
+
+    pub fn children(&self, node_ref: Ref) -> impl Iterator<Item = Ref> + '_ {
+        let maybe_chain = if self.child_index {
+            Some(self.chain_of(Some(&node_ref)))
+        } else {
+            None
         };
-        Forest2Down1InProgressMerger::make_state(self.inner_merger.proceed(node))
+        chain_or_scan(&self.nodes, maybe_chain, move |entry| entry.node.parent == Some(node_ref))
     }
-}
 
-type Forest2Down2Node<T, R> = Node<T, Ref2<Ref2<R>>>;
+    pub fn first_child(&self, node_ref: Ref) -> Option<Ref> {
+        self.children(node_ref).next()
+    }
 
-pub struct Forest2Down2InitMerger<T, R>(SetsInitMerger<Forest2Down2Node<T, R>, Node<T, Ref2<R>>>);
+    pub fn last_child(&self, node_ref: Ref) -> Option<Ref> {
+        self.local_last_child(&node_ref)
+    }
 
-pub struct Forest2Down2InProgressMerger<T, R> {
-    inner_merger: SetsInProgressMerger<Forest2Down2Node<T, R>, Node<T, Ref2<R>>>,
-    parent: Option<Ref2<Ref2<R>>>,
-    depth: usize,
-}
+    // Next child of the same parent (or next root), O(1) with the child index.
+    pub fn next_sibling(&self, node_ref: Ref) -> Option<Ref> {
+        self.local_next_sibling(node_ref)
+    }
 
-impl<T, R> InitMerger<Ref2<Ref2<R>>, Ref2<R>, T, Forest2Down2InProgressMerger<T, R>, Forest2<T, R>, Forest2<T, Ref2<R>>>
-    for Forest2Down2InitMerger<T, R>
-{
-    fn ref_transform(&self, source_ref: Ref2<Ref2<R>>) -> Option<Ref2<R>> {
-        match source_ref {
-            Ref2::Local(local_ref) =>
-                self.0.ref_transform(local_ref).map(Ref2::Local),
-            Ref2::External(external_ref) =>
-                Some(external_ref),
-        }
+    pub fn prev_sibling(&self, node_ref: Ref) -> Option<Ref> {
+        self.local_prev_sibling(node_ref)
     }
 
-    fn merge_start(self) -> Forest2Down2MergerOuterState<T, R> {
-        Forest2Down2InProgressMerger::make_state(self.0.merge_start())
+    // Ordered insertions and moves below keep children ordered by the child index, which is enabled
+    // on first use.
+
+    // Inserts a node at `index` among the children of `parent_ref` (last if out of range).
+    pub fn insert_child_at(&mut self, parent_ref: Ref, index: usize, item: T) -> Option<Ref> {
+        let depth = self.nodes.get(parent_ref)?.node.depth + 1;
+        self.enable_child_index();
+        let next_ref = self.children(parent_ref).nth(index);
+        Some(self.insert_before_ref(Node { item, parent: Some(parent_ref), depth, }, next_ref))
     }
-}
 
-type Forest2Down2MergerOuterState<T, R> =
-    MergeState<Ref2<Ref2<R>>, T, Forest2Down2InProgressMerger<T, R>, Forest2<T, R>, Forest2<T, Ref2<R>>>;
+    pub fn insert_before(&mut self, sibling_ref: Ref, item: T) -> Option<Ref> {
+        let sibling = self.get(sibling_ref)?;
+        let node = Node { item, parent: sibling.parent, depth: sibling.depth, };
+        self.enable_child_index();
+        Some(self.insert_before_ref(node, Some(sibling_ref)))
+    }
 
-type Forest2Down2MergerInnerState<T, R> =
-    MergeState<Ref, Node<T, Ref2<Ref2<R>>>, SetsInProgressMerger<
-            Forest2Down2Node<T, R>, Node<T, Ref2<R>>>, Set<Node<T, Ref2<R>>>, Set<Forest2Down2Node<T, R>>>;
+    pub fn insert_after(&mut self, sibling_ref: Ref, item: T) -> Option<Ref> {
+        let sibling = self.get(sibling_ref)?;
+        let node = Node { item, parent: sibling.parent, depth: sibling.depth, };
+        self.enable_child_index();
+        let next_ref = self.next_sibling(sibling_ref);
+        Some(self.insert_before_ref(node, next_ref))
+    }
 
-impl<T, R> Forest2Down2InProgressMerger<T, R> {
-    fn make_state(inner_state: Forest2Down2MergerInnerState<T, R>) -> Forest2Down2MergerOuterState<T, R> {
-        match inner_state {
-            MergeState::Continue { item_ref, item: node, next, } =>
-                MergeState::Continue {
-                    item_ref: Ref2::Local(item_ref),
-                    item: node.item,
-                    next: Forest2Down2InProgressMerger {
-                        inner_merger: next,
-                        parent: node.parent,
-                        depth: node.depth,
-                    },
-                },
-            MergeState::Finish { merged, empty, } =>
-                MergeState::Finish {
-                    merged: Forest2 { local_nodes: merged, },
-                    empty: Forest2 { local_nodes: empty, },
-                },
+    fn insert_before_ref(&mut self, node: Node<T, Ref>, next_ref: Option<Ref>) -> Ref {
+        let (parent, depth) = (node.parent, node.depth);
+        let node_ref = self.insert(node);
+        if next_ref.is_some() {
+            self.move_local_before(node_ref, parent, depth, next_ref);
         }
+        node_ref
     }
-}
 
-impl<T, R> InProgressMerger<Ref2<Ref2<R>>, Ref2<R>, T, T, Forest2Down2InProgressMerger<T, R>, Forest2<T, R>, Forest2<T, Ref2<R>>>
-    for Forest2Down2InProgressMerger<T, R>
-{
-    fn ref_transform(&self, source_ref: Ref2<Ref2<R>>) -> Option<Ref2<R>> {
-        match source_ref {
-            Ref2::Local(local_ref) =>
-                self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
-            Ref2::External(external_ref) =>
-                Some(external_ref),
+    // Moves the subtree of `node_ref` right in front of `sibling_ref`, taking the parent of the latter.
+    // O(1) when both have the same parent.
+    pub fn move_before(&mut self, node_ref: Ref, sibling_ref: Ref) -> Result<(), ReparentError> {
+        let parent = self.nodes.get(node_ref).ok_or(ReparentError::NodeNotFound)?.node.parent;
+        let sibling = self.get(sibling_ref).ok_or(ReparentError::SiblingNotFound)?;
+        let (new_parent, new_depth) = (sibling.parent, sibling.depth);
+        if sibling_ref == node_ref {
+            return Ok(());
         }
+        if new_parent != parent {
+            let mut cursor = new_parent;
+            while let Some(cursor_ref) = cursor {
+                if cursor_ref == node_ref {
+                    return Err(ReparentError::Cycle);
+                }
+                cursor = self.nodes.get(cursor_ref).and_then(|entry| entry.node.parent);
+            }
+        }
+        self.enable_child_index();
+        self.move_local_before(node_ref, new_parent, new_depth, Some(sibling_ref));
+        Ok(())
     }
 
-    fn proceed(self, transformed_item: T) -> Forest2Down2MergerOuterState<T, R> {
-        let node = Node {
-            item: transformed_item,
-            parent: match self.parent {
-                Some(Ref2::Local(local_ref)) =>
-                    self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
-                Some(Ref2::External(external_ref)) =>
-                    Some(external_ref),
-                None =>
-                    None,
-            },
-            depth: self.depth,
+    pub fn descendants_dfs(&self, node_ref: Ref) -> impl Iterator<Item = Ref> + '_ {
+        DescendantsDfs::new(move |child_ref| self.children(child_ref), node_ref)
+    }
+
+    pub fn descendants_bfs(&self, node_ref: Ref) -> impl Iterator<Item = Ref> + '_ {
+        DescendantsBfs::new(move |child_ref| self.children(child_ref), node_ref)
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item = Ref> + '_ {
+        let maybe_chain = if self.child_index {
+            Some(self.roots)
+        } else {
+            None
         };
-        Forest2Down2InProgressMerger::make_state(self.inner_merger.proceed(node))
+        chain_or_scan(&self.nodes, maybe_chain, |entry| entry.node.parent.is_none())
     }
-}
 
+    pub fn leaves(&self) -> impl Iterator<Item = Ref> + '_ {
+        let maybe_parents: Option<HashSet<Ref>> = if self.child_index {
+            None
+        } else {
+            Some(self.nodes.values().flat_map(|entry| entry.node.parent).collect())
+        };
+        self.nodes.iter()
+            .filter(move |&(node_ref, entry)| match maybe_parents {
+                None =>
+                    entry.children.first.is_none(),
+                Some(ref parents) =>
+                    !parents.contains(&node_ref),
+            })
+            .map(|pair| pair.0)
+    }
 
-#[cfg(test)]
-mod test {
-    use crate::{
+    pub fn merge_aflat(mut self, mut target: Forest1<T>) -> Forest1InitMerger<T> {
+        // sibling order of the source survives only in a target child index
+        if self.child_index {
+            target.enable_child_index();
+        }
+        let inner_merger = mem::take(&mut target.nodes).merge(mem::take(&mut self.nodes));
+        let mut relink = Relink::new(&target, &self);
+        relink.splice(None, mem::take(&mut self.roots), |r| inner_merger.ref_transform(r));
+        Forest1InitMerger { inner_merger, target, source: self, relink, }
+    }
+
+    // Collapses the layers collected in `squash` along with this one into a new `Forest1` in one pass,
+    // transforming items in parallel. Returned `SquashRefs` translates refs of any layer of the stack.
+    pub fn squash<U, F>(mut self, mut squash: Squash<T>, transform: F) -> (Forest1<U>, SquashRefs)
+        where T: Send, U: Send, F: Fn(T) -> U + Sync + Send
+    {
+        squash.take_layer(self.nodes.drain(), |&parent_ref| parent_ref);
+        let Squash { layers, mut shadows, .. } = squash;
+        let nodes: Vec<_> = layers.into_iter()
+            .rev()
+            .flatten()
+            .map(|(node_ref, mut node)| {
+                if let Some(item) = shadows.remove(&node_ref) {
+                    node.item = item;
+                }
+                (node_ref, node)
+            })
+            .collect();
+        let transformed: Vec<_> = nodes.into_par_iter()
+            .map(|(node_ref, node)| (node_ref, Node { item: transform(node.item), parent: node.parent, depth: node.depth, }))
+            .collect();
+
+        let mut target = Forest1::with_capacity(transformed.len());
+        target.child_index = self.child_index;
+        target.jump_index = self.jump_index;
+        target.depth_index = self.depth_index.as_ref().map(|_| DepthIndex::default());
+        let mut refs = HashMap::with_capacity(transformed.len());
+        for (node_ref, node) in transformed {
+            refs.insert(node_ref, target.nodes.insert(Entry::new(node)));
+        }
+        for (_, entry) in target.nodes.iter_mut() {
+            entry.node.parent = entry.node.parent.and_then(|parent_ref| refs.get(&parent_ref).cloned());
+        }
+        if target.child_index {
+            target.rebuild_child_index();
+        }
+        if target.jump_index {
+            target.rebuild_jump_index();
+        }
+        if target.depth_index.is_some() {
+            target.rebuild_depth_index();
+        }
+        (target, SquashRefs { refs, })
+    }
+
+    pub fn local_iter(&self) -> impl Iterator<Item = (Ref, &T)> {
+        self.nodes.iter().map(|(set_ref, entry)| (set_ref, &entry.node.item))
+    }
+
+    pub fn local_par_iter(&self) -> impl ParallelIterator<Item = (Ref, &T)> where T: Sync {
+        self.nodes.par_iter().map(|(set_ref, entry)| (set_ref, &entry.node.item))
+    }
+
+    // See `fold_up`.
+    pub fn fold_up<A, I, C>(&self, init: I, combine: C) -> HashMap<Ref, A>
+        where T: Sync, A: Send + Sync, I: Fn(&Ref, &T) -> A + Sync, C: Fn(&mut A, &A) + Sync
+    {
+        fold_up(self.nodes.iter().map(|(node_ref, entry)| (node_ref, entry.node.parent, entry.node.depth, &entry.node.item)), init, combine)
+    }
+
+    // See `propagate_down`.
+    pub fn propagate_down<A, F>(&self, f: F) -> HashMap<Ref, A>
+        where T: Sync, A: Send + Sync, F: Fn(Option<&A>, &Ref, &T) -> A + Sync
+    {
+        propagate_down(self.nodes.iter().map(|(node_ref, entry)| (node_ref, entry.node.parent, entry.node.depth, &entry.node.item)), f)
+    }
+}
+
+impl<T> Forest1<T> {
+    // Makes the forest immutable so it can be shared (also between threads) as the upper layer of
+    // any number of `Forest2` layers.
+    pub fn freeze(self) -> Frozen<Forest1<T>> {
+        Frozen::new(self)
+    }
+}
+
+impl<T> Default for Forest1<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Layer for Forest1<T> {
+    type Item = T;
+    type Parent = Ref;
+
+    fn entries(&self) -> &Set<Entry<T, Ref>> {
+        &self.nodes
+    }
+
+    fn entries_mut(&mut self) -> &mut Set<Entry<T, Ref>> {
+        &mut self.nodes
+    }
+
+    fn child_index(&self) -> bool {
+        self.child_index
+    }
+
+    fn jump_index(&self) -> bool {
+        self.jump_index
+    }
+
+    fn depth_index(&self) -> Option<&DepthIndex> {
+        self.depth_index.as_ref()
+    }
+
+    fn depth_index_mut(&mut self) -> Option<&mut DepthIndex> {
+        self.depth_index.as_mut()
+    }
+
+    fn local_parent(node_ref: Ref) -> Ref {
+        node_ref
+    }
+
+    fn as_local(parent: &Ref) -> Option<Ref> {
+        Some(*parent)
+    }
+
+    fn chain_of(&self, parent: Option<&Ref>) -> Chain {
+        match parent {
+            None =>
+                self.roots,
+            Some(&parent_ref) =>
+                self.nodes.get(parent_ref).map_or_else(Chain::default, |entry| entry.children),
+        }
+    }
+
+    fn set_chain_of(&mut self, parent: Option<&Ref>, chain: Chain) {
+        match parent {
+            None =>
+                self.roots = chain,
+            Some(&parent_ref) =>
+                if let Some(entry) = self.nodes.get_mut(parent_ref) {
+                    entry.children = chain;
+                },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Ref2<R> {
+    Local(Ref),
+    External(R),
+}
+
+// A ref of any layer in a stack resolves to the `Ref` of the set the node is actually stored in.
+pub trait LayerRef {
+    // number of layers above the bottom `Forest1` for refs of this type
+    const DEPTH: usize;
+
+    fn set_ref(&self) -> Ref;
+
+    // layer the node is stored in, zero for the bottom `Forest1`
+    fn layer(&self) -> usize;
+}
+
+impl LayerRef for Ref {
+    const DEPTH: usize = 0;
+
+    fn set_ref(&self) -> Ref {
+        *self
+    }
+
+    fn layer(&self) -> usize {
+        0
+    }
+}
+
+impl<R> LayerRef for Ref2<R> where R: LayerRef {
+    const DEPTH: usize = R::DEPTH + 1;
+
+    fn set_ref(&self) -> Ref {
+        match self {
+            &Ref2::Local(local_ref) =>
+                local_ref,
+            Ref2::External(external_ref) =>
+                external_ref.set_ref(),
+        }
+    }
+
+    fn layer(&self) -> usize {
+        match self {
+            Ref2::Local(..) =>
+                Self::DEPTH,
+            Ref2::External(external_ref) =>
+                external_ref.layer(),
+        }
+    }
+}
+
+pub struct Forest2<T, R> {
+    local_nodes: Set<Entry<T, Ref2<R>>>,
+    roots: Chain,
+    external_children: HashMap<R, Chain>,
+    shadows: HashMap<R, T>,
+    tombstones: HashSet<R>,
+    child_index: bool,
+    jump_index: bool,
+    depth_index: Option<DepthIndex>,
+    keyed: Option<Keyed<T, R>>,
+    shadowing: Option<fn(&T) -> T>,
+}
+
+// Operations on the state keyed by upper layer refs (child index, jump pointers, shadows and tombstones).
+// They are captured by the methods creating that state, so plain inserts and lookups need no bounds on `R`.
+struct Keyed<T, R> {
+    index: fn(&mut Forest2<T, R>, Ref),
+    tombstoned: fn(&Forest2<T, R>, &R) -> bool,
+    shadow: ShadowAccess<T, R>,
+    shadow_mut: ShadowAccessMut<T, R>,
+}
+
+type ShadowAccess<T, R> = for<'s> fn(&'s Forest2<T, R>, &R) -> Option<&'s T>;
+// Inserts the given copy if the node is not shadowed yet.
+type ShadowAccessMut<T, R> = for<'s> fn(&'s mut Forest2<T, R>, &R, Option<T>) -> Option<&'s mut T>;
+
+impl<T, R> Keyed<T, R> where R: Clone + Eq + Hash {
+    fn new() -> Keyed<T, R> {
+        Keyed {
+            index: Forest2::index_node,
+            tombstoned: |forest, node_ref| forest.tombstones.contains(node_ref),
+            shadow: |forest, node_ref| forest.shadows.get(node_ref),
+            shadow_mut: |forest, node_ref, maybe_copy| match maybe_copy {
+                Some(copy) =>
+                    Some(forest.shadows.entry(node_ref.clone()).or_insert(copy)),
+                None =>
+                    forest.shadows.get_mut(node_ref),
+            },
+        }
+    }
+}
+
+impl<T, R> Forest2<T, R> {
+    pub fn new() -> Forest2<T, R> {
+        Forest2 {
+            local_nodes: Set::new(),
+            roots: Chain::default(),
+            external_children: HashMap::new(),
+            shadows: HashMap::new(),
+            tombstones: HashSet::new(),
+            child_index: false,
+            jump_index: false,
+            depth_index: None,
+            keyed: None,
+            shadowing: None,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Forest2<T, R> {
+        Forest2 {
+            local_nodes: Set::with_capacity(capacity),
+            roots: Chain::default(),
+            external_children: HashMap::new(),
+            shadows: HashMap::new(),
+            tombstones: HashSet::new(),
+            child_index: false,
+            jump_index: false,
+            depth_index: None,
+            keyed: None,
+            shadowing: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.local_nodes.clear();
+        self.roots = Chain::default();
+        if let Some(depth_index) = self.depth_index.as_mut() {
+            *depth_index = DepthIndex::default();
+        }
+        self.external_children.clear();
+        self.shadows.clear();
+        self.tombstones.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.local_nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn has_child_index(&self) -> bool {
+        self.child_index
+    }
+
+    // Builds the child index over already existing local nodes (including local children
+    // of external nodes) and maintains it from now on.
+    pub fn enable_child_index(&mut self) where R: Clone + Eq + Hash {
+        if !self.child_index {
+            self.enable_keyed();
+            self.child_index = true;
+            self.external_children.clear();
+            self.rebuild_child_index();
+        }
+    }
+
+    pub fn has_jump_index(&self) -> bool {
+        self.jump_index
+    }
+
+    // Builds ancestor jump pointers for already existing local nodes and maintains them from now on.
+    pub fn enable_jump_index(&mut self) where R: Clone + Eq + Hash {
+        if !self.jump_index {
+            self.enable_keyed();
+            self.jump_index = true;
+            self.rebuild_jump_index();
+        }
+    }
+
+    pub fn has_shadowing(&self) -> bool {
+        self.shadowing.is_some()
+    }
+
+    // In the shadowing mode `get_mut` of an external node copies it into this layer on the first access
+    // (see `shadow_mut`) instead of mutating the upper layer in place.
+    pub fn enable_shadowing(&mut self) where T: Clone, R: Clone + Eq + Hash {
+        self.enable_keyed();
+        self.shadowing = Some(T::clone);
+    }
+
+    // Existing shadows stay, only new ones are not created by `get_mut`.
+    pub fn disable_shadowing(&mut self) {
+        self.shadowing = None;
+    }
+
+    pub fn has_depth_index(&self) -> bool {
+        self.depth_index.is_some()
+    }
+
+    // See `Forest1::enable_depth_index`, shadows are not indexed as their depths live in the upper layers.
+    pub fn enable_depth_index(&mut self) where R: Clone + Eq + Hash {
+        if self.depth_index.is_none() {
+            self.depth_index = Some(DepthIndex::default());
+            self.rebuild_depth_index();
+        }
+    }
+
+    // Local nodes at `depth`, shadowed nodes are found at their depths in the upper layers.
+    pub fn nodes_at_depth(&self, depth: usize) -> impl Iterator<Item = Ref2<R>> + '_ {
+        depth_or_scan(&self.local_nodes, self.depth_index.as_ref(), depth).map(Ref2::Local)
+    }
+
+    pub fn par_nodes_at_depth(&self, depth: usize) -> impl ParallelIterator<Item = Ref2<R>> + '_ where T: Sync, R: Sync + Send {
+        par_depth_or_scan(&self.local_nodes, self.depth_index.as_ref(), depth).map(Ref2::Local)
+    }
+
+    // Number of local nodes at each depth.
+    pub fn depth_histogram(&self) -> Vec<usize> {
+        histogram_or_scan(&self.local_nodes, self.depth_index.as_ref())
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.depth_histogram().len().checked_sub(1)
+    }
+
+    // Upper layer nodes hidden by this layer: shadowed or tombstoned ones.
+    pub fn masked_refs(&self) -> impl Iterator<Item = &R> where R: Eq + Hash {
+        self.shadows.keys()
+            .filter(|node_ref| !self.tombstones.contains(*node_ref))
+            .chain(self.tombstones.iter())
+    }
+
+    pub fn tombstoned_refs(&self) -> impl Iterator<Item = &R> {
+        self.tombstones.iter()
+    }
+
+    pub fn make_root(&mut self, item: T) -> Ref2<R> {
+        self.insert(Node { item, parent: None, depth: 0, })
+    }
+
+    pub fn insert(&mut self, node: Node<T, Ref2<R>>) -> Ref2<R> {
+        let depth = node.depth;
+        let node_ref = self.local_nodes.insert(Entry::new(node));
+        if let Some(index) = self.keyed.as_ref().map(|keyed| keyed.index) {
+            index(self, node_ref);
+        }
+        if let Some(depth_index) = self.depth_index.as_mut() {
+            depth_index.add(node_ref, depth);
+        }
+        Ref2::Local(node_ref)
+    }
+
+    // Links a freshly inserted node into the enabled indexes.
+    fn index_node(&mut self, node_ref: Ref) where R: Clone + Eq + Hash {
+        self.refresh_jumps(&[node_ref]);
+        if self.child_index {
+            let parent = self.local_nodes.get(node_ref).and_then(|entry| entry.node.parent.clone());
+            self.link_last(parent.as_ref(), node_ref);
+        }
+    }
+
+    fn enable_keyed(&mut self) where R: Clone + Eq + Hash {
+        if self.keyed.is_none() {
+            self.keyed = Some(Keyed::new());
+        }
+    }
+
+    // `insert` checking the parent (local or visible through upper layers) and the depth of the node first.
+    pub fn try_insert<'a, A>(&mut self, upper_layer_access: A, node: Node<T, Ref2<R>>) -> Result<Ref2<R>, InsertError>
+        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        let expected = match node.parent.clone() {
+            None =>
+                0,
+            Some(parent_ref) =>
+                self.get(upper_layer_access, parent_ref).ok_or(InsertError::ParentNotFound)?.depth + 1,
+        };
+        if node.depth != expected {
+            return Err(InsertError::DepthMismatch { expected, found: node.depth, });
+        }
+        Ok(self.insert(node))
+    }
+
+    // Reports what `merge_down` would change in upper layers: local subtrees grouped by the node they
+    // attach to, shadowed and tombstoned nodes.
+    pub fn diff(&self) -> LayerDiff<R> where R: Clone + Eq + Hash {
+        let mut diff = LayerDiff::new();
+        let mut seen = HashSet::new();
+        for (_, entry) in self.local_nodes.iter() {
+            let parent = match entry.node.parent {
+                Some(Ref2::Local(..)) =>
+                    continue,
+                Some(Ref2::External(ref parent_ref)) =>
+                    Some(parent_ref.clone()),
+                None =>
+                    None,
+            };
+            if !seen.insert(parent.clone()) {
+                continue;
+            }
+            let children = match parent {
+                Some(ref parent_ref) =>
+                    self.local_children_refs(&Ref2::External(parent_ref.clone())),
+                None =>
+                    self.local_siblings(None),
+            };
+            let added = children.iter().map(|&child_ref| self.local_subtree(child_ref).len()).sum();
+            let children = children.into_iter().map(Ref2::Local).collect();
+            diff.attachments.push(Attachment { parent, children, added, });
+        }
+        diff.added = self.local_nodes.len();
+        diff.shadowed = self.shadows.keys().filter(|node_ref| !self.tombstones.contains(*node_ref)).cloned().collect();
+        diff.tombstoned = self.tombstones.iter().cloned().collect();
+        diff
+    }
+
+    // Checks structural invariants of the layer, see `VerifyReport`. Besides local invariants external
+    // parents, shadows and tombstones must refer to nodes present in upper layers.
+    pub fn verify<'a, A>(&self, upper_layer_access: A) -> VerifyReport<Ref2<R>>
+        where T: 'a, R: Clone + Eq + Hash, A: Fn(R) -> Option<Node<&'a T, R>>
+    {
+        let mut report = self.verify_entries();
+        for (node_ref, entry) in self.local_nodes.iter() {
+            if let Some(parent_ref @ Ref2::External(..)) = entry.node.parent.clone() {
+                match self.get(&upper_layer_access, parent_ref) {
+                    None =>
+                        report.invalid_layer_refs.push(Ref2::Local(node_ref)),
+                    Some(parent) if parent.depth + 1 != entry.node.depth =>
+                        report.depth_mismatches.push(Ref2::Local(node_ref)),
+                    Some(..) =>
+                        (),
+                }
+            }
+        }
+        for external_ref in self.shadows.keys().chain(self.tombstones.iter()) {
+            if upper_layer_access(external_ref.clone()).is_none() {
+                report.invalid_layer_refs.push(Ref2::External(external_ref.clone()));
+            }
+        }
+        for (external_ref, chain) in self.external_children.iter() {
+            let parent = chain.first
+                .and_then(|first_ref| self.local_nodes.get(first_ref))
+                .and_then(|entry| entry.node.parent.clone());
+            if parent != Some(Ref2::External(external_ref.clone())) {
+                report.index_errors.push(Ref2::External(external_ref.clone()));
+            }
+        }
+        report
+    }
+
+    pub fn get<'s, 'a: 's, A>(&'s self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<&'s T, Ref2<R>>>
+        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) => {
+                self.local_nodes.get(local_node_ref)
+                    .map(|entry| Node {
+                        item: &entry.node.item,
+                        parent: entry.node.parent.clone(),
+                        depth: entry.node.depth,
+                    })
+            },
+            Ref2::External(ref external_node_ref) if self.is_tombstoned(external_node_ref) =>
+                None,
+            Ref2::External(external_node_ref) => {
+                // a shadow replaces only the item, the position is always the upper layer one
+                let maybe_shadow = self.keyed.as_ref().and_then(|keyed| (keyed.shadow)(self, &external_node_ref));
+                upper_layer_access(external_node_ref)
+                    .map(|node| Node {
+                        item: maybe_shadow.unwrap_or(node.item),
+                        parent: node.parent.map(Ref2::External),
+                        depth: node.depth,
+                    })
+            },
+        }
+    }
+
+    // Mutable access to a node: an external node is mutated in place in the upper layer unless it
+    // is already shadowed in this layer or the shadowing mode is on (see `enable_shadowing`).
+    pub fn get_mut<'s, 'a: 's, A>(&'s mut self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<&'s mut T, Ref2<R>>>
+        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a mut T, R>>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) => {
+                self.local_nodes.get_mut(local_node_ref)
+                    .map(|entry| Node {
+                        item: &mut entry.node.item,
+                        parent: entry.node.parent.clone(),
+                        depth: entry.node.depth,
+                    })
+            },
+            Ref2::External(ref external_node_ref) if self.is_tombstoned(external_node_ref) =>
+                None,
+            Ref2::External(external_node_ref) => {
+                let node = upper_layer_access(external_node_ref.clone())?;
+                let parent = node.parent.map(Ref2::External);
+                let depth = node.depth;
+                let (shadow, shadow_mut) = match self.keyed.as_ref() {
+                    Some(keyed) =>
+                        (keyed.shadow, keyed.shadow_mut),
+                    None =>
+                        return Some(Node { item: node.item, parent, depth, }),
+                };
+                let maybe_copy = match self.shadowing {
+                    Some(copy) if shadow(self, &external_node_ref).is_none() =>
+                        Some(copy(node.item)),
+                    _ =>
+                        None,
+                };
+                let item = match shadow_mut(self, &external_node_ref, maybe_copy) {
+                    Some(shadow_item) =>
+                        shadow_item,
+                    None =>
+                        node.item,
+                };
+                Some(Node { item, parent, depth, })
+            },
+        }
+    }
+
+    // Copy-on-write mutable access: the first access to an external node copies its item into this layer,
+    // and from now on the copy shadows the upper layer node for all lookups through this layer.
+    // Shadow copies are written back over the originals by `merge_down`.
+    pub fn shadow_mut<'s, 'a, A>(&'s mut self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<&'s mut T, Ref2<R>>>
+        where T: Clone + 'a, R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) => {
+                self.local_nodes.get_mut(local_node_ref)
+                    .map(|entry| Node {
+                        item: &mut entry.node.item,
+                        parent: entry.node.parent.clone(),
+                        depth: entry.node.depth,
+                    })
+            },
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                None,
+            Ref2::External(external_node_ref) => {
+                let node = upper_layer_access(external_node_ref.clone())?;
+                self.enable_keyed();
+                let item = self.shadows.entry(external_node_ref).or_insert_with(|| node.item.clone());
+                Some(Node { item, parent: node.parent.map(Ref2::External), depth: node.depth, })
+            },
+        }
+    }
+
+    pub fn is_shadowed(&self, node_ref: &R) -> bool {
+        self.keyed.as_ref().is_some_and(|keyed| (keyed.shadow)(self, node_ref).is_some())
+    }
+
+    // Drops the shadow copy of an external node so the upper layer original becomes visible again.
+    pub fn discard_shadow(&mut self, node_ref: &R) -> Option<T> where R: Eq + Hash {
+        self.shadows.remove(node_ref)
+    }
+
+    pub fn is_tombstoned(&self, node_ref: &R) -> bool {
+        self.keyed.as_ref().is_some_and(|keyed| (keyed.tombstoned)(self, node_ref))
+    }
+
+    // Checks if an upper layer node is hidden by this layer, so it should be skipped by the upper layer iteration.
+    pub fn is_masked(&self, node_ref: &R) -> bool {
+        self.is_shadowed(node_ref) || self.is_tombstoned(node_ref)
+    }
+
+    // Removes a node without touching the upper layers: an external node is marked with a tombstone
+    // and is treated as absent by this layer until `merge_down` removes it from the target.
+    // Fails if the node is missing or still has upper layer children (see `tombstone_subtree`),
+    // local children become roots like with `OrphanPolicy::Promote`.
+    pub fn tombstone<'a, A, C, I>(&mut self, upper_layer_access: A, upper_layer_children: C, node_ref: Ref2<R>) -> bool
+        where T: 'a, R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Node<&'a T, R>>, C: FnOnce(R) -> I, I: Iterator<Item = R>
+    {
+        self.remove_with(upper_layer_access, upper_layer_children, node_ref, OrphanPolicy::Promote).is_some()
+    }
+
+    // Removes a node with all of its descendants without touching the upper layers: upper layer nodes
+    // of the subtree are marked with tombstones and local ones are removed. Returns refs of all the
+    // nodes gone from this layer view.
+    pub fn tombstone_subtree<A, I>(&mut self, upper_layer_children: A, node_ref: Ref2<R>) -> Vec<Ref2<R>>
+        where R: Clone + Eq + Hash, A: Fn(R) -> I, I: Iterator<Item = R>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) =>
+                self.remove_local_subtree(local_node_ref)
+                    .into_iter()
+                    .map(|(removed_ref, _)| Ref2::Local(removed_ref))
+                    .collect(),
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                Vec::new(),
+            Ref2::External(external_node_ref) => {
+                self.enable_keyed();
+                let mut removed = Vec::new();
+                let mut stack = vec![external_node_ref];
+                while let Some(upper_ref) = stack.pop() {
+                    stack.extend(upper_layer_children(upper_ref.clone()).filter(|child_ref| !self.tombstones.contains(child_ref)));
+                    self.shadows.remove(&upper_ref);
+                    self.tombstones.insert(upper_ref.clone());
+                    let removed_ref = Ref2::External(upper_ref);
+                    for child_ref in self.local_children_refs(&removed_ref) {
+                        removed.extend(
+                            self.remove_local_subtree(child_ref)
+                                .into_iter()
+                                .map(|(local_ref, _)| Ref2::Local(local_ref)),
+                        );
+                    }
+                    removed.push(removed_ref);
+                }
+                removed
+            },
+        }
+    }
+
+    // Drops the tombstone of an external node making it visible again.
+    pub fn discard_tombstone(&mut self, node_ref: &R) -> bool where R: Eq + Hash {
+        self.tombstones.remove(node_ref)
+    }
+
+    // Upper layer nodes which became leaves because all of their children are tombstoned in this layer.
+    // `upper_layer_parent` gives the parent of an upper layer node, `has_children` checks a node through all the layers.
+    pub fn uncovered_leaves<A, C>(&self, upper_layer_parent: A, has_children: C) -> Vec<Ref2<R>>
+        where R: Clone + Eq + Hash, A: Fn(R) -> Option<R>, C: Fn(Ref2<R>) -> bool
+    {
+        let parents: HashSet<R> = self.tombstones.iter()
+            .flat_map(|node_ref| upper_layer_parent(node_ref.clone()))
+            .filter(|parent_ref| !self.tombstones.contains(parent_ref))
+            .collect();
+        parents.into_iter()
+            .map(Ref2::External)
+            .filter(|parent_ref| !has_children(parent_ref.clone()))
+            .collect()
+    }
+
+    // Removes a single node in O(1) leaving its children in place, see `Forest1::remove`. An external
+    // node stays in the upper layer and is marked with a tombstone applied by `merge_down`, so the removed
+    // item is there only for a local node or a shadowed external one.
+    pub fn remove<'a, A>(&mut self, upper_layer_access: A, node_ref: Ref2<R>) -> Option<Node<Option<T>, Ref2<R>>>
+        where T: 'a, R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) =>
+                self.drop_local(local_node_ref)
+                    .map(|node| Node { item: Some(node.item), parent: node.parent, depth: node.depth, }),
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                None,
+            Ref2::External(external_node_ref) => {
+                let node = upper_layer_access(external_node_ref.clone())?;
+                let (parent, depth) = (node.parent.map(Ref2::External), node.depth);
+                self.enable_keyed();
+                let item = self.shadows.remove(&external_node_ref);
+                self.tombstones.insert(external_node_ref);
+                Some(Node { item, parent, depth, })
+            },
+        }
+    }
+
+    // Removes a single node handling its local children (including local children of an external node)
+    // according to `policy`, see `Forest1::remove_with`. Like with `remove` an external node is only
+    // tombstoned and its item is there if it was shadowed. Upper layer children can not be moved from
+    // this layer, so an external node still having them is never removed.
+    pub fn remove_with<'a, A, C, I>(
+        &mut self,
+        upper_layer_access: A,
+        upper_layer_children: C,
+        node_ref: Ref2<R>,
+        policy: OrphanPolicy,
+    )
+        -> Option<Node<Option<T>, Ref2<R>>>
+        where T: 'a, R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Node<&'a T, R>>, C: FnOnce(R) -> I, I: Iterator<Item = R>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) =>
+                self.remove_local(local_node_ref, policy)
+                    .map(|node| Node { item: Some(node.item), parent: node.parent, depth: node.depth, }),
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                None,
+            Ref2::External(ref external_node_ref) => {
+                let (parent, depth) = match upper_layer_access(external_node_ref.clone()) {
+                    Some(node) =>
+                        (node.parent.map(Ref2::External), node.depth),
+                    None =>
+                        return None,
+                };
+                if upper_layer_children(external_node_ref.clone()).any(|child_ref| !self.tombstones.contains(&child_ref)) {
+                    return None;
+                }
+                let orphans = self.local_children_refs(&node_ref);
+                if policy == OrphanPolicy::Reject && !orphans.is_empty() {
+                    return None;
+                }
+                self.enable_keyed();
+                let item = self.shadows.remove(external_node_ref);
+                self.tombstones.insert(external_node_ref.clone());
+                self.adopt_orphans(orphans, policy, &node_ref, Node { item: (), parent: parent.clone(), depth, });
+                Some(Node { item, parent, depth, })
+            },
+        }
+    }
+
+    // Removes a node with all of its descendants in level order: upper layer nodes of the subtree are
+    // tombstoned (with items of the shadowed ones) and local nodes hanging from them are removed.
+    pub fn remove_subtree<'a, A, C, I>(&mut self, upper_layer_access: A, upper_layer_children: C, node_ref: Ref2<R>) -> RemovedNodes<Option<T>, Ref2<R>, Ref2<R>>
+        where T: 'a, R: Clone + Eq + Hash, A: Fn(R) -> Option<Node<&'a T, R>>, C: Fn(R) -> I, I: Iterator<Item = R>
+    {
+        let local_subtree = |forest: &mut Forest2<T, R>, local_node_ref| {
+            forest.remove_local_subtree(local_node_ref)
+                .into_iter()
+                .map(|(removed_ref, node)| (Ref2::Local(removed_ref), Node { item: Some(node.item), parent: node.parent, depth: node.depth, }))
+        };
+        match node_ref {
+            Ref2::Local(local_node_ref) =>
+                local_subtree(self, local_node_ref).collect(),
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                Vec::new(),
+            Ref2::External(external_node_ref) => {
+                self.enable_keyed();
+                let mut removed = Vec::new();
+                let mut upper_refs = vec![external_node_ref];
+                let mut index = 0;
+                while index < upper_refs.len() {
+                    let upper_ref = upper_refs[index].clone();
+                    index += 1;
+                    let (parent, depth) = match upper_layer_access(upper_ref.clone()) {
+                        Some(node) =>
+                            (node.parent.map(Ref2::External), node.depth),
+                        None =>
+                            continue,
+                    };
+                    upper_refs.extend(upper_layer_children(upper_ref.clone()).filter(|child_ref| !self.tombstones.contains(child_ref)));
+                    let item = self.shadows.remove(&upper_ref);
+                    self.tombstones.insert(upper_ref.clone());
+                    let removed_ref = Ref2::External(upper_ref);
+                    let local_children = self.local_children_refs(&removed_ref);
+                    removed.push((removed_ref, Node { item, parent, depth, }));
+                    for child_ref in local_children {
+                        removed.extend(local_subtree(self, child_ref));
+                    }
+                }
+                removed
+            },
+        }
+    }
+
+    pub fn make_node<'s, 'a, A>(&'s mut self, upper_layer_access: A, parent_ref: Ref2<R>, item: T) -> Ref2<R>
+        where T: 'a, R: Clone, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        if let Some(parent_depth) = self.get(upper_layer_access, parent_ref.clone()).map(|node| node.depth) {
+            self.insert(Node { item, parent: Some(parent_ref), depth: parent_depth + 1, })
+        } else {
+            self.insert(Node { item, parent: None, depth: 0, })
+        }
+    }
+
+    // Moves the subtree of a local node under `parent_ref` (local or external) recomputing depths
+    // of the whole subtree.
+    pub fn reparent<'a, A>(&mut self, upper_layer_access: A, node_ref: Ref2<R>, parent_ref: Ref2<R>) -> Result<(), ReparentError>
+        where T: 'a, R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        let local_node_ref = match node_ref {
+            Ref2::Local(local_node_ref) if self.local_nodes.get(local_node_ref).is_some() =>
+                local_node_ref,
+            Ref2::Local(..) =>
+                return Err(ReparentError::NodeNotFound),
+            Ref2::External(..) =>
+                return Err(ReparentError::UpperLayerNode),
+        };
+        let parent_depth = self.get(upper_layer_access, parent_ref.clone())
+            .ok_or(ReparentError::ParentNotFound)?
+            .depth;
+        let mut cursor = Some(parent_ref.clone());
+        while let Some(Ref2::Local(cursor_ref)) = cursor {
+            if cursor_ref == local_node_ref {
+                return Err(ReparentError::Cycle);
+            }
+            cursor = self.local_nodes.get(cursor_ref).and_then(|entry| entry.node.parent.clone());
+        }
+        self.move_local(local_node_ref, Some(parent_ref), parent_depth + 1);
+        Ok(())
+    }
+
+    // Removes every node not descending from `roots_to_keep` across the layers: `upper_nodes` are all the
+    // nodes of upper layers with their parents and items. Local nodes are removed and upper layer ones are
+    // tombstoned. Kept local roots are detached while ancestors of kept upper layer roots are kept.
+    pub fn gc<'a, I>(&mut self, upper_nodes: I, roots_to_keep: &[Ref2<R>]) -> GcReport<Ref2<R>>
+        where T: 'a, R: Clone + Eq + Hash, I: Iterator<Item = (R, Option<R>, &'a T)>
+    {
+        let nodes: Vec<_> = self.layer_nodes(upper_nodes).into_iter().map(|(node_ref, parent, _)| (node_ref, parent)).collect();
+        let parents: HashMap<_, _> = nodes.iter()
+            .flat_map(|(node_ref, parent)| parent.clone().map(|parent_ref| (node_ref.clone(), parent_ref)))
+            .collect();
+        let known: HashSet<_> = nodes.iter().map(|pair| pair.0.clone()).collect();
+        let mut report = sweep(nodes.into_iter(), |node_ref| roots_to_keep.contains(node_ref), true);
+        let mut upper_ancestors = HashSet::new();
+        for root_ref in roots_to_keep {
+            if let Ref2::External(..) = root_ref {
+                let mut cursor = parents.get(root_ref);
+                while let Some(parent_ref) = cursor {
+                    if !upper_ancestors.insert(parent_ref.clone()) {
+                        break;
+                    }
+                    cursor = parents.get(parent_ref);
+                }
+            }
+        }
+        if !upper_ancestors.is_empty() {
+            report.removed.retain(|node_ref| !upper_ancestors.contains(node_ref));
+            report.kept += upper_ancestors.len();
+        }
+        let removed: HashSet<_> = report.removed.iter().cloned().collect();
+        for root_ref in roots_to_keep {
+            if let Ref2::Local(local_ref) = *root_ref {
+                let parent = self.local_nodes.get(local_ref).and_then(|entry| entry.node.parent.clone());
+                if parent.is_some_and(|parent_ref| removed.contains(&parent_ref) || !known.contains(&parent_ref)) {
+                    self.move_local(local_ref, None, 0);
+                }
+            }
+        }
+        self.drop_nodes(&report.removed);
+        report
+    }
+
+    // Removes the subtrees of all the nodes matching `predicate` across the layers (see `gc`).
+    pub fn prune<'a, I, P>(&mut self, upper_nodes: I, predicate: P) -> GcReport<Ref2<R>>
+        where T: 'a, R: Clone + Eq + Hash, I: Iterator<Item = (R, Option<R>, &'a T)>, P: Fn(&Ref2<R>, &T) -> bool
+    {
+        let mut matched = HashSet::new();
+        let mut nodes = Vec::new();
+        for (node_ref, parent, item) in self.layer_nodes(upper_nodes) {
+            if predicate(&node_ref, item) {
+                matched.insert(node_ref.clone());
+            }
+            nodes.push((node_ref, parent));
+        }
+        let report = sweep(nodes.into_iter(), |node_ref| matched.contains(node_ref), false);
+        self.drop_nodes(&report.removed);
+        report
+    }
+
+    // All the nodes visible through this layer: local ones and not tombstoned upper ones (with shadowed items).
+    fn layer_nodes<'s, 'a: 's, I>(&'s self, upper_nodes: I) -> LayerNodes<'s, T, Ref2<R>>
+        where T: 'a, R: Clone + Eq + Hash, I: Iterator<Item = (R, Option<R>, &'a T)>
+    {
+        self.local_nodes.iter()
+            .map(|(node_ref, entry)| (Ref2::Local(node_ref), entry.node.parent.clone(), &entry.node.item))
+            .chain(
+                upper_nodes
+                    .filter(|(node_ref, _, _)| !self.tombstones.contains(node_ref))
+                    .map(|(node_ref, parent, item)| {
+                        let item = self.shadows.get(&node_ref).unwrap_or(item);
+                        (Ref2::External(node_ref), parent.map(Ref2::External), item)
+                    }),
+            )
+            .collect()
+    }
+
+    fn drop_nodes(&mut self, node_refs: &[Ref2<R>]) where R: Clone + Eq + Hash {
+        for node_ref in node_refs {
+            match node_ref {
+                &Ref2::Local(local_ref) => {
+                    self.drop_local(local_ref);
+                },
+                Ref2::External(external_ref) => {
+                    self.enable_keyed();
+                    self.shadows.remove(external_ref);
+                    self.external_children.remove(external_ref);
+                    self.tombstones.insert(external_ref.clone());
+                },
+            }
+        }
+    }
+
+    // Detaches the subtree of a local node from its parent making it a separate tree.
+    pub fn detach(&mut self, node_ref: Ref2<R>) -> Result<(), ReparentError> where R: Clone + Eq + Hash {
+        match node_ref {
+            Ref2::Local(local_node_ref) if self.local_nodes.get(local_node_ref).is_some() => {
+                self.move_local(local_node_ref, None, 0);
+                Ok(())
+            },
+            Ref2::Local(..) =>
+                Err(ReparentError::NodeNotFound),
+            Ref2::External(..) =>
+                Err(ReparentError::UpperLayerNode),
+        }
+    }
+
+    // Recomputes depths of local nodes under `node_ref` after the upper layers were restructured
+    // (e.g. by `Forest1::reparent` or `Forest1::detach`): for an external node local subtrees hanging
+    // anywhere below it in the upper layers get depths from their upper layer parents.
+    pub fn refresh_depths<'a, A, C, I>(&mut self, upper_layer_access: A, upper_layer_children: C, node_ref: Ref2<R>)
+        where T: 'a, R: Clone + Eq + Hash, A: Fn(R) -> Option<Node<&'a T, R>>, C: Fn(R) -> I, I: Iterator<Item = R>
+    {
+        self.enable_child_index();
+        match node_ref {
+            Ref2::Local(local_node_ref) => {
+                let parent_ref = match self.local_nodes.get(local_node_ref) {
+                    Some(entry) =>
+                        entry.node.parent.clone(),
+                    None =>
+                        return,
+                };
+                let depth = parent_ref
+                    .and_then(|parent_ref| self.get(&upper_layer_access, parent_ref))
+                    .map_or(0, |parent| parent.depth + 1);
+                self.set_subtree_depth(local_node_ref, depth);
+            },
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                (),
+            Ref2::External(external_node_ref) => {
+                let mut stack = vec![external_node_ref];
+                while let Some(upper_ref) = stack.pop() {
+                    let depth = match upper_layer_access(upper_ref.clone()) {
+                        Some(node) =>
+                            node.depth,
+                        None =>
+                            continue,
+                    };
+                    for child_ref in self.local_children_refs(&Ref2::External(upper_ref.clone())) {
+                        self.set_subtree_depth(child_ref, depth + 1);
+                    }
+                    stack.extend(upper_layer_children(upper_ref).filter(|child_ref| !self.tombstones.contains(child_ref)));
+                }
+            },
+        }
+    }
+
+    pub fn ancestry<A>(&self, upper_layer_ancestry: A, node_ref: Ref2<R>) -> Option<Ancestry<Ref2<R>>>
+        where R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Ancestry<R>>
+    {
+        match node_ref {
+            Ref2::Local(local_node_ref) =>
+                self.local_nodes.get(local_node_ref)
+                    .map(|entry| Ancestry::from_entry(entry, self.jump_index)),
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                None,
+            Ref2::External(external_node_ref) =>
+                upper_layer_ancestry(external_node_ref)
+                    .map(|ancestry| Ancestry {
+                        parent: ancestry.parent.map(Ref2::External),
+                        depth: ancestry.depth,
+                        jump: ancestry.jump.map(|(jump_ref, jump_depth)| (Ref2::External(jump_ref), jump_depth)),
+                    }),
+        }
+    }
+
+    // `Forest1::towards_root_mut` across layers: shadowed nodes are mutated in this layer, other external
+    // nodes in place in upper layers. The walk stops at a tombstoned node.
+    pub fn towards_root_mut<A, F>(&mut self, upper_layer_towards_root_mut: A, node_ref: Ref2<R>, mut f: F)
+        where R: Clone + Eq + Hash, A: FnOnce(R, &mut dyn FnMut(R, &mut T)), F: FnMut(Ref2<R>, &mut T)
+    {
+        let mut cursor = Some(node_ref);
+        let external_node_ref = loop {
+            match cursor {
+                Some(Ref2::Local(local_node_ref)) => {
+                    let entry = match self.local_nodes.get_mut(local_node_ref) {
+                        Some(entry) =>
+                            entry,
+                        None =>
+                            return,
+                    };
+                    f(Ref2::Local(local_node_ref), &mut entry.node.item);
+                    cursor = entry.node.parent.clone();
+                },
+                Some(Ref2::External(external_node_ref)) =>
+                    break external_node_ref,
+                None =>
+                    return,
+            }
+        };
+        let shadows = &mut self.shadows;
+        let tombstones = &self.tombstones;
+        let mut stopped = false;
+        upper_layer_towards_root_mut(external_node_ref, &mut |upper_node_ref, item| {
+            stopped = stopped || tombstones.contains(&upper_node_ref);
+            if stopped {
+                return;
+            }
+            match shadows.get_mut(&upper_node_ref) {
+                Some(shadow) =>
+                    f(Ref2::External(upper_node_ref), shadow),
+                None =>
+                    f(Ref2::External(upper_node_ref), item),
+            }
+        });
+    }
+
+    pub fn external_ref(&self, node_ref: R) -> Ref2<R> {
+        Ref2::External(node_ref)
+    }
+
+    // Children of `node_ref` across the layers: for an external node its upper layer children
+    // (provided by `upper_layer_children`) come first and then its children from this layer.
+    pub fn children<'s, A, I>(&'s self, upper_layer_children: A, node_ref: Ref2<R>) -> impl Iterator<Item = Ref2<R>> + 's
+        where R: Clone + Eq + Hash + 's, A: FnOnce(R) -> I, I: Iterator<Item = R> + 's
+    {
+        let maybe_upper_children = match node_ref {
+            Ref2::Local(..) =>
+                None,
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                None,
+            Ref2::External(ref external_node_ref) =>
+                Some(upper_layer_children(external_node_ref.clone())),
+        };
+        maybe_upper_children.into_iter()
+            .flatten()
+            .filter(move |child_ref| !self.tombstones.contains(child_ref))
+            .map(Ref2::External)
+            .chain(self.local_children(node_ref))
+    }
+
+    pub fn local_children(&self, node_ref: Ref2<R>) -> impl Iterator<Item = Ref2<R>> + '_ where R: Clone + Eq + Hash {
+        let maybe_chain = if self.child_index {
+            Some(self.chain_of(Some(&node_ref)))
+        } else {
+            None
+        };
+        chain_or_scan(&self.local_nodes, maybe_chain, move |entry| entry.node.parent.as_ref() == Some(&node_ref))
+            .map(Ref2::Local)
+    }
+
+    // Sibling order across layers: upper layer children (or roots) come first, then local ones, like
+    // after `merge_down`. Siblings of external nodes are found by scanning the upper layer siblings.
+
+    pub fn last_child<A, I>(&self, upper_layer_children: A, node_ref: Ref2<R>) -> Option<Ref2<R>>
+        where R: Clone + Eq + Hash, A: FnOnce(R) -> I, I: Iterator<Item = R>
+    {
+        if let Some(local_ref) = self.local_last_child(&node_ref) {
+            return Some(Ref2::Local(local_ref));
+        }
+        match node_ref {
+            Ref2::External(ref external_node_ref) if !self.tombstones.contains(external_node_ref) =>
+                upper_layer_children(external_node_ref.clone())
+                    .filter(|child_ref| !self.tombstones.contains(child_ref))
+                    .last()
+                    .map(Ref2::External),
+            _ =>
+                None,
+        }
+    }
+
+    pub fn next_sibling<'a, A, C, I, S, J>(
+        &self,
+        upper_layer_access: A,
+        upper_layer_children: C,
+        upper_layer_roots: S,
+        node_ref: Ref2<R>,
+    )
+        -> Option<Ref2<R>>
+        where T: 'a,
+              R: Clone + Eq + Hash,
+              A: FnOnce(R) -> Option<Node<&'a T, R>>,
+              C: FnOnce(R) -> I,
+              I: Iterator<Item = R>,
+              S: FnOnce() -> J,
+              J: Iterator<Item = R>,
+    {
+        let external_node_ref = match node_ref {
+            Ref2::Local(local_node_ref) =>
+                return self.local_next_sibling(local_node_ref).map(Ref2::Local),
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                return None,
+            Ref2::External(external_node_ref) =>
+                external_node_ref,
+        };
+        let parent = upper_layer_access(external_node_ref.clone())?.parent;
+        let next_upper_ref = self.upper_siblings(upper_layer_children, upper_layer_roots, parent.clone())
+            .into_iter()
+            .skip_while(|sibling_ref| sibling_ref != &external_node_ref)
+            .nth(1);
+        match next_upper_ref {
+            Some(next_upper_ref) =>
+                Some(Ref2::External(next_upper_ref)),
+            None =>
+                self.local_siblings(parent.map(Ref2::External).as_ref()).first().cloned().map(Ref2::Local),
+        }
+    }
+
+    pub fn prev_sibling<'a, A, C, I, S, J>(
+        &self,
+        upper_layer_access: A,
+        upper_layer_children: C,
+        upper_layer_roots: S,
+        node_ref: Ref2<R>,
+    )
+        -> Option<Ref2<R>>
+        where T: 'a,
+              R: Clone + Eq + Hash,
+              A: FnOnce(R) -> Option<Node<&'a T, R>>,
+              C: FnOnce(R) -> I,
+              I: Iterator<Item = R>,
+              S: FnOnce() -> J,
+              J: Iterator<Item = R>,
+    {
+        let (external_node_ref, parent) = match node_ref {
+            Ref2::Local(local_node_ref) => {
+                if let Some(prev_ref) = self.local_prev_sibling(local_node_ref) {
+                    return Some(Ref2::Local(prev_ref));
+                }
+                match self.local_nodes.get(local_node_ref)?.node.parent.clone() {
+                    Some(Ref2::Local(..)) =>
+                        return None,
+                    Some(Ref2::External(parent_ref)) =>
+                        (None, Some(parent_ref)),
+                    None =>
+                        (None, None),
+                }
+            },
+            Ref2::External(ref external_node_ref) if self.tombstones.contains(external_node_ref) =>
+                return None,
+            Ref2::External(external_node_ref) => {
+                let parent = upper_layer_access(external_node_ref.clone())?.parent;
+                (Some(external_node_ref), parent)
+            },
+        };
+        self.upper_siblings(upper_layer_children, upper_layer_roots, parent)
+            .into_iter()
+            .take_while(|sibling_ref| Some(sibling_ref) != external_node_ref.as_ref())
+            .last()
+            .map(Ref2::External)
+    }
+
+    // Upper layer children of `parent` (or upper layer roots) not tombstoned in this layer.
+    fn upper_siblings<C, I, S, J>(&self, upper_layer_children: C, upper_layer_roots: S, parent: Option<R>) -> Vec<R>
+        where R: Eq + Hash, C: FnOnce(R) -> I, I: Iterator<Item = R>, S: FnOnce() -> J, J: Iterator<Item = R>
+    {
+        match parent {
+            Some(parent_ref) =>
+                upper_layer_children(parent_ref).filter(|r| !self.tombstones.contains(r)).collect(),
+            None =>
+                upper_layer_roots().filter(|r| !self.tombstones.contains(r)).collect(),
+        }
+    }
+
+    // Ordered insertions and moves, see `Forest1::insert_child_at`. Upper layer nodes keep their order,
+    // so only local nodes can be positioned.
+
+    // Inserts a node at `index` among the local children of `parent_ref`.
+    pub fn insert_child_at<'a, A>(&mut self, upper_layer_access: A, parent_ref: Ref2<R>, index: usize, item: T) -> Option<Ref2<R>>
+        where T: 'a, R: Clone + Eq + Hash, A: FnOnce(R) -> Option<Node<&'a T, R>>
+    {
+        let depth = self.get(upper_layer_access, parent_ref.clone())?.depth + 1;
+        self.enable_child_index();
+        let next_ref = self.local_siblings(Some(&parent_ref)).get(index).cloned();
+        Some(self.insert_before_ref(Node { item, parent: Some(parent_ref), depth, }, next_ref))
+    }
+
+    // Inserts a node in front of a local sibling.
+    pub fn insert_before(&mut self, sibling_ref: Ref2<R>, item: T) -> Option<Ref2<R>> where R: Clone + Eq + Hash {
+        let local_sibling_ref = Self::as_local(&sibling_ref)?;
+        let node = self.sibling_node(local_sibling_ref, item)?;
+        self.enable_child_index();
+        Some(self.insert_before_ref(node, Some(local_sibling_ref)))
+    }
+
+    // Inserts a node right after a local sibling.
+    pub fn insert_after(&mut self, sibling_ref: Ref2<R>, item: T) -> Option<Ref2<R>> where R: Clone + Eq + Hash {
+        let local_sibling_ref = Self::as_local(&sibling_ref)?;
+        let node = self.sibling_node(local_sibling_ref, item)?;
+        self.enable_child_index();
+        let next_ref = self.local_next_sibling(local_sibling_ref);
+        Some(self.insert_before_ref(node, next_ref))
+    }
+
+    fn sibling_node(&self, sibling_ref: Ref, item: T) -> Option<Node<T, Ref2<R>>> where R: Clone {
+        let sibling = self.local_nodes.get(sibling_ref)?;
+        Some(Node { item, parent: sibling.node.parent.clone(), depth: sibling.node.depth, })
+    }
+
+    fn insert_before_ref(&mut self, node: Node<T, Ref2<R>>, next_ref: Option<Ref>) -> Ref2<R> where R: Clone + Eq + Hash {
+        let (parent, depth) = (node.parent.clone(), node.depth);
+        let node_ref = self.insert(node);
+        if let (Ref2::Local(local_node_ref), Some(..)) = (&node_ref, next_ref) {
+            self.move_local_before(*local_node_ref, parent, depth, next_ref);
+        }
+        node_ref
+    }
+
+    // Moves the subtree of a local node right in front of a local sibling, see `Forest1::move_before`.
+    pub fn move_before(&mut self, node_ref: Ref2<R>, sibling_ref: Ref2<R>) -> Result<(), ReparentError> where R: Clone + Eq + Hash {
+        let (local_node_ref, local_sibling_ref) = match (node_ref, sibling_ref) {
+            (Ref2::Local(local_node_ref), Ref2::Local(local_sibling_ref)) =>
+                (local_node_ref, local_sibling_ref),
+            _ =>
+                return Err(ReparentError::UpperLayerNode),
+        };
+        let parent = self.local_nodes.get(local_node_ref).ok_or(ReparentError::NodeNotFound)?.node.parent.clone();
+        let sibling = self.local_nodes.get(local_sibling_ref).ok_or(ReparentError::SiblingNotFound)?;
+        let (new_parent, new_depth) = (sibling.node.parent.clone(), sibling.node.depth);
+        if local_sibling_ref == local_node_ref {
+            return Ok(());
+        }
+        if new_parent != parent {
+            let mut cursor = new_parent.as_ref().and_then(Self::as_local);
+            while let Some(cursor_ref) = cursor {
+                if cursor_ref == local_node_ref {
+                    return Err(ReparentError::Cycle);
+                }
+                cursor = self.local_nodes.get(cursor_ref).and_then(|entry| entry.node.parent.as_ref().and_then(Self::as_local));
+            }
+        }
+        self.enable_child_index();
+        self.move_local_before(local_node_ref, new_parent, new_depth, Some(local_sibling_ref));
+        Ok(())
+    }
+
+    pub fn local_roots(&self) -> impl Iterator<Item = Ref2<R>> + '_ {
+        let maybe_chain = if self.child_index {
+            Some(self.roots)
+        } else {
+            None
+        };
+        chain_or_scan(&self.local_nodes, maybe_chain, |entry| entry.node.parent.is_none())
+            .map(Ref2::Local)
+    }
+
+    pub fn local_leaves(&self) -> impl Iterator<Item = Ref2<R>> + '_ {
+        let maybe_parents: Option<HashSet<Ref>> = if self.child_index {
+            None
+        } else {
+            Some(self.local_nodes.values()
+                 .flat_map(|entry| match entry.node.parent {
+                     Some(Ref2::Local(parent_ref)) =>
+                         Some(parent_ref),
+                     _ =>
+                         None,
+                 })
+                 .collect())
+        };
+        self.local_nodes.iter()
+            .filter(move |&(node_ref, entry)| match maybe_parents {
+                None =>
+                    entry.children.first.is_none(),
+                Some(ref parents) =>
+                    !parents.contains(&node_ref),
+            })
+            .map(|pair| Ref2::Local(pair.0))
+    }
+
+    pub fn has_local_children(&self, node_ref: Ref2<R>) -> bool where R: Clone + Eq + Hash {
+        self.local_children(node_ref).next().is_some()
+    }
+
+    // Local nodes followed by shadow copies of external nodes.
+    pub fn local_iter(&self) -> impl Iterator<Item = (Ref2<R>, &T)> where R: Clone {
+        self.local_nodes.iter()
+            .map(|(set_ref, entry)| (Ref2::Local(set_ref), &entry.node.item))
+            .chain(self.shadows.iter().map(|(node_ref, item)| (Ref2::External(node_ref.clone()), item)))
+    }
+
+    pub fn local_par_iter(&self) -> impl ParallelIterator<Item = (Ref2<R>, &T)> where T: Sync, R: Clone + Sync + Send {
+        self.local_nodes.par_iter()
+            .map(|(set_ref, entry)| (Ref2::Local(set_ref), &entry.node.item))
+            .chain(self.shadows.par_iter().map(|(node_ref, item)| (Ref2::External(node_ref.clone()), item)))
+    }
+}
+
+impl<T, R> Forest2<T, R> {
+    // See `Forest1::freeze`, a frozen `Forest2` is accessed along with its own upper layers.
+    pub fn freeze(self) -> Frozen<Forest2<T, R>> {
+        Frozen::new(self)
+    }
+}
+
+impl<T, R> Default for Forest2<T, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, R> Layer for Forest2<T, R> where R: Clone + Eq + Hash {
+    type Item = T;
+    type Parent = Ref2<R>;
+
+    fn entries(&self) -> &Set<Entry<T, Ref2<R>>> {
+        &self.local_nodes
+    }
+
+    fn entries_mut(&mut self) -> &mut Set<Entry<T, Ref2<R>>> {
+        &mut self.local_nodes
+    }
+
+    fn child_index(&self) -> bool {
+        self.child_index
+    }
+
+    fn jump_index(&self) -> bool {
+        self.jump_index
+    }
+
+    fn depth_index(&self) -> Option<&DepthIndex> {
+        self.depth_index.as_ref()
+    }
+
+    fn depth_index_mut(&mut self) -> Option<&mut DepthIndex> {
+        self.depth_index.as_mut()
+    }
+
+    fn local_parent(node_ref: Ref) -> Ref2<R> {
+        Ref2::Local(node_ref)
+    }
+
+    fn as_local(parent: &Ref2<R>) -> Option<Ref> {
+        match parent {
+            &Ref2::Local(local_ref) =>
+                Some(local_ref),
+            Ref2::External(..) =>
+                None,
+        }
+    }
+
+    fn chain_of(&self, parent: Option<&Ref2<R>>) -> Chain {
+        match parent {
+            None =>
+                self.roots,
+            Some(&Ref2::Local(parent_ref)) =>
+                self.local_nodes.get(parent_ref).map_or_else(Chain::default, |entry| entry.children),
+            Some(Ref2::External(parent_ref)) =>
+                self.external_children.get(parent_ref).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn set_chain_of(&mut self, parent: Option<&Ref2<R>>, chain: Chain) {
+        match parent {
+            None =>
+                self.roots = chain,
+            Some(&Ref2::Local(parent_ref)) =>
+                if let Some(entry) = self.local_nodes.get_mut(parent_ref) {
+                    entry.children = chain;
+                },
+            Some(Ref2::External(parent_ref)) =>
+                if chain.first.is_none() {
+                    self.external_children.remove(parent_ref);
+                } else {
+                    self.external_children.insert(parent_ref.clone(), chain);
+                },
+        }
+    }
+}
+
+impl<T, R> Forest2<T, R> where R: Clone + Eq + Hash {
+    pub fn merge_aflat(mut self, mut target: Forest2<T, R>) -> Forest2AflatInitMerger<T, R> {
+        if self.keyed.is_some() {
+            target.enable_keyed();
+        }
+        for node_ref in self.tombstones.drain() {
+            let removed_ref = Ref2::External(node_ref.clone());
+            for child_ref in target.local_children_refs(&removed_ref) {
+                target.remove_local_subtree(child_ref);
+            }
+            target.shadows.remove(&node_ref);
+            target.tombstones.insert(node_ref);
+        }
+        target.shadows.extend(self.shadows.drain());
+        // sibling order of the source survives only in a target child index
+        if self.child_index {
+            target.enable_child_index();
+        }
+        let inner_merger = mem::take(&mut target.local_nodes).merge(mem::take(&mut self.local_nodes));
+        let mut relink = Relink::new(&target, &self);
+        relink.splice(None, mem::take(&mut self.roots), |r| inner_merger.ref_transform(r));
+        for (external_ref, chain) in self.external_children.drain() {
+            relink.splice(Some(Ref2::External(external_ref)), chain, |r| inner_merger.ref_transform(r));
+        }
+        Forest2AflatInitMerger { inner_merger, target, source: self, relink, }
+    }
+}
+
+impl<T, R> Forest2<T, R> where R: LayerRef {
+    // Moves this layer into a stack squash: see `Forest1::squash`.
+    pub fn squash_into(mut self, squash: &mut Squash<T>) {
+        squash.tombstones.extend(self.tombstones.iter().map(LayerRef::set_ref));
+        for (node_ref, shadow) in self.shadows.drain() {
+            let set_ref = node_ref.set_ref();
+            if !squash.tombstones.contains(&set_ref) {
+                squash.shadows.entry(set_ref).or_insert(shadow);
+            }
+        }
+        squash.take_layer(self.local_nodes.drain(), |parent_ref| parent_ref.set_ref());
+    }
+}
+
+impl<T> Forest2<T, Ref> {
+    pub fn merge_down(mut self, mut target: Forest1<T>) -> Forest2Down1InitMerger<T> {
+        // deepest nodes go first so the tombstoned subtrees never produce orphans in the target
+        let mut tombstones: Vec<_> = self.tombstones.drain()
+            .flat_map(|node_ref| target.get(node_ref).map(|node| (node.depth, node_ref)))
+            .collect();
+        tombstones.sort_by_key(|&(depth, _)| Reverse(depth));
+        for (_, node_ref) in tombstones {
+            target.remove(node_ref);
+        }
+        for (node_ref, shadow) in self.shadows.drain() {
+            if let Some(entry) = target.nodes.get_mut(node_ref) {
+                entry.node.item = shadow;
+            }
+        }
+        // sibling order of the source survives only in a target child index
+        if self.child_index {
+            target.enable_child_index();
+        }
+        let inner_merger = mem::take(&mut target.nodes).merge(mem::take(&mut self.local_nodes));
+        let mut relink = Relink::new(&target, &self);
+        relink.splice(None, mem::take(&mut self.roots), |r| inner_merger.ref_transform(r));
+        for (external_ref, chain) in self.external_children.drain() {
+            relink.splice(Some(external_ref), chain, |r| inner_merger.ref_transform(r));
+        }
+        Forest2Down1InitMerger { inner_merger, target, source: self, relink, }
+    }
+}
+
+impl<T, R> Forest2<T, Ref2<R>> where R: Clone + Eq + Hash {
+    pub fn merge_down(mut self, mut target: Forest2<T, R>) -> Forest2Down2InitMerger<T, R> {
+        if self.keyed.is_some() {
+            target.enable_keyed();
+        }
+        let mut local_tombstones = Vec::new();
+        for node_ref in self.tombstones.drain() {
+            match node_ref {
+                Ref2::Local(local_node_ref) =>
+                    if let Some(entry) = target.local_nodes.get(local_node_ref) {
+                        local_tombstones.push((entry.node.depth, local_node_ref));
+                    },
+                Ref2::External(external_node_ref) => {
+                    target.shadows.remove(&external_node_ref);
+                    target.tombstones.insert(external_node_ref);
+                },
+            }
+        }
+        local_tombstones.sort_by_key(|&(depth, _)| Reverse(depth));
+        for (_, local_node_ref) in local_tombstones {
+            target.drop_local(local_node_ref);
+        }
+        for (node_ref, shadow) in self.shadows.drain() {
+            match node_ref {
+                Ref2::Local(local_node_ref) =>
+                    if let Some(entry) = target.local_nodes.get_mut(local_node_ref) {
+                        entry.node.item = shadow;
+                    },
+                Ref2::External(external_node_ref) => {
+                    target.shadows.insert(external_node_ref, shadow);
+                },
+            }
+        }
+        // sibling order of the source survives only in a target child index
+        if self.child_index {
+            target.enable_child_index();
+        }
+        let inner_merger = mem::take(&mut target.local_nodes).merge(mem::take(&mut self.local_nodes));
+        let mut relink = Relink::new(&target, &self);
+        relink.splice(None, mem::take(&mut self.roots), |r| inner_merger.ref_transform(r));
+        for (external_ref, chain) in self.external_children.drain() {
+            relink.splice(Some(external_ref), chain, |r| inner_merger.ref_transform(r));
+        }
+        Forest2Down2InitMerger { inner_merger, target, source: self, relink, }
+    }
+}
+
+// Forest shared read only by `Arc`. It dereferences to the forest so it works with `layers!` like
+// a plain reference: `layers!([&mut forest2, &frozen].get(node_ref))`.
+pub struct Frozen<F> {
+    forest: Arc<F>,
+}
+
+impl<F> Frozen<F> {
+    pub fn new(forest: F) -> Frozen<F> {
+        Frozen { forest: Arc::new(forest), }
+    }
+
+    // Number of handles sharing the forest.
+    pub fn shares(&self) -> usize {
+        Arc::strong_count(&self.forest)
+    }
+
+    // Gives the forest back for modifications (like `merge_down`) when this is the last handle,
+    // otherwise returns the handle unchanged.
+    pub fn unfreeze(self) -> Result<F, Frozen<F>> {
+        Arc::try_unwrap(self.forest).map_err(|forest| Frozen { forest, })
+    }
+}
+
+impl<F> Clone for Frozen<F> {
+    fn clone(&self) -> Self {
+        Frozen { forest: self.forest.clone(), }
+    }
+}
+
+impl<F> Deref for Frozen<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        &self.forest
+    }
+}
+
+#[macro_export]
+macro_rules! layers {
+    // [&forest].get(ref)
+    { [$f:expr].get($ref:expr) } => {
+        $crate::forest::Forest1::get($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].get($ref:expr) } => {
+        $crate::forest::Forest2::get($f, |r| layers!([$($fs),*].get(r)), $ref)
+    };
+
+    // [&mut forest].get_mut(ref)
+    { [$f:expr].get_mut($ref:expr) } => {
+        $crate::forest::Forest1::get_mut($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].get_mut($ref:expr) } => {
+        $crate::forest::Forest2::get_mut($f, |r| layers!([$($fs),*].get_mut(r)), $ref)
+    };
+
+    // [&mut forest].make_node(parent_ref, item)
+    { [$f:expr].make_node($ref:expr, $item:expr) } => {
+        $crate::forest::Forest1::make_node($f, $ref, $item)
+    };
+    { [$f:expr $(, $fs:expr)+].make_node($ref:expr, $item:expr) } => {
+        $crate::forest::Forest2::make_node($f, |r| layers!([$($fs),*].get(r)), $ref, $item)
+    };
+
+    // [&mut forest].remove(ref)
+    { [$f:expr].remove($ref:expr) } => {
+        $crate::forest::Forest1::remove($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].remove($ref:expr) } => {
+        $crate::forest::Forest2::remove($f, |r| layers!([$($fs),*].get(r)), $ref)
+    };
+
+    // [&mut forest].remove_with(ref, policy)
+    { [$f:expr].remove_with($ref:expr, $policy:expr) } => {
+        $crate::forest::Forest1::remove_with($f, $ref, $policy)
+    };
+    { [$f:expr $(, $fs:expr)+].remove_with($ref:expr, $policy:expr) } => {
+        $crate::forest::Forest2::remove_with($f, |r| layers!([$($fs),*].get(r)), |r| layers!([$($fs),*].children(r)), $ref, $policy)
+    };
+
+    // [&mut forest].remove_subtree(ref)
+    { [$f:expr].remove_subtree($ref:expr) } => {
+        $crate::forest::Forest1::remove_subtree($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].remove_subtree($ref:expr) } => {
+        $crate::forest::Forest2::remove_subtree($f, |r| layers!([$($fs),*].get(r)), |r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&mut forest].reparent(ref, parent_ref)
+    { [$f:expr].reparent($ref:expr, $parent:expr) } => {
+        $crate::forest::Forest1::reparent($f, $ref, $parent)
+    };
+    { [$f:expr $(, $fs:expr)+].reparent($ref:expr, $parent:expr) } => {
+        $crate::forest::Forest2::reparent($f, |r| layers!([$($fs),*].get(r)), $ref, $parent)
+    };
+
+    // [&mut forest].detach(ref)
+    { [$f:expr].detach($ref:expr) } => {
+        $crate::forest::Forest1::detach($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].detach($ref:expr) } => {
+        $crate::forest::Forest2::detach($f, $ref)
+    };
+
+    // [&mut forest].refresh_depths(ref)
+    { [$f:expr $(, $fs:expr)+].refresh_depths($ref:expr) } => {
+        $crate::forest::Forest2::refresh_depths($f, |r| layers!([$($fs),*].get(r)), |r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&mut forest].try_insert(node)
+    { [$f:expr].try_insert($node:expr) } => {
+        $crate::forest::Forest1::try_insert($f, $node)
+    };
+    { [$f:expr $(, $fs:expr)+].try_insert($node:expr) } => {
+        $crate::forest::Forest2::try_insert($f, |r| layers!([$($fs),*].get(r)), $node)
+    };
+
+    // [&forest].verify()
+    { [$f:expr].verify() } => {
+        $crate::forest::Forest1::verify($f)
+    };
+    { [$f:expr $(, $fs:expr)+].verify() } => {
+        $crate::forest::Forest2::verify($f, |r| layers!([$($fs),*].get(r)))
+    };
+
+    // [&mut forest].shadow_mut(ref)
+    { [$f:expr].shadow_mut($ref:expr) } => {
+        $crate::forest::Forest1::get_mut($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].shadow_mut($ref:expr) } => {
+        $crate::forest::Forest2::shadow_mut($f, |r| layers!([$($fs),*].get(r)), $ref)
+    };
+
+    // [&mut forest].tombstone(ref)
+    { [$f:expr].tombstone($ref:expr) } => {
+        $crate::forest::Forest1::remove($f, $ref).is_some()
+    };
+    { [$f:expr $(, $fs:expr)+].tombstone($ref:expr) } => {
+        $crate::forest::Forest2::tombstone($f, |r| layers!([$($fs),*].get(r)), |r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&mut forest].tombstone_subtree(ref)
+    { [$f:expr].tombstone_subtree($ref:expr) } => {
+        $crate::forest::Forest1::remove_subtree($f, $ref)
+            .into_iter()
+            .map(|(removed_ref, _)| removed_ref)
+            .collect::<Vec<_>>()
+    };
+    { [$f:expr $(, $fs:expr)+].tombstone_subtree($ref:expr) } => {
+        $crate::forest::Forest2::tombstone_subtree($f, |r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [forest3, forest2, forest1].squash(transform)
+    { [$f:expr].squash($transform:expr) } => {
+        $crate::forest::Forest1::squash($f, $crate::forest::Squash::new(), $transform)
+    };
+    { [$f:expr $(, $fs:expr)+].squash($transform:expr) } => {
+        {
+            let mut squash = $crate::forest::Squash::new();
+            layers!(@squash_into squash, $transform, $f $(, $fs)+)
+        }
+    };
+    { @squash_into $squash:ident, $transform:expr, $f:expr } => {
+        $crate::forest::Forest1::squash($f, $squash, $transform)
+    };
+    { @squash_into $squash:ident, $transform:expr, $f:expr $(, $fs:expr)+ } => {
+        {
+            $crate::forest::Forest2::squash_into($f, &mut $squash);
+            layers!(@squash_into $squash, $transform, $($fs),+)
+        }
+    };
+
+    // [&forest].to_dot(label, style)
+    { [$($fs:expr),+].to_dot($label:expr, $style:expr) } => {
+        $crate::graphviz::to_dot(
+            layers!([$($fs),*].iter()),
+            |r| layers!([$($fs),*].get(r)).and_then(|node| node.parent),
+            $label,
+            $style,
+        )
+    };
+
+    // [&forest].fold_up(init, combine)
+    { [$f:expr].fold_up($init:expr, $combine:expr) } => {
+        $crate::forest::Forest1::fold_up($f, $init, $combine)
+    };
+    { [$f:expr $(, $fs:expr)+].fold_up($init:expr, $combine:expr) } => {
+        $crate::forest::fold_up(
+            layers!([$f, $($fs),*].iter())
+                .flat_map(|(r, _)| layers!([$f, $($fs),*].get(r.clone())).map(|node| (r, node.parent, node.depth, node.item))),
+            $init,
+            $combine,
+        )
+    };
+
+    // [&forest].propagate_down(f)
+    { [$f:expr].propagate_down($func:expr) } => {
+        $crate::forest::Forest1::propagate_down($f, $func)
+    };
+    { [$f:expr $(, $fs:expr)+].propagate_down($func:expr) } => {
+        $crate::forest::propagate_down(
+            layers!([$f, $($fs),*].iter())
+                .flat_map(|(r, _)| layers!([$f, $($fs),*].get(r.clone())).map(|node| (r, node.parent, node.depth, node.item))),
+            $func,
+        )
+    };
+
+    // [&mut forest].gc(roots_to_keep)
+    { [$f:expr].gc($roots:expr) } => {
+        $crate::forest::Forest1::gc($f, $roots)
+    };
+    { [$f:expr $(, $fs:expr)+].gc($roots:expr) } => {
+        $crate::forest::Forest2::gc(
+            $f,
+            layers!([$($fs),*].iter())
+                .map(|(r, item)| (r.clone(), layers!([$($fs),*].get(r)).and_then(|node| node.parent), item)),
+            $roots,
+        )
+    };
+
+    // [&mut forest].prune(predicate)
+    { [$f:expr].prune($predicate:expr) } => {
+        $crate::forest::Forest1::prune($f, $predicate)
+    };
+    { [$f:expr $(, $fs:expr)+].prune($predicate:expr) } => {
+        $crate::forest::Forest2::prune(
+            $f,
+            layers!([$($fs),*].iter())
+                .map(|(r, item)| (r.clone(), layers!([$($fs),*].get(r)).and_then(|node| node.parent), item)),
+            $predicate,
+        )
+    };
+
+    // [&forest].towards_root_iter(ref)
+    { [$($fs:expr),+].towards_root_iter($ref:expr) } => {
+        $crate::forest::TowardsRootIter::new(|r| layers!([$($fs),*].get(r)), $ref)
+    };
+
+    // [&forest].ancestry(ref)
+    { [$f:expr].ancestry($ref:expr) } => {
+        $crate::forest::Forest1::ancestry($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].ancestry($ref:expr) } => {
+        $crate::forest::Forest2::ancestry($f, |r| layers!([$($fs),*].ancestry(r)), $ref)
+    };
+
+    // [&mut forest].towards_root_mut(ref, |ref, &mut item| ..)
+    { [$f:expr].towards_root_mut($ref:expr, $g:expr) } => {
+        $crate::forest::Forest1::towards_root_mut($f, $ref, $g)
+    };
+    { [$f:expr $(, $fs:expr)+].towards_root_mut($ref:expr, $g:expr) } => {
+        $crate::forest::Forest2::towards_root_mut($f, |r, g| layers!([$($fs),*].towards_root_mut(r, g)), $ref, $g)
+    };
+
+    // [&forest].ancestors()
+    { [$($fs:expr),+].ancestors() } => {
+        $crate::forest::Ancestors::new(|r| layers!([$($fs),*].ancestry(r)))
+    };
+
+    // [&forest].is_ancestor(ancestor_ref, ref)
+    { [$($fs:expr),+].is_ancestor($ancestor_ref:expr, $ref:expr) } => {
+        layers!([$($fs),*].ancestors()).is_ancestor($ancestor_ref, $ref)
+    };
+
+    // [&forest].lca(ref_a, ref_b)
+    { [$($fs:expr),+].lca($ref_a:expr, $ref_b:expr) } => {
+        layers!([$($fs),*].ancestors()).lca($ref_a, $ref_b)
+    };
+
+    // [&forest].distance(ref_a, ref_b)
+    { [$($fs:expr),+].distance($ref_a:expr, $ref_b:expr) } => {
+        layers!([$($fs),*].ancestors()).distance($ref_a, $ref_b)
+    };
+
+    // [&forest].path(ref_a, ref_b)
+    { [$($fs:expr),+].path($ref_a:expr, $ref_b:expr) } => {
+        layers!([$($fs),*].ancestors()).path($ref_a, $ref_b)
+    };
+
+    // [&forest].path_to_root(ref, order)
+    { [$($fs:expr),+].path_to_root($ref:expr, $order:expr) } => {
+        layers!([$($fs),*].ancestors()).path_to_root($ref, $order)
+    };
+
+    // [&forest].children(ref)
+    { [$f:expr].children($ref:expr) } => {
+        $crate::forest::Forest1::children($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].children($ref:expr) } => {
+        $crate::forest::Forest2::children($f, |r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&forest].descendants_dfs(ref)
+    { [$($fs:expr),+].descendants_dfs($ref:expr) } => {
+        $crate::forest::DescendantsDfs::new(|r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&forest].descendants_bfs(ref)
+    { [$($fs:expr),+].descendants_bfs($ref:expr) } => {
+        $crate::forest::DescendantsBfs::new(|r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&forest].roots()
+    { [$f:expr].roots() } => {
+        $crate::forest::Forest1::roots($f)
+    };
+    { [$f:expr $(, $fs:expr)+].roots() } => {
+        layers!([$($fs),*].roots())
+            .filter(|r| !$crate::forest::Forest2::is_tombstoned($f, r))
+            .map($crate::forest::Ref2::External)
+            .chain($crate::forest::Forest2::local_roots($f))
+    };
+
+    // [&forest].leaves()
+    { [$f:expr].leaves() } => {
+        $crate::forest::Forest1::leaves($f)
+    };
+    { [$f:expr $(, $fs:expr)+].leaves() } => {
+        $crate::forest::Forest2::local_leaves($f)
+            .chain(
+                layers!([$($fs),*].leaves())
+                    .filter(|r| !$crate::forest::Forest2::is_tombstoned($f, r))
+                    .map($crate::forest::Ref2::External)
+                    .filter(|r| !$crate::forest::Forest2::has_local_children($f, r.clone())),
+            )
+            .chain(
+                $crate::forest::Forest2::uncovered_leaves(
+                    $f,
+                    |r| layers!([$($fs),*].get(r)).and_then(|node| node.parent),
+                    |r| layers!([$f, $($fs),*].children(r)).next().is_some(),
+                ),
+            )
+    };
+
+    // [&forest].first_child(ref)
+    { [$f:expr].first_child($ref:expr) } => {
+        $crate::forest::Forest1::first_child($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].first_child($ref:expr) } => {
+        layers!([$f, $($fs),*].children($ref)).next()
+    };
+
+    // [&forest].last_child(ref)
+    { [$f:expr].last_child($ref:expr) } => {
+        $crate::forest::Forest1::last_child($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].last_child($ref:expr) } => {
+        $crate::forest::Forest2::last_child($f, |r| layers!([$($fs),*].children(r)), $ref)
+    };
+
+    // [&forest].next_sibling(ref)
+    { [$f:expr].next_sibling($ref:expr) } => {
+        $crate::forest::Forest1::next_sibling($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].next_sibling($ref:expr) } => {
+        $crate::forest::Forest2::next_sibling(
+            $f,
+            |r| layers!([$($fs),*].get(r)),
+            |r| layers!([$($fs),*].children(r)),
+            || layers!([$($fs),*].roots()),
+            $ref,
+        )
+    };
+
+    // [&forest].prev_sibling(ref)
+    { [$f:expr].prev_sibling($ref:expr) } => {
+        $crate::forest::Forest1::prev_sibling($f, $ref)
+    };
+    { [$f:expr $(, $fs:expr)+].prev_sibling($ref:expr) } => {
+        $crate::forest::Forest2::prev_sibling(
+            $f,
+            |r| layers!([$($fs),*].get(r)),
+            |r| layers!([$($fs),*].children(r)),
+            || layers!([$($fs),*].roots()),
+            $ref,
+        )
+    };
+
+    // [&mut forest].insert_child_at(parent_ref, index, item)
+    { [$f:expr].insert_child_at($parent:expr, $index:expr, $item:expr) } => {
+        $crate::forest::Forest1::insert_child_at($f, $parent, $index, $item)
+    };
+    { [$f:expr $(, $fs:expr)+].insert_child_at($parent:expr, $index:expr, $item:expr) } => {
+        $crate::forest::Forest2::insert_child_at($f, |r| layers!([$($fs),*].get(r)), $parent, $index, $item)
+    };
+
+    // [&mut forest].insert_before(sibling_ref, item)
+    { [$f:expr $(, $fs:expr)*].insert_before($sibling:expr, $item:expr) } => {
+        $f.insert_before($sibling, $item)
+    };
+
+    // [&mut forest].insert_after(sibling_ref, item)
+    { [$f:expr $(, $fs:expr)*].insert_after($sibling:expr, $item:expr) } => {
+        $f.insert_after($sibling, $item)
+    };
+
+    // [&mut forest].move_before(ref, sibling_ref)
+    { [$f:expr $(, $fs:expr)*].move_before($ref:expr, $sibling:expr) } => {
+        $f.move_before($ref, $sibling)
+    };
+
+    // [&forest].nodes_at_depth(depth)
+    { [$f:expr].nodes_at_depth($depth:expr) } => {
+        $crate::forest::Forest1::nodes_at_depth($f, $depth)
+    };
+    { [$f:expr $(, $fs:expr)+].nodes_at_depth($depth:expr) } => {
+        $crate::forest::Forest2::nodes_at_depth($f, $depth)
+            .chain(
+                layers!([$($fs),*].nodes_at_depth($depth))
+                    .filter(|set_ref| !$crate::forest::Forest2::is_tombstoned($f, set_ref))
+                    .map($crate::forest::Ref2::External),
+            )
+    };
+
+    // [&forest].par_nodes_at_depth(depth)
+    { [$f:expr].par_nodes_at_depth($depth:expr) } => {
+        $crate::forest::Forest1::par_nodes_at_depth($f, $depth)
+    };
+    { [$f:expr $(, $fs:expr)+].par_nodes_at_depth($depth:expr) } => {
+        $crate::forest::Forest2::par_nodes_at_depth($f, $depth)
+            .chain(
+                layers!([$($fs),*].par_nodes_at_depth($depth))
+                    .filter(|set_ref| !$crate::forest::Forest2::is_tombstoned($f, set_ref))
+                    .map($crate::forest::Ref2::External),
+            )
+    };
+
+    // [&forest].depth_histogram()
+    { [$f:expr].depth_histogram() } => {
+        $crate::forest::Forest1::depth_histogram($f)
+    };
+    { [$f:expr $(, $fs:expr)+].depth_histogram() } => {{
+        let mut histogram = layers!([$($fs),*].depth_histogram());
+        // a node tombstoned in a lower layer too is not found there any more, so it is subtracted once
+        for masked_ref in $crate::forest::Forest2::tombstoned_refs($f) {
+            if let Some(node) = layers!([$($fs),*].get(masked_ref.clone())) {
+                if let Some(count) = histogram.get_mut(node.depth) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+        for (depth, count) in $crate::forest::Forest2::depth_histogram($f).into_iter().enumerate() {
+            if histogram.len() <= depth {
+                histogram.resize(depth + 1, 0);
+            }
+            histogram[depth] += count;
+        }
+        while histogram.last() == Some(&0) {
+            histogram.pop();
+        }
+        histogram
+    }};
+
+    // [&forest].max_depth()
+    { [$($fs:expr),+].max_depth() } => {
+        layers!([$($fs),*].depth_histogram()).len().checked_sub(1)
+    };
+
+    // [&forest].iter()
+    { [$f:expr].iter() } => {
+        $crate::forest::Forest1::local_iter($f)
+    };
+    { [$f:expr $(, $fs:expr)+].iter() } => {
+        $crate::forest::Forest2::local_iter($f)
+            .chain(
+                layers!([$($fs),*].iter())
+                    .filter(|(set_ref, _)| !$crate::forest::Forest2::is_masked($f, set_ref))
+                    .map(|(set_ref, item)| ($crate::forest::Ref2::External(set_ref), item)),
+            )
+    };
+
+    // [&forest].par_iter()
+    { [$f:expr].par_iter() } => {
+        $crate::forest::Forest1::local_par_iter($f)
+    };
+    { [$f:expr $(, $fs:expr)+].par_iter() } => {
+        $crate::forest::Forest2::local_par_iter($f)
+            .chain(
+                layers!([$($fs),*].par_iter())
+                    .filter(|(set_ref, _)| !$crate::forest::Forest2::is_masked($f, set_ref))
+                    .map(|(set_ref, item)| ($crate::forest::Ref2::External(set_ref), item)),
+            )
+    };
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GcReport<R> {
+    // removed nodes, parents go before their children
+    pub removed: Vec<R>,
+    pub kept: usize,
+    // removed nodes which parent was missing
+    pub dangling: usize,
+}
+
+// Violated invariants found by `verify`, each list holds the offending nodes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyReport<R> {
+    // depth is not one more than the depth of the parent (zero for roots)
+    pub depth_mismatches: Vec<R>,
+    // parent is missing from the layer
+    pub dangling_parents: Vec<R>,
+    // nodes found on a parent cycle
+    pub cycles: Vec<R>,
+    // external parents, shadows and tombstones not resolving in upper layers
+    pub invalid_layer_refs: Vec<R>,
+    // broken children chains or jump pointers
+    pub index_errors: Vec<R>,
+}
+
+impl<R> VerifyReport<R> {
+    pub fn new() -> VerifyReport<R> {
+        VerifyReport {
+            depth_mismatches: Vec::new(),
+            dangling_parents: Vec::new(),
+            cycles: Vec::new(),
+            invalid_layer_refs: Vec::new(),
+            index_errors: Vec::new(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.depth_mismatches.is_empty()
+            && self.dangling_parents.is_empty()
+            && self.cycles.is_empty()
+            && self.invalid_layer_refs.is_empty()
+            && self.index_errors.is_empty()
+    }
+}
+
+impl<R> Default for VerifyReport<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Local subtrees a layer hangs under one upper layer node (or as new roots).
+#[derive(Clone, PartialEq, Debug)]
+pub struct Attachment<R> {
+    // upper layer node extended by the layer, none for new roots
+    pub parent: Option<R>,
+    // local nodes attached directly, in sibling order
+    pub children: Vec<Ref2<R>>,
+    // number of local nodes in the attached subtrees
+    pub added: usize,
+}
+
+// What a `Forest2` layer changes in the layers below, see `Forest2::diff`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LayerDiff<R> {
+    // added local nodes grouped by attachment point
+    pub attachments: Vec<Attachment<R>>,
+    // total number of local nodes
+    pub added: usize,
+    // upper layer nodes with modified items
+    pub shadowed: Vec<R>,
+    // upper layer nodes removed with their subtrees
+    pub tombstoned: Vec<R>,
+}
+
+impl<R> LayerDiff<R> {
+    pub fn new() -> LayerDiff<R> {
+        LayerDiff {
+            attachments: Vec::new(),
+            added: 0,
+            shadowed: Vec::new(),
+            tombstoned: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.shadowed.is_empty() && self.tombstoned.is_empty()
+    }
+
+    // Upper layer nodes whose subtrees are extended by the layer.
+    pub fn attachment_points(&self) -> impl Iterator<Item = &R> {
+        self.attachments.iter().flat_map(|attachment| attachment.parent.as_ref())
+    }
+
+    // Local subtrees attached under `parent_ref`.
+    pub fn attachment(&self, parent_ref: &R) -> Option<&Attachment<R>> where R: PartialEq {
+        self.attachments.iter().find(|attachment| attachment.parent.as_ref() == Some(parent_ref))
+    }
+
+    // Attached subtrees that start new roots.
+    pub fn new_roots(&self) -> Option<&Attachment<R>> {
+        self.attachments.iter().find(|attachment| attachment.parent.is_none())
+    }
+}
+
+impl<R> Default for LayerDiff<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Marks the subtrees of nodes matching `is_start`: with `keep` the marked nodes are kept and the rest
+// is removed, otherwise the marked ones are removed.
+fn sweep<R, I, S>(nodes: I, is_start: S, keep: bool) -> GcReport<R> where R: Clone + Eq + Hash, I: Iterator<Item = (R, Option<R>)>, S: Fn(&R) -> bool {
+    let mut all = Vec::new();
+    let mut children: HashMap<R, Vec<R>> = HashMap::new();
+    let mut queue = VecDeque::new();
+    for (node_ref, parent) in nodes {
+        if is_start(&node_ref) {
+            queue.push_back(node_ref.clone());
+        }
+        if let Some(parent_ref) = parent.clone() {
+            children.entry(parent_ref).or_default().push(node_ref.clone());
+        }
+        all.push((node_ref, parent));
+    }
+    let mut marked = HashSet::new();
+    let mut marked_order = Vec::new();
+    while let Some(node_ref) = queue.pop_front() {
+        if !marked.insert(node_ref.clone()) {
+            continue;
+        }
+        queue.extend(children.get(&node_ref).into_iter().flatten().cloned());
+        marked_order.push(node_ref);
+    }
+    let present: HashSet<_> = all.iter().map(|pair| pair.0.clone()).collect();
+    let is_dangling = |parent: &Option<R>| parent.as_ref().is_some_and(|parent_ref| !present.contains(parent_ref));
+    if keep {
+        let mut removed = Vec::new();
+        let mut dangling = 0;
+        let mut queue: VecDeque<_> = all.iter()
+            .filter(|(node_ref, parent)| !marked.contains(node_ref) && (parent.is_none() || is_dangling(parent)))
+            .map(|pair| pair.0.clone())
+            .collect();
+        dangling += all.iter().filter(|(node_ref, parent)| !marked.contains(node_ref) && is_dangling(parent)).count();
+        let mut visited = HashSet::new();
+        while let Some(node_ref) = queue.pop_front() {
+            if marked.contains(&node_ref) || !visited.insert(node_ref.clone()) {
+                continue;
+            }
+            queue.extend(children.get(&node_ref).into_iter().flatten().cloned());
+            removed.push(node_ref);
+        }
+        // cycles are unreachable from any root
+        removed.extend(all.iter().filter(|(node_ref, _)| !marked.contains(node_ref) && !visited.contains(node_ref)).map(|pair| pair.0.clone()));
+        GcReport { kept: all.len() - removed.len(), removed, dangling, }
+    } else {
+        let dangling = all.iter().filter(|(node_ref, parent)| marked.contains(node_ref) && is_dangling(parent)).count();
+        GcReport { kept: all.len() - marked_order.len(), removed: marked_order, dangling, }
+    }
+}
+
+// Nodes as `(ref, parent, depth, item)` grouped by depth, along with the children of every parent.
+type Levels<'a, R, T> = (Vec<Vec<(R, &'a T)>>, HashMap<R, Vec<R>>);
+
+fn levels<'a, R, T, I>(nodes: I) -> Levels<'a, R, T> where R: Clone + Eq + Hash, I: Iterator<Item = (R, Option<R>, usize, &'a T)> {
+    let mut levels: Vec<Vec<_>> = Vec::new();
+    let mut children: HashMap<R, Vec<R>> = HashMap::new();
+    for (node_ref, parent, depth, item) in nodes {
+        if levels.len() <= depth {
+            levels.resize_with(depth + 1, Vec::new);
+        }
+        if let Some(parent_ref) = parent {
+            children.entry(parent_ref).or_default().push(node_ref.clone());
+        }
+        levels[depth].push((node_ref, item));
+    }
+    (levels, children)
+}
+
+// Computes a value for every subtree: `init` makes the value of a node and `combine` accounts the value
+// of each of its children. Nodes are processed level by level from the deepest one (so children are done
+// before their parents) and each level is processed in parallel.
+pub fn fold_up<'a, R, T, A, N, I, C>(nodes: N, init: I, combine: C) -> HashMap<R, A>
+    where R: Clone + Eq + Hash + Send + Sync,
+          T: Sync + 'a,
+          A: Send + Sync,
+          N: Iterator<Item = (R, Option<R>, usize, &'a T)>,
+          I: Fn(&R, &T) -> A + Sync,
+          C: Fn(&mut A, &A) + Sync,
+{
+    let (levels, children) = levels(nodes);
+    let mut values = HashMap::new();
+    for level in levels.into_iter().rev() {
+        let level_values: Vec<_> = level.into_par_iter()
+            .map(|(node_ref, item)| {
+                let mut value = init(&node_ref, item);
+                for child_ref in children.get(&node_ref).into_iter().flatten() {
+                    if let Some(child_value) = values.get(child_ref) {
+                        combine(&mut value, child_value);
+                    }
+                }
+                (node_ref, value)
+            })
+            .collect();
+        values.extend(level_values);
+    }
+    values
+}
+
+// Computes a value for every node from the value of its parent (none for roots). Nodes are processed
+// level by level from the roots and each level is processed in parallel.
+pub fn propagate_down<'a, R, T, A, N, F>(nodes: N, f: F) -> HashMap<R, A>
+    where R: Clone + Eq + Hash + Send + Sync,
+          T: Sync + 'a,
+          A: Send + Sync,
+          N: Iterator<Item = (R, Option<R>, usize, &'a T)>,
+          F: Fn(Option<&A>, &R, &T) -> A + Sync,
+{
+    let mut parents = HashMap::new();
+    let (levels, _) = levels(nodes.map(|(node_ref, parent, depth, item)| {
+        if let Some(parent_ref) = parent.clone() {
+            parents.insert(node_ref.clone(), parent_ref);
+        }
+        (node_ref, None, depth, item)
+    }));
+    let mut values = HashMap::new();
+    for level in levels {
+        let level_values: Vec<_> = level.into_par_iter()
+            .map(|(node_ref, item)| {
+                let parent_value = parents.get(&node_ref).and_then(|parent_ref| values.get(parent_ref));
+                let value = f(parent_value, &node_ref, item);
+                (node_ref, value)
+            })
+            .collect();
+        values.extend(level_values);
+    }
+    values
+}
+
+// Links of a node used for ancestor queries: `jump` is some ancestor farther than or equal to the parent
+// (the parent itself when jump index is disabled) along with its depth.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Ancestry<R> {
+    pub parent: Option<R>,
+    pub depth: usize,
+    pub jump: Option<(R, usize)>,
+}
+
+impl<R> Ancestry<R> where R: Clone {
+    fn from_entry<T>(entry: &Entry<T, R>, jump_index: bool) -> Ancestry<R> {
+        Ancestry {
+            parent: entry.node.parent.clone(),
+            depth: entry.node.depth,
+            jump: if jump_index {
+                entry.jump.clone()
+            } else {
+                entry.node.parent.clone().map(|parent| (parent, entry.node.depth.saturating_sub(1)))
+            },
+        }
+    }
+}
+
+// Ancestor queries over a forest or a stack of layers given an ancestry accessor: O(log depth) with
+// jump index enabled on every layer, O(depth) otherwise.
+pub struct Ancestors<A> {
+    layer_ancestry: A,
+}
+
+impl<A> Ancestors<A> {
+    pub fn new(layer_ancestry: A) -> Ancestors<A> {
+        Ancestors { layer_ancestry, }
+    }
+
+    pub fn ancestor_at_depth<R>(&self, node_ref: R, depth: usize) -> Option<R> where R: Clone, A: Fn(R) -> Option<Ancestry<R>> {
+        let mut cursor = node_ref;
+        let mut ancestry = (self.layer_ancestry)(cursor.clone())?;
+        if ancestry.depth < depth {
+            return None;
+        }
+        while ancestry.depth > depth {
+            cursor = match ancestry.jump {
+                Some((jump_ref, jump_depth)) if jump_depth >= depth =>
+                    jump_ref,
+                _ =>
+                    ancestry.parent?,
+            };
+            ancestry = (self.layer_ancestry)(cursor.clone())?;
+        }
+        Some(cursor)
+    }
+
+    // Checks if `ancestor_ref` is a proper ancestor of `node_ref`.
+    pub fn is_ancestor<R>(&self, ancestor_ref: R, node_ref: R) -> bool where R: Clone + PartialEq, A: Fn(R) -> Option<Ancestry<R>> {
+        let ancestor_depth = match (self.layer_ancestry)(ancestor_ref.clone()) {
+            Some(ancestry) =>
+                ancestry.depth,
+            None =>
+                return false,
+        };
+        match (self.layer_ancestry)(node_ref.clone()) {
+            Some(ancestry) if ancestry.depth > ancestor_depth =>
+                self.ancestor_at_depth(node_ref, ancestor_depth) == Some(ancestor_ref),
+            _ =>
+                false,
+        }
+    }
+
+    // Lowest common ancestor of two nodes (a node is considered an ancestor of itself),
+    // `None` if they belong to different trees.
+    pub fn lca<R>(&self, node_a_ref: R, node_b_ref: R) -> Option<R> where R: Clone + PartialEq, A: Fn(R) -> Option<Ancestry<R>> {
+        let depth_a = (self.layer_ancestry)(node_a_ref.clone())?.depth;
+        let depth_b = (self.layer_ancestry)(node_b_ref.clone())?.depth;
+        let depth = depth_a.min(depth_b);
+        let mut cursor_a = self.ancestor_at_depth(node_a_ref, depth)?;
+        let mut cursor_b = self.ancestor_at_depth(node_b_ref, depth)?;
+        while cursor_a != cursor_b {
+            let ancestry_a = (self.layer_ancestry)(cursor_a)?;
+            let ancestry_b = (self.layer_ancestry)(cursor_b)?;
+            match (ancestry_a.jump, ancestry_b.jump) {
+                (Some((jump_a, jump_depth_a)), Some((jump_b, jump_depth_b))) if jump_depth_a == jump_depth_b && jump_a != jump_b => {
+                    cursor_a = jump_a;
+                    cursor_b = jump_b;
+                },
+                _ => {
+                    cursor_a = ancestry_a.parent?;
+                    cursor_b = ancestry_b.parent?;
+                },
+            }
+        }
+        Some(cursor_a)
+    }
+
+    // Number of edges on the path between two nodes.
+    pub fn distance<R>(&self, node_a_ref: R, node_b_ref: R) -> Option<usize> where R: Clone + PartialEq, A: Fn(R) -> Option<Ancestry<R>> {
+        let depth_a = (self.layer_ancestry)(node_a_ref.clone())?.depth;
+        let depth_b = (self.layer_ancestry)(node_b_ref.clone())?.depth;
+        let lca_ref = self.lca(node_a_ref, node_b_ref)?;
+        let lca_depth = (self.layer_ancestry)(lca_ref)?.depth;
+        Some(depth_a + depth_b - 2 * lca_depth)
+    }
+
+    // Path from `node_a_ref` up to the lowest common ancestor and then down to `node_b_ref`, both ends included.
+    pub fn path<R>(&self, node_a_ref: R, node_b_ref: R) -> Option<Vec<R>> where R: Clone + PartialEq, A: Fn(R) -> Option<Ancestry<R>> {
+        let depth_a = (self.layer_ancestry)(node_a_ref.clone())?.depth;
+        let depth_b = (self.layer_ancestry)(node_b_ref.clone())?.depth;
+        let lca_ref = self.lca(node_a_ref.clone(), node_b_ref.clone())?;
+        let lca_depth = (self.layer_ancestry)(lca_ref)?.depth;
+
+        let mut path = Vec::with_capacity(depth_a + depth_b - 2 * lca_depth + 1);
+        let mut cursor = node_a_ref;
+        for _ in lca_depth .. depth_a {
+            let parent_ref = (self.layer_ancestry)(cursor.clone())?.parent?;
+            path.push(cursor);
+            cursor = parent_ref;
+        }
+        path.push(cursor);
+        let mark = path.len();
+        let mut cursor = node_b_ref;
+        for _ in lca_depth .. depth_b {
+            let parent_ref = (self.layer_ancestry)(cursor.clone())?.parent?;
+            path.push(cursor);
+            cursor = parent_ref;
+        }
+        path[mark ..].reverse();
+        Some(path)
+    }
+
+    // Nodes from `node_ref` up to its root (both ends included), allocated at once from the node depth.
+    pub fn path_to_root<R>(&self, node_ref: R, order: PathOrder) -> Option<Vec<R>> where R: Clone, A: Fn(R) -> Option<Ancestry<R>> {
+        let depth = (self.layer_ancestry)(node_ref.clone())?.depth;
+        let mut path = Vec::with_capacity(depth + 1);
+        let mut cursor = Some(node_ref);
+        while let Some(cursor_ref) = cursor {
+            cursor = (self.layer_ancestry)(cursor_ref.clone())?.parent;
+            path.push(cursor_ref);
+        }
+        if order == PathOrder::RootFirst {
+            path.reverse();
+        }
+        Some(path)
+    }
+}
+
+// Order of the nodes returned by `path_to_root`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PathOrder {
+    RootFirst,
+    LeafFirst,
+}
+
+// Nodes of a layer stack collected by `Forest2::squash_into` starting from the lowest layer.
+pub struct Squash<T> {
+    layers: Vec<Vec<(Ref, Node<T, Ref>)>>,
+    shadows: HashMap<Ref, T>,
+    tombstones: HashSet<Ref>,
+}
+
+impl<T> Squash<T> {
+    pub fn new() -> Squash<T> {
+        Squash {
+            layers: Vec::new(),
+            shadows: HashMap::new(),
+            tombstones: HashSet::new(),
+        }
+    }
+
+    fn take_layer<P, I, F>(&mut self, entries: I, parent_transform: F)
+        where I: Iterator<Item = (Ref, Entry<T, P>)>, F: Fn(&P) -> Ref
+    {
+        let tombstones = &self.tombstones;
+        let layer = entries
+            .filter(|(node_ref, _)| !tombstones.contains(node_ref))
+            .map(|(node_ref, entry)| (node_ref, Node {
+                item: entry.node.item,
+                parent: entry.node.parent.as_ref().map(&parent_transform),
+                depth: entry.node.depth,
+            }))
+            .collect();
+        self.layers.push(layer);
+    }
+}
+
+impl<T> Default for Squash<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Translates refs of any layer of a squashed stack into refs of the resulting `Forest1`.
+pub struct SquashRefs {
+    refs: HashMap<Ref, Ref>,
+}
+
+impl SquashRefs {
+    pub fn ref_transform<R>(&self, node_ref: R) -> Option<Ref> where R: LayerRef {
+        self.refs.get(&node_ref.set_ref()).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.refs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.refs.is_empty()
+    }
+}
+
+pub struct TowardsRootIter<R, A> {
+    start: R,
+    cursor: Option<R>,
+    layer_access: A,
+}
+
+impl<R, A> TowardsRootIter<R, A> {
+    pub fn new(layer_access: A, node_ref: R) -> TowardsRootIter<R, A> where R: Clone {
+        TowardsRootIter {
+            start: node_ref.clone(),
+            cursor: Some(node_ref),
+            layer_access,
+        }
+    }
+}
+
+impl<'a, T: 'a, R, A> Iterator for TowardsRootIter<R, A> where R: Clone, A: Fn(R) -> Option<Node<&'a T, R>> {
+    type Item = Node<&'a T, R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node_ref = self.cursor.take()?;
+        let node = (self.layer_access)(node_ref)?;
+        self.cursor = node.parent.clone();
+        Some(node)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.layer_access)(self.start.clone())
+            .map_or((0, None), |node| (node.depth + 1, Some(node.depth + 1)))
+    }
+
+    fn count(self) -> usize where Self: Sized {
+        (self.layer_access)(self.start.clone())
+            .map_or(0, |node| node.depth + 1)
+    }
+}
+
+// Depth-first (preorder) walk over descendants of a node, excluding the node itself.
+pub struct DescendantsDfs<R, A> {
+    stack: Vec<R>,
+    children_access: A,
+}
+
+impl<R, A> DescendantsDfs<R, A> {
+    pub fn new<I>(children_access: A, node_ref: R) -> DescendantsDfs<R, A> where A: Fn(R) -> I, I: Iterator<Item = R> {
+        let mut iter = DescendantsDfs { stack: Vec::new(), children_access, };
+        iter.push_children(node_ref);
+        iter
+    }
+
+    fn push_children<I>(&mut self, node_ref: R) where A: Fn(R) -> I, I: Iterator<Item = R> {
+        let mark = self.stack.len();
+        self.stack.extend((self.children_access)(node_ref));
+        self.stack[mark ..].reverse();
+    }
+}
+
+impl<R, A, I> Iterator for DescendantsDfs<R, A> where R: Clone, A: Fn(R) -> I, I: Iterator<Item = R> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node_ref = self.stack.pop()?;
+        self.push_children(node_ref.clone());
+        Some(node_ref)
+    }
+}
+
+// Breadth-first (level order) walk over descendants of a node, excluding the node itself.
+pub struct DescendantsBfs<R, A> {
+    queue: VecDeque<R>,
+    children_access: A,
+}
+
+impl<R, A> DescendantsBfs<R, A> {
+    pub fn new<I>(children_access: A, node_ref: R) -> DescendantsBfs<R, A> where A: Fn(R) -> I, I: Iterator<Item = R> {
+        let queue = children_access(node_ref).collect();
+        DescendantsBfs { queue, children_access, }
+    }
+}
+
+impl<R, A, I> Iterator for DescendantsBfs<R, A> where R: Clone, A: Fn(R) -> I, I: Iterator<Item = R> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node_ref = self.queue.pop_front()?;
+        self.queue.extend((self.children_access)(node_ref.clone()));
+        Some(node_ref)
+    }
+}
+
+// Child index bookkeeping carried through a merge: chains of the source hanging from nodes outside
+// of it are spliced into the target after all the nodes are moved.
+struct Relink<P> {
+    mode: RelinkMode,
+    jumps: RelinkMode,
+    splices: Vec<(Option<P>, Chain)>,
+    appends: Vec<Ref>,
+    // merged nodes with their depths when the target has a depth index
+    depths: Option<Vec<(Ref, usize)>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RelinkMode {
+    // target has no index
+    Skip,
+    // both target and source have index: links are transformed
+    Transform,
+    // only target has index: merged nodes are appended to their parents chains,
+    // and their jump pointers are set to parents
+    Append,
+}
+
+impl RelinkMode {
+    fn new(target_index: bool, source_index: bool) -> RelinkMode {
+        match (target_index, source_index) {
+            (false, _) =>
+                RelinkMode::Skip,
+            (true, true) =>
+                RelinkMode::Transform,
+            (true, false) =>
+                RelinkMode::Append,
+        }
+    }
+}
+
+impl<P> Relink<P> {
+    fn new<L, K>(target: &L, source: &K) -> Relink<P> where L: Layer, K: Layer {
+        Relink {
+            mode: RelinkMode::new(target.child_index(), source.child_index()),
+            jumps: RelinkMode::new(target.jump_index(), source.jump_index()),
+            splices: Vec::new(),
+            appends: Vec::new(),
+            depths: target.depth_index().map(|_| Vec::new()),
+        }
+    }
+
+    fn splice<F>(&mut self, parent: Option<P>, chain: Chain, ref_transform: F) where F: Fn(Ref) -> Option<Ref> {
+        if self.mode == RelinkMode::Transform && chain.first.is_some() {
+            self.splices.push((parent, chain.transform(ref_transform)));
+        }
+    }
+
+    fn relink_entry<T, R, F>(&mut self, entry: Entry<T, R>, target_ref: Option<Ref>, ref_transform: F) -> Entry<T, R>
+        where R: Clone, F: Fn(Ref) -> Option<Ref>
+    {
+        if let (Some(depths), Some(target_ref)) = (self.depths.as_mut(), target_ref) {
+            depths.push((target_ref, entry.node.depth));
+        }
+        let jump = match self.jumps {
+            RelinkMode::Transform =>
+                entry.jump,
+            RelinkMode::Append =>
+                entry.node.parent.clone().map(|parent| (parent, entry.node.depth.saturating_sub(1))),
+            RelinkMode::Skip =>
+                None,
+        };
+        match self.mode {
+            RelinkMode::Transform =>
+                Entry {
+                    node: entry.node,
+                    children: entry.children.transform(&ref_transform),
+                    siblings: entry.siblings.transform(&ref_transform),
+                    jump,
+                },
+            RelinkMode::Skip | RelinkMode::Append => {
+                if self.mode == RelinkMode::Append {
+                    self.appends.extend(target_ref);
+                }
+                Entry { jump, ..Entry::new(entry.node) }
+            },
+        }
+    }
+
+    fn finish<L>(self, layer: &mut L) where L: Layer<Parent = P>, P: Clone {
+        for (parent, chain) in self.splices {
+            layer.splice(parent.as_ref(), chain);
+        }
+        for node_ref in self.appends {
+            let parent = layer.entries().get(node_ref).and_then(|entry| entry.node.parent.clone());
+            layer.link_last(parent.as_ref(), node_ref);
+        }
+        if let (Some(depths), Some(depth_index)) = (self.depths, layer.depth_index_mut()) {
+            for (node_ref, depth) in depths {
+                depth_index.add(node_ref, depth);
+            }
+        }
+    }
+}
+
+// Source entry of the node being merged, except for the item which is handed out for transformation.
+struct Pending<R> {
+    target_ref: Option<Ref>,
+    entry: Entry<(), R>,
+}
+
+impl<R> Pending<R> {
+    fn new<T>(entry: Entry<T, R>, target_ref: Option<Ref>) -> (T, Pending<R>) {
+        let Entry { node: Node { item, parent, depth, }, children, siblings, jump, } = entry;
+        let pending = Pending {
+            target_ref,
+            entry: Entry { node: Node { item: (), parent, depth, }, children, siblings, jump, },
+        };
+        (item, pending)
+    }
+
+    // Restores the entry with the transformed item, `ref_transform` is applied to parent and jump refs.
+    fn with_item<T, Q, F>(self, item: T, ref_transform: F) -> (Option<Ref>, Entry<T, Q>) where F: Fn(R) -> Option<Q> {
+        let Entry { node: Node { parent, depth, .. }, children, siblings, jump, } = self.entry;
+        let parent = parent.and_then(&ref_transform);
+        let jump = jump.and_then(|(jump_ref, jump_depth)| ref_transform(jump_ref).map(|jump_ref| (jump_ref, jump_depth)));
+        (self.target_ref, Entry { node: Node { item, parent, depth, }, children, siblings, jump, })
+    }
+}
+
+pub struct Forest1InitMerger<T> {
+    inner_merger: SetsInitMerger<Entry<T, Ref>, Entry<T, Ref>>,
+    target: Forest1<T>,
+    source: Forest1<T>,
+    relink: Relink<Ref>,
+}
+
+pub struct Forest1InProgressMerger<T> {
+    inner_merger: SetsInProgressMerger<Entry<T, Ref>, Entry<T, Ref>>,
+    target: Forest1<T>,
+    source: Forest1<T>,
+    relink: Relink<Ref>,
+    pending: Pending<Ref>,
+}
+
+impl<T> InitMerger<Ref, Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> for Forest1InitMerger<T> {
+    fn ref_transform(&self, source_ref: Ref) -> Option<Ref> {
+        self.inner_merger.ref_transform(source_ref)
+    }
+
+    fn merge_start(self) -> MergeState<Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> {
+        Forest1InProgressMerger::make_state(self.inner_merger.merge_start(), self.target, self.source, self.relink)
+    }
+}
+
+type Forest1MergerInnerState<T> =
+    MergeState<Ref, Entry<T, Ref>, SetsInProgressMerger<Entry<T, Ref>, Entry<T, Ref>>, Set<Entry<T, Ref>>, Set<Entry<T, Ref>>>;
+
+impl<T> Forest1InProgressMerger<T> {
+    fn make_state(
+        inner_state: Forest1MergerInnerState<T>,
+        mut target: Forest1<T>,
+        mut source: Forest1<T>,
+        relink: Relink<Ref>,
+    )
+        -> MergeState<Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>>
+    {
+        match inner_state {
+            MergeState::Continue { item_ref, item: entry, next, } => {
+                let (item, pending) = Pending::new(entry, next.ref_transform(item_ref));
+                MergeState::Continue {
+                    item_ref,
+                    item,
+                    next: Forest1InProgressMerger { inner_merger: next, target, source, relink, pending, },
+                }
+            },
+            MergeState::Finish { merged, empty, } => {
+                target.nodes = merged;
+                relink.finish(&mut target);
+                source.nodes = empty;
+                MergeState::Finish { merged: target, empty: source, }
+            },
+        }
+    }
+}
+
+impl<T> InProgressMerger<Ref, Ref, T, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> for Forest1InProgressMerger<T> {
+    fn ref_transform(&self, source_ref: Ref) -> Option<Ref> {
+        self.inner_merger.ref_transform(source_ref)
+    }
+
+    fn proceed(mut self, transformed_item: T) -> MergeState<Ref, T, Forest1InProgressMerger<T>, Forest1<T>, Forest1<T>> {
+        let inner_merger = self.inner_merger;
+        let (target_ref, entry) = self.pending.with_item(
+            transformed_item,
+            |parent_ref| inner_merger.ref_transform(parent_ref),
+        );
+        let entry = self.relink.relink_entry(entry, target_ref, |r| inner_merger.ref_transform(r));
+        Forest1InProgressMerger::make_state(inner_merger.proceed(entry), self.target, self.source, self.relink)
+    }
+}
+
+pub struct Forest2AflatInitMerger<T, R> {
+    inner_merger: SetsInitMerger<Entry<T, Ref2<R>>, Entry<T, Ref2<R>>>,
+    target: Forest2<T, R>,
+    source: Forest2<T, R>,
+    relink: Relink<Ref2<R>>,
+}
+
+pub struct Forest2AflatInProgressMerger<T, R> {
+    inner_merger: SetsInProgressMerger<Entry<T, Ref2<R>>, Entry<T, Ref2<R>>>,
+    target: Forest2<T, R>,
+    source: Forest2<T, R>,
+    relink: Relink<Ref2<R>>,
+    pending: Pending<Ref2<R>>,
+}
+
+impl<T, R> InitMerger<Ref2<R>, Ref2<R>, T, Forest2AflatInProgressMerger<T, R>, Forest2<T, R>, Forest2<T, R>> for Forest2AflatInitMerger<T, R>
+    where R: Clone + Eq + Hash
+{
+    fn ref_transform(&self, source_ref: Ref2<R>) -> Option<Ref2<R>> {
+        match source_ref {
+            Ref2::Local(local_ref) =>
+                self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
+            Ref2::External(external_ref) =>
+                Some(Ref2::External(external_ref)),
+        }
+    }
+
+    fn merge_start(self) -> Forest2AflatMergerOuterState<T, R> {
+        Forest2AflatInProgressMerger::make_state(self.inner_merger.merge_start(), self.target, self.source, self.relink)
+    }
+}
+
+type Forest2AflatMergerOuterState<T, R> =
+    MergeState<Ref2<R>, T, Forest2AflatInProgressMerger<T, R>, Forest2<T, R>, Forest2<T, R>>;
+
+type Forest2AflatMergerInnerState<T, R> =
+    MergeState<Ref, Entry<T, Ref2<R>>, SetsInProgressMerger<Entry<T, Ref2<R>>, Entry<T, Ref2<R>>>, Set<Entry<T, Ref2<R>>>, Set<Entry<T, Ref2<R>>>>;
+
+impl<T, R> Forest2AflatInProgressMerger<T, R> where R: Clone + Eq + Hash {
+    fn make_state(
+        inner_state: Forest2AflatMergerInnerState<T, R>,
+        mut target: Forest2<T, R>,
+        mut source: Forest2<T, R>,
+        relink: Relink<Ref2<R>>,
+    )
+        -> Forest2AflatMergerOuterState<T, R>
+    {
+        match inner_state {
+            MergeState::Continue { item_ref, item: entry, next, } => {
+                let (item, pending) = Pending::new(entry, next.ref_transform(item_ref));
+                MergeState::Continue {
+                    item_ref: Ref2::Local(item_ref),
+                    item,
+                    next: Forest2AflatInProgressMerger { inner_merger: next, target, source, relink, pending, },
+                }
+            },
+            MergeState::Finish { merged, empty, } => {
+                target.local_nodes = merged;
+                relink.finish(&mut target);
+                source.local_nodes = empty;
+                MergeState::Finish { merged: target, empty: source, }
+            },
+        }
+    }
+}
+
+impl<T, R> InProgressMerger<Ref2<R>, Ref2<R>, T, T, Forest2AflatInProgressMerger<T, R>, Forest2<T, R>, Forest2<T, R>>
+    for Forest2AflatInProgressMerger<T, R>
+    where R: Clone + Eq + Hash
+{
+    fn ref_transform(&self, source_ref: Ref2<R>) -> Option<Ref2<R>> {
+        match source_ref {
+            Ref2::Local(local_ref) =>
+                self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
+            Ref2::External(external_ref) =>
+                Some(Ref2::External(external_ref)),
+        }
+    }
+
+    fn proceed(mut self, transformed_item: T) -> Forest2AflatMergerOuterState<T, R> {
+        let inner_merger = self.inner_merger;
+        let (target_ref, entry) = self.pending.with_item(
+            transformed_item,
+            |parent_ref| match parent_ref {
+                Ref2::Local(local_ref) =>
+                    inner_merger.ref_transform(local_ref).map(Ref2::Local),
+                Ref2::External(external_ref) =>
+                    Some(Ref2::External(external_ref)),
+            },
+        );
+        let entry = self.relink.relink_entry(entry, target_ref, |r| inner_merger.ref_transform(r));
+        Forest2AflatInProgressMerger::make_state(inner_merger.proceed(entry), self.target, self.source, self.relink)
+    }
+}
+
+pub struct Forest2Down1InitMerger<T> {
+    inner_merger: SetsInitMerger<Entry<T, Ref2<Ref>>, Entry<T, Ref>>,
+    target: Forest1<T>,
+    source: Forest2<T, Ref>,
+    relink: Relink<Ref>,
+}
+
+pub struct Forest2Down1InProgressMerger<T> {
+    inner_merger: SetsInProgressMerger<Entry<T, Ref2<Ref>>, Entry<T, Ref>>,
+    target: Forest1<T>,
+    source: Forest2<T, Ref>,
+    relink: Relink<Ref>,
+    pending: Pending<Ref2<Ref>>,
+}
+
+impl<T> InitMerger<Ref2<Ref>, Ref, T, Forest2Down1InProgressMerger<T>, Forest1<T>, Forest2<T, Ref>> for Forest2Down1InitMerger<T> {
+    fn ref_transform(&self, source_ref: Ref2<Ref>) -> Option<Ref> {
+        match source_ref {
+            Ref2::Local(local_ref) =>
+                self.inner_merger.ref_transform(local_ref),
+            Ref2::External(external_ref) =>
+                Some(external_ref),
+        }
+    }
+
+    fn merge_start(self) -> Forest2Down1MergerOuterState<T> {
+        Forest2Down1InProgressMerger::make_state(self.inner_merger.merge_start(), self.target, self.source, self.relink)
+    }
+}
+
+type Forest2Down1MergerOuterState<T> =
+    MergeState<Ref2<Ref>, T, Forest2Down1InProgressMerger<T>, Forest1<T>, Forest2<T, Ref>>;
+
+type Forest2Down1MergerInnerState<T> =
+    MergeState<Ref, Entry<T, Ref2<Ref>>, SetsInProgressMerger<Entry<T, Ref2<Ref>>, Entry<T, Ref>>, Set<Entry<T, Ref>>, Set<Entry<T, Ref2<Ref>>>>;
+
+impl<T> Forest2Down1InProgressMerger<T> {
+    fn make_state(
+        inner_state: Forest2Down1MergerInnerState<T>,
+        mut target: Forest1<T>,
+        mut source: Forest2<T, Ref>,
+        relink: Relink<Ref>,
+    )
+        -> Forest2Down1MergerOuterState<T>
+    {
+        match inner_state {
+            MergeState::Continue { item_ref, item: entry, next, } => {
+                let (item, pending) = Pending::new(entry, next.ref_transform(item_ref));
+                MergeState::Continue {
+                    item_ref: Ref2::Local(item_ref),
+                    item,
+                    next: Forest2Down1InProgressMerger { inner_merger: next, target, source, relink, pending, },
+                }
+            },
+            MergeState::Finish { merged, empty, } => {
+                target.nodes = merged;
+                relink.finish(&mut target);
+                source.local_nodes = empty;
+                MergeState::Finish { merged: target, empty: source, }
+            },
+        }
+    }
+}
+
+impl<T> InProgressMerger<Ref2<Ref>, Ref, T, T, Forest2Down1InProgressMerger<T>, Forest1<T>, Forest2<T, Ref>>
+    for Forest2Down1InProgressMerger<T>
+{
+    fn ref_transform(&self, source_ref: Ref2<Ref>) -> Option<Ref> {
+        match source_ref {
+            Ref2::Local(local_ref) =>
+                self.inner_merger.ref_transform(local_ref),
+            Ref2::External(external_ref) =>
+                Some(external_ref),
+        }
+    }
+
+    fn proceed(mut self, transformed_item: T) -> Forest2Down1MergerOuterState<T> {
+        let inner_merger = self.inner_merger;
+        let (target_ref, entry) = self.pending.with_item(
+            transformed_item,
+            |parent_ref| match parent_ref {
+                Ref2::Local(local_ref) =>
+                    inner_merger.ref_transform(local_ref),
+                Ref2::External(external_ref) =>
+                    Some(external_ref),
+            },
+        );
+        let entry = self.relink.relink_entry(entry, target_ref, |r| inner_merger.ref_transform(r));
+        Forest2Down1InProgressMerger::make_state(inner_merger.proceed(entry), self.target, self.source, self.relink)
+    }
+}
+
+type Forest2Down2Entry<T, R> = Entry<T, Ref2<Ref2<R>>>;
+
+pub struct Forest2Down2InitMerger<T, R> {
+    inner_merger: SetsInitMerger<Forest2Down2Entry<T, R>, Entry<T, Ref2<R>>>,
+    target: Forest2<T, R>,
+    source: Forest2<T, Ref2<R>>,
+    relink: Relink<Ref2<R>>,
+}
+
+pub struct Forest2Down2InProgressMerger<T, R> {
+    inner_merger: SetsInProgressMerger<Forest2Down2Entry<T, R>, Entry<T, Ref2<R>>>,
+    target: Forest2<T, R>,
+    source: Forest2<T, Ref2<R>>,
+    relink: Relink<Ref2<R>>,
+    pending: Pending<Ref2<Ref2<R>>>,
+}
+
+impl<T, R> InitMerger<Ref2<Ref2<R>>, Ref2<R>, T, Forest2Down2InProgressMerger<T, R>, Forest2<T, R>, Forest2<T, Ref2<R>>>
+    for Forest2Down2InitMerger<T, R>
+    where R: Clone + Eq + Hash
+{
+    fn ref_transform(&self, source_ref: Ref2<Ref2<R>>) -> Option<Ref2<R>> {
+        match source_ref {
+            Ref2::Local(local_ref) =>
+                self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
+            Ref2::External(external_ref) =>
+                Some(external_ref),
+        }
+    }
+
+    fn merge_start(self) -> Forest2Down2MergerOuterState<T, R> {
+        Forest2Down2InProgressMerger::make_state(self.inner_merger.merge_start(), self.target, self.source, self.relink)
+    }
+}
+
+type Forest2Down2MergerOuterState<T, R> =
+    MergeState<Ref2<Ref2<R>>, T, Forest2Down2InProgressMerger<T, R>, Forest2<T, R>, Forest2<T, Ref2<R>>>;
+
+type Forest2Down2MergerInnerState<T, R> =
+    MergeState<Ref, Forest2Down2Entry<T, R>, SetsInProgressMerger<
+            Forest2Down2Entry<T, R>, Entry<T, Ref2<R>>>, Set<Entry<T, Ref2<R>>>, Set<Forest2Down2Entry<T, R>>>;
+
+impl<T, R> Forest2Down2InProgressMerger<T, R> where R: Clone + Eq + Hash {
+    fn make_state(
+        inner_state: Forest2Down2MergerInnerState<T, R>,
+        mut target: Forest2<T, R>,
+        mut source: Forest2<T, Ref2<R>>,
+        relink: Relink<Ref2<R>>,
+    )
+        -> Forest2Down2MergerOuterState<T, R>
+    {
+        match inner_state {
+            MergeState::Continue { item_ref, item: entry, next, } => {
+                let (item, pending) = Pending::new(entry, next.ref_transform(item_ref));
+                MergeState::Continue {
+                    item_ref: Ref2::Local(item_ref),
+                    item,
+                    next: Forest2Down2InProgressMerger { inner_merger: next, target, source, relink, pending, },
+                }
+            },
+            MergeState::Finish { merged, empty, } => {
+                target.local_nodes = merged;
+                relink.finish(&mut target);
+                source.local_nodes = empty;
+                MergeState::Finish { merged: target, empty: source, }
+            },
+        }
+    }
+}
+
+impl<T, R> InProgressMerger<Ref2<Ref2<R>>, Ref2<R>, T, T, Forest2Down2InProgressMerger<T, R>, Forest2<T, R>, Forest2<T, Ref2<R>>>
+    for Forest2Down2InProgressMerger<T, R>
+    where R: Clone + Eq + Hash
+{
+    fn ref_transform(&self, source_ref: Ref2<Ref2<R>>) -> Option<Ref2<R>> {
+        match source_ref {
+            Ref2::Local(local_ref) =>
+                self.inner_merger.ref_transform(local_ref).map(Ref2::Local),
+            Ref2::External(external_ref) =>
+                Some(external_ref),
+        }
+    }
+
+    fn proceed(mut self, transformed_item: T) -> Forest2Down2MergerOuterState<T, R> {
+        let inner_merger = self.inner_merger;
+        let (target_ref, entry) = self.pending.with_item(
+            transformed_item,
+            |parent_ref| match parent_ref {
+                Ref2::Local(local_ref) =>
+                    inner_merger.ref_transform(local_ref).map(Ref2::Local),
+                Ref2::External(external_ref) =>
+                    Some(external_ref),
+            },
+        );
+        let entry = self.relink.relink_entry(entry, target_ref, |r| inner_merger.ref_transform(r));
+        Forest2Down2InProgressMerger::make_state(inner_merger.proceed(entry), self.target, self.source, self.relink)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{self, Rng};
+    use rayon::{
+        iter::{
+            ParallelIterator,
+            IntoParallelIterator,
+        },
+    };
+
+    use crate::{
         merge::{
             merge_no_transform,
         },
         forest::{
             Forest1,
             Forest2,
+            Frozen,
+            Node,
+            PathOrder,
+            Ref2,
+            InsertError,
+            OrphanPolicy,
+            ReparentError,
             TowardsRootIter,
         },
     };
 
     #[test]
-    fn direct_access() {
+    fn direct_access() {
+        let mut forest1 = Forest1::new();
+
+        let root = forest1.make_root("root");
+        assert_eq!(forest1.get(root).map(|node| node.item), Some(&"root"));
+        assert_eq!(forest1.get(root).map(|node| node.parent), Some(None));
+        assert_eq!(forest1.get(root).map(|node| node.depth), Some(0));
+
+        let child_a = forest1.make_node(root, "child_a");
+        assert_eq!(forest1.get(child_a).map(|node| node.item), Some(&"child_a"));
+        assert_eq!(forest1.get(child_a).map(|node| node.parent), Some(Some(root)));
+        assert_eq!(forest1.get(child_a).map(|node| node.depth), Some(1));
+
+        let child_b = forest1.make_node(root, "child_b");
+        assert_eq!(forest1.get(child_b).map(|node| node.item), Some(&"child_b"));
+        assert_eq!(forest1.get(child_b).map(|node| node.parent), Some(Some(root)));
+        assert_eq!(forest1.get(child_b).map(|node| node.depth), Some(1));
+
+        let child_c = forest1.make_node(child_a, "child_c");
+        assert_eq!(forest1.get(child_c).map(|node| node.item), Some(&"child_c"));
+        assert_eq!(forest1.get(child_c).map(|node| node.parent), Some(Some(child_a)));
+        assert_eq!(forest1.get(child_c).map(|node| node.depth), Some(2));
+
+        let mut forest2 = Forest2::new();
+
+        let root2 = forest2.make_root("root2");
+        assert_eq!(forest2.get(|r| forest1.get(r), root2).map(|node| node.item), Some(&"root2"));
+        assert_eq!(forest2.get(|r| forest1.get(r), root2).map(|node| node.parent), Some(None));
+        assert_eq!(forest2.get(|r| forest1.get(r), root2).map(|node| node.depth), Some(0));
+
+        let child_d = forest2.make_node(|r| forest1.get(r), root2, "child_d");
+        assert_eq!(forest2.get(|r| forest1.get(r), child_d).map(|node| node.item), Some(&"child_d"));
+        assert_eq!(forest2.get(|r| forest1.get(r), child_d).map(|node| node.parent), Some(Some(root2)));
+        assert_eq!(forest2.get(|r| forest1.get(r), child_d).map(|node| node.depth), Some(1));
+
+        let child_c_ext = forest2.external_ref(child_c);
+        let child_e = forest2.make_node(|r| forest1.get(r), child_c_ext, "child_e");
+        assert_eq!(forest2.get(|r| forest1.get(r), child_e).map(|node| node.item), Some(&"child_e"));
+        assert_eq!(forest2.get(|r| forest1.get(r), child_e).map(|node| node.parent), Some(Some(child_c_ext)));
+        assert_eq!(forest2.get(|r| forest1.get(r), child_e).map(|node| node.depth), Some(3));
+
+        let iter = TowardsRootIter::new(|rr| forest2.get(|r| forest1.get(r), rr), child_e);
+        assert_eq!(iter.map(|node| node.item).collect::<Vec<_>>(), vec![&"child_e", &"child_c", &"child_a", &"root"]);
+    }
+
+    #[test]
+    fn layers_access_macro() {
+        let mut forest1 = Forest1::new();
+        let mut forest2 = Forest2::new();
+
+        let root = forest1.make_root("root");
+        assert_eq!(layers!([&forest1].get(root)).map(|node| node.item), Some(&"root"));
+
+        let root2 = forest2.make_root("root2");
+        assert_eq!(layers!([&forest2, &forest1].get(root2)).map(|node| node.item), Some(&"root2"));
+
+        let root_ext = forest2.external_ref(root);
+        let child_a = layers!([&mut forest2, &forest1].make_node(root_ext, "child a"));
+        assert_eq!(layers!([&forest2, &forest1].get(child_a)).map(|node| node.item), Some(&"child a"));
+
+        layers!([&mut forest2, &mut forest1].get_mut(child_a)).map(|node| *node.item = "other child");
+        assert_eq!(layers!([&forest2, &forest1].get(child_a)).map(|node| node.item), Some(&"other child"));
+
+        let child_b = layers!([&mut forest2, &forest1].make_node(child_a, "child b"));
+        assert_eq!(layers!([&forest2, &forest1].get(child_b)).map(|node| node.item), Some(&"child b"));
+
+        {
+            let iter = layers!([&forest2, &forest1].towards_root_iter(child_b));
+            assert_eq!(iter.map(|node| node.item).collect::<Vec<_>>(), vec![&"child b", &"other child", &"root"]);
+        }
+        {
+            let iter = layers!([&forest2, &forest1].iter()).map(|p| p.1);
+            let mut items: Vec<_> = iter.collect();
+            items.sort();
+            assert_eq!(items, vec![&"child b", &"other child", &"root", &"root2"]);
+        }
+
+        let mut maybe_node_ref = Some(child_b);
+        while let Some(node_ref) = maybe_node_ref {
+            maybe_node_ref = layers!([&forest2, &forest1].get(node_ref)).and_then(|node| node.parent);
+            layers!([&mut forest2, &mut forest1].remove(node_ref));
+        }
+        assert_eq!(layers!([&forest2, &forest1].get(child_b)).map(|node| node.item), None);
+        assert_eq!(layers!([&forest2, &forest1].get(child_a)).map(|node| node.item), None);
+        assert_eq!(layers!([&forest2, &forest1].get(root_ext)).map(|node| node.item), None);
+        assert_eq!(layers!([&forest2, &forest1].get(root2)).map(|node| node.item), Some(&"root2"));
+        // the upper layer node is only tombstoned until the layers are merged
+        assert!(forest2.is_tombstoned(&root));
+        assert_eq!(forest1.get(root).map(|node| node.item), Some(&"root"));
+        assert!(layers!([&mut forest2, &forest1].remove(root_ext)).is_none());
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert!(forest1.get(root).is_none());
+        assert_eq!(forest1.len(), 1);
+    }
+
+    #[test]
+    fn merge_aflat_forest1() {
+        let mut forest1_a = Forest1::new();
+        let root_a = forest1_a.make_root("root a");
+        let _child_a_a = layers!([&mut forest1_a].make_node(root_a, "child_a a"));
+        let _child_b_a = layers!([&mut forest1_a].make_node(root_a, "child_b a"));
+
+        let mut forest1_b = Forest1::new();
+        let root_b = forest1_b.make_root("root b");
+        let child_a_b = layers!([&mut forest1_b].make_node(root_b, "child_a b"));
+        let _child_b_b = layers!([&mut forest1_b].make_node(child_a_b, "child_b b"));
+
+        let forest1_a = merge_no_transform(forest1_b.merge_aflat(forest1_a));
+
+        let mut items: Vec<_> = layers!([&forest1_a].iter()).collect();
+        items.sort_by_key(|item| item.1);
+        let verify = vec![&"child_a a", &"child_a b", &"child_b a", &"child_b b", &"root a", &"root b"];
+        assert_eq!(items.len(), verify.len());
+        for ((set_ref, item), verify_item) in items.into_iter().zip(verify) {
+            assert_eq!(item, verify_item);
+            assert_eq!(layers!([&forest1_a].get(set_ref)).map(|node| node.item), Some(item));
+        }
+    }
+
+    #[test]
+    fn merge_aflat_forest2() {
+        let forest1 = Forest1::new();
+        let mut forest2_a = Forest2::new();
+        let root_a = forest2_a.make_root("root a");
+        let _child_a_a = layers!([&mut forest2_a, &forest1].make_node(root_a, "child_a a"));
+        let _child_b_a = layers!([&mut forest2_a, &forest1].make_node(root_a, "child_b a"));
+
+        let mut forest2_b = Forest2::new();
+        let root_b = forest2_b.make_root("root b");
+        let child_a_b = layers!([&mut forest2_b, &forest1].make_node(root_b, "child_a b"));
+        let _child_b_b = layers!([&mut forest2_b, &forest1].make_node(child_a_b, "child_b b"));
+
+        let forest2_a = merge_no_transform(forest2_b.merge_aflat(forest2_a));
+
+        let mut items: Vec<_> = layers!([&forest2_a, &forest1].iter()).collect();
+        items.sort_by_key(|item| item.1);
+        let verify = vec![&"child_a a", &"child_a b", &"child_b a", &"child_b b", &"root a", &"root b"];
+        assert_eq!(items.len(), verify.len());
+        for ((set_ref, item), verify_item) in items.into_iter().zip(verify) {
+            assert_eq!(item, verify_item);
+            assert_eq!(layers!([&forest2_a, &forest1].get(set_ref)).map(|node| node.item), Some(item));
+        }
+    }
+
+    #[test]
+    fn merge_down_forest1() {
         let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = layers!([&mut forest1].make_node(root1, "child1 a"));
+        let _child1_b = layers!([&mut forest1].make_node(root1, "child1 b"));
 
-        let root = forest1.make_root("root");
-        assert_eq!(forest1.get(root).map(|node| node.item), Some(&"root"));
-        assert_eq!(forest1.get(root).map(|node| node.parent), Some(None));
-        assert_eq!(forest1.get(root).map(|node| node.depth), Some(0));
+        let mut forest2 = Forest2::new();
+        let root2 = forest2.make_root("root2");
+        let child2_a = layers!([&mut forest2, &forest1].make_node(root2, "child2 a"));
+        let _child2_b = layers!([&mut forest2, &forest1].make_node(child2_a, "child2 b"));
+        let child1_a2 = forest2.external_ref(child1_a);
+        let _child2_c = layers!([&mut forest2, &forest1].make_node(child1_a2, "child2 c"));
 
-        let child_a = forest1.make_node(root, "child_a");
-        assert_eq!(forest1.get(child_a).map(|node| node.item), Some(&"child_a"));
-        assert_eq!(forest1.get(child_a).map(|node| node.parent), Some(Some(root)));
-        assert_eq!(forest1.get(child_a).map(|node| node.depth), Some(1));
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
 
-        let child_b = forest1.make_node(root, "child_b");
-        assert_eq!(forest1.get(child_b).map(|node| node.item), Some(&"child_b"));
-        assert_eq!(forest1.get(child_b).map(|node| node.parent), Some(Some(root)));
-        assert_eq!(forest1.get(child_b).map(|node| node.depth), Some(1));
+        let mut items: Vec<_> = layers!([&forest1].iter()).collect();
+        items.sort_by_key(|item| item.1);
+        let verify = vec![&"child1 a", &"child1 b", &"child2 a", &"child2 b", &"child2 c", &"root1", &"root2"];
+        assert_eq!(items.len(), verify.len());
+        for ((set_ref, item), verify_item) in items.iter().cloned().zip(verify) {
+            assert_eq!(item, verify_item);
+            assert_eq!(layers!([&forest1].get(set_ref)).map(|node| node.item), Some(item));
+        }
+        let child2_c = items[4].0;
+        let path: Vec<_> = layers!([&forest1].towards_root_iter(child2_c)).map(|p| p.item).collect();
+        assert_eq!(path, vec![&"child2 c", &"child1 a", &"root1"]);
+    }
 
-        let child_c = forest1.make_node(child_a, "child_c");
-        assert_eq!(forest1.get(child_c).map(|node| node.item), Some(&"child_c"));
-        assert_eq!(forest1.get(child_c).map(|node| node.parent), Some(Some(child_a)));
-        assert_eq!(forest1.get(child_c).map(|node| node.depth), Some(2));
+    #[test]
+    fn merge_down_forest2() {
+        let forest0 = Forest1::new();
+        let mut forest1 = Forest2::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = layers!([&mut forest1, &forest0].make_node(root1, "child1 a"));
+        let _child1_b = layers!([&mut forest1, &forest0].make_node(root1, "child1 b"));
+
+        let mut forest2 = Forest2::new();
+        let root2 = forest2.make_root("root2");
+        let child2_a = layers!([&mut forest2, &forest1, &forest0].make_node(root2, "child2 a"));
+        let _child2_b = layers!([&mut forest2, &forest1, &forest0].make_node(child2_a, "child2 b"));
+        let child1_a2 = forest2.external_ref(child1_a);
+        let _child2_c = layers!([&mut forest2, &forest1, &forest0].make_node(child1_a2, "child2 c"));
+
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+
+        let mut items: Vec<_> = layers!([&forest1, &forest0].iter()).collect();
+        items.sort_by_key(|item| item.1);
+        let verify = vec![&"child1 a", &"child1 b", &"child2 a", &"child2 b", &"child2 c", &"root1", &"root2"];
+        assert_eq!(items.len(), verify.len());
+        for ((set_ref, item), verify_item) in items.iter().cloned().zip(verify) {
+            assert_eq!(item, verify_item);
+            assert_eq!(layers!([&forest1, &forest0].get(set_ref)).map(|node| node.item), Some(item));
+        }
+        let child2_c = items[4].0;
+        let forest0_ref = &forest0;
+        let path: Vec<_> = layers!([&forest1, forest0_ref].towards_root_iter(child2_c)).map(|p| p.item).collect();
+        assert_eq!(path, vec![&"child2 c", &"child1 a", &"root1"]);
+    }
+
+    #[test]
+    fn merge_down_forest21() {
+        let mut forest0 = Forest1::new();
+        let root0 = forest0.make_root("root0");
+        let _child0_a = layers!([&mut forest0].make_node(root0, "child0 a"));
+        let child0_b = layers!([&mut forest0].make_node(root0, "child0 b"));
+
+        let mut forest1 = Forest2::new();
+        let root1 = forest1.make_root("root1");
+        let child0_b = forest1.external_ref(child0_b);
+        let child1_a = layers!([&mut forest1, &forest0].make_node(child0_b, "child1 a"));
+        let _child1_b = layers!([&mut forest1, &forest0].make_node(root1, "child1 b"));
+
+        let mut forest2 = Forest2::new();
+        let root2 = forest2.make_root("root2");
+        let child2_a = layers!([&mut forest2, &forest1, &forest0].make_node(root2, "child2 a"));
+        let _child2_b = layers!([&mut forest2, &forest1, &forest0].make_node(child2_a, "child2 b"));
+        let child1_a2 = forest2.external_ref(child1_a);
+        let _child2_c = layers!([&mut forest2, &forest1, &forest0].make_node(child1_a2, "child2 c"));
+
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        let forest0 = merge_no_transform(forest1.merge_down(forest0));
+
+        let mut items: Vec<_> = layers!([&forest0].iter()).collect();
+        items.sort_by_key(|item| item.1);
+        let verify = vec![&"child0 a", &"child0 b", &"child1 a", &"child1 b", &"child2 a", &"child2 b", &"child2 c", &"root0", &"root1", &"root2"];
+        assert_eq!(items.len(), verify.len());
+        for ((set_ref, item), verify_item) in items.iter().cloned().zip(verify) {
+            assert_eq!(item, verify_item);
+            assert_eq!(layers!([&forest0].get(set_ref)).map(|node| node.item), Some(item));
+        }
+        let child2_c = items[6].0;
+        let path: Vec<_> = layers!([&forest0].towards_root_iter(child2_c)).map(|p| p.item).collect();
+        assert_eq!(path, vec![&"child2 c", &"child1 a", &"child0 b", &"root0"]);
+    }
+
+    #[test]
+    fn merge_aflat_dangling_forest1() {
+        let mut forest1_a = Forest1::new();
+        let _root_a = forest1_a.make_root("root a");
+
+        let mut forest1_b = Forest1::new();
+        let root_b = forest1_b.make_root("root b");
+        let _child_a_b = layers!([&mut forest1_b].make_node(root_b, "child_a b"));
+        // the child is left pointing to the removed root
+        assert_eq!(forest1_b.remove(root_b).map(|node| node.item), Some("root b"));
+
+        let forest1_a = merge_no_transform(forest1_b.merge_aflat(forest1_a));
+
+        let mut items: Vec<_> = layers!([&forest1_a].iter()).map(|(_, &item)| item).collect();
+        items.sort();
+        assert_eq!(items, vec!["child_a b", "root a"]);
+        // the parent of the child has no counterpart in the target, so the child keeps a stale depth
+        let child_a_b = layers!([&forest1_a].iter()).find(|&(_, &item)| item == "child_a b").map(|pair| pair.0);
+        assert_eq!(forest1_a.verify().depth_mismatches, child_a_b.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn merge_down_tombstone_with_children() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let _child1_a = layers!([&mut forest1].make_node(root1, "child1 a"));
+
+        let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        let _child2_a = layers!([&mut forest2, &forest1].make_node(root1_ext, "child2 a"));
+        // the upper layer child is left pointing to the tombstoned node
+        let removed = layers!([&mut forest2, &forest1].remove(root1_ext));
+        assert_eq!(removed.map(|node| node.item), Some(None));
+
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+
+        let mut items: Vec<_> = layers!([&forest1].iter()).map(|(_, &item)| item).collect();
+        items.sort();
+        assert_eq!(items, vec!["child1 a", "child2 a"]);
+        assert_eq!(forest1.verify().dangling_parents.len(), 2);
+    }
+
+    #[test]
+    fn par_iter_forest21() {
+        let mut forest0 = Forest1::new();
+        let root0 = forest0.make_root("root0");
+        let _child0_a = layers!([&mut forest0].make_node(root0, "child0 a"));
+        let child0_b = layers!([&mut forest0].make_node(root0, "child0 b"));
+
+        let mut forest1 = Forest2::new();
+        let root1 = forest1.make_root("root1");
+        let child0_b = forest1.external_ref(child0_b);
+        let child1_a = layers!([&mut forest1, &forest0].make_node(child0_b, "child1 a"));
+        let _child1_b = layers!([&mut forest1, &forest0].make_node(root1, "child1 b"));
+
+        let mut forest2 = Forest2::new();
+        let root2 = forest2.make_root("root2");
+        let child2_a = layers!([&mut forest2, &forest1, &forest0].make_node(root2, "child2 a"));
+        let _child2_b = layers!([&mut forest2, &forest1, &forest0].make_node(child2_a, "child2 b"));
+        let child1_a2 = forest2.external_ref(child1_a);
+        let _child2_c = layers!([&mut forest2, &forest1, &forest0].make_node(child1_a2, "child2 c"));
+
+        use rayon::iter::ParallelIterator;
+        let mut items: Vec<_> = layers!([&forest2, &forest1, &forest0].par_iter()).map(|p| p.1).collect();
+        items.sort();
+        assert_eq!(items, vec![
+            &"child0 a", &"child0 b", &"child1 a", &"child1 b",
+            &"child2 a", &"child2 b", &"child2 c",
+            &"root0", &"root1", &"root2",
+        ]);
+    }
+
+    #[test]
+    fn child_index_forest1() {
+        let mut forest_indexed = Forest1::new();
+        forest_indexed.enable_child_index();
+        let mut forest_scan = Forest1::new();
+
+        for forest in [&mut forest_indexed, &mut forest_scan] {
+            let root_a = forest.make_root("root a");
+            let child_a = forest.make_node(root_a, "child a");
+            let child_b = forest.make_node(root_a, "child b");
+            let _child_c = forest.make_node(root_a, "child c");
+            let _child_d = forest.make_node(child_a, "child d");
+            let _child_e = forest.make_node(child_b, "child e");
+            let root_b = forest.make_root("root b");
+            let _child_f = forest.make_node(root_b, "child f");
+            assert_eq!(forest.remove(child_b).map(|node| node.item), Some("child b"));
+
+            let items = |refs: Vec<_>| -> Vec<_> {
+                refs.into_iter().map(|r| *forest.get(r).unwrap().item).collect()
+            };
+            assert_eq!(items(forest.children(root_a).collect()), vec!["child a", "child c"]);
+            assert_eq!(items(forest.descendants_dfs(root_a).collect()), vec!["child a", "child d", "child c"]);
+            assert_eq!(items(forest.descendants_bfs(root_a).collect()), vec!["child a", "child c", "child d"]);
+            assert_eq!(items(forest.roots().collect()), vec!["root a", "root b"]);
+            let mut leaves = items(forest.leaves().collect());
+            leaves.sort();
+            assert_eq!(leaves, vec!["child c", "child d", "child e", "child f"]);
+        }
+
+        forest_scan.enable_child_index();
+        let root_a = forest_scan.roots().next().unwrap();
+        let items: Vec<_> = forest_scan.descendants_dfs(root_a).map(|r| *forest_scan.get(r).unwrap().item).collect();
+        assert_eq!(items, vec!["child a", "child d", "child c"]);
+    }
+
+    #[test]
+    fn child_index_forest2() {
+        let mut forest1 = Forest1::new();
+        forest1.enable_child_index();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+
+        let mut forest2 = Forest2::new();
+        forest2.enable_child_index();
+        let root1_ext = forest2.external_ref(root1);
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child2_a = layers!([&mut forest2, &forest1].make_node(root1_ext, "child2 a"));
+        let _child2_b = layers!([&mut forest2, &forest1].make_node(child2_a, "child2 b"));
+        let _child2_c = layers!([&mut forest2, &forest1].make_node(child1_a_ext, "child2 c"));
+        let _root2 = forest2.make_root("root2");
+
+        let item = |r| *layers!([&forest2, &forest1].get(r)).unwrap().item;
+        let children: Vec<_> = layers!([&forest2, &forest1].children(root1_ext)).map(item).collect();
+        assert_eq!(children, vec!["child1 a", "child2 a"]);
+        let dfs: Vec<_> = layers!([&forest2, &forest1].descendants_dfs(root1_ext)).map(item).collect();
+        assert_eq!(dfs, vec!["child1 a", "child2 c", "child2 a", "child2 b"]);
+        let bfs: Vec<_> = layers!([&forest2, &forest1].descendants_bfs(root1_ext)).map(item).collect();
+        assert_eq!(bfs, vec!["child1 a", "child2 a", "child2 c", "child2 b"]);
+        let roots: Vec<_> = layers!([&forest2, &forest1].roots()).map(item).collect();
+        assert_eq!(roots, vec!["root1", "root2"]);
+        let mut leaves: Vec<_> = layers!([&forest2, &forest1].leaves()).map(item).collect();
+        leaves.sort();
+        assert_eq!(leaves, vec!["child2 b", "child2 c", "root2"]);
+
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        let item = |r| *forest1.get(r).unwrap().item;
+        let dfs: Vec<_> = forest1.descendants_dfs(root1).map(item).collect();
+        assert_eq!(dfs, vec!["child1 a", "child2 c", "child2 a", "child2 b"]);
+        let roots: Vec<_> = forest1.roots().map(item).collect();
+        assert_eq!(roots, vec!["root1", "root2"]);
+    }
+
+    #[test]
+    fn plain_upper_refs_forest2() {
+        // upper layer refs without `Eq` or `Hash` are fine as long as no index is enabled
+        #[derive(Clone, Debug, PartialEq)]
+        struct PlainRef(usize);
+
+        let upper = ["upper"];
+        let access = |r: PlainRef| upper.get(r.0).map(|item| Node { item, parent: None, depth: 0, });
+        let mut forest2: Forest2<&str, PlainRef> = Forest2::new();
+        let upper_ext = forest2.external_ref(PlainRef(0));
+        let child = forest2.make_node(access, upper_ext.clone(), "child");
+        let root = forest2.make_root("root");
+        let node = forest2.get(access, child).unwrap();
+        assert_eq!((*node.item, node.parent, node.depth), ("child", Some(upper_ext.clone()), 1));
+        assert_eq!(forest2.get(access, upper_ext).map(|node| *node.item), Some("upper"));
+        assert_eq!(forest2.get(access, root).map(|node| node.depth), Some(0));
+    }
+
+    #[test]
+    fn remove_subtree_forest1() {
+        for child_index in [false, true] {
+            let mut forest = Forest1::new();
+            if child_index {
+                forest.enable_child_index();
+            }
+            let root = forest.make_root("root");
+            let child_a = forest.make_node(root, "child a");
+            let child_b = forest.make_node(child_a, "child b");
+            let _child_c = forest.make_node(child_b, "child c");
+            let child_d = forest.make_node(root, "child d");
+
+            let removed: Vec<_> = forest.remove_subtree(child_a).into_iter().map(|(_, node)| node.item).collect();
+            assert_eq!(removed, vec!["child a", "child b", "child c"]);
+            assert_eq!(forest.len(), 2);
+            assert_eq!(forest.children(root).collect::<Vec<_>>(), vec![child_d]);
+            assert!(forest.get(child_b).is_none());
+        }
+    }
+
+    #[test]
+    fn orphan_policy_forest1() {
+        for child_index in [false, true] {
+            let mut forest = Forest1::new();
+            if child_index {
+                forest.enable_child_index();
+            }
+            let root = forest.make_root("root");
+            let child_a = forest.make_node(root, "child a");
+            let child_b = forest.make_node(child_a, "child b");
+            let child_c = forest.make_node(child_b, "child c");
+
+            assert!(forest.remove_with(child_a, OrphanPolicy::Reject).is_none());
+            assert_eq!(forest.len(), 4);
+            assert_eq!(forest.has_child_index(), child_index);
+
+            assert_eq!(forest.remove_with(child_a, OrphanPolicy::Reparent).map(|node| node.item), Some("child a"));
+            assert_eq!(forest.get(child_b).map(|node| (node.parent, node.depth)), Some((Some(root), 1)));
+            assert_eq!(forest.get(child_c).map(|node| node.depth), Some(2));
+            assert_eq!(forest.children(root).collect::<Vec<_>>(), vec![child_b]);
+            let path: Vec<_> = layers!([&forest].towards_root_iter(child_c)).map(|node| *node.item).collect();
+            assert_eq!(path, vec!["child c", "child b", "root"]);
+            assert_eq!(layers!([&forest].towards_root_iter(child_c)).count(), 3);
+
+            assert_eq!(forest.remove_with(root, OrphanPolicy::Promote).map(|node| node.item), Some("root"));
+            assert_eq!(forest.get(child_b).map(|node| (node.parent, node.depth)), Some((None, 0)));
+            assert_eq!(forest.get(child_c).map(|node| node.depth), Some(1));
+            assert_eq!(forest.roots().collect::<Vec<_>>(), vec![child_b]);
+
+            // several orphans are promoted along with their subtrees
+            let child_d = forest.make_node(child_b, "child d");
+            let child_e = forest.make_node(child_d, "child e");
+            assert!(forest.remove_with(child_b, OrphanPolicy::Promote).is_some());
+            let depths: Vec<_> = [child_c, child_d, child_e].iter().map(|&node_ref| forest.get(node_ref).map(|node| node.depth)).collect();
+            assert_eq!(depths, vec![Some(0), Some(0), Some(1)]);
+            assert_eq!(forest.has_child_index(), child_index);
+        }
+    }
+
+    #[test]
+    fn orphan_policy_forest2() {
+        let mut forest1 = Forest1::new();
+        forest1.enable_child_index();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+        let child1_b = forest1.make_node(child1_a, "child1 b");
+
+        let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2_a = layers!([&mut forest2, &forest1].make_node(child1_a_ext, "child2 a"));
+        let child2_b = layers!([&mut forest2, &forest1].make_node(child2_a, "child2 b"));
+        let child2_c = layers!([&mut forest2, &forest1].make_node(child1_b_ext, "child2 c"));
+        let parent_depth = |forest2: &Forest2<_, _>, forest1: &Forest1<_>, r| {
+            layers!([forest2, forest1].get(r)).map(|node| (node.parent, node.depth))
+        };
+
+        // upper layer children can not be moved from this layer whatever the policy
+        assert!(layers!([&mut forest2, &forest1].remove_with(child1_a_ext, OrphanPolicy::Reject)).is_none());
+        assert!(layers!([&mut forest2, &forest1].remove_with(child1_a_ext, OrphanPolicy::Reparent)).is_none());
+        assert_eq!(parent_depth(&forest2, &forest1, child2_c), Some((Some(child1_b_ext), 3)));
+        assert!(layers!([&mut forest2, &forest1].remove_with(child1_b_ext, OrphanPolicy::Reject)).is_none());
+
+        // external nodes are tombstoned and only their shadowed items are returned
+        let removed = layers!([&mut forest2, &forest1].remove_with(child1_b_ext, OrphanPolicy::Reparent)).unwrap();
+        assert_eq!((removed.item, removed.parent, removed.depth), (None, Some(child1_a_ext), 2));
+        assert!(forest2.is_tombstoned(&child1_b));
+        assert_eq!(parent_depth(&forest2, &forest1, child2_c), Some((Some(child1_a_ext), 2)));
+        layers!([&mut forest2, &forest1].shadow_mut(child1_a_ext)).unwrap();
+        let removed = layers!([&mut forest2, &forest1].remove_with(child1_a_ext, OrphanPolicy::Reparent));
+        assert_eq!(removed.map(|node| node.item), Some(Some("child1 a")));
+        assert!(!forest2.is_shadowed(&child1_a));
+        assert_eq!(parent_depth(&forest2, &forest1, child2_a), Some((Some(root1_ext), 1)));
+        assert_eq!(parent_depth(&forest2, &forest1, child2_b), Some((Some(child2_a), 2)));
+        assert_eq!(parent_depth(&forest2, &forest1, child2_c), Some((Some(root1_ext), 1)));
+        let children: Vec<_> = layers!([&forest2, &forest1].children(root1_ext)).collect();
+        assert_eq!(children, vec![child2_a, child2_c]);
+        assert_eq!(forest1.len(), 3);
+
+        let removed = layers!([&mut forest2, &forest1].remove_with(child2_a, OrphanPolicy::Promote));
+        assert_eq!(removed.map(|node| node.item), Some(Some("child2 a")));
+        assert_eq!(parent_depth(&forest2, &forest1, child2_b), Some((None, 0)));
+        let removed: Vec<_> = layers!([&mut forest2, &forest1].remove_subtree(root1_ext))
+            .into_iter()
+            .map(|(node_ref, node)| (node_ref, node.item))
+            .collect();
+        assert_eq!(removed, vec![(root1_ext, None), (child2_c, Some("child2 c"))]);
+        assert!(layers!([&mut forest2, &forest1].remove_subtree(root1_ext)).is_empty());
+        assert_eq!(forest1.len(), 3);
+        assert_eq!(forest2.len(), 1);
+        assert_eq!(layers!([&forest2, &forest1].iter()).count(), 1);
+
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert_eq!(forest1.local_iter().map(|(_, &item)| item).collect::<Vec<_>>(), vec!["child2 b"]);
+    }
+
+    #[test]
+    fn reparent_forest1() {
+        let mut forest = Forest1::new();
+        forest.enable_child_index();
+        let root_a = forest.make_root("root a");
+        let child_a = forest.make_node(root_a, "child a");
+        let child_b = forest.make_node(child_a, "child b");
+        let child_c = forest.make_node(child_b, "child c");
+        let root_b = forest.make_root("root b");
+        let child_d = forest.make_node(root_b, "child d");
+
+        assert_eq!(forest.reparent(child_a, child_c), Err(ReparentError::Cycle));
+        assert_eq!(forest.reparent(child_a, child_a), Err(ReparentError::Cycle));
+        assert_eq!(forest.reparent(child_b, child_d), Ok(()));
+        assert_eq!(forest.get(child_b).map(|node| (node.parent, node.depth)), Some((Some(child_d), 2)));
+        assert_eq!(forest.get(child_c).map(|node| node.depth), Some(3));
+        assert_eq!(forest.children(child_a).count(), 0);
+        assert_eq!(forest.children(child_d).collect::<Vec<_>>(), vec![child_b]);
+        let path: Vec<_> = layers!([&forest].towards_root_iter(child_c)).map(|node| *node.item).collect();
+        assert_eq!(path, vec!["child c", "child b", "child d", "root b"]);
+
+        assert_eq!(forest.detach(child_b), Ok(()));
+        assert_eq!(forest.get(child_b).map(|node| (node.parent, node.depth)), Some((None, 0)));
+        assert_eq!(forest.get(child_c).map(|node| node.depth), Some(1));
+        assert_eq!(forest.roots().collect::<Vec<_>>(), vec![root_a, root_b, child_b]);
+    }
+
+    #[test]
+    fn reparent_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+
+        let mut forest2 = Forest2::new();
+        let root2 = forest2.make_root("root2");
+        let child2_a = layers!([&mut forest2, &forest1].make_node(root2, "child2 a"));
+        let child2_b = layers!([&mut forest2, &forest1].make_node(child2_a, "child2 b"));
+        let child1_a_ext = forest2.external_ref(child1_a);
+
+        assert_eq!(layers!([&mut forest2, &forest1].reparent(root2, child2_b)), Err(ReparentError::Cycle));
+        assert_eq!(layers!([&mut forest2, &forest1].reparent(child1_a_ext, root2)), Err(ReparentError::UpperLayerNode));
+        assert_eq!(layers!([&mut forest2, &forest1].reparent(child2_a, child1_a_ext)), Ok(()));
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).map(|node| node.depth), Some(3));
+        let path: Vec<_> = layers!([&forest2, &forest1].towards_root_iter(child2_b)).map(|node| *node.item).collect();
+        assert_eq!(path, vec!["child2 b", "child2 a", "child1 a", "root1"]);
+
+        assert_eq!(layers!([&mut forest2, &forest1].detach(child2_a)), Ok(()));
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).map(|node| node.depth), Some(1));
+    }
+
+    #[test]
+    fn refresh_depths_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+        let child1_b = forest1.make_node(child1_a, "child1 b");
+        let child1_c = forest1.make_node(root1, "child1 c");
+
+        let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2_a = layers!([&mut forest2, &forest1].make_node(child1_b_ext, "child2 a"));
+        let child2_b = layers!([&mut forest2, &forest1].make_node(child2_a, "child2 b"));
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).map(|node| node.depth), Some(4));
+
+        assert_eq!(forest1.reparent(child1_a, child1_c), Ok(()));
+        layers!([&mut forest2, &forest1].refresh_depths(root1_ext));
+        assert_eq!(layers!([&forest2, &forest1].get(child2_a)).map(|node| node.depth), Some(4));
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).map(|node| node.depth), Some(5));
+
+        assert_eq!(forest1.detach(child1_a), Ok(()));
+        layers!([&mut forest2, &forest1].refresh_depths(child2_a));
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).map(|node| node.depth), Some(3));
+        assert_eq!(layers!([&forest2, &forest1].verify()).index_errors.len(), 0);
+    }
+
+    #[test]
+    fn set_subtree_depth_inconsistent_forest1() {
+        let mut forest = Forest1::new();
+        let root = forest.make_root("root");
+        let child = forest.make_node(root, "child");
+        // depths inserted inconsistently with parents must not underflow when the subtree is moved up
+        let deep = forest.insert(Node { item: "deep", parent: Some(child), depth: 7, });
+        let leaf = forest.insert(Node { item: "leaf", parent: Some(deep), depth: 2, });
+        assert_eq!(forest.detach(deep), Ok(()));
+        assert_eq!(forest.get(leaf).map(|node| node.depth), Some(1));
+    }
+
+    #[test]
+    fn ancestors_forest1_random() {
+        let mut rng = rand::thread_rng();
+        let mut forest_jumps = Forest1::new();
+        forest_jumps.enable_jump_index();
+        let mut forest_plain = Forest1::new();
+        let mut refs = Vec::new();
+        for index in 0 .. 2000 {
+            if refs.is_empty() || rng.gen_range(0 .. 100) < 2 {
+                refs.push((forest_jumps.make_root(index), forest_plain.make_root(index)));
+            } else {
+                // deep chains with occasional branching
+                let parent_index = if rng.gen_range(0 .. 4) == 0 { rng.gen_range(0 .. refs.len()) } else { refs.len() - 1 };
+                let (parent_jumps, parent_plain) = refs[parent_index];
+                refs.push((forest_jumps.make_node(parent_jumps, index), forest_plain.make_node(parent_plain, index)));
+            }
+        }
+        let naive_path = |forest: &Forest1<usize>, node_ref| -> Vec<usize> {
+            layers!([forest].towards_root_iter(node_ref)).map(|node| *node.item).collect()
+        };
+        for round in 0 .. 1000 {
+            if round % 100 == 99 {
+                // move some subtrees around to make sure jumps are maintained
+                let (node_jumps, node_plain) = refs[rng.gen_range(0 .. refs.len())];
+                let (parent_jumps, parent_plain) = refs[rng.gen_range(0 .. refs.len())];
+                let result = forest_jumps.reparent(node_jumps, parent_jumps);
+                assert_eq!(forest_plain.reparent(node_plain, parent_plain), result);
+            }
+            let (a_jumps, a_plain) = refs[rng.gen_range(0 .. refs.len())];
+            let (b_jumps, b_plain) = refs[rng.gen_range(0 .. refs.len())];
+            let path_a = naive_path(&forest_plain, a_plain);
+            let path_b = naive_path(&forest_plain, b_plain);
+            let naive_lca = path_a.iter().find(|item| path_b.contains(item)).cloned();
+            let naive_is_ancestor = a_plain != b_plain && path_b.contains(forest_plain.get(a_plain).unwrap().item);
+
+            let item_jumps = |r| *forest_jumps.get(r).unwrap().item;
+            let item_plain = |r| *forest_plain.get(r).unwrap().item;
+            assert_eq!(forest_jumps.lca(a_jumps, b_jumps).map(item_jumps), naive_lca);
+            assert_eq!(forest_plain.lca(a_plain, b_plain).map(item_plain), naive_lca);
+            assert_eq!(forest_jumps.is_ancestor(a_jumps, b_jumps), naive_is_ancestor);
+            assert_eq!(forest_plain.is_ancestor(a_plain, b_plain), naive_is_ancestor);
+            if let Some(lca_item) = naive_lca {
+                let up = path_a.iter().position(|&item| item == lca_item).unwrap();
+                let down = path_b.iter().position(|&item| item == lca_item).unwrap();
+                let mut naive = path_a[.. up + 1].to_vec();
+                naive.extend(path_b[.. down].iter().rev());
+                assert_eq!(forest_jumps.distance(a_jumps, b_jumps), Some(up + down));
+                assert_eq!(forest_jumps.path(a_jumps, b_jumps).map(|path| path.into_iter().map(item_jumps).collect()), Some(naive.clone()));
+                assert_eq!(forest_plain.path(a_plain, b_plain).map(|path| path.into_iter().map(item_plain).collect()), Some(naive));
+            } else {
+                assert_eq!(forest_jumps.distance(a_jumps, b_jumps), None);
+                assert_eq!(forest_jumps.path(a_jumps, b_jumps), None);
+            }
+        }
+    }
+
+    #[test]
+    fn ancestors_layers() {
+        let mut forest1 = Forest1::new();
+        forest1.enable_jump_index();
+        let root1 = forest1.make_root("root1");
+        let mut cursor1 = root1;
+        for _ in 0 .. 10 {
+            cursor1 = forest1.make_node(cursor1, "chain1");
+        }
+        let fork1 = cursor1;
+        let child1_a = forest1.make_node(fork1, "child1 a");
+
+        let mut forest2 = Forest2::new();
+        forest2.enable_jump_index();
+        let mut cursor2 = forest2.external_ref(fork1);
+        for _ in 0 .. 10 {
+            cursor2 = layers!([&mut forest2, &forest1].make_node(cursor2, "chain2"));
+        }
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let root1_ext = forest2.external_ref(root1);
+        let fork1_ext = forest2.external_ref(fork1);
+
+        assert_eq!(layers!([&forest2, &forest1].lca(cursor2, child1_a_ext)), Some(fork1_ext));
+        assert!(layers!([&forest2, &forest1].is_ancestor(root1_ext, cursor2)));
+        assert!(!layers!([&forest2, &forest1].is_ancestor(child1_a_ext, cursor2)));
+        assert_eq!(layers!([&forest2, &forest1].distance(cursor2, child1_a_ext)), Some(11));
+        let path = layers!([&forest2, &forest1].path(child1_a_ext, cursor2)).unwrap();
+        assert_eq!(path.len(), 12);
+        assert_eq!(path[0], child1_a_ext);
+        assert_eq!(path[1], fork1_ext);
+        assert_eq!(path[11], cursor2);
+
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        let leaf = forest1.leaves().find(|&r| forest1.get(r).map(|node| node.depth) == Some(20)).unwrap();
+        assert_eq!(forest1.lca(leaf, child1_a), Some(fork1));
+        assert_eq!(forest1.distance(leaf, root1), Some(20));
+    }
+
+    #[test]
+    fn shadow_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1".to_string());
+        let child1 = forest1.make_node(root1, "child1".to_string());
+
+        let mut forest2 = Forest2::new();
+        let child1_ext = forest2.external_ref(child1);
+        let child2 = layers!([&mut forest2, &forest1].make_node(child1_ext, "child2".to_string()));
+        layers!([&mut forest2, &forest1].shadow_mut(child1_ext)).unwrap().item.push_str(" shadow");
+        assert!(forest2.is_shadowed(&child1));
+
+        assert_eq!(forest1.get(child1).unwrap().item, "child1");
+        assert_eq!(layers!([&forest2, &forest1].get(child1_ext)).unwrap().item, "child1 shadow");
+        assert_eq!(layers!([&forest2, &forest1].towards_root_iter(child2)).count(), 3);
 
-        let mut forest2 = Forest2::new();
+        let mut items: Vec<_> = layers!([&forest2, &forest1].iter()).map(|(_, item)| item.clone()).collect();
+        items.sort();
+        assert_eq!(items, vec!["child1 shadow".to_string(), "child2".to_string(), "root1".to_string()]);
 
-        let root2 = forest2.make_root("root2");
-        assert_eq!(forest2.get(|r| forest1.get(r), root2).map(|node| node.item), Some(&"root2"));
-        assert_eq!(forest2.get(|r| forest1.get(r), root2).map(|node| node.parent), Some(None));
-        assert_eq!(forest2.get(|r| forest1.get(r), root2).map(|node| node.depth), Some(0));
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert_eq!(forest1.get(child1).unwrap().item, "child1 shadow");
+        assert_eq!(forest1.len(), 3);
+    }
 
-        let child_d = forest2.make_node(|r| forest1.get(r), root2, "child_d");
-        assert_eq!(forest2.get(|r| forest1.get(r), child_d).map(|node| node.item), Some(&"child_d"));
-        assert_eq!(forest2.get(|r| forest1.get(r), child_d).map(|node| node.parent), Some(Some(root2)));
-        assert_eq!(forest2.get(|r| forest1.get(r), child_d).map(|node| node.depth), Some(1));
+    #[test]
+    fn shadow_forest21() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root(1);
+        let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        let mut forest3 = Forest2::new();
+        let root1_ext_ext = forest3.external_ref(root1_ext);
+
+        *layers!([&mut forest3, &mut forest2, &forest1].shadow_mut(root1_ext_ext)).unwrap().item += 10;
+        assert_eq!(*layers!([&forest3, &forest2, &forest1].get(root1_ext_ext)).unwrap().item, 11);
+        assert_eq!(*layers!([&forest2, &forest1].get(root1_ext)).unwrap().item, 1);
+
+        let mut forest2 = merge_no_transform(forest3.merge_down(forest2));
+        assert!(forest2.is_shadowed(&root1));
+        assert_eq!(*layers!([&forest2, &forest1].get(root1_ext)).unwrap().item, 11);
+        assert!(forest2.discard_shadow(&root1).is_some());
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert_eq!(*forest1.get(root1).unwrap().item, 1);
+    }
 
-        let child_c_ext = forest2.external_ref(child_c);
-        let child_e = forest2.make_node(|r| forest1.get(r), child_c_ext, "child_e");
-        assert_eq!(forest2.get(|r| forest1.get(r), child_e).map(|node| node.item), Some(&"child_e"));
-        assert_eq!(forest2.get(|r| forest1.get(r), child_e).map(|node| node.parent), Some(Some(child_c_ext)));
-        assert_eq!(forest2.get(|r| forest1.get(r), child_e).map(|node| node.depth), Some(3));
+    #[test]
+    fn shadowing_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root(1);
+        let child1_a = forest1.make_node(root1, 2);
+        let child1_b = forest1.make_node(root1, 3);
 
-        let iter = TowardsRootIter::new(|rr| forest2.get(|r| forest1.get(r), rr), child_e);
-        assert_eq!(iter.map(|node| node.item).collect::<Vec<_>>(), vec![&"child_e", &"child_c", &"child_a", &"root"]);
+        let mut forest2 = Forest2::new();
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        *layers!([&mut forest2, &mut forest1].get_mut(child1_a_ext)).unwrap().item += 10;
+        assert_eq!(*forest1.get(child1_a).unwrap().item, 12);
+        assert!(!forest2.is_shadowed(&child1_a));
+
+        forest2.enable_shadowing();
+        assert!(forest2.has_shadowing());
+        *layers!([&mut forest2, &mut forest1].get_mut(child1_b_ext)).unwrap().item += 10;
+        *layers!([&mut forest2, &mut forest1].get_mut(child1_b_ext)).unwrap().item += 10;
+        assert!(forest2.is_shadowed(&child1_b));
+        assert_eq!(*forest1.get(child1_b).unwrap().item, 3);
+        assert_eq!(*layers!([&forest2, &forest1].get(child1_b_ext)).unwrap().item, 23);
+
+        // positions of shadowed nodes are read from the upper layer
+        assert_eq!(forest1.reparent(child1_b, child1_a), Ok(()));
+        let node = layers!([&forest2, &forest1].get(child1_b_ext)).unwrap();
+        assert_eq!((node.parent, node.depth), (Some(child1_a_ext), 2));
+        assert_eq!(layers!([&mut forest2, &mut forest1].get_mut(child1_b_ext)).unwrap().depth, 2);
+
+        forest2.disable_shadowing();
+        *layers!([&mut forest2, &mut forest1].get_mut(child1_b_ext)).unwrap().item += 10;
+        assert_eq!(*forest1.get(child1_b).unwrap().item, 3);
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert_eq!(*forest1.get(child1_b).unwrap().item, 33);
+        assert_eq!(*forest1.get(child1_a).unwrap().item, 12);
     }
 
     #[test]
-    fn layers_access_macro() {
+    fn tombstone_forest2() {
         let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+        let child1_b = forest1.make_node(root1, "child1 b");
+        let child1_c = forest1.make_node(child1_b, "child1 c");
+
         let mut forest2 = Forest2::new();
+        forest2.enable_child_index();
+        let root1_ext = forest2.external_ref(root1);
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2_a = layers!([&mut forest2, &forest1].make_node(child1_a_ext, "child2 a"));
+        let child2_b = layers!([&mut forest2, &forest1].make_node(child1_b_ext, "child2 b"));
+
+        // upper children are still alive
+        assert!(!layers!([&mut forest2, &forest1].tombstone(root1_ext)));
+        assert!(layers!([&mut forest2, &forest1].tombstone(child1_a_ext)));
+        assert!(forest2.is_tombstoned(&child1_a));
+        assert_eq!(forest1.len(), 4);
+        assert!(layers!([&forest2, &forest1].get(child1_a_ext)).is_none());
+        assert_eq!(layers!([&forest2, &forest1].get(child2_a)).unwrap().parent, None);
+        assert_eq!(layers!([&forest2, &forest1].children(root1_ext)).collect::<Vec<_>>(), vec![child1_b_ext]);
+
+        let removed = layers!([&mut forest2, &forest1].tombstone_subtree(child1_b_ext));
+        assert_eq!(removed.len(), 3);
+        assert!(!removed.contains(&child2_a));
+        assert!(layers!([&forest2, &forest1].get(child2_b)).is_none());
+        let mut items: Vec<_> = layers!([&forest2, &forest1].iter()).map(|(_, &item)| item).collect();
+        items.sort();
+        assert_eq!(items, vec!["child2 a", "root1"]);
+        let leaves: Vec<_> = layers!([&forest2, &forest1].leaves()).collect();
+        assert_eq!(leaves.len(), 2);
+        assert!(leaves.contains(&root1_ext) && leaves.contains(&child2_a));
+        assert_eq!(layers!([&forest2, &forest1].roots()).count(), 2);
 
-        let root = forest1.make_root("root");
-        assert_eq!(layers!([&forest1].get(root)).map(|node| node.item), Some(&"root"));
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert_eq!(forest1.len(), 2);
+        assert!(forest1.get(child1_c).is_none());
+        let mut items: Vec<_> = forest1.local_iter().map(|(_, &item)| item).collect();
+        items.sort();
+        assert_eq!(items, vec!["child2 a", "root1"]);
+    }
 
-        let root2 = forest2.make_root("root2");
-        assert_eq!(layers!([&forest2, &forest1].get(root2)).map(|node| node.item), Some(&"root2"));
+    #[test]
+    fn tombstone_forest21() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1 = forest1.make_node(root1, "child1");
+        let mut forest2 = Forest2::new();
+        let child1_ext = forest2.external_ref(child1);
+        let child2 = layers!([&mut forest2, &forest1].make_node(child1_ext, "child2"));
+        let mut forest3 = Forest2::new();
+        let child1_ext_ext = forest3.external_ref(child1_ext);
+        let child2_ext = forest3.external_ref(child2);
+
+        assert_eq!(layers!([&mut forest3, &forest2, &forest1].tombstone_subtree(child1_ext_ext)).len(), 2);
+        assert!(layers!([&forest3, &forest2, &forest1].get(child2_ext)).is_none());
+        assert_eq!(layers!([&forest3, &forest2, &forest1].iter()).count(), 1);
+
+        let forest2 = merge_no_transform(forest3.merge_down(forest2));
+        assert!(forest2.is_tombstoned(&child1));
+        assert_eq!(forest2.len(), 0);
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        assert_eq!(forest1.len(), 1);
+        assert!(forest1.get(child1).is_none());
+    }
 
-        let root_ext = forest2.external_ref(root);
-        let child_a = layers!([&mut forest2, &forest1].make_node(root_ext, "child a"));
-        assert_eq!(layers!([&forest2, &forest1].get(child_a)).map(|node| node.item), Some(&"child a"));
+    #[test]
+    fn squash_forest21() {
+        let mut forest1 = Forest1::new();
+        forest1.enable_child_index();
+        let root1 = forest1.make_root(1);
+        let child1_a = forest1.make_node(root1, 2);
+        let child1_b = forest1.make_node(root1, 3);
 
-        layers!([&mut forest2, &mut forest1].get_mut(child_a)).map(|node| *node.item = "other child");
-        assert_eq!(layers!([&forest2, &forest1].get(child_a)).map(|node| node.item), Some(&"other child"));
+        let mut forest2 = Forest2::new();
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2 = layers!([&mut forest2, &forest1].make_node(child1_a_ext, 4));
+        *layers!([&mut forest2, &forest1].shadow_mut(child1_b_ext)).unwrap().item += 100;
+
+        let mut forest3 = Forest2::new();
+        let child2_ext = forest3.external_ref(child2);
+        let child1_b_ext_ext = forest3.external_ref(child1_b_ext);
+        let child3 = layers!([&mut forest3, &forest2, &forest1].make_node(child2_ext, 5));
+        assert!(layers!([&mut forest3, &forest2, &forest1].tombstone(child1_b_ext_ext)));
+
+        let (squashed, refs) = layers!([forest3, forest2, forest1].squash(|item| item * 10));
+        assert_eq!(squashed.len(), 4);
+        assert_eq!(refs.len(), 4);
+        assert!(squashed.has_child_index());
+        assert!(refs.ref_transform(child1_b).is_none());
+        let root = refs.ref_transform(root1).unwrap();
+        let leaf = refs.ref_transform(child3).unwrap();
+        assert_eq!(refs.ref_transform(child2_ext), refs.ref_transform(child2));
+        let path: Vec<_> = layers!([&squashed].towards_root_iter(leaf)).map(|node| (*node.item, node.depth)).collect();
+        assert_eq!(path, vec![(50, 3), (40, 2), (20, 1), (10, 0)]);
+        assert_eq!(squashed.children(root).collect::<Vec<_>>(), vec![refs.ref_transform(child1_a).unwrap()]);
+        assert_eq!(squashed.leaves().collect::<Vec<_>>(), vec![leaf]);
+    }
 
-        let child_b = layers!([&mut forest2, &forest1].make_node(child_a, "child b"));
-        assert_eq!(layers!([&forest2, &forest1].get(child_b)).map(|node| node.item), Some(&"child b"));
+    #[test]
+    fn squash_shadow_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1".to_string());
+        let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        layers!([&mut forest2, &forest1].shadow_mut(root1_ext)).unwrap().item.push_str(" shadow");
+        let (squashed, refs) = layers!([forest2, forest1].squash(|item| item.len()));
+        assert_eq!(*squashed.get(refs.ref_transform(root1).unwrap()).unwrap().item, "root1 shadow".len());
+    }
 
-        {
-            let iter = layers!([&forest2, &forest1].towards_root_iter(child_b));
-            assert_eq!(iter.map(|node| node.item).collect::<Vec<_>>(), vec![&"child b", &"other child", &"root"]);
+    #[test]
+    fn fold_up_propagate_down_forest1() {
+        let mut forest = Forest1::new();
+        let mut rng = rand::thread_rng();
+        let mut nodes = vec![forest.make_root(1u64), forest.make_root(2)];
+        for _ in 0 .. 1000 {
+            let parent = nodes[rng.gen_range(0 .. nodes.len())];
+            nodes.push(forest.make_node(parent, rng.gen_range(0 .. 10)));
         }
-        {
-            let iter = layers!([&forest2, &forest1].iter()).map(|p| p.1);
-            let mut items: Vec<_> = iter.collect();
-            items.sort();
-            assert_eq!(items, vec![&"child b", &"other child", &"root", &"root2"]);
+        let sizes = forest.fold_up(|_, _| 1usize, |size, child_size| *size += child_size);
+        let sums = forest.fold_up(|_, &item| item, |sum, child_sum| *sum += child_sum);
+        let paths = forest.propagate_down(|parent_sum: Option<&u64>, _, &item| parent_sum.cloned().unwrap_or(0) + item);
+        assert_eq!(sizes.len(), forest.len());
+        assert_eq!(sizes[&nodes[0]] + sizes[&nodes[1]], forest.len());
+        for &node_ref in &nodes {
+            let subtree: Vec<_> = forest.descendants_dfs(node_ref).collect();
+            assert_eq!(sizes[&node_ref], subtree.len() + 1);
+            let sum: u64 = subtree.iter().map(|&r| *forest.get(r).unwrap().item).sum();
+            assert_eq!(sums[&node_ref], sum + forest.get(node_ref).unwrap().item);
+            let path_sum: u64 = TowardsRootIter::new(|r| forest.get(r), node_ref).map(|node| *node.item).sum();
+            assert_eq!(paths[&node_ref], path_sum);
         }
+    }
 
-        let mut maybe_node_ref = Some(child_b);
-        while let Some(node_ref) = maybe_node_ref {
-            maybe_node_ref = layers!([&forest2, &forest1].get(node_ref)).and_then(|node| node.parent);
-            layers!([&mut forest2, &mut forest1].remove(node_ref));
-        }
-        assert_eq!(layers!([&forest2, &forest1].get(child_b)).map(|node| node.item), None);
-        assert_eq!(layers!([&forest2, &forest1].get(child_a)).map(|node| node.item), None);
-        assert_eq!(layers!([&forest2, &forest1].get(root_ext)).map(|node| node.item), None);
-        assert_eq!(layers!([&forest2, &forest1].get(root2)).map(|node| node.item), Some(&"root2"));
+    #[test]
+    fn fold_up_propagate_down_layers() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root(1);
+        let child1 = forest1.make_node(root1, 2);
+        let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        let child1_ext = forest2.external_ref(child1);
+        let child2_a = layers!([&mut forest2, &forest1].make_node(child1_ext, 3));
+        let child2_b = layers!([&mut forest2, &forest1].make_node(root1_ext, 4));
+
+        let sums = layers!([&forest2, &forest1].fold_up(|_, &item| item, |sum, child_sum| *sum += child_sum));
+        assert_eq!(sums[&root1_ext], 10);
+        assert_eq!(sums[&child1_ext], 5);
+        assert_eq!(sums[&child2_b], 4);
+        let depths = layers!([&forest2, &forest1].propagate_down(|parent: Option<&usize>, _, _| parent.map_or(0, |depth| depth + 1)));
+        assert_eq!(depths[&child2_a], 2);
+        assert_eq!(depths[&root1_ext], 0);
     }
 
     #[test]
-    fn merge_aflat_forest1() {
-        let mut forest1_a = Forest1::new();
-        let root_a = forest1_a.make_root("root a");
-        let _child_a_a = layers!([&mut forest1_a].make_node(root_a, "child_a a"));
-        let _child_b_a = layers!([&mut forest1_a].make_node(root_a, "child_b a"));
+    fn gc_prune_forest1() {
+        let mut forest = Forest1::new();
+        let root1 = forest.make_root("root1");
+        let child1 = forest.make_node(root1, "child1");
+        let child2 = forest.make_node(child1, "child2");
+        let child3 = forest.make_node(child2, "child3");
+        let other = forest.make_node(root1, "other");
+        forest.make_node(other, "other child");
+        let root2 = forest.make_root("root2");
+        let dangling_parent = forest.make_node(root2, "dangling parent");
+        let dangling = forest.make_node(dangling_parent, "dangling");
+        // break the chain leaving `dangling` with a missing parent
+        forest.nodes.remove(dangling_parent);
+        forest.enable_child_index();
+        forest.enable_jump_index();
+        let root3 = forest.make_root("root3");
+        let kept = forest.make_node(root3, "kept");
+
+        let report = forest.gc(&[child2, root2, kept]);
+        assert_eq!(report.kept, 4);
+        assert_eq!(report.dangling, 1);
+        assert_eq!(report.removed.len(), 6);
+        assert!(report.removed.contains(&dangling));
+        assert!(report.removed.contains(&root1));
+        let mut roots: Vec<_> = forest.roots().collect();
+        roots.sort();
+        let mut expected = vec![child2, root2, kept];
+        expected.sort();
+        assert_eq!(roots, expected);
+        assert_eq!(forest.get(child3).unwrap().depth, 1);
+        assert_eq!(forest.ancestry(child3).unwrap().parent, Some(child2));
+        assert_eq!(forest.children(root2).count(), 0);
+
+        let report = forest.prune(|_, &item| item == "child2" || item == "kept");
+        assert_eq!(report.removed, vec![child2, kept, child3]);
+        assert_eq!(report.kept, 1);
+        assert_eq!(forest.len(), 1);
+        assert_eq!(forest.leaves().collect::<Vec<_>>(), vec![root2]);
+    }
 
-        let mut forest1_b = Forest1::new();
-        let root_b = forest1_b.make_root("root b");
-        let child_a_b = layers!([&mut forest1_b].make_node(root_b, "child_a b"));
-        let _child_b_b = layers!([&mut forest1_b].make_node(child_a_b, "child_b b"));
+    #[test]
+    fn gc_prune_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+        let child1_b = forest1.make_node(root1, "child1 b");
+        let root1b = forest1.make_root("root1b");
 
-        let forest1_a = merge_no_transform(forest1_b.merge_aflat(forest1_a));
+        let mut forest2 = Forest2::new();
+        forest2.enable_child_index();
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2_a = layers!([&mut forest2, &forest1].make_node(child1_a_ext, "child2 a"));
+        let child2_b = layers!([&mut forest2, &forest1].make_node(child1_b_ext, "child2 b"));
+        let child2_c = layers!([&mut forest2, &forest1].make_node(child2_b, "child2 c"));
+
+        let report = layers!([&mut forest2, &forest1].gc(&[child1_a_ext, child2_b]));
+        assert_eq!(report.kept, 5);
+        let mut removed = report.removed.clone();
+        removed.sort();
+        let mut expected = vec![child1_b_ext, forest2.external_ref(root1b)];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(forest2.is_tombstoned(&child1_b));
+        assert!(!forest2.is_tombstoned(&root1));
+        assert_eq!(forest1.len(), 4);
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).unwrap().depth, 0);
+        assert_eq!(layers!([&forest2, &forest1].get(child2_c)).unwrap().depth, 1);
+        assert_eq!(layers!([&forest2, &forest1].iter()).count(), 5);
+
+        let report = layers!([&mut forest2, &forest1].prune(|_, item: &&str| item.starts_with("child1")));
+        assert_eq!(report.removed, vec![child1_a_ext, child2_a]);
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        let mut items: Vec<_> = forest1.local_iter().map(|(_, &item)| item).collect();
+        items.sort();
+        assert_eq!(items, vec!["child2 b", "child2 c", "root1"]);
+    }
 
-        let mut items: Vec<_> = layers!([&forest1_a].iter()).collect();
-        items.sort_by_key(|item| item.1);
-        let verify = vec![&"child_a a", &"child_a b", &"child_b a", &"child_b b", &"root a", &"root b"];
-        assert_eq!(items.len(), verify.len());
-        for ((set_ref, item), verify_item) in items.into_iter().zip(verify) {
-            assert_eq!(item, verify_item);
-            assert_eq!(layers!([&forest1_a].get(set_ref)).map(|node| node.item), Some(item));
+    #[test]
+    fn try_insert_verify_forest1() {
+        let mut forest = Forest1::new();
+        forest.enable_child_index();
+        forest.enable_jump_index();
+        let root = forest.try_insert(Node { item: 0, parent: None, depth: 0, }).unwrap();
+        let mut refs = vec![root];
+        let mut rng = rand::thread_rng();
+        for item in 1 .. 200 {
+            let parent_ref = refs[rng.gen_range(0 .. refs.len())];
+            refs.push(forest.make_node(parent_ref, item));
         }
+        for _ in 0 .. 100 {
+            let node_ref = refs[rng.gen_range(0 .. refs.len())];
+            let parent_ref = refs[rng.gen_range(0 .. refs.len())];
+            let _ = forest.reparent(node_ref, parent_ref);
+        }
+        for _ in 0 .. 20 {
+            let node_ref = refs[rng.gen_range(0 .. refs.len())];
+            forest.remove_with(node_ref, OrphanPolicy::Promote);
+        }
+        assert!(forest.verify().is_ok());
+
+        let leaf = forest.leaves().next().unwrap();
+        let depth = forest.get(leaf).unwrap().depth;
+        assert_eq!(
+            forest.try_insert(Node { item: -1, parent: Some(leaf), depth, }).err(),
+            Some(InsertError::DepthMismatch { expected: depth + 1, found: depth, }),
+        );
+        assert_eq!(forest.try_insert(Node { item: -1, parent: None, depth: 1, }).err(), Some(InsertError::DepthMismatch { expected: 0, found: 1, }));
+        let removed = forest.make_node(leaf, -1);
+        forest.remove(removed);
+        assert_eq!(forest.try_insert(Node { item: -1, parent: Some(removed), depth: depth + 2, }).err(), Some(InsertError::ParentNotFound));
+        assert_eq!(forest.try_insert(Node { item: -1, parent: Some(Forest1::<i32>::new().make_root(0)), depth: 1, }).err(), Some(InsertError::ParentNotFound));
+        assert!(forest.verify().is_ok());
+
+        let misplaced = forest.insert(Node { item: -1, parent: Some(leaf), depth: depth + 3, });
+        let dangling = forest.insert(Node { item: -1, parent: Some(removed), depth: depth + 2, });
+        let report = forest.verify();
+        assert!(!report.is_ok());
+        assert_eq!(report.depth_mismatches, vec![misplaced]);
+        assert_eq!(report.dangling_parents, vec![dangling]);
+        assert!(report.cycles.is_empty());
+        assert_eq!(report.index_errors, vec![dangling]);
     }
 
     #[test]
-    fn merge_aflat_forest2() {
-        let forest1 = Forest1::new();
-        let mut forest2_a = Forest2::new();
-        let root_a = forest2_a.make_root("root a");
-        let _child_a_a = layers!([&mut forest2_a, &forest1].make_node(root_a, "child_a a"));
-        let _child_b_a = layers!([&mut forest2_a, &forest1].make_node(root_a, "child_b a"));
-
-        let mut forest2_b = Forest2::new();
-        let root_b = forest2_b.make_root("root b");
-        let child_a_b = layers!([&mut forest2_b, &forest1].make_node(root_b, "child_a b"));
-        let _child_b_b = layers!([&mut forest2_b, &forest1].make_node(child_a_b, "child_b b"));
+    fn try_insert_verify_forest2() {
+        let mut forest1 = Forest1::new();
+        let root1 = forest1.make_root("root1");
+        let child1_a = forest1.make_node(root1, "child1 a");
+        let child1_b = forest1.make_node(root1, "child1 b");
 
-        let forest2_a = merge_no_transform(forest2_b.merge_aflat(forest2_a));
+        let mut forest2 = Forest2::new();
+        forest2.enable_child_index();
+        forest2.enable_jump_index();
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2_a = layers!([&mut forest2, &forest1].try_insert(Node { item: "child2 a", parent: Some(child1_a_ext), depth: 2, })).unwrap();
+        assert_eq!(
+            layers!([&mut forest2, &forest1].try_insert(Node { item: "child2 b", parent: Some(child2_a), depth: 2, })).err(),
+            Some(InsertError::DepthMismatch { expected: 3, found: 2, }),
+        );
+        let child2_b = layers!([&mut forest2, &forest1].try_insert(Node { item: "child2 b", parent: Some(child2_a), depth: 3, })).unwrap();
+        layers!([&mut forest2, &forest1].shadow_mut(child1_a_ext)).unwrap();
+        assert!(layers!([&mut forest2, &forest1].tombstone(child1_b_ext)));
+        assert_eq!(
+            layers!([&mut forest2, &forest1].try_insert(Node { item: "child2 c", parent: Some(child1_b_ext), depth: 2, })).err(),
+            Some(InsertError::ParentNotFound),
+        );
+        assert!(layers!([&forest2, &forest1].verify()).is_ok());
+
+        let stale = forest2.insert(Node { item: "child2 c", parent: Some(child1_b_ext), depth: 2, });
+        forest1.remove(child1_a);
+        let report = layers!([&forest2, &forest1].verify());
+        let mut invalid = report.invalid_layer_refs.clone();
+        invalid.sort();
+        // the shadow of the removed upper node does not keep it alive for its local children
+        let mut expected = vec![stale, child1_a_ext, child2_a];
+        expected.sort();
+        assert_eq!(invalid, expected);
+        assert!(report.depth_mismatches.is_empty());
+        assert!(report.index_errors.is_empty());
+        assert_eq!(layers!([&forest2, &forest1].get(child2_b)).unwrap().depth, 3);
+    }
 
-        let mut items: Vec<_> = layers!([&forest2_a, &forest1].iter()).collect();
-        items.sort_by_key(|item| item.1);
-        let verify = vec![&"child_a a", &"child_a b", &"child_b a", &"child_b b", &"root a", &"root b"];
-        assert_eq!(items.len(), verify.len());
-        for ((set_ref, item), verify_item) in items.into_iter().zip(verify) {
-            assert_eq!(item, verify_item);
-            assert_eq!(layers!([&forest2_a, &forest1].get(set_ref)).map(|node| node.item), Some(item));
+    #[test]
+    fn frozen_shared_layers() {
+        let mut forest1 = Forest1::new();
+        let root = forest1.make_root(0);
+        let frozen = forest1.freeze();
+
+        let layers: Vec<Forest2<_, _>> = (1 .. 5).into_par_iter()
+            .map_with(frozen.clone(), |frozen, worker| {
+                let mut forest2 = Forest2::new();
+                let root_ext = forest2.external_ref(root);
+                let child = layers!([&mut forest2, frozen].make_node(root_ext, worker));
+                layers!([&mut forest2, frozen].make_node(child, worker * 10));
+                assert_eq!(layers!([&forest2, frozen].iter()).count(), 3);
+                forest2
+            })
+            .collect();
+        assert_eq!(frozen.shares(), 1);
+        assert_eq!(frozen.len(), 1);
+
+        let shared = frozen.clone();
+        let frozen = frozen.unfreeze().err().unwrap();
+        drop(shared);
+        let mut forest1 = frozen.unfreeze().ok().unwrap();
+        for forest2 in layers {
+            forest1 = merge_no_transform(forest2.merge_down(forest1));
         }
+        assert_eq!(forest1.len(), 9);
+        assert_eq!(forest1.children(root).count(), 4);
+        assert_eq!(forest1.get(root).unwrap().depth, 0);
+
+        let frozen1 = Frozen::new(forest1);
+        let mut forest2 = Forest2::new();
+        let root_ext = forest2.external_ref(root);
+        let child2 = layers!([&mut forest2, &frozen1].make_node(root_ext, 100));
+        let frozen2 = forest2.freeze();
+        let mut forest3 = Forest2::new();
+        let child2_ext = forest3.external_ref(child2);
+        let child3 = layers!([&mut forest3, &frozen2, &frozen1].make_node(child2_ext, 1000));
+        assert_eq!(layers!([&forest3, &frozen2, &frozen1].get(child3)).unwrap().depth, 2);
     }
 
     #[test]
-    fn merge_down_forest1() {
+    fn towards_root_mut_path_to_root() {
         let mut forest1 = Forest1::new();
-        let root1 = forest1.make_root("root1");
-        let child1_a = layers!([&mut forest1].make_node(root1, "child1 a"));
-        let _child1_b = layers!([&mut forest1].make_node(root1, "child1 b"));
+        let root1 = forest1.make_root(0);
+        let child1_a = forest1.make_node(root1, 0);
+        let child1_b = forest1.make_node(child1_a, 0);
+        let mut visited = Vec::new();
+        forest1.towards_root_mut(child1_b, |node_ref, item| {
+            visited.push(node_ref);
+            *item += 1;
+        });
+        assert_eq!(visited, vec![child1_b, child1_a, root1]);
+        assert_eq!(forest1.path_to_root(child1_b, PathOrder::LeafFirst), Some(visited));
 
         let mut forest2 = Forest2::new();
-        let root2 = forest2.make_root("root2");
-        let child2_a = layers!([&mut forest2, &forest1].make_node(root2, "child2 a"));
-        let _child2_b = layers!([&mut forest2, &forest1].make_node(child2_a, "child2 b"));
-        let child1_a2 = forest2.external_ref(child1_a);
-        let _child2_c = layers!([&mut forest2, &forest1].make_node(child1_a2, "child2 c"));
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child2 = layers!([&mut forest2, &forest1].make_node(child1_b_ext, 0));
+        *layers!([&mut forest2, &forest1].shadow_mut(child1_a_ext)).unwrap().item += 10;
+        layers!([&mut forest2, &mut forest1].towards_root_mut(child2, |_, item| *item += 1));
+        let items: Vec<_> = layers!([&forest2, &forest1].towards_root_iter(child2)).map(|node| *node.item).collect();
+        assert_eq!(items, vec![1, 2, 12, 2]);
+        assert_eq!(forest1.get(child1_a).unwrap().item, &1);
+
+        let mut forest3 = Forest2::new();
+        let child2_ext = forest3.external_ref(child2);
+        let child3 = layers!([&mut forest3, &forest2, &forest1].make_node(child2_ext, 0));
+        let path = layers!([&forest3, &forest2, &forest1].path_to_root(child3, PathOrder::RootFirst)).unwrap();
+        assert_eq!(path.len(), path.capacity());
+        assert_eq!(path, vec![
+            Ref2::External(Ref2::External(root1)),
+            Ref2::External(child1_a_ext),
+            Ref2::External(child1_b_ext),
+            Ref2::External(child2),
+            child3,
+        ]);
 
-        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        let mut visited = Vec::new();
+        layers!([&mut forest3, &mut forest2, &mut forest1].towards_root_mut(child3, |node_ref, item| {
+            visited.push(node_ref);
+            *item += 1;
+        }));
+        visited.reverse();
+        assert_eq!(visited, path);
+        let items: Vec<_> = layers!([&forest3, &forest2, &forest1].towards_root_iter(child3)).map(|node| *node.item).collect();
+        assert_eq!(items, vec![1, 2, 3, 13, 3]);
+        assert_eq!(forest1.get(child1_a).unwrap().item, &1);
+    }
 
-        let mut items: Vec<_> = layers!([&forest1].iter()).collect();
-        items.sort_by_key(|item| item.1);
-        let verify = vec![&"child1 a", &"child1 b", &"child2 a", &"child2 b", &"child2 c", &"root1", &"root2"];
-        assert_eq!(items.len(), verify.len());
-        for ((set_ref, item), verify_item) in items.iter().cloned().zip(verify) {
-            assert_eq!(item, verify_item);
-            assert_eq!(layers!([&forest1].get(set_ref)).map(|node| node.item), Some(item));
+    #[test]
+    fn depth_index_forest1() {
+        let mut forest = Forest1::new();
+        forest.enable_child_index();
+        let mut refs = vec![forest.make_root(0)];
+        let mut rng = rand::thread_rng();
+        for item in 1 .. 100 {
+            let parent_ref = refs[rng.gen_range(0 .. refs.len())];
+            refs.push(forest.make_node(parent_ref, item));
         }
-        let child2_c = items[4].0;
-        let path: Vec<_> = layers!([&forest1].towards_root_iter(child2_c)).map(|p| p.item).collect();
-        assert_eq!(path, vec![&"child2 c", &"child1 a", &"root1"]);
+        forest.enable_depth_index();
+        for item in 100 .. 300 {
+            let node_ref = refs[rng.gen_range(0 .. refs.len())];
+            match rng.gen_range(0 .. 4) {
+                0 => {
+                    forest.remove_with(node_ref, OrphanPolicy::Promote);
+                },
+                1 => {
+                    let parent_ref = refs[rng.gen_range(0 .. refs.len())];
+                    let _ = forest.reparent(node_ref, parent_ref);
+                },
+                2 if item % 10 == 0 => {
+                    forest.remove_subtree(node_ref);
+                },
+                _ =>
+                    refs.push(forest.make_node(node_ref, item)),
+            }
+        }
+
+        let mut other = Forest1::new();
+        let other_root = other.make_root(-1);
+        other.make_node(other_root, -2);
+        let mut forest = merge_no_transform(other.merge_aflat(forest));
+        let mut forest2 = Forest2::new();
+        let root_ext = forest2.external_ref(forest.roots().next().unwrap());
+        let child2 = layers!([&mut forest2, &forest].make_node(root_ext, -3));
+        layers!([&mut forest2, &forest].make_node(child2, -4));
+        forest = merge_no_transform(forest2.merge_down(forest));
+        assert!(forest.verify().is_ok());
+
+        let mut histogram = Vec::new();
+        for (node_ref, _) in forest.local_iter() {
+            let depth = forest.get(node_ref).unwrap().depth;
+            if histogram.len() <= depth {
+                histogram.resize(depth + 1, 0);
+            }
+            histogram[depth] += 1;
+            assert!(forest.nodes_at_depth(depth).any(|r| r == node_ref));
+        }
+        assert_eq!(forest.depth_histogram(), histogram);
+        assert_eq!(forest.max_depth(), Some(histogram.len() - 1));
+        for (depth, &count) in histogram.iter().enumerate() {
+            assert_eq!(forest.nodes_at_depth(depth).count(), count);
+            assert_eq!(forest.par_nodes_at_depth(depth).count(), count);
+        }
+        assert_eq!(forest.nodes_at_depth(histogram.len()).count(), 0);
+
+        let (squashed, _) = forest.squash(Default::default(), |item| item);
+        assert!(squashed.has_depth_index());
+        assert_eq!(squashed.depth_histogram(), histogram);
+        let mut empty = squashed;
+        empty.clear();
+        assert_eq!(empty.max_depth(), None);
     }
 
     #[test]
-    fn merge_down_forest2() {
-        let forest0 = Forest1::new();
-        let mut forest1 = Forest2::new();
+    fn depth_index_layers() {
+        let mut forest1 = Forest1::new();
         let root1 = forest1.make_root("root1");
-        let child1_a = layers!([&mut forest1, &forest0].make_node(root1, "child1 a"));
-        let _child1_b = layers!([&mut forest1, &forest0].make_node(root1, "child1 b"));
+        let child1_a = forest1.make_node(root1, "child1 a");
+        let child1_b = forest1.make_node(child1_a, "child1 b");
+        let child1_c = forest1.make_node(root1, "child1 c");
+        forest1.enable_depth_index();
 
         let mut forest2 = Forest2::new();
-        let root2 = forest2.make_root("root2");
-        let child2_a = layers!([&mut forest2, &forest1, &forest0].make_node(root2, "child2 a"));
-        let _child2_b = layers!([&mut forest2, &forest1, &forest0].make_node(child2_a, "child2 b"));
-        let child1_a2 = forest2.external_ref(child1_a);
-        let _child2_c = layers!([&mut forest2, &forest1, &forest0].make_node(child1_a2, "child2 c"));
-
+        forest2.enable_depth_index();
+        let child1_a_ext = forest2.external_ref(child1_a);
+        let child1_b_ext = forest2.external_ref(child1_b);
+        let child1_c_ext = forest2.external_ref(child1_c);
+        let child2 = layers!([&mut forest2, &forest1].make_node(child1_c_ext, "child2"));
+        layers!([&mut forest2, &forest1].shadow_mut(child1_a_ext)).unwrap();
+        assert!(layers!([&mut forest2, &forest1].tombstone(child1_b_ext)));
+
+        assert_eq!(forest2.depth_histogram(), vec![0, 0, 1]);
+        assert_eq!(layers!([&forest2, &forest1].depth_histogram()), vec![1, 2, 1]);
+        assert_eq!(layers!([&forest2, &forest1].max_depth()), Some(2));
+        let mut level1: Vec<_> = layers!([&forest2, &forest1].nodes_at_depth(1)).collect();
+        level1.sort();
+        let mut expected = vec![child1_a_ext, child1_c_ext];
+        expected.sort();
+        assert_eq!(level1, expected);
+        assert_eq!(layers!([&forest2, &forest1].nodes_at_depth(2)).collect::<Vec<_>>(), vec![child2]);
+        assert_eq!(layers!([&forest2, &forest1].par_nodes_at_depth(1)).count(), 2);
+
+        layers!([&mut forest2, &mut forest1].remove(child2));
+        assert_eq!(layers!([&forest2, &forest1].max_depth()), Some(1));
+        assert!(layers!([&forest2, &forest1].verify()).is_ok());
         let forest1 = merge_no_transform(forest2.merge_down(forest1));
-
-        let mut items: Vec<_> = layers!([&forest1, &forest0].iter()).collect();
-        items.sort_by_key(|item| item.1);
-        let verify = vec![&"child1 a", &"child1 b", &"child2 a", &"child2 b", &"child2 c", &"root1", &"root2"];
-        assert_eq!(items.len(), verify.len());
-        for ((set_ref, item), verify_item) in items.iter().cloned().zip(verify) {
-            assert_eq!(item, verify_item);
-            assert_eq!(layers!([&forest1, &forest0].get(set_ref)).map(|node| node.item), Some(item));
-        }
-        let child2_c = items[4].0;
-        let forest0_ref = &forest0;
-        let path: Vec<_> = layers!([&forest1, forest0_ref].towards_root_iter(child2_c)).map(|p| p.item).collect();
-        assert_eq!(path, vec![&"child2 c", &"child1 a", &"root1"]);
+        assert_eq!(forest1.depth_histogram(), vec![1, 2]);
     }
 
     #[test]
-    fn merge_down_forest21() {
+    fn depth_histogram_masked_twice() {
         let mut forest0 = Forest1::new();
         let root0 = forest0.make_root("root0");
-        let _child0_a = layers!([&mut forest0].make_node(root0, "child0 a"));
-        let child0_b = layers!([&mut forest0].make_node(root0, "child0 b"));
+        let child0_a = forest0.make_node(root0, "child0 a");
+        let _child0_b = forest0.make_node(root0, "child0 b");
 
         let mut forest1 = Forest2::new();
+        let child0_a_ext = forest1.external_ref(child0_a);
+        let mut forest2 = Forest2::new();
+        let child0_a_ext2 = forest2.external_ref(child0_a_ext);
+        // the outer layer masks the node first, then the middle one masks it again
+        assert!(layers!([&mut forest2, &forest1, &forest0].tombstone(child0_a_ext2)));
+        assert!(layers!([&mut forest1, &forest0].tombstone(child0_a_ext)));
+
+        assert_eq!(layers!([&forest1, &forest0].depth_histogram()), vec![1, 1]);
+        assert_eq!(layers!([&forest2, &forest1, &forest0].depth_histogram()), vec![1, 1]);
+        assert_eq!(layers!([&forest2, &forest1, &forest0].iter()).count(), 2);
+    }
+
+    #[test]
+    fn ordered_children_forest1() {
+        let mut forest1 = Forest1::new();
+        let root = forest1.make_root("root");
+        let b = forest1.make_node(root, "b");
+        let d = forest1.make_node(root, "d");
+        let a = forest1.insert_child_at(root, 0, "a").unwrap();
+        let c = forest1.insert_before(d, "c").unwrap();
+        let e = forest1.insert_after(d, "e").unwrap();
+        assert_eq!(forest1.insert_child_at(root, 10, "f").map(|f| forest1.last_child(root) == Some(f)), Some(true));
+        let names = |forest: &Forest1<&'static str>, parent| forest.children(parent).map(|r| *forest.get(r).unwrap().item).collect::<Vec<_>>();
+        assert_eq!(names(&forest1, root), vec!["a", "b", "c", "d", "e", "f"]);
+        assert_eq!(forest1.first_child(root), Some(a));
+        assert_eq!(forest1.next_sibling(c), Some(d));
+        assert_eq!(forest1.prev_sibling(c), Some(b));
+        assert_eq!(forest1.prev_sibling(a), None);
+        assert_eq!(forest1.next_sibling(root), None);
+
+        assert_eq!(forest1.move_before(e, a), Ok(()));
+        assert_eq!(forest1.move_before(b, e), Ok(()));
+        assert_eq!(names(&forest1, root), vec!["b", "e", "a", "c", "d", "f"]);
+        let g = forest1.make_node(c, "g");
+        assert_eq!(forest1.move_before(c, g), Err(ReparentError::Cycle));
+        assert_eq!(forest1.move_before(root, g), Err(ReparentError::Cycle));
+        assert_eq!(forest1.move_before(d, g), Ok(()));
+        assert_eq!(forest1.get(d).map(|node| (node.parent, node.depth)), Some((Some(c), 2)));
+        assert_eq!(names(&forest1, c), vec!["d", "g"]);
+        forest1.remove(g);
+        assert_eq!(forest1.move_before(a, g), Err(ReparentError::SiblingNotFound));
+        assert!(forest1.verify().is_ok());
+
+        let mut forest1_b = Forest1::new();
+        let root_b = forest1_b.make_root("root b");
+        forest1_b.insert_child_at(root_b, 0, "y").unwrap();
+        forest1_b.insert_child_at(root_b, 0, "x").unwrap();
+        let forest1 = merge_no_transform(forest1_b.merge_aflat(forest1));
+        let root_b = forest1.roots().find(|&r| forest1.get(r).map(|node| node.item) == Some(&"root b")).unwrap();
+        assert_eq!(names(&forest1, root_b), vec!["x", "y"]);
+        let root = forest1.roots().find(|&r| forest1.get(r).map(|node| node.item) == Some(&"root")).unwrap();
+        assert_eq!(names(&forest1, root), vec!["b", "e", "a", "c", "f"]);
+    }
+
+    #[test]
+    fn ordered_children_layers() {
+        let mut forest1 = Forest1::new();
         let root1 = forest1.make_root("root1");
-        let child0_b = forest1.external_ref(child0_b);
-        let child1_a = layers!([&mut forest1, &forest0].make_node(child0_b, "child1 a"));
-        let _child1_b = layers!([&mut forest1, &forest0].make_node(root1, "child1 b"));
+        let a = forest1.make_node(root1, "a");
+        let b = forest1.make_node(root1, "b");
+        let c = forest1.make_node(root1, "c");
 
         let mut forest2 = Forest2::new();
+        let root1_ext = forest2.external_ref(root1);
+        let (a_ext, b_ext, c_ext) = (forest2.external_ref(a), forest2.external_ref(b), forest2.external_ref(c));
+        let y = layers!([&mut forest2, &forest1].insert_child_at(root1_ext, 0, "y")).unwrap();
+        let x = layers!([&mut forest2, &forest1].insert_child_at(root1_ext, 0, "x")).unwrap();
+        let z = layers!([&mut forest2].insert_after(y, "z")).unwrap();
+        assert_eq!(layers!([&mut forest2].insert_before(a_ext, "w")), None);
+        assert!(layers!([&mut forest2, &forest1].tombstone(c_ext)));
+
+        let names: Vec<_> = layers!([&forest2, &forest1].children(root1_ext))
+            .map(|r| *layers!([&forest2, &forest1].get(r)).unwrap().item)
+            .collect();
+        assert_eq!(names, vec!["a", "b", "x", "y", "z"]);
+        assert_eq!(layers!([&forest2, &forest1].first_child(root1_ext)), Some(a_ext));
+        assert_eq!(layers!([&forest2, &forest1].last_child(root1_ext)), Some(z));
+        assert_eq!(layers!([&forest2, &forest1].next_sibling(a_ext)), Some(b_ext));
+        assert_eq!(layers!([&forest2, &forest1].next_sibling(b_ext)), Some(x));
+        assert_eq!(layers!([&forest2, &forest1].prev_sibling(x)), Some(b_ext));
+        assert_eq!(layers!([&forest2, &forest1].prev_sibling(a_ext)), None);
+        assert_eq!(layers!([&forest2, &forest1].next_sibling(z)), None);
+        assert_eq!(layers!([&forest2, &forest1].next_sibling(c_ext)), None);
+
         let root2 = forest2.make_root("root2");
-        let child2_a = layers!([&mut forest2, &forest1, &forest0].make_node(root2, "child2 a"));
-        let _child2_b = layers!([&mut forest2, &forest1, &forest0].make_node(child2_a, "child2 b"));
-        let child1_a2 = forest2.external_ref(child1_a);
-        let _child2_c = layers!([&mut forest2, &forest1, &forest0].make_node(child1_a2, "child2 c"));
+        assert_eq!(layers!([&forest2, &forest1].next_sibling(root1_ext)), Some(root2));
+        assert_eq!(layers!([&forest2, &forest1].prev_sibling(root2)), Some(root1_ext));
+        assert_eq!(layers!([&forest2, &forest1].roots()).collect::<Vec<_>>(), vec![root1_ext, root2]);
 
-        let forest1 = merge_no_transform(forest2.merge_down(forest1));
-        let forest0 = merge_no_transform(forest1.merge_down(forest0));
+        assert_eq!(layers!([&mut forest2].move_before(a_ext, x)), Err(ReparentError::UpperLayerNode));
+        assert_eq!(layers!([&mut forest2].move_before(z, x)), Ok(()));
+        assert!(layers!([&forest2, &forest1].verify()).is_ok());
 
-        let mut items: Vec<_> = layers!([&forest0].iter()).collect();
-        items.sort_by_key(|item| item.1);
-        let verify = vec![&"child0 a", &"child0 b", &"child1 a", &"child1 b", &"child2 a", &"child2 b", &"child2 c", &"root0", &"root1", &"root2"];
-        assert_eq!(items.len(), verify.len());
-        for ((set_ref, item), verify_item) in items.iter().cloned().zip(verify) {
-            assert_eq!(item, verify_item);
-            assert_eq!(layers!([&forest0].get(set_ref)).map(|node| node.item), Some(item));
-        }
-        let child2_c = items[6].0;
-        let path: Vec<_> = layers!([&forest0].towards_root_iter(child2_c)).map(|p| p.item).collect();
-        assert_eq!(path, vec![&"child2 c", &"child1 a", &"child0 b", &"root0"]);
+        let forest1 = merge_no_transform(forest2.merge_down(forest1));
+        let root1 = forest1.roots().find(|&r| forest1.get(r).map(|node| node.item) == Some(&"root1")).unwrap();
+        let names: Vec<_> = forest1.children(root1).map(|r| *forest1.get(r).unwrap().item).collect();
+        assert_eq!(names, vec!["a", "b", "z", "x", "y"]);
     }
 
     #[test]
-    fn par_iter_forest21() {
-        let mut forest0 = Forest1::new();
-        let root0 = forest0.make_root("root0");
-        let _child0_a = layers!([&mut forest0].make_node(root0, "child0 a"));
-        let child0_b = layers!([&mut forest0].make_node(root0, "child0 b"));
-
-        let mut forest1 = Forest2::new();
+    fn layer_diff() {
+        let mut forest1 = Forest1::new();
         let root1 = forest1.make_root("root1");
-        let child0_b = forest1.external_ref(child0_b);
-        let child1_a = layers!([&mut forest1, &forest0].make_node(child0_b, "child1 a"));
-        let _child1_b = layers!([&mut forest1, &forest0].make_node(root1, "child1 b"));
+        let a = forest1.make_node(root1, "a");
+        let b = forest1.make_node(root1, "b");
+        let c = forest1.make_node(root1, "c");
 
         let mut forest2 = Forest2::new();
+        assert!(forest2.diff().is_empty());
+        let (a_ext, b_ext, c_ext) = (forest2.external_ref(a), forest2.external_ref(b), forest2.external_ref(c));
+        let x = layers!([&mut forest2, &forest1].make_node(a_ext, "x"));
+        let y = layers!([&mut forest2, &forest1].make_node(x, "y"));
+        let z = layers!([&mut forest2, &forest1].make_node(a_ext, "z"));
+        let w = layers!([&mut forest2, &forest1].make_node(b_ext, "w"));
         let root2 = forest2.make_root("root2");
-        let child2_a = layers!([&mut forest2, &forest1, &forest0].make_node(root2, "child2 a"));
-        let _child2_b = layers!([&mut forest2, &forest1, &forest0].make_node(child2_a, "child2 b"));
-        let child1_a2 = forest2.external_ref(child1_a);
-        let _child2_c = layers!([&mut forest2, &forest1, &forest0].make_node(child1_a2, "child2 c"));
-
-        use rayon::iter::ParallelIterator;
-        let mut items: Vec<_> = layers!([&forest2, &forest1, &forest0].par_iter()).map(|p| p.1).collect();
-        items.sort();
-        assert_eq!(items, vec![
-            &"child0 a", &"child0 b", &"child1 a", &"child1 b",
-            &"child2 a", &"child2 b", &"child2 c",
-            &"root0", &"root1", &"root2",
-        ]);
+        layers!([&mut forest2, &forest1].make_node(root2, "v"));
+        layers!([&mut forest2, &forest1].shadow_mut(b_ext)).unwrap();
+        assert!(layers!([&mut forest2, &forest1].tombstone(c_ext)));
+
+        let diff = forest2.diff();
+        assert!(!diff.is_empty());
+        assert_eq!(diff.added, 6);
+        let mut points: Vec<_> = diff.attachment_points().cloned().collect();
+        points.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(points, expected);
+        let attachment = diff.attachment(&a).unwrap();
+        assert_eq!((attachment.children.clone(), attachment.added), (vec![x, z], 3));
+        assert_eq!(diff.attachment(&b).map(|attachment| attachment.children.clone()), Some(vec![w]));
+        assert_eq!(diff.attachment(&c), None);
+        assert_eq!(diff.new_roots().map(|attachment| (attachment.children.clone(), attachment.added)), Some((vec![root2], 2)));
+        assert_eq!(diff.shadowed, vec![b]);
+        assert_eq!(diff.tombstoned, vec![c]);
+        assert_eq!(diff.attachments.iter().map(|attachment| attachment.added).sum::<usize>(), diff.added);
+        assert!(layers!([&forest2, &forest1].get(y)).is_some());
     }
 }
@@ -0,0 +1,119 @@
+use std::{
+    fmt::{
+        Write,
+    },
+    hash::{
+        Hash,
+    },
+    collections::{
+        HashMap,
+    },
+};
+
+use crate::{
+    forest::{
+        LayerRef,
+    },
+};
+
+// Renders nodes as a Graphviz digraph with edges from parents to children. `label` gives the node text
+// and `style` optional extra attributes (like `color=red`) of a node.
+pub fn to_dot<'a, T, R, I, P, L, S>(nodes: I, parent: P, label: L, style: S) -> String
+    where T: 'a,
+          R: Clone + Eq + Hash,
+          I: Iterator<Item = (R, &'a T)>,
+          P: Fn(R) -> Option<R>,
+          L: Fn(&T) -> String,
+          S: Fn(&R) -> Option<String>,
+{
+    let mut ids = HashMap::new();
+    let mut out = String::from("digraph forest {\n");
+    for (node_ref, item) in nodes {
+        let id = ids.len();
+        let _ = write!(out, "    n{} [label=\"{}\"", id, escape(&label(item)));
+        if let Some(attrs) = style(&node_ref) {
+            let _ = write!(out, ", {}", attrs);
+        }
+        out.push_str("];\n");
+        ids.insert(node_ref, id);
+    }
+    let mut edges: Vec<_> = ids.iter()
+        .flat_map(|(node_ref, &id)| {
+            parent(node_ref.clone())
+                .and_then(|parent_ref| ids.get(&parent_ref))
+                .map(|&parent_id| (parent_id, id))
+        })
+        .collect();
+    edges.sort_unstable();
+    for (parent_id, id) in edges {
+        let _ = writeln!(out, "    n{} -> n{};", parent_id, id);
+    }
+    out.push_str("}\n");
+    out
+}
+
+const LAYER_COLORS: [&str; 6] = ["black", "blue", "darkgreen", "red", "purple", "orange"];
+
+// Style for `to_dot` coloring nodes by the layer of a layer stack they are stored in.
+pub fn layer_style<R>(node_ref: &R) -> Option<String> where R: LayerRef {
+    match node_ref.layer() {
+        0 =>
+            None,
+        layer =>
+            Some(format!("color={}", LAYER_COLORS[layer % LAYER_COLORS.len()])),
+    }
+}
+
+fn escape(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for ch in label.chars() {
+        match ch {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(ch);
+            },
+            '\n' =>
+                escaped.push_str("\\n"),
+            _ =>
+                escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        to_dot,
+        layer_style,
+    };
+    use crate::{
+        layers,
+        forest::{
+            Forest1,
+            Forest2,
+        },
+    };
+
+    #[test]
+    fn forest1_dot() {
+        let mut forest = Forest1::new();
+        let root = forest.make_root("root \"a\"");
+        forest.make_node(root, "child");
+        let dot = to_dot(forest.local_iter(), |r| forest.get(r).and_then(|node| node.parent), |item| item.to_string(), |_| None);
+        assert_eq!(dot, "digraph forest {\n    n0 [label=\"root \\\"a\\\"\"];\n    n1 [label=\"child\"];\n    n0 -> n1;\n}\n");
+    }
+
+    #[test]
+    fn layers_dot() {
+        let mut forest1 = Forest1::new();
+        let root = forest1.make_root(1);
+        let mut forest2 = Forest2::new();
+        let root_ext = forest2.external_ref(root);
+        layers!([&mut forest2, &forest1].make_node(root_ext, 2));
+        let dot = layers!([&forest2, &forest1].to_dot(|item: &i32| item.to_string(), layer_style));
+        assert!(dot.contains("[label=\"2\", color=blue];"));
+        assert!(dot.contains("[label=\"1\"];"));
+        assert_eq!(dot.matches("->").count(), 1);
+    }
+}
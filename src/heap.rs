@@ -0,0 +1,388 @@
+use std::{
+    mem,
+};
+
+use crate::{
+    set::{
+        Ref,
+        Set,
+        SetsInitMerger,
+        SetsInProgressMerger,
+    },
+    merge::{
+        MergeState,
+        InitMerger,
+        InProgressMerger,
+    },
+};
+
+// children per heap node: a shallower tree makes `push` and priority decreases cheaper
+const ARITY: usize = 4;
+
+// Indexed d-ary min-heap: items live in a `Set` and the `Ref` returned by `push` is a handle for
+// priority changes and removals. Handles of popped or removed items are stale and rejected.
+pub struct RefHeap<P, T> {
+    set: Set<HeapEntry<P, T>>,
+    heap: Vec<Ref>,
+}
+
+struct HeapEntry<P, T> {
+    priority: P,
+    item: T,
+    position: usize,
+}
+
+impl<P, T> Default for RefHeap<P, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P, T> RefHeap<P, T> {
+    pub fn new() -> RefHeap<P, T> {
+        RefHeap {
+            set: Set::new(),
+            heap: Vec::new(),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> RefHeap<P, T> {
+        RefHeap {
+            set: Set::with_capacity(capacity),
+            heap: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn contains(&self, heap_ref: Ref) -> bool {
+        self.set.get(heap_ref).is_some()
+    }
+
+    pub fn get(&self, heap_ref: Ref) -> Option<&T> {
+        self.set.get(heap_ref).map(|entry| &entry.item)
+    }
+
+    pub fn get_mut(&mut self, heap_ref: Ref) -> Option<&mut T> {
+        self.set.get_mut(heap_ref).map(|entry| &mut entry.item)
+    }
+
+    pub fn priority(&self, heap_ref: Ref) -> Option<&P> {
+        self.set.get(heap_ref).map(|entry| &entry.priority)
+    }
+
+    // Items in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Ref, &P, &T)> {
+        self.set.iter().map(|(heap_ref, entry)| (heap_ref, &entry.priority, &entry.item))
+    }
+
+    pub fn clear(&mut self) {
+        self.set.clear();
+        self.heap.clear();
+    }
+}
+
+impl<P, T> RefHeap<P, T> where P: Ord {
+    pub fn push(&mut self, priority: P, item: T) -> Ref {
+        let position = self.heap.len();
+        let heap_ref = self.set.insert(HeapEntry { priority, item, position, });
+        self.heap.push(heap_ref);
+        self.sift_up(position);
+        heap_ref
+    }
+
+    // Item with the lowest priority.
+    pub fn peek(&self) -> Option<(Ref, &P, &T)> {
+        let heap_ref = *self.heap.first()?;
+        self.set.get(heap_ref).map(|entry| (heap_ref, &entry.priority, &entry.item))
+    }
+
+    pub fn pop(&mut self) -> Option<(P, T)> {
+        let heap_ref = *self.heap.first()?;
+        self.remove(heap_ref)
+    }
+
+    pub fn remove(&mut self, heap_ref: Ref) -> Option<(P, T)> {
+        let HeapEntry { priority, item, position, } = self.set.remove(heap_ref)?;
+        let last_ref = self.heap.pop()?;
+        if position < self.heap.len() {
+            self.heap[position] = last_ref;
+            self.set_position(position);
+            self.restore(position);
+        }
+        Some((priority, item))
+    }
+
+    // Sets a new priority of an item in O(log n) returning the previous one.
+    pub fn change_priority(&mut self, heap_ref: Ref, priority: P) -> Option<P> {
+        let entry = self.set.get_mut(heap_ref)?;
+        let prev_priority = mem::replace(&mut entry.priority, priority);
+        let position = entry.position;
+        self.restore(position);
+        Some(prev_priority)
+    }
+
+    // Lowers the priority of an item, false if the handle is stale or `priority` is not lower.
+    pub fn decrease_priority(&mut self, heap_ref: Ref, priority: P) -> bool {
+        match self.set.get_mut(heap_ref) {
+            Some(entry) if priority < entry.priority => {
+                entry.priority = priority;
+                let position = entry.position;
+                self.sift_up(position);
+                true
+            },
+            _ =>
+                false,
+        }
+    }
+
+    // Moves all items of `source` into this heap in O(m log(n + m)). Handles of this heap stay valid, handles
+    // of `source` are translated by `ref_transform` of the returned merger like with `Set::merge`.
+    pub fn merge(self, source: RefHeap<P, T>) -> RefHeapInitMerger<P, T> {
+        let RefHeap { set, heap, } = self;
+        let RefHeap { set: source_set, heap: mut source_heap, } = source;
+        source_heap.clear();
+        let merged_from = heap.len();
+        RefHeapInitMerger { inner_merger: set.merge(source_set), heap, merged_from, source_heap, }
+    }
+
+    fn priority_at(&self, position: usize) -> Option<&P> {
+        self.heap.get(position).and_then(|&heap_ref| self.priority(heap_ref))
+    }
+
+    fn restore(&mut self, position: usize) {
+        let parent = position.checked_sub(1).map(|index| index / ARITY);
+        match parent {
+            Some(parent) if self.priority_at(position) < self.priority_at(parent) =>
+                self.sift_up(position),
+            _ =>
+                self.sift_down(position),
+        }
+    }
+
+    fn sift_up(&mut self, mut position: usize) {
+        while position > 0 {
+            let parent = (position - 1) / ARITY;
+            if self.priority_at(position) >= self.priority_at(parent) {
+                break;
+            }
+            self.swap(position, parent);
+            position = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut position: usize) {
+        loop {
+            let first_child = position * ARITY + 1;
+            let smallest = (first_child .. (first_child + ARITY).min(self.heap.len()))
+                .min_by(|&a, &b| self.priority_at(a).cmp(&self.priority_at(b)))
+                .filter(|&child| self.priority_at(child) < self.priority_at(position));
+            match smallest {
+                Some(child) => {
+                    self.swap(position, child);
+                    position = child;
+                },
+                None =>
+                    break,
+            }
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.set_position(a);
+        self.set_position(b);
+    }
+
+    fn set_position(&mut self, position: usize) {
+        if let Some(entry) = self.heap.get(position).and_then(|&heap_ref| self.set.get_mut(heap_ref)) {
+            entry.position = position;
+        }
+    }
+}
+
+pub struct RefHeapInitMerger<P, T> {
+    inner_merger: SetsInitMerger<HeapEntry<P, T>, HeapEntry<P, T>>,
+    heap: Vec<Ref>,
+    // items from this position on come from the source and are not in heap order yet
+    merged_from: usize,
+    source_heap: Vec<Ref>,
+}
+
+pub struct RefHeapInProgressMerger<P, T> {
+    inner_merger: SetsInProgressMerger<HeapEntry<P, T>, HeapEntry<P, T>>,
+    heap: Vec<Ref>,
+    merged_from: usize,
+    source_heap: Vec<Ref>,
+    target_ref: Option<Ref>,
+    priority: P,
+}
+
+impl<P, T> InitMerger<Ref, Ref, T, RefHeapInProgressMerger<P, T>, RefHeap<P, T>, RefHeap<P, T>> for RefHeapInitMerger<P, T> where P: Ord {
+    fn ref_transform(&self, source_ref: Ref) -> Option<Ref> {
+        self.inner_merger.ref_transform(source_ref)
+    }
+
+    fn merge_start(self) -> RefHeapMergeState<P, T> {
+        RefHeapInProgressMerger::make_state(self.inner_merger.merge_start(), self.heap, self.merged_from, self.source_heap)
+    }
+}
+
+type RefHeapMergeState<P, T> = MergeState<Ref, T, RefHeapInProgressMerger<P, T>, RefHeap<P, T>, RefHeap<P, T>>;
+
+type RefHeapMergerInnerState<P, T> =
+    MergeState<Ref, HeapEntry<P, T>, SetsInProgressMerger<HeapEntry<P, T>, HeapEntry<P, T>>, Set<HeapEntry<P, T>>, Set<HeapEntry<P, T>>>;
+
+impl<P, T> RefHeapInProgressMerger<P, T> where P: Ord {
+    fn make_state(inner_state: RefHeapMergerInnerState<P, T>, heap: Vec<Ref>, merged_from: usize, source_heap: Vec<Ref>) -> RefHeapMergeState<P, T> {
+        match inner_state {
+            MergeState::Continue { item_ref, item: HeapEntry { priority, item, .. }, next, } => {
+                let target_ref = next.ref_transform(item_ref);
+                MergeState::Continue {
+                    item_ref,
+                    item,
+                    next: RefHeapInProgressMerger { inner_merger: next, heap, merged_from, source_heap, target_ref, priority, },
+                }
+            },
+            MergeState::Finish { merged, empty, } => {
+                let mut target = RefHeap { set: merged, heap, };
+                for position in merged_from .. target.heap.len() {
+                    target.sift_up(position);
+                }
+                MergeState::Finish { merged: target, empty: RefHeap { set: empty, heap: source_heap, }, }
+            },
+        }
+    }
+}
+
+impl<P, T> InProgressMerger<Ref, Ref, T, T, RefHeapInProgressMerger<P, T>, RefHeap<P, T>, RefHeap<P, T>> for RefHeapInProgressMerger<P, T>
+    where P: Ord
+{
+    fn ref_transform(&self, source_ref: Ref) -> Option<Ref> {
+        self.inner_merger.ref_transform(source_ref)
+    }
+
+    fn proceed(mut self, transformed_item: T) -> RefHeapMergeState<P, T> {
+        let position = self.heap.len();
+        self.heap.extend(self.target_ref);
+        let entry = HeapEntry { priority: self.priority, item: transformed_item, position, };
+        RefHeapInProgressMerger::make_state(self.inner_merger.proceed(entry), self.heap, self.merged_from, self.source_heap)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{
+            BTreeSet,
+        },
+    };
+
+    use rand::{self, Rng};
+
+    use crate::{
+        merge::{
+            merge_no_transform,
+            InitMerger,
+        },
+    };
+
+    use super::{
+        RefHeap,
+    };
+
+    #[test]
+    fn push_pop_change_remove() {
+        let mut heap = RefHeap::new();
+        let a = heap.push(5, "a");
+        let b = heap.push(3, "b");
+        let c = heap.push(8, "c");
+        heap.push(1, "d");
+        assert_eq!(heap.peek().map(|(_, &priority, &item)| (priority, item)), Some((1, "d")));
+        assert_eq!(heap.pop(), Some((1, "d")));
+        assert_eq!(heap.change_priority(c, 2), Some(8));
+        assert_eq!(heap.peek().map(|(heap_ref, _, _)| heap_ref), Some(c));
+        assert!(!heap.decrease_priority(a, 6));
+        assert!(heap.decrease_priority(a, 0));
+        assert_eq!(heap.remove(b), Some((3, "b")));
+        assert_eq!(heap.remove(b), None);
+        assert_eq!(heap.change_priority(b, 0), None);
+        assert_eq!(heap.priority(c), Some(&2));
+        assert_eq!(heap.pop(), Some((0, "a")));
+        assert_eq!(heap.pop(), Some((2, "c")));
+        assert_eq!(heap.pop(), None);
+        assert!(!heap.contains(c));
+        let e = heap.push(4, "e");
+        assert_ne!(e, c);
+        assert_eq!(heap.get(c), None);
+    }
+
+    #[test]
+    fn merge() {
+        let mut heap_a = RefHeap::new();
+        let a = heap_a.push(4, 'a');
+        let b = heap_a.push(2, 'b');
+        let mut heap_b = RefHeap::new();
+        let c = heap_b.push(3, 'c');
+        let d = heap_b.push(1, 'd');
+        let e = heap_b.push(6, 'e');
+        assert_eq!(heap_b.remove(e), Some((6, 'e')));
+
+        let merger = heap_a.merge(heap_b);
+        let c_moved = merger.ref_transform(c).unwrap();
+        let d_moved = merger.ref_transform(d).unwrap();
+        assert_eq!(merger.ref_transform(e), None);
+        let mut heap_a = merge_no_transform(merger);
+        assert_eq!(heap_a.len(), 4);
+        assert_eq!(heap_a.peek().map(|(heap_ref, _, _)| heap_ref), Some(d_moved));
+        assert_eq!((heap_a.get(a), heap_a.get(b), heap_a.get(c_moved)), (Some(&'a'), Some(&'b'), Some(&'c')));
+        assert_eq!(heap_a.get(c), None);
+
+        assert_eq!(heap_a.remove(b), Some((2, 'b')));
+        assert_eq!(heap_a.change_priority(c_moved, 0), Some(3));
+        assert_eq!(heap_a.change_priority(d_moved, 5), Some(1));
+        assert!(heap_a.decrease_priority(a, 1));
+        let order: Vec<_> = std::iter::from_fn(|| heap_a.pop().map(|pair| pair.1)).collect();
+        assert_eq!(order, vec!['c', 'a', 'd']);
+    }
+
+    #[test]
+    fn stress() {
+        let mut heap = RefHeap::new();
+        let mut model = BTreeSet::new();
+        let mut refs = Vec::new();
+        let mut rng = rand::thread_rng();
+
+        for step in 0 .. 16384 {
+            let choice = rng.gen_range(0 .. 100);
+            if choice < 45 {
+                let priority: u32 = rng.gen_range(0 .. 1000);
+                refs.push((heap.push((priority, step), step), (priority, step)));
+                model.insert((priority, step));
+            } else if choice < 65 && !refs.is_empty() {
+                let index = rng.gen_range(0 .. refs.len());
+                let priority = (rng.gen_range(0 .. 1000), refs[index].1.1);
+                assert_eq!(heap.change_priority(refs[index].0, priority), Some(refs[index].1));
+                model.remove(&refs[index].1);
+                model.insert(priority);
+                refs[index].1 = priority;
+            } else if choice < 80 && !refs.is_empty() {
+                let index = rng.gen_range(0 .. refs.len());
+                let (heap_ref, priority) = refs.swap_remove(index);
+                assert_eq!(heap.remove(heap_ref), Some((priority, priority.1)));
+                model.remove(&priority);
+            } else {
+                let popped = heap.pop().map(|pair| pair.0);
+                assert_eq!(popped, model.pop_first());
+                refs.retain(|&(_, priority)| Some(priority) != popped);
+            }
+            assert_eq!(heap.len(), model.len());
+        }
+    }
+}
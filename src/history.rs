@@ -0,0 +1,398 @@
+use crate::{
+    set::{
+        Ref,
+    },
+    forest::{
+        Forest1,
+    },
+};
+
+// Change of a document state recorded by a commit.
+pub trait Delta<S> {
+    fn apply(&self, state: &mut S);
+
+    // Undoes `apply` leaving the state untouched and returning false if the delta cannot be inverted,
+    // then the state is rebuilt from the closest snapshot instead.
+    fn revert(&self, _state: &mut S) -> bool {
+        false
+    }
+}
+
+// History node stored in the forest.
+pub struct Version<S, D> {
+    // delta leading to the version, none for the root
+    pub delta: Option<D>,
+    // full state kept to bound checkouts
+    pub snapshot: Option<S>,
+    // upper bound of deltas to replay from the closest snapshot
+    since_snapshot: usize,
+    // child followed by `redo`: the last one committed or checked out through
+    redo: Option<Ref>,
+}
+
+// Branching undo history: every commit is a node whose parent is the previous version, so undoing
+// and committing again starts a new branch instead of dropping the old one.
+pub struct History<S, D> {
+    forest: Forest1<Version<S, D>>,
+    root: Ref,
+    head: Ref,
+    state: S,
+    snapshot_every: usize,
+}
+
+impl<S, D> History<S, D> where S: Clone, D: Delta<S> {
+    // A history starting at `initial`, a snapshot of the state is stored every `snapshot_every` edits
+    // so a checkout replays at most that many deltas.
+    pub fn new(initial: S, snapshot_every: usize) -> History<S, D> {
+        let mut forest = Forest1::new();
+        forest.enable_child_index();
+        forest.enable_jump_index();
+        let root = forest.make_root(Version { delta: None, snapshot: Some(initial.clone()), since_snapshot: 0, redo: None, });
+        History { forest, root, head: root, state: initial, snapshot_every: snapshot_every.max(1), }
+    }
+
+    pub fn forest(&self) -> &Forest1<Version<S, D>> {
+        &self.forest
+    }
+
+    pub fn root(&self) -> Ref {
+        self.root
+    }
+
+    pub fn head(&self) -> Ref {
+        self.head
+    }
+
+    // State at the head version.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    // Applies `delta` to the head state and records it as a child of the head, which becomes the new head.
+    pub fn commit(&mut self, delta: D) -> Ref {
+        delta.apply(&mut self.state);
+        let since_snapshot = self.forest.get(self.head).map_or(0, |node| node.item.since_snapshot) + 1;
+        let (snapshot, since_snapshot) = if since_snapshot >= self.snapshot_every {
+            (Some(self.state.clone()), 0)
+        } else {
+            (None, since_snapshot)
+        };
+        let version = Version { delta: Some(delta), snapshot, since_snapshot, redo: None, };
+        let version_ref = self.forest.make_node(self.head, version);
+        if let Some(head) = self.forest.get_mut(self.head) {
+            head.item.redo = Some(version_ref);
+        }
+        self.head = version_ref;
+        version_ref
+    }
+
+    // Makes `version_ref` the head rebuilding its state from the closest snapshot. Its parent remembers
+    // the branch for a later redo, further ancestors are updated while undoing towards them.
+    pub fn checkout(&mut self, version_ref: Ref) -> bool {
+        let state = match self.state_at(version_ref) {
+            Some(state) =>
+                state,
+            None =>
+                return false,
+        };
+        self.remember_redo(version_ref);
+        self.state = state;
+        self.head = version_ref;
+        true
+    }
+
+    fn remember_redo(&mut self, version_ref: Ref) {
+        let maybe_parent_ref = self.forest.get(version_ref).and_then(|node| node.parent);
+        if let Some(parent) = maybe_parent_ref.and_then(|parent_ref| self.forest.get_mut(parent_ref)) {
+            parent.item.redo = Some(version_ref);
+        }
+    }
+
+    // State of a version replaying deltas from the closest snapshot on the way to the root.
+    pub fn state_at(&self, version_ref: Ref) -> Option<S> {
+        let mut deltas = Vec::new();
+        let mut cursor = version_ref;
+        let mut state = loop {
+            let node = self.forest.get(cursor)?;
+            if let Some(snapshot) = node.item.snapshot.as_ref() {
+                break snapshot.clone();
+            }
+            deltas.extend(node.item.delta.as_ref());
+            cursor = node.parent?;
+        };
+        for delta in deltas.into_iter().rev() {
+            delta.apply(&mut state);
+        }
+        Some(state)
+    }
+
+    // Moves the head to its parent reverting the head delta, false at the root.
+    pub fn undo(&mut self) -> bool {
+        let node = match self.forest.get(self.head) {
+            Some(node) =>
+                node,
+            None =>
+                return false,
+        };
+        let parent_ref = match node.parent {
+            Some(parent_ref) =>
+                parent_ref,
+            None =>
+                return false,
+        };
+        if !node.item.delta.as_ref().is_some_and(|delta| delta.revert(&mut self.state)) {
+            match self.state_at(parent_ref) {
+                Some(state) =>
+                    self.state = state,
+                None =>
+                    return false,
+            }
+        }
+        self.remember_redo(self.head);
+        self.head = parent_ref;
+        true
+    }
+
+    // Moves the head to one of its children applying its delta: `branch` or the last visited child
+    // when none is given. False if there is nothing to redo or `branch` is not a child of the head.
+    pub fn redo(&mut self, branch: Option<Ref>) -> bool {
+        let head_redo = self.forest.get(self.head).and_then(|node| node.item.redo);
+        let child_ref = match branch.or(head_redo) {
+            Some(child_ref) =>
+                child_ref,
+            None =>
+                return false,
+        };
+        match self.forest.get(child_ref) {
+            Some(child) if child.parent == Some(self.head) => {
+                if let Some(delta) = child.item.delta.as_ref() {
+                    delta.apply(&mut self.state);
+                }
+            },
+            _ =>
+                return false,
+        }
+        self.remember_redo(child_ref);
+        self.head = child_ref;
+        true
+    }
+
+    // Children of the head available for `redo`.
+    pub fn branches(&self) -> impl Iterator<Item = Ref> + '_ {
+        self.forest.children(self.head)
+    }
+
+    // Versions to go through from `from_ref` to `to_ref` (both included), via their common ancestor.
+    pub fn path_between(&self, from_ref: Ref, to_ref: Ref) -> Option<Vec<Ref>> {
+        self.forest.path(from_ref, to_ref)
+    }
+
+    // Removes all branches forking off the way from the root to the head. Versions after the head
+    // are kept. Returns the number of removed versions.
+    pub fn prune_branches(&mut self) -> usize {
+        let mut removed = 0;
+        let mut keep_ref = self.head;
+        while let Some(parent_ref) = self.forest.get(keep_ref).and_then(|node| node.parent) {
+            let forks: Vec<_> = self.forest.children(parent_ref)
+                .filter(|&child_ref| child_ref != keep_ref)
+                .collect();
+            for fork_ref in forks {
+                removed += self.forest.remove_subtree(fork_ref).len();
+            }
+            if let Some(parent) = self.forest.get_mut(parent_ref) {
+                parent.item.redo = Some(keep_ref);
+            }
+            keep_ref = parent_ref;
+        }
+        removed
+    }
+
+    // Drops all the history before `version_ref`, an ancestor of the head (or the head itself), which
+    // becomes the new root holding a snapshot. Returns the number of removed versions.
+    pub fn squash(&mut self, version_ref: Ref) -> Option<usize> {
+        if !self.forest.is_ancestor(version_ref, self.head) && version_ref != self.head {
+            return None;
+        }
+        let state = self.state_at(version_ref)?;
+        let version = self.forest.get_mut(version_ref)?.item;
+        version.delta = None;
+        version.snapshot = Some(state);
+        version.since_snapshot = 0;
+        if version_ref == self.root {
+            return Some(0);
+        }
+        let _ = self.forest.detach(version_ref);
+        let removed = self.forest.remove_subtree(self.root).len();
+        self.root = version_ref;
+        Some(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::{
+            Cell,
+        },
+    };
+
+    use super::{
+        Delta,
+        History,
+    };
+
+    struct Push<'a> {
+        value: char,
+        applied: &'a Cell<usize>,
+    }
+
+    impl<'a> Delta<String> for Push<'a> {
+        fn apply(&self, state: &mut String) {
+            self.applied.set(self.applied.get() + 1);
+            state.push(self.value);
+        }
+    }
+
+    // `Push` which can be reverted without replaying the history
+    struct Revertible<'a>(Push<'a>);
+
+    impl<'a> Delta<String> for Revertible<'a> {
+        fn apply(&self, state: &mut String) {
+            self.0.apply(state);
+        }
+
+        fn revert(&self, state: &mut String) -> bool {
+            state.pop();
+            true
+        }
+    }
+
+    #[test]
+    fn undo_redo_branches() {
+        let applied = Cell::new(0);
+        let push = |value| Push { value, applied: &applied, };
+        let mut history = History::new(String::new(), 4);
+        let a = history.commit(push('a'));
+        let b = history.commit(push('b'));
+        assert_eq!(history.state(), "ab");
+        assert!(history.undo());
+        assert_eq!(history.state(), "a");
+        let c = history.commit(push('c'));
+        assert_eq!(history.state(), "ac");
+        assert!(history.undo());
+        let mut branches: Vec<_> = history.branches().collect();
+        branches.sort();
+        assert_eq!(branches, vec![b, c]);
+
+        assert!(history.redo(None));
+        assert_eq!((history.head(), history.state().as_str()), (c, "ac"));
+        assert!(history.undo());
+        assert!(history.redo(Some(b)));
+        assert_eq!(history.state(), "ab");
+        assert!(!history.redo(None));
+        assert!(!history.redo(Some(a)));
+        assert!(history.undo());
+        assert!(history.undo());
+        assert!(!history.undo());
+        assert_eq!(history.state(), "");
+        assert!(history.redo(None));
+        assert!(history.redo(None));
+        assert_eq!(history.head(), b);
+
+        assert_eq!(history.path_between(b, c), Some(vec![b, a, c]));
+        assert!(history.checkout(c));
+        assert_eq!(history.state(), "ac");
+        assert!(history.undo());
+        assert!(history.redo(None));
+        assert_eq!(history.head(), c);
+    }
+
+    #[test]
+    fn snapshots_bound_checkout() {
+        let applied = Cell::new(0);
+        let mut history = History::new(String::new(), 4);
+        let mut refs = vec![history.root()];
+        for value in "abcdefghij".chars() {
+            refs.push(history.commit(Push { value, applied: &applied, }));
+        }
+        assert_eq!(history.state(), "abcdefghij");
+        assert_eq!(history.forest().local_iter().filter(|(_, version)| version.snapshot.is_some()).count(), 3);
+
+        applied.set(0);
+        assert!(history.checkout(refs[7]));
+        assert_eq!(history.state(), "abcdefg");
+        assert_eq!(applied.get(), 3);
+        applied.set(0);
+        assert!(history.checkout(refs[8]));
+        assert_eq!(applied.get(), 0);
+
+        // undo replays at most the deltas since the closest snapshot
+        applied.set(0);
+        assert!(history.undo());
+        assert_eq!(history.state(), "abcdefg");
+        assert_eq!(applied.get(), 3);
+    }
+
+    #[test]
+    fn undo_reverts() {
+        let applied = Cell::new(0);
+        let push = |value| Revertible(Push { value, applied: &applied, });
+        let mut history = History::new(String::new(), 100);
+        let mut refs = vec![history.root()];
+        for value in "abcde".chars() {
+            refs.push(history.commit(push(value)));
+        }
+        applied.set(0);
+        assert!(history.undo());
+        assert!(history.undo());
+        assert_eq!(history.state(), "abc");
+        assert_eq!(applied.get(), 0);
+        assert!(history.redo(None));
+        assert!(history.redo(None));
+        assert_eq!((history.head(), history.state().as_str()), (refs[5], "abcde"));
+
+        // a checkout updates the redo of its parent only, ancestors follow undos
+        assert!(history.checkout(refs[1]));
+        let c = history.commit(push('x'));
+        assert!(history.checkout(refs[4]));
+        assert_eq!(history.forest().get(refs[1]).unwrap().item.redo, Some(c));
+        assert_eq!(history.forest().get(refs[3]).unwrap().item.redo, Some(refs[4]));
+        for _ in 0 .. 3 {
+            assert!(history.undo());
+        }
+        assert_eq!(history.forest().get(refs[1]).unwrap().item.redo, Some(refs[2]));
+        assert_eq!(history.state(), "a");
+    }
+
+    #[test]
+    fn prune_squash() {
+        let applied = Cell::new(0);
+        let push = |value| Push { value, applied: &applied, };
+        let mut history = History::new(String::new(), 3);
+        let a = history.commit(push('a'));
+        history.commit(push('x'));
+        history.commit(push('y'));
+        assert!(history.checkout(a));
+        let b = history.commit(push('b'));
+        let c = history.commit(push('c'));
+        history.commit(push('d'));
+        assert!(history.undo());
+        assert_eq!(history.forest().len(), 7);
+
+        assert_eq!(history.prune_branches(), 2);
+        assert_eq!(history.forest().len(), 5);
+        assert_eq!(history.state(), "abc");
+        assert!(history.redo(None));
+        assert_eq!(history.state(), "abcd");
+
+        assert_eq!(history.squash(b), Some(2));
+        assert_eq!(history.root(), b);
+        assert_eq!(history.forest().len(), 3);
+        assert!(history.checkout(c));
+        assert_eq!(history.state(), "abc");
+        assert!(history.undo());
+        assert!(!history.undo());
+        assert_eq!(history.state(), "ab");
+        assert_eq!(history.squash(a), None);
+    }
+}
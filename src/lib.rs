@@ -3,3 +3,13 @@ pub mod vec;
 pub mod forest;
 pub mod merge;
 pub mod dll;
+pub mod mcts;
+pub mod search;
+pub mod graphviz;
+pub mod newick;
+pub mod history;
+pub mod trie;
+pub mod disjoint;
+pub mod heap;
+pub mod timer;
+pub mod cache;
@@ -0,0 +1,731 @@
+use std::{
+    mem,
+    cmp::{
+        Ordering,
+    },
+    collections::{
+        HashMap,
+    },
+};
+
+use rayon::{
+    iter::{
+        ParallelIterator,
+        IntoParallelIterator,
+    },
+};
+
+use crate::{
+    layers,
+    set::{
+        Ref,
+    },
+    forest::{
+        Ref2,
+        Node,
+        Forest1,
+        Forest2,
+    },
+    merge::{
+        merge_no_transform,
+    },
+};
+
+// Game state as seen by the search.
+pub trait State: Clone {
+    type Move: Clone + PartialEq;
+
+    fn moves(&self) -> Vec<Self::Move>;
+
+    fn play(&mut self, game_move: &Self::Move);
+
+    // player to make the next move
+    fn player(&self) -> usize;
+
+    fn is_terminal(&self) -> bool;
+
+    // reward of `player` in a terminal state, normally in [0, 1]
+    fn reward(&self, player: usize) -> f64;
+
+    // prior probabilities of `moves` used by PUCT selection
+    fn priors(&self, moves: &[Self::Move]) -> Vec<f64> {
+        vec![1.0 / moves.len() as f64; moves.len()]
+    }
+
+    // index of the move to make during a rollout
+    fn rollout_move(&self, moves: &[Self::Move], rng: &mut Rng64) -> usize {
+        rng.below(moves.len())
+    }
+
+    // states with equal keys share statistics when transpositions are enabled
+    fn transposition_key(&self) -> Option<u64> {
+        None
+    }
+}
+
+// Search node stored in the forest.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Stats<M> {
+    // move leading to the node, none for the root
+    pub game_move: Option<M>,
+    // player who made `game_move`
+    pub player: usize,
+    pub visits: u64,
+    // total reward of `player`
+    pub value: f64,
+    pub prior: f64,
+    pub key: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Policy {
+    Uct { exploration: f64, },
+    Puct { exploration: f64, },
+}
+
+// Small xorshift64* generator: rollouts stay reproducible for a given seed.
+#[derive(Clone, Debug)]
+pub struct Rng64 {
+    state: u64,
+}
+
+impl Rng64 {
+    pub fn new(seed: u64) -> Rng64 {
+        Rng64 { state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1, }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+type Transpositions = HashMap<u64, (u64, f64)>;
+
+pub struct Mcts<S> where S: State {
+    forest: Forest1<Stats<S::Move>>,
+    root: Ref,
+    root_state: S,
+    policy: Policy,
+    transpositions: Option<Transpositions>,
+    rng: Rng64,
+}
+
+impl<S> Mcts<S> where S: State {
+    pub fn new(root_state: S, policy: Policy) -> Mcts<S> {
+        let mut forest = Forest1::new();
+        forest.enable_child_index();
+        let root = forest.make_root(root_stats(&root_state));
+        Mcts { forest, root, root_state, policy, transpositions: None, rng: Rng64::new(0), }
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng64::new(seed);
+    }
+
+    pub fn enable_transpositions(&mut self) {
+        if self.transpositions.is_none() {
+            self.transpositions = Some(HashMap::new());
+        }
+    }
+
+    pub fn forest(&self) -> &Forest1<Stats<S::Move>> {
+        &self.forest
+    }
+
+    pub fn root(&self) -> Ref {
+        self.root
+    }
+
+    pub fn root_state(&self) -> &S {
+        &self.root_state
+    }
+
+    // Descends from the root to a leaf, `state` is advanced along the way.
+    pub fn select(&mut self, state: &mut S) -> Ref {
+        let tree = BaseTree { forest: &mut self.forest, transpositions: self.transpositions.as_mut(), };
+        select(&tree, self.policy, self.root, state)
+    }
+
+    // Adds all the moves of `state` as children of the leaf and picks one of them advancing `state`.
+    pub fn expand(&mut self, node_ref: Ref, state: &mut S) -> Ref {
+        let mut tree = BaseTree { forest: &mut self.forest, transpositions: self.transpositions.as_mut(), };
+        expand(&mut tree, node_ref, state, &mut self.rng)
+    }
+
+    // Plays random moves until the end of the game.
+    pub fn simulate(&mut self, state: S) -> S {
+        rollout(state, &mut self.rng)
+    }
+
+    // Accounts the outcome of `terminal` in all nodes from `node_ref` up to the root.
+    pub fn backpropagate(&mut self, node_ref: Ref, terminal: &S) {
+        let mut tree = BaseTree { forest: &mut self.forest, transpositions: self.transpositions.as_mut(), };
+        backpropagate(&mut tree, node_ref, terminal)
+    }
+
+    pub fn search(&mut self, iterations: usize) {
+        let mut tree = BaseTree { forest: &mut self.forest, transpositions: self.transpositions.as_mut(), };
+        for _ in 0 .. iterations {
+            iterate(&mut tree, self.policy, self.root, &self.root_state, &mut self.rng);
+        }
+    }
+
+    // Runs `iterations` per each of `workers` rayon tasks. Every task grows its own `Forest2` layer over
+    // the current tree, and layers are merged down once all of them are done.
+    pub fn par_search(&mut self, workers: usize, iterations: usize)
+        where S: Send + Sync, S::Move: Send + Sync
+    {
+        let seeds: Vec<_> = (0 .. workers).map(|_| self.rng.next_u64()).collect();
+        let base = &self.forest;
+        let base_transpositions = self.transpositions.as_ref();
+        let (policy, root, root_state) = (self.policy, self.root, &self.root_state);
+        let layers: Vec<_> = seeds.into_par_iter()
+            .map(|seed| {
+                let mut rng = Rng64::new(seed);
+                let mut layer = Forest2::new();
+                layer.enable_child_index();
+                let mut tree = LayerTree {
+                    layer: &mut layer,
+                    base,
+                    base_transpositions,
+                    transpositions: base_transpositions.map(|_| HashMap::new()),
+                };
+                for _ in 0 .. iterations {
+                    iterate(&mut tree, policy, Ref2::External(root), root_state, &mut rng);
+                }
+                let transpositions = tree.transpositions.take();
+                (layer, transpositions)
+            })
+            .collect();
+
+        // shadows of the base nodes are turned into deltas while the base is still intact
+        let mut deltas = Vec::new();
+        let layers: Vec<_> = layers.into_iter()
+            .map(|(mut layer, transpositions)| {
+                let shadows: Vec<_> = layer.local_iter()
+                    .flat_map(|(node_ref, stats)| match node_ref {
+                        Ref2::External(base_ref) =>
+                            Some((base_ref, stats.visits, stats.value)),
+                        Ref2::Local(..) =>
+                            None,
+                    })
+                    .collect();
+                for (base_ref, visits, value) in shadows {
+                    layer.discard_shadow(&base_ref);
+                    if let Some(node) = self.forest.get(base_ref) {
+                        deltas.push((base_ref, visits - node.item.visits, value - node.item.value));
+                    }
+                }
+                (layer, transpositions)
+            })
+            .collect();
+        for (base_ref, visits, value) in deltas {
+            if let Some(node) = self.forest.get_mut(base_ref) {
+                node.item.visits += visits;
+                node.item.value += value;
+            }
+        }
+
+        for (mut layer, transpositions) in layers {
+            reconcile(&mut self.forest, &mut layer);
+            let forest = mem::take(&mut self.forest);
+            self.forest = merge_no_transform(layer.merge_down(forest));
+            if let (Some(base_transpositions), Some(transpositions)) = (self.transpositions.as_mut(), transpositions) {
+                for (key, (visits, value)) in transpositions {
+                    let entry = base_transpositions.entry(key).or_insert((0, 0.0));
+                    entry.0 += visits;
+                    entry.1 += value;
+                }
+            }
+        }
+    }
+
+    // Most visited move of the root.
+    pub fn best_move(&self) -> Option<S::Move> {
+        self.forest.children(self.root)
+            .flat_map(|child_ref| self.forest.get(child_ref))
+            .max_by_key(|node| node.item.visits)
+            .and_then(|node| node.item.game_move.clone())
+    }
+
+    // Plays `game_move` at the root keeping the corresponding subtree as the new tree.
+    pub fn commit_move(&mut self, game_move: &S::Move) {
+        let maybe_child_ref = self.forest.children(self.root)
+            .find(|&child_ref| self.forest.get(child_ref).and_then(|node| node.item.game_move.as_ref()) == Some(game_move));
+        self.root_state.play(game_move);
+        match maybe_child_ref {
+            Some(child_ref) => {
+                let _ = self.forest.detach(child_ref);
+                self.forest.remove_subtree(self.root);
+                self.root = child_ref;
+            },
+            None => {
+                self.forest.clear();
+                self.root = self.forest.make_root(root_stats(&self.root_state));
+            },
+        }
+    }
+}
+
+fn root_stats<S>(state: &S) -> Stats<S::Move> where S: State {
+    Stats {
+        game_move: None,
+        player: state.player(),
+        visits: 0,
+        value: 0.0,
+        prior: 1.0,
+        key: state.transposition_key(),
+    }
+}
+
+// Several layers may expand the same base leaf: children of a leaf already expanded by a previous layer
+// only pass their statistics to the existing children with the same move (deeper nodes are dropped).
+fn reconcile<M>(forest: &mut Forest1<Stats<M>>, layer: &mut Forest2<Stats<M>, Ref>) where M: Clone + PartialEq {
+    let attached: Vec<_> = layer.local_iter()
+        .flat_map(|(node_ref, _)| match layers!([&*layer, &*forest].get(node_ref)).and_then(|node| node.parent) {
+            Some(Ref2::External(parent_ref)) if forest.children(parent_ref).next().is_some() =>
+                Some((node_ref, parent_ref)),
+            _ =>
+                None,
+        })
+        .collect();
+    for (node_ref, parent_ref) in attached {
+        let removed = layers!([&mut *layer, &*forest].remove_subtree(node_ref));
+        let stats = match removed.into_iter().find(|pair| pair.0 == node_ref) {
+            Some((_, Node { item: Some(stats), .. })) =>
+                stats,
+            _ =>
+                continue,
+        };
+        let maybe_sibling_ref = forest.children(parent_ref)
+            .find(|&child_ref| forest.get(child_ref).map(|node| &node.item.game_move) == Some(&stats.game_move));
+        if let Some(node) = maybe_sibling_ref.and_then(|sibling_ref| forest.get_mut(sibling_ref)) {
+            node.item.visits += stats.visits;
+            node.item.value += stats.value;
+        }
+    }
+}
+
+// Search tree access shared by the plain forest and a forest layer.
+trait Tree<M> {
+    type Ref: Clone;
+
+    fn children(&self, node_ref: Self::Ref) -> Vec<Self::Ref>;
+    fn stats(&self, node_ref: Self::Ref) -> Option<&Stats<M>>;
+    fn parent(&self, node_ref: Self::Ref) -> Option<Self::Ref>;
+    fn stats_mut(&mut self, node_ref: Self::Ref) -> Option<&mut Stats<M>>;
+    fn add_child(&mut self, parent_ref: Self::Ref, stats: Stats<M>) -> Self::Ref;
+    fn has_transpositions(&self) -> bool;
+    fn transposition(&self, key: u64) -> Option<(u64, f64)>;
+    fn record_transposition(&mut self, key: u64, reward: f64);
+}
+
+struct BaseTree<'a, M> {
+    forest: &'a mut Forest1<Stats<M>>,
+    transpositions: Option<&'a mut Transpositions>,
+}
+
+impl<'a, M> Tree<M> for BaseTree<'a, M> {
+    type Ref = Ref;
+
+    fn children(&self, node_ref: Ref) -> Vec<Ref> {
+        self.forest.children(node_ref).collect()
+    }
+
+    fn stats(&self, node_ref: Ref) -> Option<&Stats<M>> {
+        self.forest.get(node_ref).map(|node| node.item)
+    }
+
+    fn parent(&self, node_ref: Ref) -> Option<Ref> {
+        self.forest.get(node_ref).and_then(|node| node.parent)
+    }
+
+    fn stats_mut(&mut self, node_ref: Ref) -> Option<&mut Stats<M>> {
+        self.forest.get_mut(node_ref).map(|node| node.item)
+    }
+
+    fn add_child(&mut self, parent_ref: Ref, stats: Stats<M>) -> Ref {
+        self.forest.make_node(parent_ref, stats)
+    }
+
+    fn has_transpositions(&self) -> bool {
+        self.transpositions.is_some()
+    }
+
+    fn transposition(&self, key: u64) -> Option<(u64, f64)> {
+        self.transpositions.as_ref().and_then(|transpositions| transpositions.get(&key).cloned())
+    }
+
+    fn record_transposition(&mut self, key: u64, reward: f64) {
+        if let Some(transpositions) = self.transpositions.as_mut() {
+            let entry = transpositions.entry(key).or_insert((0, 0.0));
+            entry.0 += 1;
+            entry.1 += reward;
+        }
+    }
+}
+
+// A worker layer over the shared tree: base nodes statistics are updated in shadow copies.
+struct LayerTree<'a, M> {
+    layer: &'a mut Forest2<Stats<M>, Ref>,
+    base: &'a Forest1<Stats<M>>,
+    base_transpositions: Option<&'a Transpositions>,
+    transpositions: Option<Transpositions>,
+}
+
+impl<'a, M> Tree<M> for LayerTree<'a, M> where M: Clone {
+    type Ref = Ref2<Ref>;
+
+    fn children(&self, node_ref: Ref2<Ref>) -> Vec<Ref2<Ref>> {
+        layers!([&*self.layer, self.base].children(node_ref)).collect()
+    }
+
+    fn stats(&self, node_ref: Ref2<Ref>) -> Option<&Stats<M>> {
+        layers!([&*self.layer, self.base].get(node_ref)).map(|node| node.item)
+    }
+
+    fn parent(&self, node_ref: Ref2<Ref>) -> Option<Ref2<Ref>> {
+        layers!([&*self.layer, self.base].get(node_ref)).and_then(|node| node.parent)
+    }
+
+    fn stats_mut(&mut self, node_ref: Ref2<Ref>) -> Option<&mut Stats<M>> {
+        layers!([&mut *self.layer, self.base].shadow_mut(node_ref)).map(|node| node.item)
+    }
+
+    fn add_child(&mut self, parent_ref: Ref2<Ref>, stats: Stats<M>) -> Ref2<Ref> {
+        layers!([&mut *self.layer, self.base].make_node(parent_ref, stats))
+    }
+
+    fn has_transpositions(&self) -> bool {
+        self.transpositions.is_some()
+    }
+
+    fn transposition(&self, key: u64) -> Option<(u64, f64)> {
+        let base = self.base_transpositions.and_then(|transpositions| transpositions.get(&key).cloned());
+        let local = self.transpositions.as_ref().and_then(|transpositions| transpositions.get(&key).cloned());
+        match (base, local) {
+            (Some((base_visits, base_value)), Some((visits, value))) =>
+                Some((base_visits + visits, base_value + value)),
+            (base, None) =>
+                base,
+            (None, local) =>
+                local,
+        }
+    }
+
+    fn record_transposition(&mut self, key: u64, reward: f64) {
+        if let Some(transpositions) = self.transpositions.as_mut() {
+            let entry = transpositions.entry(key).or_insert((0, 0.0));
+            entry.0 += 1;
+            entry.1 += reward;
+        }
+    }
+}
+
+// Visits and value of a node pooled over its transpositions if they are tracked.
+fn pooled<M, T>(tree: &T, stats: &Stats<M>) -> (u64, f64) where T: Tree<M> {
+    stats.key
+        .and_then(|key| tree.transposition(key))
+        .unwrap_or((stats.visits, stats.value))
+}
+
+fn score<M, T>(tree: &T, policy: Policy, parent_visits: u64, stats: &Stats<M>) -> f64 where T: Tree<M> {
+    let (visits, value) = pooled(tree, stats);
+    let parent_visits = parent_visits.max(1) as f64;
+    match policy {
+        Policy::Uct { .. } if visits == 0 =>
+            f64::INFINITY,
+        Policy::Uct { exploration, } =>
+            value / visits as f64 + exploration * (parent_visits.ln() / visits as f64).sqrt(),
+        Policy::Puct { exploration, } => {
+            let mean = if visits == 0 { 0.0 } else { value / visits as f64 };
+            mean + exploration * stats.prior * parent_visits.sqrt() / (1 + visits) as f64
+        },
+    }
+}
+
+fn select<S, T>(tree: &T, policy: Policy, node_ref: T::Ref, state: &mut S) -> T::Ref where S: State, T: Tree<S::Move> {
+    let mut cursor = node_ref;
+    while !state.is_terminal() {
+        // children are scored with pooled counts so the parent count has to be pooled as well
+        let parent_visits = tree.stats(cursor.clone()).map_or(0, |stats| pooled(tree, stats).0);
+        let best = tree.children(cursor.clone())
+            .into_iter()
+            .flat_map(|child_ref| tree.stats(child_ref.clone()).map(|stats| (score(tree, policy, parent_visits, stats), child_ref, stats)))
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        match best {
+            Some((_, child_ref, stats)) => {
+                if let Some(game_move) = stats.game_move.as_ref() {
+                    state.play(game_move);
+                }
+                cursor = child_ref;
+            },
+            None =>
+                break,
+        }
+    }
+    cursor
+}
+
+fn expand<S, T>(tree: &mut T, node_ref: T::Ref, state: &mut S, rng: &mut Rng64) -> T::Ref where S: State, T: Tree<S::Move> {
+    if state.is_terminal() {
+        return node_ref;
+    }
+    let moves = state.moves();
+    if moves.is_empty() {
+        return node_ref;
+    }
+    let priors = state.priors(&moves);
+    let player = state.player();
+    let mut children = Vec::with_capacity(moves.len());
+    for (game_move, prior) in moves.into_iter().zip(priors) {
+        let key = if tree.has_transpositions() {
+            let mut next_state = state.clone();
+            next_state.play(&game_move);
+            next_state.transposition_key()
+        } else {
+            None
+        };
+        let stats = Stats { game_move: Some(game_move.clone()), player, visits: 0, value: 0.0, prior, key, };
+        children.push((tree.add_child(node_ref.clone(), stats), game_move));
+    }
+    let (child_ref, game_move) = children.swap_remove(rng.below(children.len()));
+    state.play(&game_move);
+    child_ref
+}
+
+fn rollout<S>(mut state: S, rng: &mut Rng64) -> S where S: State {
+    while !state.is_terminal() {
+        let moves = state.moves();
+        if moves.is_empty() {
+            break;
+        }
+        let index = state.rollout_move(&moves, rng);
+        state.play(&moves[index]);
+    }
+    state
+}
+
+fn backpropagate<S, T>(tree: &mut T, node_ref: T::Ref, terminal: &S) where S: State, T: Tree<S::Move> {
+    let mut cursor = Some(node_ref);
+    while let Some(node_ref) = cursor {
+        cursor = tree.parent(node_ref.clone());
+        let maybe_key = tree.stats_mut(node_ref)
+            .map(|stats| {
+                let reward = terminal.reward(stats.player);
+                stats.visits += 1;
+                stats.value += reward;
+                (stats.key, reward)
+            });
+        if let Some((Some(key), reward)) = maybe_key {
+            tree.record_transposition(key, reward);
+        }
+    }
+}
+
+fn iterate<S, T>(tree: &mut T, policy: Policy, root_ref: T::Ref, root_state: &S, rng: &mut Rng64) where S: State, T: Tree<S::Move> {
+    let mut state = root_state.clone();
+    let leaf_ref = select(tree, policy, root_ref, &mut state);
+    let node_ref = expand(tree, leaf_ref, &mut state, rng);
+    let terminal = rollout(state, rng);
+    backpropagate(tree, node_ref, &terminal);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        State,
+        Policy,
+        Mcts,
+        Stats,
+        BaseTree,
+        Transpositions,
+        Forest1,
+        root_stats,
+        select,
+    };
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct TicTacToe {
+        cells: [Option<usize>; 9],
+        player: usize,
+    }
+
+    const LINES: [[usize; 3]; 8] = [
+        [0, 1, 2], [3, 4, 5], [6, 7, 8],
+        [0, 3, 6], [1, 4, 7], [2, 5, 8],
+        [0, 4, 8], [2, 4, 6],
+    ];
+
+    impl TicTacToe {
+        fn new() -> TicTacToe {
+            TicTacToe { cells: [None; 9], player: 0, }
+        }
+
+        fn from_moves(moves: &[usize]) -> TicTacToe {
+            let mut state = TicTacToe::new();
+            for game_move in moves {
+                state.play(game_move);
+            }
+            state
+        }
+
+        fn winner(&self) -> Option<usize> {
+            LINES.iter()
+                .flat_map(|line| match (self.cells[line[0]], self.cells[line[1]], self.cells[line[2]]) {
+                    (Some(a), Some(b), Some(c)) if a == b && b == c =>
+                        Some(a),
+                    _ =>
+                        None,
+                })
+                .next()
+        }
+    }
+
+    impl State for TicTacToe {
+        type Move = usize;
+
+        fn moves(&self) -> Vec<usize> {
+            (0 .. 9).filter(|&index| self.cells[index].is_none()).collect()
+        }
+
+        fn play(&mut self, game_move: &usize) {
+            self.cells[*game_move] = Some(self.player);
+            self.player = 1 - self.player;
+        }
+
+        fn player(&self) -> usize {
+            self.player
+        }
+
+        fn is_terminal(&self) -> bool {
+            self.winner().is_some() || self.cells.iter().all(Option::is_some)
+        }
+
+        fn reward(&self, player: usize) -> f64 {
+            match self.winner() {
+                Some(winner) if winner == player =>
+                    1.0,
+                Some(..) =>
+                    0.0,
+                None =>
+                    0.5,
+            }
+        }
+
+        fn transposition_key(&self) -> Option<u64> {
+            Some(self.cells.iter().fold(0, |key, cell| key * 3 + cell.map_or(0, |player| player as u64 + 1)))
+        }
+    }
+
+    fn check_visits(mcts: &Mcts<TicTacToe>) {
+        let forest = mcts.forest();
+        for (node_ref, stats) in forest.local_iter() {
+            let children_visits: u64 = forest.children(node_ref)
+                .map(|child_ref| forest.get(child_ref).unwrap().item.visits)
+                .sum();
+            assert!(stats.visits >= children_visits);
+        }
+    }
+
+    #[test]
+    fn select_pools_parent_visits() {
+        let state = TicTacToe::new();
+        let mut forest = Forest1::new();
+        let root = forest.make_root(root_stats(&state));
+        let child = |game_move, visits, value| Stats { game_move: Some(game_move), player: 0, visits, value, prior: 0.5, key: None, };
+        let unexplored = forest.make_node(root, child(0, 1, 0.0));
+        let exploited = forest.make_node(root, child(1, 100, 60.0));
+        forest.get_mut(root).unwrap().item.visits = 1;
+        let root_key = state.transposition_key().unwrap();
+        let policy = Policy::Uct { exploration: 1.4, };
+
+        let mut transpositions = Transpositions::new();
+        let tree = BaseTree { forest: &mut forest, transpositions: Some(&mut transpositions), };
+        assert_eq!(select(&tree, policy, root, &mut state.clone()), exploited);
+
+        // the root position was visited many times through other paths
+        transpositions.insert(root_key, (1_000_000, 0.0));
+        let tree = BaseTree { forest: &mut forest, transpositions: Some(&mut transpositions), };
+        assert_eq!(select(&tree, policy, root, &mut state.clone()), unexplored);
+    }
+
+    #[test]
+    fn phases() {
+        let mut mcts = Mcts::new(TicTacToe::new(), Policy::Uct { exploration: 1.4, });
+        let mut state = mcts.root_state().clone();
+        let leaf_ref = mcts.select(&mut state);
+        assert_eq!(leaf_ref, mcts.root());
+        let node_ref = mcts.expand(leaf_ref, &mut state);
+        assert_eq!(mcts.forest().len(), 10);
+        assert_eq!(mcts.forest().get(node_ref).unwrap().depth, 1);
+        let terminal = mcts.simulate(state);
+        assert!(terminal.is_terminal());
+        mcts.backpropagate(node_ref, &terminal);
+        assert_eq!(mcts.forest().get(mcts.root()).unwrap().item.visits, 1);
+        assert_eq!(mcts.forest().get(node_ref).unwrap().item.visits, 1);
+    }
+
+    #[test]
+    fn uct_wins() {
+        // x to move and win with 2
+        let state = TicTacToe::from_moves(&[0, 3, 1, 4]);
+        let mut mcts = Mcts::new(state, Policy::Uct { exploration: 1.4, });
+        mcts.search(2000);
+        assert_eq!(mcts.best_move(), Some(2));
+        check_visits(&mcts);
+    }
+
+    #[test]
+    fn puct_blocks() {
+        // o to move and block at 2
+        let state = TicTacToe::from_moves(&[0, 4, 1]);
+        let mut mcts = Mcts::new(state, Policy::Puct { exploration: 1.5, });
+        mcts.enable_transpositions();
+        mcts.search(4000);
+        assert_eq!(mcts.best_move(), Some(2));
+    }
+
+    #[test]
+    fn par_search_merges_layers() {
+        let state = TicTacToe::from_moves(&[0, 3, 1, 4]);
+        let mut mcts = Mcts::new(state, Policy::Uct { exploration: 1.4, });
+        mcts.set_seed(7);
+        mcts.par_search(4, 500);
+        assert_eq!(mcts.forest().get(mcts.root()).unwrap().item.visits, 2000);
+        check_visits(&mcts);
+        mcts.par_search(4, 500);
+        assert_eq!(mcts.forest().get(mcts.root()).unwrap().item.visits, 4000);
+        check_visits(&mcts);
+        assert_eq!(mcts.best_move(), Some(2));
+    }
+
+    #[test]
+    fn commit_move_keeps_subtree() {
+        let mut mcts = Mcts::new(TicTacToe::new(), Policy::Uct { exploration: 1.4, });
+        mcts.enable_transpositions();
+        mcts.par_search(2, 300);
+        let best_move = mcts.best_move().unwrap();
+        let child_visits = mcts.forest().children(mcts.root())
+            .map(|child_ref| mcts.forest().get(child_ref).unwrap())
+            .find(|node| node.item.game_move == Some(best_move))
+            .unwrap()
+            .item
+            .visits;
+        mcts.commit_move(&best_move);
+        let root = mcts.forest().get(mcts.root()).unwrap();
+        assert_eq!(root.depth, 0);
+        assert_eq!(root.item.visits, child_visits);
+        assert_eq!(mcts.root_state().cells[best_move], Some(0));
+        assert_eq!(mcts.forest().roots().count(), 1);
+        mcts.search(100);
+        check_visits(&mcts);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    fmt::{
+        Write,
+    },
+};
+
+use crate::{
+    set::{
+        Ref,
+    },
+    forest::{
+        Forest1,
+    },
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum NewickError {
+    UnexpectedEnd,
+    UnexpectedChar { position: usize, found: char, },
+    InvalidLength { position: usize, },
+}
+
+// Writes every tree of `roots` as a Newick string terminated by `;`, one tree per line. `node` gives
+// the label and the optional branch length of a node.
+pub fn to_newick<R, I, C, J, N>(roots: I, children: C, node: N) -> String
+    where I: Iterator<Item = R>, C: Fn(R) -> J, J: Iterator<Item = R>, N: Fn(R) -> (String, Option<f64>), R: Clone
+{
+    enum Step<R> {
+        Open(R),
+        Close(R, bool),
+        Separator,
+    }
+
+    let mut out = String::new();
+    for root_ref in roots {
+        let mut stack = vec![Step::Open(root_ref)];
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::Open(node_ref) => {
+                    let children_refs: Vec<_> = children(node_ref.clone()).collect();
+                    stack.push(Step::Close(node_ref, !children_refs.is_empty()));
+                    if !children_refs.is_empty() {
+                        out.push('(');
+                    }
+                    for (index, child_ref) in children_refs.into_iter().enumerate().rev() {
+                        stack.push(Step::Open(child_ref));
+                        if index > 0 {
+                            stack.push(Step::Separator);
+                        }
+                    }
+                },
+                Step::Close(node_ref, has_children) => {
+                    if has_children {
+                        out.push(')');
+                    }
+                    let (label, length) = node(node_ref);
+                    write_label(&mut out, &label);
+                    if let Some(length) = length {
+                        let _ = write!(out, ":{}", length);
+                    }
+                },
+                Step::Separator =>
+                    out.push(','),
+            }
+        }
+        out.push_str(";\n");
+    }
+    out
+}
+
+// `to_newick` for all the trees of a forest.
+pub fn forest_to_newick<T, L, B>(forest: &Forest1<T>, label: L, length: B) -> String
+    where L: Fn(&T) -> String, B: Fn(&T) -> Option<f64>
+{
+    to_newick(
+        forest.roots(),
+        |node_ref| forest.children(node_ref),
+        |node_ref| match forest.get(node_ref) {
+            Some(node) =>
+                (label(node.item), length(node.item)),
+            None =>
+                (String::new(), None),
+        },
+    )
+}
+
+// Parses a sequence of `;` terminated Newick trees into a new forest. `make_item` builds a node item
+// from its label and branch length. Returns the forest along with the refs of the roots.
+pub fn from_newick<T, F>(input: &str, make_item: F) -> Result<(Forest1<T>, Vec<Ref>), NewickError>
+    where F: Fn(&str, Option<f64>) -> T
+{
+    struct Parsed {
+        parent: Option<usize>,
+        label: String,
+        length: Option<f64>,
+    }
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut nodes: Vec<Parsed> = Vec::new();
+    let mut stack: Vec<usize> = Vec::new();
+    let mut current: Option<usize> = None;
+    let mut expect_subtree = true;
+    let mut tree_started = false;
+    let mut position = 0;
+
+    let make_node = |nodes: &mut Vec<Parsed>, stack: &Vec<usize>| {
+        nodes.push(Parsed { parent: stack.last().cloned(), label: String::new(), length: None, });
+        nodes.len() - 1
+    };
+
+    while position < chars.len() {
+        let ch = chars[position];
+        match ch {
+            _ if ch.is_whitespace() =>
+                position += 1,
+            '[' => {
+                while position < chars.len() && chars[position] != ']' {
+                    position += 1;
+                }
+                if position == chars.len() {
+                    return Err(NewickError::UnexpectedEnd);
+                }
+                position += 1;
+            },
+            '(' if expect_subtree => {
+                let node_index = make_node(&mut nodes, &stack);
+                stack.push(node_index);
+                current = None;
+                tree_started = true;
+                position += 1;
+            },
+            ',' | ')' if !stack.is_empty() => {
+                if expect_subtree {
+                    make_node(&mut nodes, &stack);
+                }
+                if ch == ',' {
+                    current = None;
+                    expect_subtree = true;
+                } else {
+                    current = stack.pop();
+                    expect_subtree = false;
+                }
+                position += 1;
+            },
+            ';' if stack.is_empty() => {
+                if !tree_started {
+                    make_node(&mut nodes, &stack);
+                }
+                current = None;
+                expect_subtree = true;
+                tree_started = false;
+                position += 1;
+            },
+            ':' if expect_subtree || current.is_some_and(|node_index| nodes[node_index].length.is_none()) => {
+                let node_index = match current {
+                    Some(node_index) =>
+                        node_index,
+                    None =>
+                        make_node(&mut nodes, &stack),
+                };
+                tree_started = true;
+                let start = position + 1;
+                position = start;
+                while position < chars.len() && !is_delimiter(chars[position]) {
+                    position += 1;
+                }
+                let length_str: String = chars[start .. position].iter().collect();
+                let length = length_str.trim().parse().map_err(|_| NewickError::InvalidLength { position: start, })?;
+                nodes[node_index].length = Some(length);
+                current = Some(node_index);
+                expect_subtree = false;
+            },
+            '(' | ')' | ',' | ';' | ':' =>
+                return Err(NewickError::UnexpectedChar { position, found: ch, }),
+            _ if expect_subtree || current.is_some_and(|node_index| nodes[node_index].label.is_empty() && nodes[node_index].length.is_none()) => {
+                let node_index = match current {
+                    Some(node_index) =>
+                        node_index,
+                    None =>
+                        make_node(&mut nodes, &stack),
+                };
+                tree_started = true;
+                let (label, next_position) = read_label(&chars, position)?;
+                nodes[node_index].label = label;
+                position = next_position;
+                current = Some(node_index);
+                expect_subtree = false;
+            },
+            _ =>
+                return Err(NewickError::UnexpectedChar { position, found: ch, }),
+        }
+    }
+    if !stack.is_empty() || tree_started {
+        return Err(NewickError::UnexpectedEnd);
+    }
+
+    let mut forest = Forest1::with_capacity(nodes.len());
+    let mut refs: Vec<Ref> = Vec::with_capacity(nodes.len());
+    let mut roots = Vec::new();
+    for parsed in nodes {
+        let item = make_item(&parsed.label, parsed.length);
+        let node_ref = match parsed.parent {
+            Some(parent_index) =>
+                forest.make_node(refs[parent_index], item),
+            None => {
+                let root_ref = forest.make_root(item);
+                roots.push(root_ref);
+                root_ref
+            },
+        };
+        refs.push(node_ref);
+    }
+    Ok((forest, roots))
+}
+
+fn is_delimiter(ch: char) -> bool {
+    matches!(ch, '(' | ')' | ',' | ':' | ';' | '[') || ch.is_whitespace()
+}
+
+fn read_label(chars: &[char], mut position: usize) -> Result<(String, usize), NewickError> {
+    let mut label = String::new();
+    if chars[position] == '\'' {
+        position += 1;
+        loop {
+            match chars.get(position) {
+                None =>
+                    return Err(NewickError::UnexpectedEnd),
+                Some('\'') if chars.get(position + 1) == Some(&'\'') => {
+                    label.push('\'');
+                    position += 2;
+                },
+                Some('\'') =>
+                    return Ok((label, position + 1)),
+                Some(&ch) => {
+                    label.push(ch);
+                    position += 1;
+                },
+            }
+        }
+    }
+    while position < chars.len() && !is_delimiter(chars[position]) && chars[position] != '\'' {
+        label.push(chars[position]);
+        position += 1;
+    }
+    Ok((label, position))
+}
+
+fn write_label(out: &mut String, label: &str) {
+    if label.chars().any(|ch| is_delimiter(ch) || matches!(ch, '\'' | ']')) {
+        out.push('\'');
+        out.push_str(&label.replace('\'', "''"));
+        out.push('\'');
+    } else {
+        out.push_str(label);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        NewickError,
+        from_newick,
+        forest_to_newick,
+    };
+
+    fn roundtrip(input: &str) -> String {
+        let (forest, _) = from_newick(input, |label, length| (label.to_string(), length)).unwrap();
+        forest_to_newick(&forest, |item| item.0.clone(), |item| item.1)
+    }
+
+    #[test]
+    fn parse_tree() {
+        let (forest, roots) = from_newick("((A:0.1,B:0.2)AB:0.5,'C d':1)root;", |label, length| (label.to_string(), length)).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(forest.len(), 5);
+        let root = forest.get(roots[0]).unwrap();
+        assert_eq!(root.item, &("root".to_string(), None));
+        let labels: Vec<_> = forest.children(roots[0]).map(|r| forest.get(r).unwrap().item.clone()).collect();
+        assert_eq!(labels, vec![("AB".to_string(), Some(0.5)), ("C d".to_string(), Some(1.0))]);
+        let leaf = forest.leaves().find(|&r| forest.get(r).unwrap().item.0 == "B").unwrap();
+        assert_eq!(forest.get(leaf).unwrap().depth, 2);
+        assert_eq!(forest.get(leaf).unwrap().item.1, Some(0.2));
+    }
+
+    #[test]
+    fn roundtrips() {
+        assert_eq!(roundtrip("((A:0.1,B:0.2)AB:0.5,'C d':1)root;"), "((A:0.1,B:0.2)AB:0.5,'C d':1)root;\n");
+        assert_eq!(roundtrip("(,(,));\n[comment] (x,'it''s')y;"), "(,(,));\n(x,'it''s')y;\n");
+        assert_eq!(roundtrip("A;"), "A;\n");
+    }
+
+    #[test]
+    fn errors() {
+        let make_item = |label: &str, _| label.to_string();
+        assert_eq!(from_newick("(A,B", make_item).err(), Some(NewickError::UnexpectedEnd));
+        assert_eq!(from_newick("(A,B)", make_item).err(), Some(NewickError::UnexpectedEnd));
+        assert_eq!(from_newick("(A,B));", make_item).err(), Some(NewickError::UnexpectedChar { position: 5, found: ')', }));
+        assert_eq!(from_newick("(A:x,B);", make_item).err(), Some(NewickError::InvalidLength { position: 3, }));
+        assert_eq!(from_newick("A:1:2;", make_item).err(), Some(NewickError::UnexpectedChar { position: 3, found: ':', }));
+        assert_eq!(from_newick("A B;", make_item).err(), Some(NewickError::UnexpectedChar { position: 2, found: 'B', }));
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    ops::{
+        Add,
+    },
+    hash::{
+        Hash,
+    },
+    collections::{
+        HashMap,
+        HashSet,
+    },
+};
+
+use crate::{
+    set::{
+        Ref,
+    },
+    heap::{
+        RefHeap,
+    },
+    forest::{
+        Ref2,
+        Node,
+        Forest1,
+        Forest2,
+        PathOrder,
+        TowardsRootIter,
+    },
+};
+
+// Search space description.
+pub trait Problem {
+    type State: Clone + Eq + Hash;
+    type Cost: Copy + Ord + Default + Add<Output = Self::Cost>;
+
+    fn successors(&self, state: &Self::State) -> Vec<(Self::State, Self::Cost)>;
+
+    fn is_goal(&self, state: &Self::State) -> bool;
+
+    // estimated cost to the closest goal, it should never overestimate for A* and IDA* to be optimal
+    fn heuristic(&self, _state: &Self::State) -> Self::Cost {
+        Self::Cost::default()
+    }
+}
+
+// Search node stored in the forest: `cost` is the path cost from the start.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SearchNode<S, C> {
+    pub state: S,
+    pub cost: C,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Solution<S, C> {
+    // states from the start to the goal
+    pub path: Vec<S>,
+    pub cost: C,
+    pub expanded: usize,
+}
+
+pub fn astar<P>(problem: &P, start: P::State) -> Option<Solution<P::State, P::Cost>> where P: Problem {
+    best_first(problem, start, |state| problem.heuristic(state))
+}
+
+pub fn dijkstra<P>(problem: &P, start: P::State) -> Option<Solution<P::State, P::Cost>> where P: Problem {
+    best_first(problem, start, |_| P::Cost::default())
+}
+
+fn best_first<P, H>(problem: &P, start: P::State, heuristic: H) -> Option<Solution<P::State, P::Cost>>
+    where P: Problem, H: Fn(&P::State) -> P::Cost
+{
+    let mut forest = Forest1::new();
+    // moving an open node finds its (empty) subtree through the index instead of a scan of the forest
+    forest.enable_child_index();
+    let mut frontier = RefHeap::new();
+    // heap handles of the open nodes
+    let mut open: HashMap<Ref, Ref> = HashMap::new();
+    let mut best: HashMap<P::State, Ref> = HashMap::new();
+    let mut expanded = 0;
+
+    let start_h = heuristic(&start);
+    let start_ref = forest.make_root(SearchNode { state: start.clone(), cost: P::Cost::default(), });
+    best.insert(start, start_ref);
+    open.insert(start_ref, frontier.push((start_h, start_h), start_ref));
+
+    while let Some((_, node_ref)) = frontier.pop() {
+        open.remove(&node_ref);
+        let (state, cost) = match forest.get(node_ref) {
+            Some(node) =>
+                (node.item.state.clone(), node.item.cost),
+            None =>
+                continue,
+        };
+        if problem.is_goal(&state) {
+            return Some(Solution { path: path_to(&forest, node_ref), cost, expanded, });
+        }
+        expanded += 1;
+        for (next_state, step_cost) in problem.successors(&state) {
+            let next_cost = cost + step_cost;
+            let next_h = heuristic(&next_state);
+            let priority = (next_cost + next_h, next_h);
+            let known = best.get(&next_state)
+                .and_then(|&next_ref| forest.get(next_ref).map(|node| (next_ref, node.item.cost)));
+            match known {
+                Some((_, known_cost)) if known_cost <= next_cost =>
+                    (),
+                // a better path to an open node (always a leaf) is found: move it under the current one
+                Some((next_ref, _)) if open.contains_key(&next_ref) => {
+                    if forest.reparent(next_ref, node_ref).is_ok() {
+                        if let Some(node) = forest.get_mut(next_ref) {
+                            node.item.cost = next_cost;
+                        }
+                        reopen(&mut frontier, &mut open, next_ref, priority);
+                    }
+                },
+                // a closed node has descendants costed through the old path, so it is opened again
+                // as a fresh node and its subtree is rebuilt with the new costs
+                _ => {
+                    let next_ref = forest.make_node(node_ref, SearchNode { state: next_state.clone(), cost: next_cost, });
+                    best.insert(next_state, next_ref);
+                    reopen(&mut frontier, &mut open, next_ref, priority);
+                },
+            }
+        }
+    }
+    None
+}
+
+// Keeps `width` best nodes (by cost plus heuristic) of each level. Candidates of a level are made in a `Forest2`
+// layer over the survivors: survivors are moved down to the base and the layer with the pruned ones is dropped at once.
+pub fn beam<P>(problem: &P, start: P::State, width: usize) -> Option<Solution<P::State, P::Cost>> where P: Problem {
+    let mut forest = Forest1::new();
+    let mut seen = HashSet::new();
+    let mut expanded = 0;
+
+    seen.insert(start.clone());
+    let mut level = vec![forest.make_root(SearchNode { state: start, cost: P::Cost::default(), })];
+    while !level.is_empty() {
+        let goal = level.iter()
+            .flat_map(|&node_ref| forest.get(node_ref).map(|node| (node.item.cost, node_ref, node.item)))
+            .filter(|(_, _, search_node)| problem.is_goal(&search_node.state))
+            .min_by_key(|pair| pair.0);
+        if let Some((cost, node_ref, _)) = goal {
+            return Some(Solution { path: path_to(&forest, node_ref), cost, expanded, });
+        }
+
+        let mut candidates = Forest2::new();
+        let mut scored = Vec::new();
+        for &node_ref in &level {
+            let (state, cost) = match forest.get(node_ref) {
+                Some(node) =>
+                    (node.item.state.clone(), node.item.cost),
+                None =>
+                    continue,
+            };
+            expanded += 1;
+            for (next_state, step_cost) in problem.successors(&state) {
+                if seen.contains(&next_state) {
+                    continue;
+                }
+                let next_cost = cost + step_cost;
+                let score = next_cost + problem.heuristic(&next_state);
+                let next_ref = candidates.insert(Node {
+                    item: SearchNode { state: next_state, cost: next_cost, },
+                    parent: Some(Ref2::External(node_ref)),
+                    depth: forest.get(node_ref).map_or(0, |node| node.depth + 1),
+                });
+                scored.push((score, next_ref));
+            }
+        }
+        scored.sort_by_key(|pair| pair.0);
+
+        level.clear();
+        for (_, candidate_ref) in scored {
+            if level.len() >= width {
+                break;
+            }
+            let (item, parent, depth) = match candidates.remove(|_| None, candidate_ref) {
+                Some(Node { item: Some(item), parent, depth, }) =>
+                    (item, parent, depth),
+                _ =>
+                    continue,
+            };
+            if !seen.insert(item.state.clone()) {
+                continue;
+            }
+            let parent = match parent {
+                Some(Ref2::External(parent_ref)) =>
+                    Some(parent_ref),
+                _ =>
+                    None,
+            };
+            level.push(forest.insert(Node { item, parent, depth, }));
+        }
+        drop(candidates);
+    }
+    None
+}
+
+// Iterative deepening A*: depth first passes bounded by the cost plus heuristic threshold. The forest holds
+// the current path along with pending siblings only.
+pub fn ida_star<P>(problem: &P, start: P::State) -> Option<Solution<P::State, P::Cost>> where P: Problem {
+    let mut forest = Forest1::new();
+    forest.enable_child_index();
+    let mut expanded = 0;
+    let mut threshold = problem.heuristic(&start);
+
+    loop {
+        let mut next_threshold = None;
+        let mut stack = vec![forest.make_root(SearchNode { state: start.clone(), cost: P::Cost::default(), })];
+        while let Some(node_ref) = stack.pop() {
+            let (state, cost) = match forest.get(node_ref) {
+                Some(node) =>
+                    (node.item.state.clone(), node.item.cost),
+                None =>
+                    continue,
+            };
+            let estimate = cost + problem.heuristic(&state);
+            if estimate > threshold {
+                next_threshold = Some(next_threshold.map_or(estimate, |value: P::Cost| value.min(estimate)));
+                prune_path(&mut forest, node_ref);
+                continue;
+            }
+            if problem.is_goal(&state) {
+                return Some(Solution { path: path_to(&forest, node_ref), cost, expanded, });
+            }
+            expanded += 1;
+            let mut children = 0;
+            for (next_state, step_cost) in problem.successors(&state) {
+                let on_path = TowardsRootIter::new(|r| forest.get(r), node_ref)
+                    .any(|node| node.item.state == next_state);
+                if !on_path {
+                    stack.push(forest.make_node(node_ref, SearchNode { state: next_state, cost: cost + step_cost, }));
+                    children += 1;
+                }
+            }
+            if children == 0 {
+                prune_path(&mut forest, node_ref);
+            }
+        }
+        match next_threshold {
+            Some(value) =>
+                threshold = value,
+            None =>
+                return None,
+        }
+    }
+}
+
+// Pushes a node to the frontier or lowers its priority if it is already open.
+fn reopen<K>(frontier: &mut RefHeap<K, Ref>, open: &mut HashMap<Ref, Ref>, node_ref: Ref, priority: K) where K: Ord {
+    match open.get(&node_ref) {
+        Some(&heap_ref) => {
+            frontier.decrease_priority(heap_ref, priority);
+        },
+        None => {
+            open.insert(node_ref, frontier.push(priority, node_ref));
+        },
+    }
+}
+
+// Removes a finished node along with the ancestors left without children.
+fn prune_path<T>(forest: &mut Forest1<T>, node_ref: Ref) {
+    let mut cursor = Some(node_ref);
+    while let Some(cursor_ref) = cursor {
+        if forest.children(cursor_ref).next().is_some() {
+            break;
+        }
+        cursor = forest.get(cursor_ref).and_then(|node| node.parent);
+        forest.remove(cursor_ref);
+    }
+}
+
+fn path_to<S, C>(forest: &Forest1<SearchNode<S, C>>, node_ref: Ref) -> Vec<S> where S: Clone {
+    forest.path_to_root(node_ref, PathOrder::RootFirst)
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|path_ref| forest.get(path_ref))
+        .map(|node| node.item.state.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Problem,
+        astar,
+        dijkstra,
+        beam,
+        ida_star,
+    };
+
+    struct Grid {
+        rows: Vec<&'static [u8]>,
+        goal: (i32, i32),
+    }
+
+    impl Problem for Grid {
+        type State = (i32, i32);
+        type Cost = u32;
+
+        fn successors(&self, &(x, y): &(i32, i32)) -> Vec<((i32, i32), u32)> {
+            [(1, 0), (-1, 0), (0, 1), (0, -1)].iter()
+                .map(|&(dx, dy)| (x + dx, y + dy))
+                .filter(|&(x, y)| y >= 0 && x >= 0 && (y as usize) < self.rows.len() && (x as usize) < self.rows[y as usize].len())
+                .filter(|&(x, y)| self.rows[y as usize][x as usize] != b'#')
+                .map(|state| (state, 1))
+                .collect()
+        }
+
+        fn is_goal(&self, state: &(i32, i32)) -> bool {
+            *state == self.goal
+        }
+
+        fn heuristic(&self, &(x, y): &(i32, i32)) -> u32 {
+            ((x - self.goal.0).abs() + (y - self.goal.1).abs()) as u32
+        }
+    }
+
+    fn grid() -> Grid {
+        Grid {
+            rows: vec![
+                b"..........",
+                b".########.",
+                b".#......#.",
+                b".#.####.#.",
+                b"...#..#...",
+                b"####..####",
+            ],
+            goal: (4, 2),
+        }
+    }
+
+    fn check_path(problem: &Grid, path: &[(i32, i32)]) {
+        assert_eq!(path[0], (0, 0));
+        assert_eq!(*path.last().unwrap(), problem.goal);
+        for pair in path.windows(2) {
+            assert!(problem.successors(&pair[0]).iter().any(|&(state, _)| state == pair[1]));
+        }
+    }
+
+    #[test]
+    fn grid_astar_dijkstra_ida() {
+        let problem = grid();
+        let astar_solution = astar(&problem, (0, 0)).unwrap();
+        let dijkstra_solution = dijkstra(&problem, (0, 0)).unwrap();
+        let ida_solution = ida_star(&problem, (0, 0)).unwrap();
+        assert_eq!(astar_solution.cost, 10);
+        assert_eq!(dijkstra_solution.cost, 10);
+        assert_eq!(ida_solution.cost, 10);
+        assert!(astar_solution.expanded <= dijkstra_solution.expanded);
+        for solution in [astar_solution, dijkstra_solution, ida_solution] {
+            assert_eq!(solution.path.len(), 11);
+            check_path(&problem, &solution.path);
+        }
+    }
+
+    #[test]
+    fn grid_beam() {
+        let problem = grid();
+        let solution = beam(&problem, (0, 0), 4).unwrap();
+        assert_eq!(solution.cost, 10);
+        check_path(&problem, &solution.path);
+        assert!(beam(&problem, (0, 0), 1).is_none_or(|solution| solution.cost >= 10));
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let mut problem = grid();
+        problem.goal = (4, 4);
+        assert!(astar(&problem, (0, 0)).is_none());
+        assert!(ida_star(&problem, (0, 0)).is_none());
+        assert!(beam(&problem, (0, 0), 3).is_none());
+    }
+
+    // the direct edge to `b` is found first and then improved through `a`
+    struct Weighted;
+
+    impl Problem for Weighted {
+        type State = char;
+        type Cost = u32;
+
+        fn successors(&self, state: &char) -> Vec<(char, u32)> {
+            match state {
+                's' =>
+                    vec![('b', 10), ('a', 1)],
+                'a' =>
+                    vec![('b', 2)],
+                'b' =>
+                    vec![('c', 1)],
+                'c' =>
+                    vec![('g', 1)],
+                _ =>
+                    vec![],
+            }
+        }
+
+        fn is_goal(&self, state: &char) -> bool {
+            *state == 'g'
+        }
+    }
+
+    #[test]
+    fn decrease_key() {
+        let solution = dijkstra(&Weighted, 's').unwrap();
+        assert_eq!(solution.cost, 5);
+        assert_eq!(solution.path, vec!['s', 'a', 'b', 'c', 'g']);
+        assert_eq!(ida_star(&Weighted, 's').unwrap().cost, 5);
+    }
+
+    // `b` is expanded through the direct edge before the inconsistent heuristic lets `a` improve it
+    struct Inconsistent;
+
+    impl Problem for Inconsistent {
+        type State = char;
+        type Cost = u32;
+
+        fn successors(&self, state: &char) -> Vec<(char, u32)> {
+            match state {
+                's' =>
+                    vec![('a', 1), ('b', 4)],
+                'a' =>
+                    vec![('b', 1)],
+                'b' =>
+                    vec![('c', 1)],
+                'c' =>
+                    vec![('g', 7)],
+                _ =>
+                    vec![],
+            }
+        }
+
+        fn is_goal(&self, state: &char) -> bool {
+            *state == 'g'
+        }
+
+        fn heuristic(&self, state: &char) -> u32 {
+            match state {
+                'a' =>
+                    10,
+                'b' =>
+                    5,
+                _ =>
+                    0,
+            }
+        }
+    }
+
+    #[test]
+    fn reopen_closed() {
+        let solution = astar(&Inconsistent, 's').unwrap();
+        assert_eq!(solution.path, vec!['s', 'a', 'b', 'c', 'g']);
+        assert_eq!(solution.cost, 10);
+        assert_eq!(solution.expanded, 6);
+    }
+}
@@ -200,6 +200,21 @@ impl<T> Set<T> {
             })
     }
 
+    // Takes all the items out leaving the set empty.
+    pub fn drain(&mut self) -> impl Iterator<Item = (Ref, T)> + '_ {
+        let set_uid = self.uid;
+        self.free.clear();
+        self.len = 0;
+        self.cells.drain(..)
+            .enumerate()
+            .flat_map(move |(index, cell)| match cell.state {
+                CellState::Regular { item: Some(item), } =>
+                    Some((Ref { index, set_uid, serial: cell.serial, }, item)),
+                _ =>
+                    None,
+            })
+    }
+
     fn insert_empty(&mut self) -> Ref {
         self.serial += 1;
         let serial = self.serial;
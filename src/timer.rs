@@ -0,0 +1,342 @@
+use crate::{
+    set::{
+        Ref,
+        Set,
+    },
+    dll::{
+        List,
+    },
+};
+
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+// enough levels to cover the whole u64 tick range
+const LEVELS: usize = 64_usize.div_ceil(SLOT_BITS);
+// list of timers already due when scheduled
+const DUE: usize = LEVELS * SLOTS;
+
+// Source of the current tick for `TimerWheel::poll`.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+// Clock moved by hand, for deterministic tests and simulations.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ManualClock {
+    now: u64,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> ManualClock {
+        ManualClock { now, }
+    }
+
+    pub fn set(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    pub fn advance(&mut self, ticks: u64) -> u64 {
+        self.now = self.now.saturating_add(ticks);
+        self.now
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now
+    }
+}
+
+// Hierarchical timer wheel: level `n` has 64 slots of 64^n ticks each, a timer sits in the level
+// of the highest 6 bit block where its deadline differs from the current tick. Scheduling and
+// cancelling are O(1), timers move down at most once per level before they fire.
+pub struct TimerWheel<T> {
+    timers: Set<Timer<T>>,
+    slots: Vec<List<Ref>>,
+    // bit masks of nonempty slots per level
+    occupied: [u64; LEVELS],
+    now: u64,
+}
+
+struct Timer<T> {
+    item: T,
+    deadline: u64,
+    slot: usize,
+    link: Ref,
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new() -> TimerWheel<T> {
+        TimerWheel::starting_at(0)
+    }
+
+    pub fn starting_at(now: u64) -> TimerWheel<T> {
+        TimerWheel {
+            timers: Set::new(),
+            slots: (0 ..= DUE).map(|_| List::new()).collect(),
+            occupied: [0; LEVELS],
+            now,
+        }
+    }
+
+    // Tick the wheel was last advanced to.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    // Number of pending timers.
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    pub fn get(&self, timer_ref: Ref) -> Option<&T> {
+        self.timers.get(timer_ref).map(|timer| &timer.item)
+    }
+
+    pub fn get_mut(&mut self, timer_ref: Ref) -> Option<&mut T> {
+        self.timers.get_mut(timer_ref).map(|timer| &mut timer.item)
+    }
+
+    pub fn deadline(&self, timer_ref: Ref) -> Option<u64> {
+        self.timers.get(timer_ref).map(|timer| timer.deadline)
+    }
+
+    // Schedules `item` to fire at `deadline`, at the next `advance` if it is not after the current tick.
+    pub fn schedule(&mut self, deadline: u64, item: T) -> Ref {
+        let slot = self.slot_of(deadline);
+        let slots = &mut self.slots;
+        let timer_ref = self.timers.insert_with(|timer_ref| {
+            let link = slots[slot].push_back(timer_ref);
+            Timer { item, deadline, slot, link, }
+        });
+        self.mark(slot);
+        timer_ref
+    }
+
+    pub fn cancel(&mut self, timer_ref: Ref) -> Option<T> {
+        self.unlink(timer_ref)?;
+        self.timers.remove(timer_ref).map(|timer| timer.item)
+    }
+
+    // Moves a pending timer to a new deadline keeping its handle.
+    pub fn reschedule(&mut self, timer_ref: Ref, deadline: u64) -> bool {
+        if self.unlink(timer_ref).is_none() {
+            return false;
+        }
+        if let Some(timer) = self.timers.get_mut(timer_ref) {
+            timer.deadline = deadline;
+        }
+        self.link(timer_ref);
+        true
+    }
+
+    // Moves the wheel to `now` returning the items of all the timers expired by then, ordered by
+    // deadline. Time never goes backwards: an earlier `now` only fires the timers already due.
+    pub fn advance(&mut self, now: u64) -> Vec<(u64, T)> {
+        let mut expired = Vec::new();
+        self.drain_slot(DUE, &mut expired);
+        expired.sort_by_key(|pair| pair.0);
+        while let Some((level, index)) = self.next_slot() {
+            let start = slot_start(self.now, level, index);
+            if start > now {
+                break;
+            }
+            self.now = start;
+            self.drain_slot(level * SLOTS + index, &mut expired);
+        }
+        self.now = self.now.max(now);
+        expired
+    }
+
+    // Advances the wheel to the current tick of `clock`.
+    pub fn poll<C>(&mut self, clock: &C) -> Vec<(u64, T)> where C: Clock {
+        self.advance(clock.now())
+    }
+
+    // Fires the timers of a slot due at the current tick and moves the rest down the levels.
+    fn drain_slot(&mut self, slot: usize, expired: &mut Vec<(u64, T)>) {
+        let mut timer_refs = Vec::with_capacity(self.slots[slot].len());
+        while let Some(timer_ref) = self.slots[slot].pop_front() {
+            timer_refs.push(timer_ref);
+        }
+        self.unmark(slot);
+        for timer_ref in timer_refs {
+            match self.timers.get(timer_ref) {
+                Some(timer) if timer.deadline <= self.now || slot == DUE => {
+                    if let Some(timer) = self.timers.remove(timer_ref) {
+                        expired.push((timer.deadline, timer.item));
+                    }
+                },
+                Some(..) =>
+                    self.link(timer_ref),
+                None =>
+                    (),
+            }
+        }
+    }
+
+    // Lowest nonempty slot: slots of lower levels always expire before the ones of upper levels.
+    fn next_slot(&self) -> Option<(usize, usize)> {
+        self.occupied.iter()
+            .enumerate()
+            .find(|&(_, &mask)| mask != 0)
+            .map(|(level, &mask)| (level, mask.trailing_zeros() as usize))
+    }
+
+    fn slot_of(&self, deadline: u64) -> usize {
+        if deadline <= self.now {
+            return DUE;
+        }
+        let significant = 63 - ((deadline ^ self.now) | (SLOTS as u64 - 1)).leading_zeros() as usize;
+        let level = significant / SLOT_BITS;
+        let index = (deadline >> (level * SLOT_BITS)) as usize & (SLOTS - 1);
+        level * SLOTS + index
+    }
+
+    fn link(&mut self, timer_ref: Ref) {
+        let deadline = match self.timers.get(timer_ref) {
+            Some(timer) =>
+                timer.deadline,
+            None =>
+                return,
+        };
+        let slot = self.slot_of(deadline);
+        let link = self.slots[slot].push_back(timer_ref);
+        if let Some(timer) = self.timers.get_mut(timer_ref) {
+            timer.slot = slot;
+            timer.link = link;
+        }
+        self.mark(slot);
+    }
+
+    fn unlink(&mut self, timer_ref: Ref) -> Option<()> {
+        let timer = self.timers.get(timer_ref)?;
+        let slot = timer.slot;
+        self.slots[slot].remove(timer.link)?;
+        if self.slots[slot].is_empty() {
+            self.unmark(slot);
+        }
+        Some(())
+    }
+
+    fn mark(&mut self, slot: usize) {
+        if slot != DUE {
+            self.occupied[slot / SLOTS] |= 1 << (slot % SLOTS);
+        }
+    }
+
+    fn unmark(&mut self, slot: usize) {
+        if slot != DUE {
+            self.occupied[slot / SLOTS] &= !(1 << (slot % SLOTS));
+        }
+    }
+}
+
+// First tick of slot `index` of `level` in the current rotation of that level.
+fn slot_start(now: u64, level: usize, index: usize) -> u64 {
+    let shift = level * SLOT_BITS;
+    let rotation = shift + SLOT_BITS;
+    let high = if rotation >= 64 { 0 } else { now & !((1 << rotation) - 1) };
+    high | ((index as u64) << shift)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{
+            BTreeMap,
+        },
+    };
+
+    use rand::{self, Rng};
+
+    use super::{
+        Clock,
+        ManualClock,
+        TimerWheel,
+    };
+
+    #[test]
+    fn schedule_cancel_reschedule() {
+        let mut clock = ManualClock::new(100);
+        let mut wheel = TimerWheel::starting_at(clock.now());
+        let a = wheel.schedule(105, "a");
+        let b = wheel.schedule(170, "b");
+        let c = wheel.schedule(100_000, "c");
+        wheel.schedule(90, "late");
+        let d = wheel.schedule(105, "d");
+        assert_eq!(wheel.len(), 5);
+        assert_eq!(wheel.poll(&clock), vec![(90, "late")]);
+
+        clock.advance(5);
+        assert_eq!(wheel.poll(&clock), vec![(105, "a"), (105, "d")]);
+        assert_eq!(wheel.cancel(a), None);
+        assert_eq!(wheel.deadline(d), None);
+        assert!(wheel.reschedule(b, 120));
+        assert_eq!(wheel.cancel(c), Some("c"));
+        assert!(!wheel.reschedule(c, 200));
+        assert_eq!(wheel.advance(119), vec![]);
+        assert_eq!(wheel.advance(150), vec![(120, "b")]);
+        assert_eq!(wheel.now(), 150);
+        assert!(wheel.is_empty());
+
+        let far = wheel.schedule(u64::MAX, "far");
+        wheel.schedule(1 << 40, "mid");
+        assert_eq!(wheel.get(far), Some(&"far"));
+        assert_eq!(wheel.advance(1 << 41), vec![(1 << 40, "mid")]);
+        assert_eq!(wheel.advance(u64::MAX), vec![(u64::MAX, "far")]);
+    }
+
+    #[test]
+    fn stress() {
+        let mut clock = ManualClock::default();
+        let mut wheel = TimerWheel::new();
+        // model keyed by (deadline, sequence number)
+        let mut model = BTreeMap::new();
+        let mut refs = Vec::new();
+        let mut rng = rand::thread_rng();
+
+        for sequence in 0 .. 16384u64 {
+            let choice = rng.gen_range(0 .. 100);
+            if choice < 50 {
+                let span = [64, 4096, 1 << 20][rng.gen_range(0 .. 3)];
+                let deadline = clock.now() + rng.gen_range(0 .. span);
+                refs.push((wheel.schedule(deadline, sequence), (deadline, sequence)));
+                model.insert((deadline, sequence), sequence);
+            } else if choice < 60 && !refs.is_empty() {
+                let index = rng.gen_range(0 .. refs.len());
+                let (timer_ref, key) = refs.swap_remove(index);
+                assert_eq!(wheel.cancel(timer_ref), model.remove(&key));
+            } else if choice < 70 && !refs.is_empty() {
+                let index = rng.gen_range(0 .. refs.len());
+                let deadline = clock.now() + rng.gen_range(0 .. 8192);
+                let (timer_ref, key) = refs[index];
+                let pending = model.remove(&key);
+                assert_eq!(wheel.reschedule(timer_ref, deadline), pending.is_some());
+                if let Some(item) = pending {
+                    model.insert((deadline, key.1), item);
+                    refs[index].1 = (deadline, key.1);
+                }
+            } else {
+                clock.advance(rng.gen_range(0 .. 2048));
+                let mut fired = wheel.poll(&clock);
+                fired.sort();
+                let rest = model.split_off(&(clock.now() + 1, 0));
+                let expected: Vec<_> = model.into_iter().map(|((deadline, _), item)| (deadline, item)).collect();
+                model = rest;
+                assert_eq!(fired, expected);
+            }
+            assert_eq!(wheel.len(), model.len());
+        }
+    }
+}
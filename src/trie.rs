@@ -0,0 +1,329 @@
+use std::{
+    mem,
+    iter,
+};
+
+use crate::{
+    layers,
+    set::{
+        Ref,
+    },
+    forest::{
+        Ref2,
+        Forest1,
+        Forest2,
+    },
+    merge::{
+        merge_no_transform,
+    },
+};
+
+// Trie node stored in the forest.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TrieNode<K, V> {
+    // symbol of the edge leading to the node, none for the root
+    pub symbol: Option<K>,
+    pub value: Option<V>,
+}
+
+// Prefix tree with a forest node per symbol. Refs of nodes stay valid until the nodes are pruned,
+// so they can be held as cursors.
+pub struct Trie<K, V> {
+    forest: Forest1<TrieNode<K, V>>,
+    root: Ref,
+    len: usize,
+}
+
+impl<K, V> Trie<K, V> where K: Clone + Eq {
+    pub fn new() -> Trie<K, V> {
+        let mut forest = Forest1::new();
+        forest.enable_child_index();
+        let root = forest.make_root(TrieNode { symbol: None, value: None, });
+        Trie { forest, root, len: 0, }
+    }
+
+    // Number of stored values.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn forest(&self) -> &Forest1<TrieNode<K, V>> {
+        &self.forest
+    }
+
+    pub fn root(&self) -> Ref {
+        self.root
+    }
+
+    // Child of the cursor along `symbol`.
+    pub fn child(&self, node_ref: Ref, symbol: &K) -> Option<Ref> {
+        self.forest.children(node_ref)
+            .find(|&child_ref| self.forest.get(child_ref).and_then(|node| node.item.symbol.as_ref()) == Some(symbol))
+    }
+
+    // Node of `key`, whether or not it holds a value.
+    pub fn find(&self, key: &[K]) -> Option<Ref> {
+        key.iter().try_fold(self.root, |node_ref, symbol| self.child(node_ref, symbol))
+    }
+
+    // Stores `value` under `key` returning the node of the key and the value it replaced.
+    pub fn insert(&mut self, key: &[K], value: V) -> (Ref, Option<V>) {
+        let mut node_ref = self.root;
+        for symbol in key {
+            node_ref = match self.child(node_ref, symbol) {
+                Some(child_ref) =>
+                    child_ref,
+                None =>
+                    self.forest.make_node(node_ref, TrieNode { symbol: Some(symbol.clone()), value: None, }),
+            };
+        }
+        let prev_value = self.forest.get_mut(node_ref).and_then(|node| node.item.value.replace(value));
+        if prev_value.is_none() {
+            self.len += 1;
+        }
+        (node_ref, prev_value)
+    }
+
+    pub fn get(&self, key: &[K]) -> Option<&V> {
+        self.find(key).and_then(|node_ref| self.value(node_ref))
+    }
+
+    pub fn get_mut(&mut self, key: &[K]) -> Option<&mut V> {
+        let node_ref = self.find(key)?;
+        self.value_mut(node_ref)
+    }
+
+    // Value at a cursor.
+    pub fn value(&self, node_ref: Ref) -> Option<&V> {
+        self.forest.get(node_ref).and_then(|node| node.item.value.as_ref())
+    }
+
+    pub fn value_mut(&mut self, node_ref: Ref) -> Option<&mut V> {
+        self.forest.get_mut(node_ref).and_then(|node| node.item.value.as_mut())
+    }
+
+    // Key leading to a cursor.
+    pub fn key(&self, node_ref: Ref) -> Option<Vec<K>> {
+        let depth = self.forest.get(node_ref)?.depth;
+        let mut key = Vec::with_capacity(depth);
+        let mut cursor = Some(node_ref);
+        while let Some(node) = cursor.and_then(|node_ref| self.forest.get(node_ref)) {
+            key.extend(node.item.symbol.clone());
+            cursor = node.parent;
+        }
+        key.reverse();
+        Some(key)
+    }
+
+    // Value of the longest prefix of `key` having one, along with the length of the prefix.
+    pub fn longest_prefix(&self, key: &[K]) -> Option<(usize, &V)> {
+        let mut found = self.value(self.root).map(|value| (0, value));
+        let mut node_ref = self.root;
+        for (index, symbol) in key.iter().enumerate() {
+            node_ref = match self.child(node_ref, symbol) {
+                Some(child_ref) =>
+                    child_ref,
+                None =>
+                    break,
+            };
+            if let Some(value) = self.value(node_ref) {
+                found = Some((index + 1, value));
+            }
+        }
+        found
+    }
+
+    // Nodes holding values for all the keys starting with `prefix` in depth first order, see `key`.
+    pub fn prefix_iter(&self, prefix: &[K]) -> impl Iterator<Item = (Ref, &V)> + '_ {
+        self.find(prefix)
+            .into_iter()
+            .flat_map(move |node_ref| iter::once(node_ref).chain(self.forest.descendants_dfs(node_ref)))
+            .flat_map(move |node_ref| self.value(node_ref).map(|value| (node_ref, value)))
+    }
+
+    // Removes the value of `key` along with the nodes left without values and children.
+    pub fn remove(&mut self, key: &[K]) -> Option<V> {
+        let node_ref = self.find(key)?;
+        let value = self.forest.get_mut(node_ref)?.item.value.take()?;
+        self.len -= 1;
+        self.prune(node_ref);
+        Some(value)
+    }
+
+    fn prune(&mut self, mut node_ref: Ref) {
+        while node_ref != self.root {
+            let parent_ref = match self.forest.get(node_ref) {
+                Some(node) if node.item.value.is_none() && self.forest.children(node_ref).next().is_none() =>
+                    node.parent,
+                _ =>
+                    return,
+            };
+            self.forest.remove(node_ref);
+            match parent_ref {
+                Some(parent_ref) =>
+                    node_ref = parent_ref,
+                None =>
+                    return,
+            }
+        }
+    }
+}
+
+impl<K, V> Default for Trie<K, V> where K: Clone + Eq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Speculative insertions over a trie kept in a separate layer until committed.
+pub struct TrieLayer<K, V> {
+    forest: Forest2<TrieNode<K, V>, Ref>,
+    added: usize,
+}
+
+impl<K, V> TrieLayer<K, V> where K: Clone + Eq, V: Clone {
+    pub fn new() -> TrieLayer<K, V> {
+        let mut forest = Forest2::new();
+        forest.enable_child_index();
+        TrieLayer { forest, added: 0, }
+    }
+
+    pub fn forest(&self) -> &Forest2<TrieNode<K, V>, Ref> {
+        &self.forest
+    }
+
+    fn child(&self, base: &Trie<K, V>, node_ref: Ref2<Ref>, symbol: &K) -> Option<Ref2<Ref>> {
+        layers!([&self.forest, &base.forest].children(node_ref))
+            .find(|&child_ref| {
+                layers!([&self.forest, &base.forest].get(child_ref))
+                    .and_then(|node| node.item.symbol.as_ref()) == Some(symbol)
+            })
+    }
+
+    pub fn find(&self, base: &Trie<K, V>, key: &[K]) -> Option<Ref2<Ref>> {
+        let root_ref = self.forest.external_ref(base.root);
+        key.iter().try_fold(root_ref, |node_ref, symbol| self.child(base, node_ref, symbol))
+    }
+
+    // `Trie::insert` over `base` leaving it unchanged: new nodes are added to the layer, values of
+    // existing base nodes are shadowed.
+    pub fn insert(&mut self, base: &Trie<K, V>, key: &[K], value: V) -> (Ref2<Ref>, Option<V>) {
+        let mut node_ref = self.forest.external_ref(base.root);
+        for symbol in key {
+            node_ref = match self.child(base, node_ref, symbol) {
+                Some(child_ref) =>
+                    child_ref,
+                None => {
+                    let node = TrieNode { symbol: Some(symbol.clone()), value: None, };
+                    layers!([&mut self.forest, &base.forest].make_node(node_ref, node))
+                },
+            };
+        }
+        let prev_value = layers!([&mut self.forest, &base.forest].shadow_mut(node_ref))
+            .and_then(|node| node.item.value.replace(value));
+        if prev_value.is_none() {
+            self.added += 1;
+        }
+        (node_ref, prev_value)
+    }
+
+    pub fn get<'a>(&'a self, base: &'a Trie<K, V>, key: &[K]) -> Option<&'a V> {
+        let node_ref = self.find(base, key)?;
+        layers!([&self.forest, &base.forest].get(node_ref)).and_then(|node| node.item.value.as_ref())
+    }
+
+    // Number of values the layer adds to the base.
+    pub fn added(&self) -> usize {
+        self.added
+    }
+
+    // Merges the layer down into `base`, refs of the base nodes stay valid.
+    pub fn commit(self, base: &mut Trie<K, V>) {
+        let forest = mem::take(&mut base.forest);
+        base.forest = merge_no_transform(self.forest.merge_down(forest));
+        base.len += self.added;
+    }
+}
+
+impl<K, V> Default for TrieLayer<K, V> where K: Clone + Eq, V: Clone {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Trie,
+        TrieLayer,
+    };
+
+    #[test]
+    fn insert_lookup_remove() {
+        let mut trie = Trie::new();
+        let (tea, _) = trie.insert(b"tea", 1);
+        trie.insert(b"ten", 2);
+        trie.insert(b"to", 3);
+        assert_eq!(trie.insert(b"tea", 4), (tea, Some(1)));
+        assert_eq!(trie.len(), 3);
+        assert_eq!(trie.get(b"tea"), Some(&4));
+        assert_eq!(trie.get(b"te"), None);
+        assert_eq!(trie.get(b"tex"), None);
+        *trie.get_mut(b"to").unwrap() += 10;
+        assert_eq!(trie.value(trie.find(b"to").unwrap()), Some(&13));
+        assert_eq!(trie.key(tea), Some(b"tea".to_vec()));
+
+        assert_eq!(trie.longest_prefix(b"teapot"), Some((3, &4)));
+        assert_eq!(trie.longest_prefix(b"tx"), None);
+        trie.insert(b"", 0);
+        assert_eq!(trie.longest_prefix(b"tx"), Some((0, &0)));
+
+        let mut keys: Vec<_> = trie.prefix_iter(b"te").map(|(node_ref, &value)| (trie.key(node_ref).unwrap(), value)).collect();
+        keys.sort();
+        assert_eq!(keys, vec![(b"tea".to_vec(), 4), (b"ten".to_vec(), 2)]);
+        assert_eq!(trie.prefix_iter(b"").count(), 4);
+        assert_eq!(trie.prefix_iter(b"x").count(), 0);
+
+        let nodes = trie.forest().len();
+        assert_eq!(trie.remove(b"te"), None);
+        assert_eq!(trie.remove(b"ten"), Some(2));
+        assert_eq!(trie.forest().len(), nodes - 1);
+        assert_eq!(trie.value(tea), Some(&4));
+        assert_eq!(trie.remove(b"tea"), Some(4));
+        assert_eq!(trie.forest().len(), nodes - 3);
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.get(b"to"), Some(&13));
+    }
+
+    #[test]
+    fn layer_commit() {
+        let mut trie = Trie::new();
+        let (tea, _) = trie.insert(b"tea", 1);
+        trie.insert(b"to", 2);
+
+        let mut layer = TrieLayer::new();
+        layer.insert(&trie, b"team", 3);
+        assert_eq!(layer.insert(&trie, b"tea", 4).1, Some(1));
+        layer.insert(&trie, b"t", 5);
+        assert_eq!(layer.get(&trie, b"team"), Some(&3));
+        assert_eq!(layer.get(&trie, b"tea"), Some(&4));
+        assert_eq!(layer.get(&trie, b"to"), Some(&2));
+        assert_eq!(layer.added(), 2);
+        assert_eq!(trie.get(b"tea"), Some(&1));
+        assert_eq!(trie.get(b"team"), None);
+
+        layer.commit(&mut trie);
+        assert_eq!(trie.len(), 4);
+        assert_eq!(trie.value(tea), Some(&4));
+        assert_eq!(trie.get(b"team"), Some(&3));
+        assert_eq!(trie.get(b"t"), Some(&5));
+        assert_eq!(trie.longest_prefix(b"teams"), Some((4, &3)));
+        assert_eq!(trie.remove(b"team"), Some(3));
+        assert_eq!(trie.find(b"team"), None);
+        assert!(trie.forest().verify().is_ok());
+    }
+}